@@ -0,0 +1,40 @@
+use std::fmt::Write;
+
+/// An index of `count` made up packages, as large as stable on amd64 at 60000
+///
+/// Every entry has the fields a real one has, a few dependencies and sometimes a `Provides`,
+/// so parsing and lookups do about the same work as on a real index.
+pub fn synthetic_index(count: usize) -> String {
+    let sections = ["utils", "libs", "devel", "net", "x11", "games"];
+    let mut index = String::new();
+    for i in 0..count {
+        let section = sections[i % sections.len()];
+        writeln!(index, "Package: pkg-{i}").unwrap();
+        writeln!(index, "Version: {}.{}-{}", i % 7, i % 13, i % 3).unwrap();
+        writeln!(index, "Architecture: amd64").unwrap();
+        writeln!(index, "Section: {section}").unwrap();
+        writeln!(index, "Installed-Size: {}", 100 + i % 5000).unwrap();
+        writeln!(
+            index,
+            "Maintainer: AOSC OS Maintainers <maintainers@aosc.io>"
+        )
+        .unwrap();
+        writeln!(index, "Filename: pool/stable/main/p/pkg-{i}_amd64.deb").unwrap();
+        writeln!(index, "Size: {}", 1000 + i % 100000).unwrap();
+        writeln!(index, "SHA256: {:064x}", i).unwrap();
+        writeln!(index, "Description: Package number {i}").unwrap();
+        writeln!(
+            index,
+            "Depends: glibc (>= 2.38), pkg-{} | pkg-{}",
+            i / 2,
+            i / 3
+        )
+        .unwrap();
+        if i % 10 == 0 {
+            writeln!(index, "Provides: virtual-{}", i % 100).unwrap();
+        }
+        writeln!(index).unwrap();
+    }
+
+    index
+}
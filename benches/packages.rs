@@ -0,0 +1,43 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use libaosc::packages::Packages;
+
+mod common;
+
+/// Parsing isn't meant to get slower for callers that never look anything up
+fn parse(c: &mut Criterion) {
+    let index = common::synthetic_index(60000);
+    let mut group = c.benchmark_group("parse");
+    group.sample_size(10);
+    group.bench_function("60000 entries", |b| {
+        b.iter(|| black_box(&index).parse::<Packages>().unwrap())
+    });
+    group.finish();
+}
+
+/// The first lookup builds the name index, later ones only use it
+fn lookup(c: &mut Criterion) {
+    let entries = common::synthetic_index(60000)
+        .parse::<Packages>()
+        .unwrap()
+        .into_inner();
+    let mut group = c.benchmark_group("lookup");
+    group.bench_function("first of 60000 entries", |b| {
+        b.iter_batched(
+            || Packages::from(entries.clone()),
+            |pkgs| pkgs.get(black_box("pkg-30000")).is_some(),
+            BatchSize::LargeInput,
+        )
+    });
+
+    let pkgs = Packages::from(entries);
+    pkgs.get("pkg-0");
+    group.bench_function("later of 60000 entries", |b| {
+        b.iter(|| pkgs.get(black_box("pkg-30000")).is_some())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse, lookup);
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use libaosc::{packages::Packages, query::Query};
+
+mod common;
+
+/// Filtering the whole index has to stay fast enough to run on every keystroke
+fn filter(c: &mut Criterion) {
+    let pkgs: Packages = common::synthetic_index(60000).parse().unwrap();
+    let queries = [
+        ("name", Query::new().name("pkg-30000")),
+        ("name prefix", Query::new().name_prefix("pkg-3000")),
+        (
+            "section and name substring",
+            Query::new().section("utils").name_contains("99"),
+        ),
+        (
+            "maintainer and size range",
+            Query::new()
+                .maintainer_contains("AOSC")
+                .size_range(50000..60000),
+        ),
+        (
+            "or of nots",
+            (!Query::new().architecture("amd64")).or(!Query::new().section("libs")),
+        ),
+        ("empty", Query::new()),
+    ];
+
+    let mut group = c.benchmark_group("filter 60000 entries");
+    for (name, query) in &queries {
+        group.bench_function(*name, |b| b.iter(|| pkgs.filter(black_box(query)).len()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, filter);
+criterion_main!(benches);
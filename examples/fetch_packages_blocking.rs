@@ -1,11 +1,11 @@
 use std::fs::create_dir_all;
 
-use libaosc::packages::FetchPackages;
+use libaosc::packages::{Compression, FetchPackages};
 
 fn main() {
     create_dir_all("./test").unwrap();
 
-    let fetch = FetchPackages::new(true, "./test", None);
+    let fetch = FetchPackages::new(Compression::Xz, "./test", None);
     let pkgs = fetch.fetch_packages("amd64", "stable").unwrap();
-    dbg!(pkgs.0.first());
+    dbg!(pkgs.first());
 }
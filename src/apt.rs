@@ -0,0 +1,127 @@
+use deb822_lossless::Deb822;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Suites of the AOSC OS repository that the APT sources under `sysroot` track, in the order
+/// they are configured
+///
+/// Reads `etc/apt/sources.list` and the `.list` and `.sources` files in
+/// `etc/apt/sources.list.d`, the latter in name order like APT does. Pass `/` for the running
+/// system, or the mount point of a chroot. Only binary (`deb`) entries count, and only those
+/// of a repository tree named `debs` or `debs-retro`, which is how `repo.aosc.io` and its
+/// mirrors are laid out. Disabled entries and flat repositories are skipped, and each suite
+/// is listed once.
+pub fn aosc_suites(sysroot: &Path) -> io::Result<Vec<String>> {
+    let apt = sysroot.join("etc/apt");
+    let mut files = vec![apt.join("sources.list")];
+    match fs::read_dir(apt.join("sources.list.d")) {
+        Ok(entries) => {
+            let mut parts = entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<PathBuf>>>()?;
+            parts.sort();
+            files.extend(parts);
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let mut suites: Vec<String> = vec![];
+    for path in files {
+        let deb822 = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("list") => false,
+            Some("sources") => true,
+            _ => continue,
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let entries = if deb822 {
+            deb822_entries(&text).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("malformed {}: {e}", path.display()),
+                )
+            })?
+        } else {
+            one_line_entries(&text)
+        };
+
+        for (_, suite) in entries
+            .into_iter()
+            .filter(|(uri, suite)| is_aosc_repo(uri) && !suite.ends_with('/'))
+        {
+            if !suites.contains(&suite) {
+                suites.push(suite);
+            }
+        }
+    }
+
+    Ok(suites)
+}
+
+/// The URI and suite of each `deb` line of a `sources.list` file
+fn one_line_entries(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .filter_map(|line| {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            if words.next()? != "deb" {
+                return None;
+            }
+            let mut uri = words.next()?;
+            // Options such as `[arch=amd64 signed-by=...]` come before the URI.
+            if uri.starts_with('[') {
+                while !uri.ends_with(']') {
+                    uri = words.next()?;
+                }
+                uri = words.next()?;
+            }
+
+            Some((uri.to_string(), words.next()?.to_string()))
+        })
+        .collect()
+}
+
+/// Every combination of URI and suite of the enabled `deb` stanzas of a `.sources` file
+fn deb822_entries(text: &str) -> Result<Vec<(String, String)>, String> {
+    let sources: Deb822 = text.parse().map_err(|e| format!("{e:?}"))?;
+    let mut entries = vec![];
+
+    for para in sources.paragraphs() {
+        let has_deb = para
+            .get("Types")
+            .is_some_and(|types| types.split_whitespace().any(|t| t == "deb"));
+        let disabled = para
+            .get("Enabled")
+            .is_some_and(|enabled| enabled.trim() == "no");
+        if !has_deb || disabled {
+            continue;
+        }
+
+        let (uris, suites) = (
+            para.get("URIs").unwrap_or_default(),
+            para.get("Suites").unwrap_or_default(),
+        );
+        for uri in uris.split_whitespace() {
+            for suite in suites.split_whitespace() {
+                entries.push((uri.to_string(), suite.to_string()));
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Whether `uri` is the root of an AOSC OS repository tree, such as
+/// `https://repo.aosc.io/debs/`
+fn is_aosc_repo(uri: &str) -> bool {
+    matches!(
+        uri.trim_end_matches('/').rsplit('/').next(),
+        Some("debs" | "debs-retro")
+    )
+}
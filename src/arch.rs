@@ -36,6 +36,8 @@ pub fn get_arch_name() -> Option<&'static str> {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AOSCBranch {
     Mainline,
     Afterglow,
@@ -0,0 +1,260 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+use crate::{
+    dependency::{provided, Dependency, DependencyGroup, DependencyParseError, RelationKinds},
+    packages::{Package, Packages},
+};
+
+/// Packages needed to install some roots, see [`Packages::dependency_closure`]
+#[derive(Debug, Clone)]
+pub struct Closure<'a> {
+    /// The roots and everything they depend on, in the order they were reached
+    pub packages: Vec<&'a Package>,
+}
+
+impl Closure<'_> {
+    /// Sum of the `.deb` sizes in bytes
+    pub fn download_size(&self) -> u64 {
+        self.packages.iter().map(|pkg| pkg.size).sum()
+    }
+
+    /// Sum of `Installed-Size` in KiB, counting packages without one as 0
+    pub fn installed_size(&self) -> u64 {
+        self.packages
+            .iter()
+            .map(|pkg| pkg.install_size.unwrap_or(0))
+            .sum()
+    }
+}
+
+/// Dependencies [`Packages::dependency_closure`] couldn't satisfy
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("Unresolvable dependencies: {}", join(.unresolved))]
+pub struct ResolveError {
+    pub unresolved: Vec<Unresolved>,
+}
+
+/// A dependency that can't be satisfied or parsed, and how it was reached
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unresolved {
+    /// The dependency as written, or the parse error of the field it is in
+    pub dependency: String,
+    /// Packages from a root to the one with the dependency, empty for a root itself
+    pub chain: Vec<String>,
+    /// The package already in the closure, as `name version`, if only another version of it
+    /// would satisfy the dependency
+    pub conflict: Option<String>,
+}
+
+impl fmt::Display for Unresolved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.dependency)?;
+        if self.chain.is_empty() {
+            f.write_str(" (requested")?;
+        } else {
+            write!(f, " (needed by {}", self.chain.join(" -> "))?;
+        }
+        if let Some(conflict) = &self.conflict {
+            write!(f, ", but {conflict} is already chosen")?;
+        }
+
+        f.write_str(")")
+    }
+}
+
+fn join(unresolved: &[Unresolved]) -> String {
+    unresolved
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The packages `roots` need to be installed, the roots included, in the order they were
+/// reached
+///
+/// Of several alternatives the first one that can be satisfied is taken, and of several
+/// versions of a package the newest one that satisfies the dependency. Virtual packages are
+/// satisfied by the packages that provide them, and `Pre-Depends` count like `Depends`.
+/// Only the relationships in `kinds` are followed, and recommended or suggested packages
+/// that aren't available are skipped. A package is only chosen in one version, so cycles
+/// end there, and a later dependency on another version of it is a conflict. Fails with
+/// every other dependency that can't be satisfied or parsed.
+pub(crate) fn resolve<'a>(
+    pkgs: &'a Packages,
+    roots: &[&str],
+    kinds: RelationKinds,
+) -> Result<Closure<'a>, ResolveError> {
+    let mut chosen = Chosen::default();
+    // Positions in `chosen` of the packages whose relationships are still to be followed
+    let mut queue = VecDeque::new();
+    let mut unresolved = vec![];
+
+    for &root in roots {
+        let pick = match root.parse::<Dependency>() {
+            Ok(dep) => chosen.pick(pkgs, std::slice::from_ref(&dep), None),
+            Err(_) => Pick::Missing,
+        };
+        match pick {
+            Pick::Satisfied => {}
+            Pick::Added(i) => queue.push_back(i),
+            Pick::Missing | Pick::Conflict(_) => {
+                unresolved.push(chosen.unresolved(root.to_string(), None, &pick))
+            }
+        }
+    }
+
+    while let Some(current) = queue.pop_front() {
+        let groups = match relations(chosen.packages[current], kinds) {
+            Ok(groups) => groups,
+            Err(e) => {
+                let pick = Pick::Missing;
+                unresolved.push(chosen.unresolved(e.to_string(), Some(current), &pick));
+                continue;
+            }
+        };
+        for (group, required) in groups {
+            let pick = chosen.pick(pkgs, &group.0, Some(current));
+            match pick {
+                Pick::Satisfied => {}
+                Pick::Added(i) => queue.push_back(i),
+                Pick::Missing | Pick::Conflict(_) if required => {
+                    unresolved.push(chosen.unresolved(group.to_string(), Some(current), &pick))
+                }
+                Pick::Missing | Pick::Conflict(_) => {}
+            }
+        }
+    }
+
+    if unresolved.is_empty() {
+        Ok(Closure {
+            packages: chosen.packages,
+        })
+    } else {
+        Err(ResolveError { unresolved })
+    }
+}
+
+/// The packages chosen so far, with what they were pulled in by and what they provide
+#[derive(Default)]
+struct Chosen<'a> {
+    packages: Vec<&'a Package>,
+    /// Position in `packages` of the package that pulled in each one, none for the roots
+    parents: Vec<Option<usize>>,
+    /// Position in `packages` of the package chosen under each name
+    by_name: HashMap<&'a str, usize>,
+    /// Positions in `packages` of the packages providing each virtual package
+    providers: HashMap<String, Vec<usize>>,
+}
+
+/// How a group of alternatives was satisfied, see [`Chosen::pick`]
+enum Pick<'a> {
+    /// By a package already chosen
+    Satisfied,
+    /// By the package newly chosen at this position
+    Added(usize),
+    /// Not at all, as the package that would satisfy it is already chosen in this version
+    Conflict(&'a Package),
+    Missing,
+}
+
+impl<'a> Chosen<'a> {
+    /// Satisfy one of `alternatives`, choosing the package for the first one that can be if
+    /// none already is, as needed by the package at `parent`
+    fn pick(
+        &mut self,
+        pkgs: &'a Packages,
+        alternatives: &[Dependency],
+        parent: Option<usize>,
+    ) -> Pick<'a> {
+        if alternatives.iter().any(|dep| self.meets(dep)) {
+            return Pick::Satisfied;
+        }
+
+        let mut conflict = None;
+        for found in alternatives.iter().filter_map(|dep| pkgs.satisfies(dep)) {
+            match self.by_name.get(found.package.as_str()) {
+                // Another version, or it would have met the dependency above.
+                Some(&i) => {
+                    conflict.get_or_insert(self.packages[i]);
+                }
+                None => return Pick::Added(self.add(found, parent)),
+            }
+        }
+
+        conflict.map_or(Pick::Missing, Pick::Conflict)
+    }
+
+    /// Whether a package already chosen meets `dep`
+    fn meets(&self, dep: &Dependency) -> bool {
+        let named = self.by_name.get(dep.name.as_str()).into_iter();
+        let providers = self.providers.get(&dep.name).into_iter().flatten();
+
+        named
+            .chain(providers)
+            .any(|&i| dep.is_met_by(self.packages[i]))
+    }
+
+    fn add(&mut self, pkg: &'a Package, parent: Option<usize>) -> usize {
+        let i = self.packages.len();
+        self.packages.push(pkg);
+        self.parents.push(parent);
+        self.by_name.insert(&pkg.package, i);
+        for name in provided(pkg) {
+            self.providers.entry(name.name).or_default().push(i);
+        }
+
+        i
+    }
+
+    /// The failed `pick` of `dependency` of the package at `at`, none for a root
+    fn unresolved(&self, dependency: String, at: Option<usize>, pick: &Pick<'_>) -> Unresolved {
+        let mut chain = vec![];
+        let mut at = at;
+        while let Some(i) = at {
+            chain.push(self.packages[i].package.clone());
+            at = self.parents[i];
+        }
+        chain.reverse();
+        let conflict = match pick {
+            Pick::Conflict(pkg) => Some(format!("{} {}", pkg.package, pkg.version)),
+            _ => None,
+        };
+
+        Unresolved {
+            dependency,
+            chain,
+            conflict,
+        }
+    }
+}
+
+/// The groups of `pkg` to follow for `kinds`, each with whether it has to be satisfied
+///
+/// A malformed `Recommends` or `Suggests` is treated like a missing one.
+fn relations(
+    pkg: &Package,
+    kinds: RelationKinds,
+) -> Result<Vec<(DependencyGroup, bool)>, DependencyParseError> {
+    let mut groups = vec![];
+    if kinds.contains(RelationKinds::DEPENDS) {
+        let required = pkg
+            .parse_pre_depends()?
+            .into_iter()
+            .chain(pkg.parse_depends()?);
+        groups.extend(required.map(|group| (group, true)));
+    }
+    if kinds.contains(RelationKinds::RECOMMENDS) {
+        let recommends = pkg.parse_recommends().unwrap_or_default();
+        groups.extend(recommends.into_iter().map(|group| (group, false)));
+    }
+    if kinds.contains(RelationKinds::SUGGESTS) {
+        let suggests = pkg.parse_suggests().unwrap_or_default();
+        groups.extend(suggests.into_iter().map(|group| (group, false)));
+    }
+
+    Ok(groups)
+}
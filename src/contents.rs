@@ -0,0 +1,120 @@
+use std::{collections::HashMap, str::FromStr};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseContentsError {
+    #[error(transparent)]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error("Malformed Contents entry on line {0}")]
+    MalformedLine(usize),
+}
+
+/// A package listed as owning a file, written as `section/package` in the index
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Owner {
+    /// Section the package belongs to, possibly with an area such as `contrib/net`
+    pub section: Option<String>,
+    pub package: String,
+}
+
+impl Owner {
+    fn from_location(s: &str) -> Self {
+        match s.rsplit_once('/') {
+            Some((section, package)) => Owner {
+                section: Some(section.to_string()),
+                package: package.to_string(),
+            },
+            None => Owner {
+                section: None,
+                package: s.to_string(),
+            },
+        }
+    }
+}
+
+/// A parsed `Contents-<arch>` index, mapping file paths to the packages that ship them
+///
+/// Paths are stored as they appear in the index, without a leading `/`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Contents(HashMap<String, Vec<Owner>>);
+
+impl Contents {
+    /// Packages shipping `path`, with or without a leading `/`
+    pub fn packages_owning(&self, path: &str) -> &[Owner] {
+        self.0
+            .get(path.trim_start_matches('/'))
+            .map(|owners| owners.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// All entries of the index, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[Owner])> {
+        self.0
+            .iter()
+            .map(|(path, owners)| (path.as_str(), owners.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Whether `line` is the `FILE  LOCATION` line ending the free-form header of old indices
+fn is_header_end(line: &str) -> bool {
+    let mut words = line.split_whitespace();
+
+    words.next() == Some("FILE") && words.next() == Some("LOCATION") && words.next().is_none()
+}
+
+impl FromStr for Contents {
+    type Err = ParseContentsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let skip = s
+            .lines()
+            .position(is_header_end)
+            .map(|pos| pos + 1)
+            .unwrap_or(0);
+        let mut res: HashMap<String, Vec<Owner>> = HashMap::new();
+
+        for (no, line) in s.lines().enumerate().skip(skip) {
+            let line = line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+
+            // Paths may contain spaces, the owners never do.
+            let (path, owners) = line
+                .rsplit_once(char::is_whitespace)
+                .map(|(path, owners)| (path.trim_end(), owners))
+                .filter(|(path, _)| !path.is_empty())
+                .ok_or(ParseContentsError::MalformedLine(no + 1))?;
+
+            res.entry(path.trim_start_matches('/').to_string())
+                .or_default()
+                .extend(
+                    owners
+                        .split(',')
+                        .filter(|o| !o.is_empty())
+                        .map(Owner::from_location),
+                );
+        }
+
+        Ok(Self(res))
+    }
+}
+
+impl TryFrom<&[u8]> for Contents {
+    type Error = ParseContentsError;
+
+    fn try_from(input: &[u8]) -> Result<Self, Self::Error> {
+        let s = std::str::from_utf8(input)?;
+        let contents: Contents = s.parse()?;
+
+        Ok(contents)
+    }
+}
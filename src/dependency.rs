@@ -0,0 +1,394 @@
+use std::{
+    cmp::Ordering,
+    fmt,
+    ops::{BitOr, BitOrAssign},
+    str::FromStr,
+};
+
+use crate::{packages::Package, version::PkgVersion};
+
+#[derive(Debug, thiserror::Error)]
+pub enum DependencyParseError {
+    #[error("Empty alternative in {0}")]
+    EmptyAlternative(String),
+    #[error("Invalid package name in {0}")]
+    InvalidName(String),
+    #[error("Invalid version constraint in {0}")]
+    InvalidConstraint(String),
+    #[error("Invalid architecture list in {0}")]
+    InvalidArchitectures(String),
+    #[error("Unexpected text in {0}")]
+    TrailingText(String),
+    #[error("Invalid Provides entry {entry} of package {package}")]
+    InvalidProvides { package: String, entry: String },
+}
+
+/// Version comparison of a relationship, such as the `>=` of `foo (>= 1.0)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Relation {
+    /// `<<`
+    Earlier,
+    /// `<=`, also written as the obsolete `<`
+    EarlierOrEqual,
+    /// `=`
+    Exactly,
+    /// `>=`, also written as the obsolete `>`
+    LaterOrEqual,
+    /// `>>`
+    Later,
+}
+
+impl Relation {
+    /// Whether a version that compares to the required one as `ord` meets this relation
+    fn holds(self, ord: Ordering) -> bool {
+        match self {
+            Relation::Earlier => ord == Ordering::Less,
+            Relation::EarlierOrEqual => ord != Ordering::Greater,
+            Relation::Exactly => ord == Ordering::Equal,
+            Relation::LaterOrEqual => ord != Ordering::Less,
+            Relation::Later => ord == Ordering::Greater,
+        }
+    }
+}
+
+impl FromStr for Relation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "<<" => Relation::Earlier,
+            "<=" | "<" => Relation::EarlierOrEqual,
+            "=" => Relation::Exactly,
+            ">=" | ">" => Relation::LaterOrEqual,
+            ">>" => Relation::Later,
+            _ => return Err(()),
+        })
+    }
+}
+
+impl fmt::Display for Relation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Relation::Earlier => "<<",
+            Relation::EarlierOrEqual => "<=",
+            Relation::Exactly => "=",
+            Relation::LaterOrEqual => ">=",
+            Relation::Later => ">>",
+        })
+    }
+}
+
+/// Relationship fields to follow when resolving dependencies, combined with `|`
+///
+/// The default only follows `Depends` and `Pre-Depends`, which always have to be satisfied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RelationKinds(u8);
+
+impl RelationKinds {
+    /// `Depends` and `Pre-Depends`
+    pub const DEPENDS: Self = Self(1);
+    pub const RECOMMENDS: Self = Self(1 << 1);
+    pub const SUGGESTS: Self = Self(1 << 2);
+
+    /// Whether every kind of `other` is also one of these
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Default for RelationKinds {
+    fn default() -> Self {
+        Self::DEPENDS
+    }
+}
+
+impl BitOr for RelationKinds {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for RelationKinds {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Field of a package a dependency is listed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DependencyField {
+    PreDepends,
+    Depends,
+    Recommends,
+}
+
+impl fmt::Display for DependencyField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DependencyField::PreDepends => "Pre-Depends",
+            DependencyField::Depends => "Depends",
+            DependencyField::Recommends => "Recommends",
+        })
+    }
+}
+
+/// A single package of a relationship field, such as `libfoo:any (>= 1:2.0) [amd64]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Dependency {
+    pub name: String,
+    /// The comparison and the version it compares to
+    pub constraint: Option<(Relation, PkgVersion)>,
+    /// Architecture after the name, such as the `any` of `python3:any`
+    pub arch_qualifier: Option<String>,
+    /// Architectures the dependency is restricted to, such as `[amd64 !i486]`, empty if it
+    /// applies to all of them
+    pub architectures: Vec<String>,
+}
+
+impl FromStr for Dependency {
+    type Err = DependencyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(DependencyParseError::EmptyAlternative(s.to_string()));
+        }
+        let end = s
+            .find(|c: char| c.is_whitespace() || "([".contains(c))
+            .unwrap_or(s.len());
+        let (name, mut rest) = s.split_at(end);
+        let (name, arch_qualifier) = match name.split_once(':') {
+            Some((name, arch)) if is_valid_name(arch) => (name, Some(arch.to_string())),
+            Some(_) => return Err(DependencyParseError::InvalidName(s.to_string())),
+            None => (name, None),
+        };
+        if !is_valid_name(name) {
+            return Err(DependencyParseError::InvalidName(s.to_string()));
+        }
+
+        let mut constraint = None;
+        rest = rest.trim_start();
+        if let Some(inner) = rest.strip_prefix('(') {
+            let (inner, after) = inner
+                .split_once(')')
+                .ok_or_else(|| DependencyParseError::InvalidConstraint(s.to_string()))?;
+            let inner = inner.trim();
+            // The operator may or may not be separated from the version.
+            let pos = inner
+                .find(|c: char| !"<=>".contains(c))
+                .unwrap_or(inner.len());
+            let (op, version) = inner.split_at(pos);
+            match (op.parse(), version.parse()) {
+                (Ok(relation), Ok(version)) => constraint = Some((relation, version)),
+                _ => return Err(DependencyParseError::InvalidConstraint(s.to_string())),
+            }
+            rest = after.trim_start();
+        }
+
+        let mut architectures = vec![];
+        if let Some(inner) = rest.strip_prefix('[') {
+            let (inner, after) = inner
+                .split_once(']')
+                .ok_or_else(|| DependencyParseError::InvalidArchitectures(s.to_string()))?;
+            architectures = inner.split_whitespace().map(str::to_string).collect();
+            if architectures.is_empty() {
+                return Err(DependencyParseError::InvalidArchitectures(s.to_string()));
+            }
+            rest = after.trim_start();
+        }
+
+        if !rest.is_empty() {
+            return Err(DependencyParseError::TrailingText(s.to_string()));
+        }
+
+        Ok(Dependency {
+            name: name.to_string(),
+            constraint,
+            arch_qualifier,
+            architectures,
+        })
+    }
+}
+
+impl Dependency {
+    /// Whether `version` meets the version constraint, which any version does without one
+    pub fn satisfied_by(&self, version: &PkgVersion) -> bool {
+        match &self.constraint {
+            Some((relation, required)) => relation.holds(version.cmp(required)),
+            None => true,
+        }
+    }
+
+    /// Whether `pkg` is this package in a matching version, or provides it
+    ///
+    /// A virtual package only has a version if it is provided as `name (= version)`, so only
+    /// such a `Provides` meets a dependency with a constraint. Architecture qualifiers are
+    /// ignored, as they don't matter within a single index.
+    pub fn is_met_by(&self, pkg: &Package) -> bool {
+        if pkg.package == self.name {
+            return self.constraint.is_none()
+                || pkg
+                    .parsed_version()
+                    .is_ok_and(|version| self.satisfied_by(&version));
+        }
+
+        provided(pkg).any(|provided| {
+            provided.name == self.name && self.accepts_provided(provided.version.as_ref())
+        })
+    }
+
+    /// Whether a virtual package of this name provided in `version` meets the dependency
+    pub(crate) fn accepts_provided(&self, version: Option<&PkgVersion>) -> bool {
+        match (&self.constraint, version) {
+            (None, _) => true,
+            (Some(_), Some(version)) => self.satisfied_by(version),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if let Some(arch) = &self.arch_qualifier {
+            write!(f, ":{arch}")?;
+        }
+        if let Some((relation, version)) = &self.constraint {
+            write!(f, " ({relation} {version})")?;
+        }
+        if !self.architectures.is_empty() {
+            write!(f, " [{}]", self.architectures.join(" "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Alternatives separated by `|`, of which one has to be satisfied
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DependencyGroup(pub Vec<Dependency>);
+
+impl FromStr for DependencyGroup {
+    type Err = DependencyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let alternatives = s
+            .split('|')
+            .map(|alternative| match alternative.trim() {
+                "" => Err(DependencyParseError::EmptyAlternative(s.trim().to_string())),
+                alternative => alternative.parse(),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(DependencyGroup(alternatives))
+    }
+}
+
+impl fmt::Display for DependencyGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, dep) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" | ")?;
+            }
+            write!(f, "{dep}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a relationship field such as `Depends` or `Provides` into its comma-separated
+/// groups
+///
+/// Empty entries, like the one after a trailing comma, are skipped, so an empty field has no
+/// groups.
+pub fn parse_relationships(field: &str) -> Result<Vec<DependencyGroup>, DependencyParseError> {
+    field
+        .split(',')
+        .filter(|group| !group.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Render groups as a relationship field, the normalized form of what they were parsed from
+pub fn format_relationships(groups: &[DependencyGroup]) -> String {
+    groups
+        .iter()
+        .map(|group| group.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A virtual package of a `Provides` field, such as `foo (= 1.2)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProvidedName {
+    pub name: String,
+    /// The version it is provided in, only set for a versioned `Provides`
+    pub version: Option<PkgVersion>,
+}
+
+impl fmt::Display for ProvidedName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if let Some(version) = &self.version {
+            write!(f, " (= {version})")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse the `Provides` field of `package` into the names it provides
+///
+/// Unlike other relationship fields, entries can't have alternatives, architectures or a
+/// relation other than `=`. Empty entries are skipped like in [`parse_relationships`].
+pub fn parse_provided_names(
+    package: &str,
+    field: &str,
+) -> Result<Vec<ProvidedName>, DependencyParseError> {
+    field
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let invalid = || DependencyParseError::InvalidProvides {
+                package: package.to_string(),
+                entry: entry.to_string(),
+            };
+            let dep = entry.parse::<Dependency>().map_err(|_| invalid())?;
+            if dep.arch_qualifier.is_some() || !dep.architectures.is_empty() {
+                return Err(invalid());
+            }
+            let version = match dep.constraint {
+                Some((Relation::Exactly, version)) => Some(version),
+                Some(_) => return Err(invalid()),
+                None => None,
+            };
+
+            Ok(ProvidedName {
+                name: dep.name,
+                version,
+            })
+        })
+        .collect()
+}
+
+/// The virtual packages `pkg` provides, none if its `Provides` is malformed
+pub(crate) fn provided(pkg: &Package) -> impl Iterator<Item = ProvidedName> {
+    pkg.parse_provides().unwrap_or_default().into_iter()
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-._".contains(c))
+}
@@ -0,0 +1,146 @@
+use std::{cmp::Ordering, collections::HashMap, fmt};
+
+use crate::{
+    packages::{Package, Packages},
+    version::compare_versions,
+};
+
+/// What changed between two snapshots of an index, see [`Packages::diff`]
+///
+/// Entries are matched by name and architecture, and of several versions of one the newest
+/// is compared. Each group is in the order of the snapshot its entries come from, the new
+/// one except for removed packages.
+#[derive(Debug, Clone, Default)]
+pub struct PackagesDiff<'a> {
+    pub added: Vec<&'a Package>,
+    pub removed: Vec<&'a Package>,
+    /// Packages with a different version
+    pub changed: Vec<VersionChange<'a>>,
+    /// Packages with the same version but a different `.deb`, which usually means it was
+    /// rebuilt in place or the mirror is broken
+    pub rebuilt: Vec<Rebuilt<'a>>,
+}
+
+/// A package whose version changed between two snapshots
+#[derive(Debug, Clone)]
+pub struct VersionChange<'a> {
+    pub old: &'a Package,
+    pub new: &'a Package,
+}
+
+impl VersionChange<'_> {
+    /// Whether the new version is later than the old one, otherwise it is a downgrade
+    pub fn is_upgrade(&self) -> bool {
+        compare_versions(&self.new.version, &self.old.version) == Ordering::Greater
+    }
+}
+
+/// A package whose `.deb` changed between two snapshots without a new version
+#[derive(Debug, Clone)]
+pub struct Rebuilt<'a> {
+    pub old: &'a Package,
+    pub new: &'a Package,
+}
+
+impl PackagesDiff<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.rebuilt.is_empty()
+    }
+}
+
+/// One line per change, such as `upgraded foo (amd64): 1.0 -> 1.1`
+impl fmt::Display for PackagesDiff<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for pkg in &self.added {
+            writeln!(
+                f,
+                "added {} ({}): {}",
+                pkg.package, pkg.architecture, pkg.version
+            )?;
+        }
+        for pkg in &self.removed {
+            writeln!(
+                f,
+                "removed {} ({}): {}",
+                pkg.package, pkg.architecture, pkg.version
+            )?;
+        }
+        for change in &self.changed {
+            writeln!(
+                f,
+                "{} {} ({}): {} -> {}",
+                if change.is_upgrade() {
+                    "upgraded"
+                } else {
+                    "downgraded"
+                },
+                change.new.package,
+                change.new.architecture,
+                change.old.version,
+                change.new.version
+            )?;
+        }
+        for rebuilt in &self.rebuilt {
+            writeln!(
+                f,
+                "rebuilt {} ({}): {}, SHA256 {} -> {}",
+                rebuilt.new.package,
+                rebuilt.new.architecture,
+                rebuilt.new.version,
+                rebuilt.old.sha256,
+                rebuilt.new.sha256
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn diff<'a>(old: &'a Packages, new: &'a Packages) -> PackagesDiff<'a> {
+    let old_newest = newest_by_key(old);
+    let new_newest = newest_by_key(new);
+    let mut diff = PackagesDiff::default();
+
+    for pkg in new.iter() {
+        let key = (pkg.package.as_str(), pkg.architecture.as_str());
+        if !std::ptr::eq(new_newest[&key], pkg) {
+            continue;
+        }
+        match old_newest.get(&key) {
+            None => diff.added.push(pkg),
+            Some(old) if compare_versions(&old.version, &pkg.version) != Ordering::Equal => {
+                diff.changed.push(VersionChange { old, new: pkg })
+            }
+            Some(old) if old.sha256 != pkg.sha256 => diff.rebuilt.push(Rebuilt { old, new: pkg }),
+            Some(_) => {}
+        }
+    }
+    for pkg in old.iter() {
+        let key = (pkg.package.as_str(), pkg.architecture.as_str());
+        if std::ptr::eq(old_newest[&key], pkg) && !new_newest.contains_key(&key) {
+            diff.removed.push(pkg);
+        }
+    }
+
+    diff
+}
+
+/// The newest entry of each name and architecture
+fn newest_by_key(pkgs: &Packages) -> HashMap<(&str, &str), &Package> {
+    let mut newest: HashMap<_, &Package> = HashMap::new();
+    for pkg in pkgs.iter() {
+        newest
+            .entry((pkg.package.as_str(), pkg.architecture.as_str()))
+            .and_modify(|current| {
+                if compare_versions(&pkg.version, &current.version) == Ordering::Greater {
+                    *current = pkg;
+                }
+            })
+            .or_insert(pkg);
+    }
+
+    newest
+}
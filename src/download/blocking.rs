@@ -0,0 +1,1821 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, BufRead, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime},
+};
+
+use sha2::{Digest, Sha256};
+
+use http::{
+    header::{CACHE_CONTROL, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, RANGE},
+    StatusCode,
+};
+#[cfg(feature = "blocking")]
+use reqwest::blocking::{Client, RequestBuilder, Response};
+
+#[cfg(feature = "blocking-ureq")]
+use super::blocking_ureq::{Client, RequestBuilder, Response};
+
+use super::{
+    check_digest, check_fresh, check_length, check_package, check_sha256, complete_package,
+    content_length, discard_partial, file_url_path, is_content_range, local_copy_age, merge_all,
+    package_path, package_url_path, partial_path,
+    pdiff::{apply_ed, DiffIndex},
+    redact_url, strip_credentials, temp_path, Compression, Downloaded, FetchOptions,
+    FetchPackagesError, FetchReport, FetchSource, FetcherBuilder, FileLocks, FileMeta, Index,
+    IndexFile, MirrorFailures, OfflinePolicy, RepoLayout, SizeLimit, Snapshot, SnapshotParts,
+    TempPath, Transfer, WithBytes,
+};
+use crate::{
+    apt::aosc_suites,
+    arch::{aosc_branch, AOSCBranch},
+    contents::Contents,
+    packages::{Package, Packages},
+    release::{Release, ReleaseFile},
+    sources::Sources,
+};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Files being downloaded by any fetcher in this process
+static DOWNLOAD_LOCKS: FileLocks<Mutex<()>> = FileLocks::new();
+
+/// Fetcher of indices and packages from AOSC mirrors
+///
+/// Cloning is cheap: clones share the HTTP client and its connection pool, as well as the
+/// rate limit, and can be used from several threads at once.
+#[derive(Clone)]
+pub struct FetchPackages {
+    client: Client,
+    options: FetchOptions,
+}
+
+#[cfg(feature = "blocking")]
+impl FetcherBuilder<reqwest::blocking::Client> {
+    /// Build the blocking fetcher
+    ///
+    /// Unless [`FetcherBuilder::timeout`] is set, reqwest's default of 30 seconds for each
+    /// read of the blocking client applies.
+    pub fn build(mut self) -> Result<FetchPackages, FetchPackagesError> {
+        let client = match self.custom_client()? {
+            Some(client) => client,
+            None => {
+                let mut client = reqwest::blocking::Client::builder()
+                    .user_agent(self.user_agent_header())
+                    .default_headers(self.build_headers()?)
+                    .redirect(self.redirect_policy());
+                if let Some(timeout) = self.connect_timeout {
+                    client = client.connect_timeout(timeout);
+                }
+                if let Some(timeout) = self.timeout {
+                    client = client.timeout(timeout);
+                }
+                for cert in self.build_root_certificates()? {
+                    client = client.add_root_certificate(cert);
+                }
+                if let Some(identity) = self.build_identity()? {
+                    client = client.use_rustls_tls().identity(identity);
+                }
+                if self.accept_invalid_certs {
+                    client = client.danger_accept_invalid_certs(true);
+                }
+                match self.preferring_resolver() {
+                    Some(resolver) => client = client.dns_resolver(resolver),
+                    None => {
+                        for (host, addrs) in &self.resolve {
+                            client = client.resolve_to_addrs(host, addrs);
+                        }
+                    }
+                }
+                match self.build_proxy()? {
+                    Some(proxy) => client = client.proxy(proxy),
+                    None if !self.system_proxy => client = client.no_proxy(),
+                    None => {}
+                }
+
+                client.build()?
+            }
+        };
+
+        Ok(FetchPackages {
+            client,
+            options: self.options()?,
+        })
+    }
+}
+
+#[cfg(feature = "blocking-ureq")]
+impl FetcherBuilder<ureq::Agent> {
+    /// Build the blocking fetcher
+    ///
+    /// Unless [`FetcherBuilder::timeout`] is set, connecting and waiting for the response
+    /// headers each time out after 30 seconds, like reqwest's default for its blocking client.
+    pub fn build(mut self) -> Result<FetchPackages, FetchPackagesError> {
+        Ok(FetchPackages {
+            client: self.build_client()?,
+            options: self.options()?,
+        })
+    }
+}
+
+impl FetchPackages {
+    /// # Panics
+    ///
+    /// Panics if the mirror URL is invalid, see [`FetcherBuilder::mirror_url`]. Use
+    /// [`Self::builder`] to get the error instead.
+    pub fn new<P: AsRef<Path>>(
+        compression: Compression,
+        download_to: P,
+        mirror_url: Option<&str>,
+    ) -> Self {
+        let mut builder = Self::builder()
+            .compression(compression)
+            .download_to(download_to);
+        if let Some(mirror_url) = mirror_url {
+            builder = builder.mirror_url(mirror_url);
+        }
+
+        builder.build().unwrap()
+    }
+
+    /// Fetcher for the repository of the AOSC OS branch this system runs, downloading to
+    /// `download_to`
+    ///
+    /// AOSC OS/Retro systems download from its own repository, see
+    /// [`FetcherBuilder::aosc_branch`]; any other system from the mainline one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the HTTP client can't be created. Use [`Self::builder`] to get the error
+    /// instead.
+    pub fn for_system<P: AsRef<Path>>(download_to: P) -> Self {
+        Self::builder()
+            .download_to(download_to)
+            .aosc_branch(aosc_branch().unwrap_or(AOSCBranch::Mainline))
+            .build()
+            .unwrap()
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn builder() -> FetcherBuilder<reqwest::blocking::Client> {
+        FetcherBuilder::new()
+    }
+
+    #[cfg(feature = "blocking-ureq")]
+    pub fn builder() -> FetcherBuilder<ureq::Agent> {
+        FetcherBuilder::new()
+    }
+
+    /// The HTTP client of this fetcher, to share its connection pool with another one
+    ///
+    /// See [`FetcherBuilder::with_client`].
+    #[cfg(feature = "blocking")]
+    pub fn client(&self) -> &reqwest::blocking::Client {
+        &self.client
+    }
+
+    /// The ureq agent of this fetcher, to share its connection pool with another one
+    ///
+    /// See [`FetcherBuilder::with_client`].
+    #[cfg(feature = "blocking-ureq")]
+    pub fn client(&self) -> &ureq::Agent {
+        self.client.agent()
+    }
+
+    pub fn fetch_packages(&self, arch: &str, branch: &str) -> Result<Packages, FetchPackagesError> {
+        Ok(self
+            .fetch_unconditional(
+                &self.options.index(arch, branch),
+                self.options.compression,
+                &mut |_, _| {},
+            )?
+            .0)
+    }
+
+    /// Fetch the Packages of the architecture of this machine
+    ///
+    /// The index is written to `Packages_<branch>_<component>_<arch>` with the detected
+    /// architecture. Fails with [`FetchPackagesError::UnsupportedHostArch`] if AOSC OS has no
+    /// such architecture.
+    pub fn fetch_packages_native(&self, branch: &str) -> Result<Packages, FetchPackagesError> {
+        self.fetch_packages(self.options.native_arch()?, branch)
+    }
+
+    /// Fetch the Packages of each suite of the AOSC OS repository the system at `sysroot`
+    /// tracks, for the architecture of this machine, keyed by suite
+    ///
+    /// The suites are read from the APT sources, see [`aosc_suites`], but downloaded from the
+    /// mirrors of this fetcher. Each index is written to `Packages_<suite>_<component>_<arch>`.
+    /// Fails with [`FetchPackagesError::NoSystemSuites`] if the sources list none.
+    pub fn fetch_system_indices(
+        &self,
+        sysroot: &Path,
+    ) -> Result<BTreeMap<String, Packages>, FetchPackagesError> {
+        let arch = self.options.native_arch()?;
+        let suites = aosc_suites(sysroot)?;
+        if suites.is_empty() {
+            return Err(FetchPackagesError::NoSystemSuites(sysroot.to_path_buf()));
+        }
+
+        suites
+            .into_iter()
+            .map(|suite| {
+                let packages = self.fetch_packages(arch, &suite)?;
+                Ok((suite, packages))
+            })
+            .collect()
+    }
+
+    /// Fetch the Packages of the topic `topic`
+    ///
+    /// Topics often publish only some architectures, so a missing index fails with
+    /// [`FetchPackagesError::TopicArchNotFound`], or with [`FetchPackagesError::TopicNotFound`]
+    /// if the topic has no release file either.
+    pub fn fetch_topic_packages(
+        &self,
+        arch: &str,
+        topic: &str,
+    ) -> Result<Packages, FetchPackagesError> {
+        match self.fetch_packages(arch, topic) {
+            Err(e) if e.is_not_found() => Err(self.missing_topic_index(arch, topic)),
+            res => res,
+        }
+    }
+
+    /// Fetch and parse the `InRelease` file of `branch`
+    ///
+    /// The PGP signature is stripped but not verified, unless the fetcher was built
+    /// `with_keyring` with the `verify` feature.
+    pub fn fetch_release(&self, branch: &str) -> Result<Release, FetchPackagesError> {
+        Ok(self.fetch_release_with_report(branch)?.0)
+    }
+
+    /// Fetch the `InRelease` file of `branch` along with what the server answered
+    ///
+    /// The report carries the final URL, status and validators of the response, see
+    /// [`Self::fetch_packages_with_report`] for the Packages index.
+    pub fn fetch_release_with_report(
+        &self,
+        branch: &str,
+    ) -> Result<(Release, FetchReport), FetchPackagesError> {
+        let started = Instant::now();
+        let (transfer, bytes, release) =
+            match self.get_from_mirrors(&self.options.in_release_url_path(branch)) {
+                Ok((transfer, bytes)) => {
+                    let release = self.options.parse_in_release(&bytes)?;
+                    (transfer, bytes, release)
+                }
+                // A repository without InRelease signs its Release file separately.
+                #[cfg(feature = "verify")]
+                Err(e) if e.is_not_found() && self.options.keyring.is_some() => {
+                    let (transfer, bytes) =
+                        self.get_from_mirrors(&self.options.dist_path(branch, "Release"))?;
+                    let (_, signature) =
+                        self.get_from_mirrors(&self.options.dist_path(branch, "Release.gpg"))?;
+                    let release = self.options.parse_detached_release(&bytes, &signature)?;
+                    (transfer, bytes, release)
+                }
+                Err(e) => return Err(e),
+            };
+        let report = transfer.report(bytes.len() as u64, started);
+        self.options.observer().on_complete(&report);
+
+        Ok((release, report))
+    }
+
+    /// Fetch Packages merged with the `binary-all` index of the same component
+    ///
+    /// Entries of `binary-<arch>` take precedence over `binary-all` ones of the same name.
+    /// A missing `binary-all` index is ignored, as the main AOSC repository folds those
+    /// packages into every arch index. It is written to `Packages_<branch>_<component>_all`.
+    /// A [flat](RepoLayout::Flat) repository only has the one index, which is returned as is.
+    pub fn fetch_packages_with_all(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<Packages, FetchPackagesError> {
+        let packages = self.fetch_packages(arch, branch)?;
+        if self.options.layout == RepoLayout::Flat {
+            return Ok(packages);
+        }
+        let index = self
+            .options
+            .component_index("all", branch, &self.options.component);
+
+        match self.fetch_unconditional(&index, self.options.compression, &mut |_, _| {}) {
+            Ok((all, ..)) => Ok(merge_all(packages, all)),
+            Err(e) if e.is_not_found() => Ok(packages),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Fetch Packages, giving up with [`FetchPackagesError::DeadlineExceeded`] after `deadline`
+    ///
+    /// The deadline covers the whole fetch, including retries, decompression and parsing. It
+    /// is checked between chunks of the download, so a mirror that trickles data can't hold
+    /// the fetch up. Nothing of an aborted fetch is left in the download directory.
+    pub fn fetch_packages_with_deadline(
+        &self,
+        arch: &str,
+        branch: &str,
+        deadline: Duration,
+    ) -> Result<Packages, FetchPackagesError> {
+        let fetcher = FetchPackages {
+            client: self.client.clone(),
+            options: FetchOptions {
+                deadline: Some((Instant::now() + deadline, deadline)),
+                ..self.options.clone()
+            },
+        };
+        let index = self.options.index(arch, branch);
+
+        match fetcher.fetch_unconditional(&index, self.options.compression, &mut |_, _| {}) {
+            Ok((packages, ..)) => Ok(packages),
+            Err(e) => {
+                // Whatever failed last, running out of time is the reason.
+                let e = fetcher.options.check_deadline().err().unwrap_or(e);
+                if matches!(e, FetchPackagesError::DeadlineExceeded(_)) {
+                    discard_partial(&partial_path(&self.options.local_path(&index)?));
+                }
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Size of the Packages download according to the mirror, without downloading it
+    ///
+    /// Sends a HEAD request and returns `Content-Length`, or `None` if the server doesn't
+    /// send it. With [`Compression::Auto`] this is the size of the first format the mirror
+    /// has. Useful to show the expected size or to refuse large downloads on metered
+    /// connections.
+    pub fn probe_packages_size(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<Option<u64>, FetchPackagesError> {
+        let index = self.options.index(arch, branch);
+        let mut not_found = None;
+
+        for &compression in self.options.compression.candidates() {
+            match self.head_from_mirrors(&self.options.index_url_path(&index, compression)) {
+                Err(e) if e.is_not_found() => not_found = Some(e),
+                res => return res,
+            }
+        }
+
+        Err(not_found.expect("candidate list is not empty"))
+    }
+
+    /// Fetch Packages, returning the path the decompressed index was written to
+    ///
+    /// The file is `Packages_<branch>_<component>_<arch>` in the download directory, or
+    /// `Packages` if [`FetcherBuilder::plain_file_name`] is set.
+    pub fn fetch_packages_with_path(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<(Packages, PathBuf), FetchPackagesError> {
+        let index = self.options.index(arch, branch);
+        let (packages, ..) =
+            self.fetch_unconditional(&index, self.options.compression, &mut |_, _| {})?;
+
+        Ok((packages, self.options.local_path(&index)?))
+    }
+
+    /// Fetch Packages, writing the decompressed index to exactly `path`
+    ///
+    /// This bypasses the naming in the download directory, which doesn't have to be set.
+    /// Missing parent directories are created, and the index is written to a temporary file
+    /// next to `path` first, like every other fetch. The decompressed copy is written even
+    /// with [`IndexStorage::Compressed`](super::IndexStorage::Compressed). Returns the
+    /// canonicalized path written to.
+    pub fn fetch_packages_to(
+        &self,
+        arch: &str,
+        branch: &str,
+        path: &Path,
+    ) -> Result<(Packages, PathBuf), FetchPackagesError> {
+        let index = self.options.index_at(arch, branch, path)?;
+        let fetcher = FetchPackages {
+            client: self.client.clone(),
+            options: self.options.keeping_decompressed(),
+        };
+        let (packages, ..) =
+            fetcher.fetch_unconditional(&index, self.options.compression, &mut |_, _| {})?;
+        let path = std::fs::canonicalize(path)?;
+
+        Ok((packages, path))
+    }
+
+    /// Fetch Packages from `mirror` instead of the configured mirrors, for this fetch only
+    ///
+    /// Uses the same client and settings otherwise, and `mirror` is checked like the mirrors
+    /// given to the builder. The index is written to the usual file name followed by `@` and
+    /// the host and path of the mirror, such as `Packages_stable_main_amd64@repo.aosc.io_debs`,
+    /// so the copies and validators of different mirrors are kept apart.
+    pub fn fetch_packages_from(
+        &self,
+        mirror: &str,
+        arch: &str,
+        branch: &str,
+    ) -> Result<Packages, FetchPackagesError> {
+        let options = self.options.with_mirror(mirror)?;
+        let index = options.mirror_index(arch, branch);
+        let fetcher = FetchPackages {
+            client: self.client.clone(),
+            options,
+        };
+        let (packages, ..) =
+            fetcher.fetch_unconditional(&index, self.options.compression, &mut |_, _| {})?;
+
+        Ok(packages)
+    }
+
+    /// Fetch Packages, returning whether they were downloaded or the local copy was current
+    ///
+    /// Every fetch keeps the `ETag` of the download in a `.meta` file next to the index and
+    /// sends it as `If-None-Match` the next time the same URL is fetched, so switching mirror,
+    /// arch or branch never reuses a validator. On 304 the local copy is reparsed.
+    pub fn fetch_packages_with_source(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<(Packages, FetchSource), FetchPackagesError> {
+        let (packages, _, source, _) = self.fetch_unconditional(
+            &self.options.index(arch, branch),
+            self.options.compression,
+            &mut |_, _| {},
+        )?;
+
+        Ok((packages, source))
+    }
+
+    /// Fetch Packages along with statistics of the transfer
+    ///
+    /// If the local copy was current the report describes the 304, with nothing transferred
+    /// and the size of the reparsed local copy.
+    pub fn fetch_packages_with_report(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<(Packages, FetchReport), FetchPackagesError> {
+        let (packages, .., report) = self.fetch_unconditional(
+            &self.options.index(arch, branch),
+            self.options.compression,
+            &mut |_, _| {},
+        )?;
+
+        Ok((packages, report))
+    }
+
+    /// Fetch Packages in the first format the mirror publishes, returning the chosen format
+    ///
+    /// Formats are tried in [`Compression::AUTO_ORDER`]. A 404 moves on to the next format,
+    /// any other error is returned immediately.
+    pub fn fetch_packages_auto(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<(Packages, Compression), FetchPackagesError> {
+        let (packages, compression, ..) = self.fetch_unconditional(
+            &self.options.index(arch, branch),
+            Compression::Auto,
+            &mut |_, _| {},
+        )?;
+
+        Ok((packages, compression))
+    }
+
+    /// Fetch Packages, calling `progress` with `(downloaded, total)` after every chunk read
+    ///
+    /// `progress` is also called once before the first chunk so the caller can render 0%.
+    /// Sizes count the bytes transferred over the wire, and `total` comes from
+    /// `Content-Length` if the server sent one.
+    pub fn fetch_packages_with_progress(
+        &self,
+        arch: &str,
+        branch: &str,
+        mut progress: impl FnMut(u64, Option<u64>),
+    ) -> Result<Packages, FetchPackagesError> {
+        Ok(self
+            .fetch_unconditional(
+                &self.options.index(arch, branch),
+                self.options.compression,
+                &mut progress,
+            )?
+            .0)
+    }
+
+    /// Fetch Packages, reusing the local copy without any request if it is younger than `max_age`
+    ///
+    /// The age counts from the last download of the local copy or the last time the server
+    /// confirmed it with 304, as recorded in its `.meta` file. A copy downloaded from another
+    /// mirror or for another arch or branch is never reused, and neither is one that can't be
+    /// parsed. Otherwise this is [`fetch_packages`](Self::fetch_packages), which refreshes the
+    /// local copy, or an update with patches if [`FetcherBuilder::pdiff`] is enabled. The
+    /// local copy isn't checked against the release file again.
+    pub fn fetch_packages_cached(
+        &self,
+        arch: &str,
+        branch: &str,
+        max_age: Duration,
+    ) -> Result<Packages, FetchPackagesError> {
+        let index = self.options.index(arch, branch);
+        let local = self.options.local_path(&index)?;
+        if let Some(meta) = previous_download(&local) {
+            if self.options.is_cache_fresh(&index, &meta, max_age) {
+                if let Ok((packages, _)) = read_local(&local, None) {
+                    return Ok(packages);
+                }
+            }
+        }
+
+        if self.options.pdiff && self.options.offline != OfflinePolicy::Offline {
+            // A failed update leaves the local copy alone, so fetch the whole index then.
+            if let Ok(packages) = self.update_with_pdiff(&index, &local) {
+                return Ok(packages);
+            }
+        }
+
+        let (packages, ..) =
+            self.fetch_unconditional(&index, self.options.compression, &mut |_, _| {})?;
+
+        Ok(packages)
+    }
+
+    /// Fetch Packages only if it changed since the last successful download
+    ///
+    /// The `Last-Modified` date of every download is kept in a `.meta` file next to the index
+    /// and sent as `If-Modified-Since` on the next call, along with the `ETag` if there is
+    /// one. Returns `Ok(None)` if the server answers 304, in which case the copy already on
+    /// disk is current. Without recorded validators this is a normal fetch.
+    pub fn fetch_packages_if_modified(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<Option<Packages>, FetchPackagesError> {
+        Ok(self
+            .fetch(
+                &self.options.index(arch, branch),
+                self.options.compression,
+                true,
+                &mut |_, _| {},
+            )?
+            .map(|(packages, ..)| packages))
+    }
+
+    /// Fetch the Packages of each of `components`, keyed by component
+    ///
+    /// Each index is written to `Packages_<branch>_<component>_<arch>` so they don't
+    /// overwrite each other. A component missing from the mirror fails the whole call with
+    /// [`FetchPackagesError::ComponentNotFound`].
+    pub fn fetch_components(
+        &self,
+        arch: &str,
+        branch: &str,
+        components: &[&str],
+    ) -> Result<HashMap<String, Packages>, FetchPackagesError> {
+        let mut res = HashMap::new();
+
+        for &component in components {
+            let index = self.options.component_index(arch, branch, component);
+            let (packages, ..) = self
+                .fetch_unconditional(&index, self.options.compression, &mut |_, _| {})
+                .map_err(|e| {
+                    if e.is_not_found() {
+                        FetchPackagesError::ComponentNotFound(component.to_string())
+                    } else {
+                        e
+                    }
+                })?;
+            res.insert(component.to_string(), packages);
+        }
+
+        Ok(res)
+    }
+
+    /// Fetch Packages without touching the filesystem
+    ///
+    /// The index is downloaded, decompressed and parsed in memory, so this works without a
+    /// download directory. Nothing is cached and every call downloads the whole index.
+    pub fn fetch_packages_in_memory(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<Packages, FetchPackagesError> {
+        let (packages, _) =
+            self.fetch_in_memory(&self.options.index(arch, branch), self.options.compression)?;
+
+        Ok(packages)
+    }
+
+    /// Fetch Packages without touching the filesystem, also writing the decompressed index
+    /// to `w`
+    ///
+    /// Works like [`Self::fetch_packages_in_memory`]. The index is written to `w` as it is
+    /// decompressed, before it is checked against the release file and parsed, so `w` may
+    /// hold part of an index or an index that doesn't match when the fetch fails. As a
+    /// download that turns out corrupt may have been partly written already, it isn't tried
+    /// again. Failures of `w` are returned as [`FetchPackagesError::WriterError`].
+    pub fn fetch_packages_to_writer(
+        &self,
+        arch: &str,
+        branch: &str,
+        w: &mut dyn Write,
+    ) -> Result<Packages, FetchPackagesError> {
+        let index = self.options.index(arch, branch);
+        let release = self.index_release(&index.branch)?;
+        let (packages, _) =
+            self.try_fetch_in_memory_with(&index, self.options.compression, release.as_ref(), w)?;
+        w.flush().map_err(FetchPackagesError::WriterError)?;
+
+        Ok(packages)
+    }
+
+    /// Fetch the `Sources` index of the configured component
+    ///
+    /// The index is written to `Sources_<branch>_<component>`.
+    pub fn fetch_sources(&self, branch: &str) -> Result<Sources, FetchPackagesError> {
+        let (sources, ..) = self.fetch_unconditional(
+            &self.options.sources_index(branch),
+            self.options.compression,
+            &mut |_, _| {},
+        )?;
+
+        Ok(sources)
+    }
+
+    /// Fetch the `Contents-<arch>` index of the configured component
+    ///
+    /// The index is tried in every format in [`Compression::AUTO_ORDER`] and written to
+    /// `Contents_<branch>_<component>_<arch>`.
+    pub fn fetch_contents(&self, arch: &str, branch: &str) -> Result<Contents, FetchPackagesError> {
+        let (contents, ..) = self.fetch_unconditional(
+            &self.options.contents_index(arch, branch),
+            Compression::Auto,
+            &mut |_, _| {},
+        )?;
+
+        Ok(contents)
+    }
+
+    /// Fetch the Packages of `branch` together with its release file and the indices in
+    /// `what`, all checked against that one release file
+    ///
+    /// The release file is fetched once, and every index is downloaded by hash if the
+    /// release file allows it or checked against its table otherwise, so a mirror updating
+    /// halfway through can't mix old and new indices. The indices are written to the usual
+    /// files in the download directory only once all of them verified, and if any fails to
+    /// download or to be moved into place, the previous copies are kept. The files are still
+    /// replaced one after another, so a reader of the directory meanwhile may see a mix.
+    pub fn fetch_snapshot(
+        &self,
+        arch: &str,
+        branch: &str,
+        what: SnapshotParts,
+    ) -> Result<Snapshot, FetchPackagesError> {
+        let fetcher = FetchPackages {
+            client: self.client.clone(),
+            options: self.options.for_snapshot(),
+        };
+        let options = &fetcher.options;
+        let packages_index = options.index(arch, branch);
+        let contents_index = what.contents.then(|| options.contents_index(arch, branch));
+        let sources_index = what.sources.then(|| options.sources_index(branch));
+        let indices: Vec<&Index> = [Some(&packages_index)]
+            .into_iter()
+            .chain([contents_index.as_ref(), sources_index.as_ref()])
+            .flatten()
+            .collect();
+        // Fail on a missing download directory before downloading anything.
+        let paths = indices
+            .iter()
+            .map(|index| options.local_path(index))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let release = fetcher.fetch_release(branch)?;
+        if let Some(max_age) = options.max_release_age {
+            check_fresh(&release, max_age)?;
+        }
+
+        let mut files = vec![];
+        let mut fetched = |index: &Index, compression, bytes| {
+            let url = options.mirror_url(&options.index_url_path(index, compression));
+            files.push((bytes, url));
+        };
+        let (WithBytes(packages, bytes), compression) =
+            fetcher.fetch_verified(&packages_index, options.compression, &release)?;
+        fetched(&packages_index, compression, bytes);
+        let contents = match &contents_index {
+            Some(index) => {
+                let (WithBytes(contents, bytes), compression) =
+                    fetcher.fetch_verified(index, Compression::Auto, &release)?;
+                fetched(index, compression, bytes);
+                Some(contents)
+            }
+            None => None,
+        };
+        let sources = match &sources_index {
+            Some(index) => {
+                let (WithBytes(sources, bytes), compression) =
+                    fetcher.fetch_verified(index, options.compression, &release)?;
+                fetched(index, compression, bytes);
+                Some(sources)
+            }
+            None => None,
+        };
+
+        let files: Vec<_> = paths
+            .into_iter()
+            .zip(files)
+            .map(|(path, (bytes, url))| (path, bytes, url))
+            .collect();
+        // Taken in path order, so two snapshots of overlapping indices can't deadlock.
+        let mut sorted: Vec<&Path> = files.iter().map(|(path, ..)| path.as_path()).collect();
+        sorted.sort();
+        let locks: Vec<_> = sorted
+            .into_iter()
+            .map(|path| DOWNLOAD_LOCKS.get(path))
+            .collect();
+        let _guards: Vec<_> = locks
+            .iter()
+            .map(|lock| lock.lock().unwrap_or_else(PoisonError::into_inner))
+            .collect();
+        options.publish(&files)?;
+
+        Ok(Snapshot {
+            release,
+            packages,
+            contents,
+            sources,
+        })
+    }
+
+    /// Download the `.deb` of `pkg` into the directory `dest`, returning the path of the file
+    ///
+    /// The package is fetched from `<mirror>/<filename>` in either [`RepoLayout`] and stored
+    /// under the last component of its file name. The download is checked against the size
+    /// and SHA256 listed in Packages and removed if either doesn't match, see
+    /// [`FetchPackagesError::SizeMismatch`] and [`FetchPackagesError::ChecksumMismatch`]. If a
+    /// file with the right hash is already there, nothing is downloaded. No more than the
+    /// listed size is downloaded, see [`FetcherBuilder::max_download_size`].
+    pub fn download_package(
+        &self,
+        pkg: &Package,
+        dest: &Path,
+    ) -> Result<PathBuf, FetchPackagesError> {
+        let path = package_path(dest, pkg);
+        let lock = DOWNLOAD_LOCKS.get(&path);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if check_package(pkg, &path).is_ok() {
+            return Ok(path);
+        }
+
+        let fetcher = FetchPackages {
+            client: self.client.clone(),
+            options: self.options.for_package(pkg)?,
+        };
+        if !dest.exists() {
+            std::fs::create_dir_all(dest)?;
+        }
+
+        let started = Instant::now();
+        let partial = partial_path(&path);
+        let transfer = match fetcher.download_segmented(package_url_path(pkg), &partial, pkg.size) {
+            Some(transfer) => transfer,
+            None => fetcher
+                .download_from_mirrors(package_url_path(pkg), &partial, None, &mut |_, _| {})?
+                .into_transfer(),
+        };
+        complete_package(pkg, &partial, &path)?;
+        self.options
+            .observer()
+            .on_complete(&transfer.report(pkg.size, started));
+
+        Ok(path)
+    }
+
+    /// A copy of the fetcher that asks caches on the way for a fresh copy of everything
+    fn no_cache(&self) -> Self {
+        FetchPackages {
+            client: self.client.clone(),
+            options: FetchOptions {
+                no_cache: true,
+                ..self.options.clone()
+            },
+        }
+    }
+
+    fn fetch_unconditional<T, F>(
+        &self,
+        index: &Index,
+        compression: Compression,
+        progress: &mut F,
+    ) -> Result<(T, Compression, FetchSource, FetchReport), FetchPackagesError>
+    where
+        T: IndexFile,
+        F: FnMut(u64, Option<u64>),
+    {
+        Ok(self
+            .fetch(index, compression, false, progress)?
+            .expect("304 is only passed through for conditional fetches"))
+    }
+
+    /// Fetch Packages, revalidating the local copy if there is one
+    ///
+    /// Returns `None` on 304 if `conditional`, otherwise the local copy is reparsed.
+    fn fetch<T, F>(
+        &self,
+        index: &Index,
+        compression: Compression,
+        conditional: bool,
+        progress: &mut F,
+    ) -> Result<Option<(T, Compression, FetchSource, FetchReport)>, FetchPackagesError>
+    where
+        T: IndexFile,
+        F: FnMut(u64, Option<u64>),
+    {
+        let started = Instant::now();
+        let res = if self.options.offline == OfflinePolicy::Offline {
+            self.read_stale(index, compression, started).map(Some)
+        } else {
+            self.fetch_from_network(index, compression, conditional, progress, started)
+        };
+        if let Ok(Some((.., report))) = &res {
+            self.options.observer().on_complete(report);
+        }
+
+        res
+    }
+
+    /// [`Self::fetch`] unless the [`OfflinePolicy`] rules out the network
+    fn fetch_from_network<T, F>(
+        &self,
+        index: &Index,
+        compression: Compression,
+        conditional: bool,
+        progress: &mut F,
+        started: Instant,
+    ) -> Result<Option<(T, Compression, FetchSource, FetchReport)>, FetchPackagesError>
+    where
+        T: IndexFile,
+        F: FnMut(u64, Option<u64>),
+    {
+        let res = match self.try_fetch(index, compression, conditional, progress) {
+            Err(e) if e.is_corrupt_payload() && self.options.retry.retries > 0 => self
+                .no_cache()
+                .try_fetch(index, compression, conditional, progress),
+            res => res,
+        };
+
+        match res {
+            Err(e)
+                if self.options.offline == OfflinePolicy::FallbackToCache
+                    && e.is_network_error() =>
+            {
+                // Without a usable local copy, the network error is the one to report.
+                self.read_stale(index, compression, started)
+                    .map(Some)
+                    .map_err(|_| e)
+            }
+            res => res,
+        }
+    }
+
+    /// Reparse the local copy of `index` as is, as the [`OfflinePolicy`] asks for
+    fn read_stale<T: IndexFile>(
+        &self,
+        index: &Index,
+        compression: Compression,
+        started: Instant,
+    ) -> Result<(T, Compression, FetchSource, FetchReport), FetchPackagesError> {
+        let local = self.options.local_path(index)?;
+        let Ok(metadata) = std::fs::metadata(&local) else {
+            return Err(FetchPackagesError::NoLocalCopy(local));
+        };
+        let meta = previous_download(&local);
+        let (parsed, size) = read_local(&local, None)?;
+
+        let report = Transfer::local_copy(meta.as_ref()).report(size, started);
+        let age = local_copy_age(meta.as_ref(), metadata.modified().ok());
+
+        Ok((parsed, compression, FetchSource::Stale { age }, report))
+    }
+
+    fn try_fetch<T, F>(
+        &self,
+        index: &Index,
+        compression: Compression,
+        conditional: bool,
+        progress: &mut F,
+    ) -> Result<Option<(T, Compression, FetchSource, FetchReport)>, FetchPackagesError>
+    where
+        T: IndexFile,
+        F: FnMut(u64, Option<u64>),
+    {
+        let started = Instant::now();
+        let mut not_found = None;
+        let local = &self.options.local_path(index)?;
+        let dir = local
+            .parent()
+            .expect("indices are stored in the download directory");
+        let partial = partial_path(local);
+        let lock = DOWNLOAD_LOCKS.get(local);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if !dir.exists() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        // Some CDNs get Last-Modified wrong, so only rely on it when asked to.
+        let previous = previous_download(local).map(|mut meta| {
+            if !conditional {
+                meta.last_modified = None;
+            }
+            meta
+        });
+
+        let release = self.index_release(&index.branch)?;
+        let decompressed = self
+            .options
+            .release_checksum(release.as_ref(), &index.name)?;
+
+        // A local copy that matches the release file is current, a missing or broken one is
+        // downloaded again.
+        if let Some(expected) = decompressed {
+            if let Ok((parsed, size)) = read_local(local, Some(expected)) {
+                refresh_download(local);
+                if conditional {
+                    return Ok(None);
+                }
+                let meta = previous_download(local);
+                let report = Transfer::local_copy(meta.as_ref()).report(size, started);
+                return Ok(Some((
+                    parsed,
+                    compression,
+                    FetchSource::MatchesRelease,
+                    report,
+                )));
+            }
+        }
+
+        for &compression in compression.candidates() {
+            let urls = match self
+                .options
+                .index_url_paths(index, compression, release.as_ref())
+            {
+                Ok(urls) => urls,
+                // Formats the release file doesn't list are skipped like missing ones.
+                Err(e) => {
+                    not_found = Some(e);
+                    continue;
+                }
+            };
+
+            for (path, expected) in urls {
+                let mut res =
+                    self.download_from_mirrors(&path, &partial, previous.as_ref(), progress);
+                if matches!(res, Ok(Downloaded::NotModified(_))) {
+                    refresh_download(local);
+                }
+                if let (false, Ok(Downloaded::NotModified(transfer))) = (conditional, &res) {
+                    match read_local(local, decompressed) {
+                        Ok((parsed, size)) => {
+                            let report = transfer.report(size, started);
+                            return Ok(Some((parsed, compression, FetchSource::Cache, report)));
+                        }
+                        // A broken local copy can't stand in for the download.
+                        Err(_) => res = self.download_from_mirrors(&path, &partial, None, progress),
+                    }
+                }
+
+                match res {
+                    Ok(Downloaded::Complete(transfer)) => {
+                        let tmp = TempPath(temp_path(local));
+                        let res = expected
+                            .map_or(Ok(()), |expected| {
+                                check_file_sha256(
+                                    &index.release_path(compression),
+                                    expected,
+                                    &partial,
+                                )
+                            })
+                            .and_then(|()| {
+                                // Decompressing can't be interrupted, so don't start too late.
+                                self.options.check_deadline()?;
+                                self.decompress(&transfer.url, compression, &partial, &tmp)?;
+                                Ok(std::fs::read(&*tmp)?)
+                            })
+                            .and_then(|bytes| {
+                                if let Some(expected) = decompressed {
+                                    check_sha256(&index.name, expected, &bytes)?;
+                                }
+                                Ok((T::parse(&bytes)?, bytes.len() as u64))
+                            })
+                            .and_then(|(parsed, size)| {
+                                self.options
+                                    .finish_index(compression, &partial, &tmp, local)?;
+                                Ok((parsed, size))
+                            });
+                        if res.is_err() {
+                            discard_partial(&partial);
+                        }
+
+                        let (parsed, size) =
+                            res.map_err(|e| index.error(transfer.url.clone(), e))?;
+                        let report = transfer.report(size, started);
+                        return Ok(Some((parsed, compression, FetchSource::Network, report)));
+                    }
+                    Ok(Downloaded::NotModified(_)) => return Ok(None),
+                    // A by-hash copy that is gone falls back to the canonical path.
+                    Err(e) if e.is_not_found() => {
+                        not_found = Some(index.error(self.options.mirror_url(&path), e))
+                    }
+                    Err(e) => return Err(index.error(self.options.mirror_url(&path), e)),
+                }
+            }
+        }
+
+        Err(not_found.expect("candidate list is not empty"))
+    }
+
+    /// Update the local copy of `index` at `local` with the patches in its `.diff/` directory
+    fn update_with_pdiff(
+        &self,
+        index: &Index,
+        local: &Path,
+    ) -> Result<Packages, FetchPackagesError> {
+        let lock = DOWNLOAD_LOCKS.get(local);
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let previous = previous_download(local)
+            .ok_or_else(|| FetchPackagesError::NoLocalCopy(local.into()))?;
+        let mut bytes = std::fs::read(local)?;
+
+        let release = self.index_release(&index.branch)?;
+        let diff_index_name = format!("{}.diff/Index", index.name);
+        let expected = self
+            .options
+            .release_checksum(release.as_ref(), &diff_index_name)?;
+        let decompressed = self
+            .options
+            .release_checksum(release.as_ref(), &index.name)?;
+
+        let path = self.options.diff_url_path(index, "Index");
+        let (_, diff_index) = self.get_from_mirrors(&path)?;
+        if let Some(expected) = expected {
+            check_sha256(&diff_index_name, expected, &diff_index)?;
+        }
+        let malformed = |message| FetchPackagesError::Pdiff {
+            path: path.clone(),
+            message,
+        };
+        let diff_index = DiffIndex::parse(&diff_index).map_err(malformed)?;
+        let patches = diff_index
+            .patches_for(&bytes)
+            .ok_or_else(|| malformed("the local copy is not in the history".to_string()))?;
+        if patches.is_empty() {
+            refresh_download(local);
+            return Packages::parse(&bytes);
+        }
+
+        for patch in patches {
+            let path = self
+                .options
+                .diff_url_path(index, &format!("{}.gz", patch.name));
+            let (Transfer { url, .. }, compressed) = self.get_from_mirrors(&path)?;
+            if let Some(expected) = patch.download {
+                check_sha256(&path, expected, &compressed)?;
+            }
+            let script = decode(
+                &url,
+                Compression::Gzip,
+                compressed,
+                self.options.max_decompressed,
+                &mut io::sink(),
+            )?;
+            check_sha256(&path, patch.uncompressed, &script)?;
+            bytes = apply_ed(&bytes, &script)
+                .map_err(|message| FetchPackagesError::Pdiff { path, message })?;
+        }
+
+        check_sha256(&index.name, diff_index.current(), &bytes)?;
+        if let Some(expected) = decompressed {
+            check_sha256(&index.name, expected, &bytes)?;
+        }
+        let packages = Packages::parse(&bytes)?;
+
+        let tmp = TempPath(temp_path(local));
+        let mut f = std::fs::File::create(&*tmp)?;
+        f.write_all(&bytes)?;
+        // Make sure the data is on disk before the rename makes it visible.
+        f.sync_all()?;
+        self.options.finish_patched(&tmp, local, &previous.url)?;
+
+        Ok(packages)
+    }
+
+    fn fetch_in_memory<T: IndexFile>(
+        &self,
+        index: &Index,
+        compression: Compression,
+    ) -> Result<(T, Compression), FetchPackagesError> {
+        match self.try_fetch_in_memory(index, compression) {
+            Err(e) if e.is_corrupt_payload() && self.options.retry.retries > 0 => {
+                self.no_cache().try_fetch_in_memory(index, compression)
+            }
+            res => res,
+        }
+    }
+
+    fn try_fetch_in_memory<T: IndexFile>(
+        &self,
+        index: &Index,
+        compression: Compression,
+    ) -> Result<(T, Compression), FetchPackagesError> {
+        let release = self.index_release(&index.branch)?;
+        self.try_fetch_in_memory_with(index, compression, release.as_ref(), &mut io::sink())
+    }
+
+    /// Like [`Self::fetch_in_memory`], but checked against `release` that was already fetched
+    fn fetch_verified<T: IndexFile>(
+        &self,
+        index: &Index,
+        compression: Compression,
+        release: &Release,
+    ) -> Result<(T, Compression), FetchPackagesError> {
+        match self.try_fetch_in_memory_with(index, compression, Some(release), &mut io::sink()) {
+            Err(e) if e.is_corrupt_payload() && self.options.retry.retries > 0 => self
+                .no_cache()
+                .try_fetch_in_memory_with(index, compression, Some(release), &mut io::sink()),
+            res => res,
+        }
+    }
+
+    /// Fetch `index`, checked against `release` if given, and write what it decompresses to
+    /// to `sink` as it is decompressed
+    fn try_fetch_in_memory_with<T: IndexFile>(
+        &self,
+        index: &Index,
+        compression: Compression,
+        release: Option<&Release>,
+        sink: &mut dyn Write,
+    ) -> Result<(T, Compression), FetchPackagesError> {
+        let started = Instant::now();
+        let mut not_found = None;
+        let decompressed = self.options.release_checksum(release, &index.name)?;
+
+        for &compression in compression.candidates() {
+            let urls = match self.options.index_url_paths(index, compression, release) {
+                Ok(urls) => urls,
+                // Formats the release file doesn't list are skipped like missing ones.
+                Err(e) => {
+                    not_found = Some(e);
+                    continue;
+                }
+            };
+
+            for (path, expected) in urls {
+                match self.get_from_mirrors(&path) {
+                    Ok((transfer, bytes)) => {
+                        let url = &transfer.url;
+                        let res = expected
+                            .map_or(Ok(()), |expected| {
+                                check_sha256(&index.release_path(compression), expected, &bytes)
+                            })
+                            .and_then(|()| {
+                                let limit = self.options.max_decompressed;
+                                decode(url, compression, bytes, limit, sink)
+                            })
+                            .and_then(|bytes| {
+                                if let Some(expected) = decompressed {
+                                    check_sha256(&index.name, expected, &bytes)?;
+                                }
+                                Ok((T::parse(&bytes)?, bytes.len() as u64))
+                            });
+
+                        let (parsed, size) = res.map_err(|e| index.error(url.clone(), e))?;
+                        self.options
+                            .observer()
+                            .on_complete(&transfer.report(size, started));
+                        return Ok((parsed, compression));
+                    }
+                    Err(e) if e.is_not_found() => {
+                        not_found = Some(index.error(self.options.mirror_url(&path), e))
+                    }
+                    Err(e) => return Err(index.error(self.options.mirror_url(&path), e)),
+                }
+            }
+        }
+
+        Err(not_found.expect("candidate list is not empty"))
+    }
+
+    /// Tell a topic without an index for `arch` apart from one that doesn't exist
+    fn missing_topic_index(&self, arch: &str, topic: &str) -> FetchPackagesError {
+        match self.get_from_mirrors(&self.options.in_release_url_path(topic)) {
+            Ok(_) => FetchPackagesError::TopicArchNotFound {
+                topic: topic.to_string(),
+                arch: arch.to_string(),
+            },
+            Err(e) if e.is_not_found() => FetchPackagesError::TopicNotFound(topic.to_string()),
+            Err(e) => e,
+        }
+    }
+
+    /// The release file of `branch` to check indices against or download them by hash with,
+    /// if either is enabled
+    ///
+    /// Also checks that the release file is recent enough if
+    /// [`FetcherBuilder::require_fresh_metadata`] is set.
+    fn index_release(&self, branch: &str) -> Result<Option<Release>, FetchPackagesError> {
+        let release = match self.options.max_release_age {
+            Some(max_age) => {
+                let release = self.fetch_release(branch)?;
+                check_fresh(&release, max_age)?;
+                Some(release)
+            }
+            None if self.options.verify => Some(self.fetch_release(branch)?),
+            None if self.options.by_hash => match self.fetch_release(branch) {
+                Ok(release) => Some(release),
+                // Without a release file there are no hashes to go by.
+                Err(e) if e.is_not_found() => None,
+                Err(e) => return Err(e),
+            },
+            None => None,
+        };
+
+        Ok(release)
+    }
+
+    /// Download `path` into memory from the first mirror that can serve it, returning its URL
+    fn get_from_mirrors(&self, path: &str) -> Result<(Transfer, Vec<u8>), FetchPackagesError> {
+        let mut failures = MirrorFailures::default();
+
+        for url in self.options.mirror_urls(path) {
+            match self.get_with_retry(&url) {
+                Ok(res) => return Ok(res),
+                Err(e) => failures.push(&self.options, url, e)?,
+            }
+        }
+
+        Err(failures.finish())
+    }
+
+    /// `Content-Length` of `path` on the first mirror that can serve it
+    fn head_from_mirrors(&self, path: &str) -> Result<Option<u64>, FetchPackagesError> {
+        let mut failures = MirrorFailures::default();
+
+        for url in self.options.mirror_urls(path) {
+            match self.head(&url) {
+                Err(e) => failures.push(&self.options, url, e)?,
+                res => return res,
+            }
+        }
+
+        Err(failures.finish())
+    }
+
+    fn head(&self, url: &str) -> Result<Option<u64>, FetchPackagesError> {
+        if let Some(path) = file_url_path(url) {
+            return std::fs::metadata(&path)
+                .map(|m| Some(m.len()))
+                .map_err(|e| FetchPackagesError::from_local_io(&path, e));
+        }
+
+        self.options.observe_request(url);
+        let resp = self.client.head(url).send()?.error_for_status()?;
+
+        Ok(content_length(resp.headers()))
+    }
+
+    /// Download `path` to `partial` from the first mirror that can serve it
+    ///
+    /// `previous` only applies to the mirror it was downloaded from.
+    fn download_from_mirrors<F>(
+        &self,
+        path: &str,
+        partial: &Path,
+        previous: Option<&FileMeta>,
+        progress: &mut F,
+    ) -> Result<Downloaded, FetchPackagesError>
+    where
+        F: FnMut(u64, Option<u64>),
+    {
+        let mut failures = MirrorFailures::default();
+
+        for url in self.options.mirror_urls(path) {
+            let previous = previous.filter(|meta| meta.url == strip_credentials(&url));
+            match self.download_with_retry(&url, partial, previous, progress) {
+                Err(e) => failures.push(&self.options, url, e)?,
+                res => return res,
+            }
+        }
+
+        Err(failures.finish())
+    }
+
+    /// Download `path` from the preferred mirror to `partial` in several parts at once, if
+    /// [`FetcherBuilder::segmented_download`] applies to a file of `size` bytes
+    ///
+    /// Returns `None` if it doesn't, the server ignores ranges or any part fails, leaving
+    /// the file to a single-stream download. So is an interrupted download, to be resumed.
+    fn download_segmented(&self, path: &str, partial: &Path, size: u64) -> Option<Transfer> {
+        let url = self.options.mirror_url(path);
+        let ranges = self.options.segment_ranges(size)?;
+        if file_url_path(&url).is_some() || partial.exists() {
+            return None;
+        }
+
+        let started = Instant::now();
+        // A server that ignores ranges answers the first one with the whole file.
+        let first = self.request_range(&url, ranges[0])?;
+        let mut transfer = Transfer::from_response(first.url(), first.status(), first.headers());
+        transfer.bytes = size;
+        let done = std::fs::File::create(partial)
+            .and_then(|f| f.set_len(size))
+            .is_ok()
+            && std::thread::scope(|s| {
+                let rest: Vec<_> = ranges[1..]
+                    .iter()
+                    .map(|&range| {
+                        let url = &url;
+                        s.spawn(move || {
+                            self.request_range(url, range).is_some_and(|resp| {
+                                self.write_range(url, started, resp, partial, range).is_ok()
+                            })
+                        })
+                    })
+                    .collect();
+                let first = self
+                    .write_range(&url, started, first, partial, ranges[0])
+                    .is_ok();
+
+                // Wait for every part, even after one failed.
+                rest.into_iter()
+                    .fold(first, |done, part| part.join().unwrap_or(false) && done)
+            });
+
+        if !done {
+            discard_partial(partial);
+        }
+        done.then_some(transfer)
+    }
+
+    /// Request the inclusive byte range `range` of `url`, if the server answers with it
+    fn request_range(&self, url: &str, (start, end): (u64, u64)) -> Option<Response> {
+        let resp = self
+            .request(url)
+            .header(RANGE, format!("bytes={start}-{end}"))
+            .send()
+            .ok()?;
+
+        (resp.status() == StatusCode::PARTIAL_CONTENT
+            && is_content_range(resp.headers(), (start, end)))
+        .then_some(resp)
+    }
+
+    /// Write the body of `resp`, the range `range` of `url`, to its place in `partial`
+    fn write_range(
+        &self,
+        url: &str,
+        started: Instant,
+        mut resp: Response,
+        partial: &Path,
+        (start, end): (u64, u64),
+    ) -> Result<(), FetchPackagesError> {
+        let mut f = std::fs::OpenOptions::new().write(true).open(partial)?;
+        f.seek(SeekFrom::Start(start))?;
+        let mut written = 0;
+        let limit = self.options.max_download;
+        self.read_body(url, started, &mut resp, &mut f, limit, |n| {
+            written += n as u64
+        })?;
+
+        check_length(Some(end - start + 1), written)
+    }
+
+    /// Download a small file such as a release file into memory
+    fn get_with_retry(&self, url: &str) -> Result<(Transfer, Vec<u8>), FetchPackagesError> {
+        if let Some(path) = file_url_path(url) {
+            let local_err = |e| FetchPackagesError::from_local_io(&path, e);
+            let size = std::fs::metadata(&path).map_err(local_err)?.len();
+            self.options.check_size(SizeLimit::Download, size)?;
+            let bytes = std::fs::read(&path).map_err(local_err)?;
+            return Ok((Transfer::local(url, bytes.len() as u64), bytes));
+        }
+
+        let mut attempt = 1;
+
+        loop {
+            let res = (|| -> Result<(Transfer, Vec<u8>), FetchPackagesError> {
+                let started = Instant::now();
+                self.options.check_deadline()?;
+                let mut resp = self.request(url).send()?.error_for_status()?;
+                let mut transfer =
+                    Transfer::from_response(resp.url(), resp.status(), resp.headers());
+                let expected = resp.content_length();
+                self.options
+                    .check_size(SizeLimit::Download, expected.unwrap_or(0))?;
+                let mut buf = vec![];
+                let limit = self.options.max_download;
+                self.read_body(url, started, &mut resp, &mut buf, limit, |_| {})?;
+                check_length(expected, buf.len() as u64)?;
+                transfer.bytes = buf.len() as u64;
+
+                Ok((transfer, buf))
+            })();
+
+            match res {
+                Err(e) if e.is_transient() && attempt <= self.options.retry.retries => {
+                    self.options.observer().on_retry(attempt, &e);
+                    std::thread::sleep(self.options.retry.delay(attempt));
+                    attempt += 1;
+                }
+                res => return self.options.retry.finish(res, attempt),
+            }
+        }
+    }
+
+    fn download_with_retry<F>(
+        &self,
+        url: &str,
+        partial: &Path,
+        previous: Option<&FileMeta>,
+        progress: &mut F,
+    ) -> Result<Downloaded, FetchPackagesError>
+    where
+        F: FnMut(u64, Option<u64>),
+    {
+        let mut attempt = 1;
+
+        let res = loop {
+            match self.download(url, partial, previous, progress) {
+                Err(e) if e.is_transient() && attempt <= self.options.retry.retries => {
+                    self.options.observer().on_retry(attempt, &e);
+                    std::thread::sleep(self.options.retry.delay(attempt));
+                    attempt += 1;
+                }
+                res => break self.options.retry.finish(res, attempt),
+            }
+        };
+        if matches!(res, Err(FetchPackagesError::PayloadTooLarge { .. })) {
+            // There is no resuming an oversized download, so don't leave it behind.
+            discard_partial(partial);
+        }
+
+        res
+    }
+
+    /// Download `url` to `partial`, resuming a previous attempt at the same URL if possible
+    ///
+    /// If `previous` describes an earlier complete download of `url`, the request is made
+    /// conditional on its validators.
+    fn download<F>(
+        &self,
+        url: &str,
+        partial: &Path,
+        previous: Option<&FileMeta>,
+        progress: &mut F,
+    ) -> Result<Downloaded, FetchPackagesError>
+    where
+        F: FnMut(u64, Option<u64>),
+    {
+        if let Some(path) = file_url_path(url) {
+            return copy_local(&self.options, url, &path, partial, progress);
+        }
+
+        let meta_path = FileMeta::path(partial);
+        let meta = std::fs::read_to_string(&meta_path)
+            .ok()
+            .and_then(|s| FileMeta::parse(&s));
+        let mut offset = match std::fs::metadata(partial) {
+            Ok(m) => FileMeta::resume_offset(meta.as_ref(), url, m.len()),
+            Err(_) => 0,
+        };
+
+        let started = Instant::now();
+        let mut resp = loop {
+            self.options.check_deadline()?;
+            let mut req = self.request(url);
+            if let Some(validator) = meta.as_ref().and_then(|m| m.range_validator()) {
+                if offset > 0 {
+                    req = req
+                        .header(RANGE, format!("bytes={offset}-"))
+                        .header(IF_RANGE, validator);
+                }
+            }
+            if let Some(previous) = previous.filter(|_| offset == 0) {
+                if let Some(etag) = &previous.etag {
+                    req = req.header(IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &previous.last_modified {
+                    req = req.header(IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+
+            let resp = req.send()?;
+            if offset > 0 && resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+                offset = 0;
+                continue;
+            }
+
+            break resp.error_for_status()?;
+        };
+
+        let mut transfer = Transfer::from_response(resp.url(), resp.status(), resp.headers());
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Downloaded::NotModified(transfer.revalidated(previous)));
+        }
+
+        // Anything but 206 is the full body, so start over.
+        let resume = offset > 0 && resp.status() == StatusCode::PARTIAL_CONTENT;
+        let start = if resume { offset } else { 0 };
+        let mut downloaded = start;
+        let total = resp.content_length().map(|len| len + downloaded);
+        self.options
+            .check_size(SizeLimit::Download, total.unwrap_or(0))?;
+
+        std::fs::write(
+            &meta_path,
+            FileMeta::from_response(url, resp.headers()).to_string(),
+        )?;
+
+        let mut f = if resume {
+            std::fs::OpenOptions::new().append(true).open(partial)?
+        } else {
+            std::fs::File::create(partial)?
+        };
+
+        progress(downloaded, total);
+        let limit = self.options.max_download.saturating_sub(start);
+        self.read_body(url, started, &mut resp, &mut f, limit, |n| {
+            downloaded += n as u64;
+            progress(downloaded, total);
+        })?;
+
+        f.flush()?;
+        // Anything short of the announced size must not be decompressed.
+        check_length(total, downloaded)?;
+        transfer.bytes = downloaded - start;
+
+        Ok(Downloaded::Complete(transfer))
+    }
+
+    /// A GET request for `url` that is cut off at the deadline of the current fetch
+    fn request(&self, url: &str) -> RequestBuilder {
+        self.options.observe_request(url);
+        let mut req = self.client.get(url);
+        if self.options.no_cache {
+            req = req.header(CACHE_CONTROL, "no-cache");
+        }
+        match self.options.remaining() {
+            Some(remaining) => req.timeout(remaining),
+            None => req,
+        }
+    }
+
+    /// Copy the body of `resp` to `out`, calling `on_chunk` with the size of every chunk
+    ///
+    /// Fails once more than `limit` bytes arrived, which are left unwritten.
+    fn read_body(
+        &self,
+        url: &str,
+        started: Instant,
+        resp: &mut Response,
+        out: &mut impl Write,
+        limit: u64,
+        mut on_chunk: impl FnMut(usize),
+    ) -> Result<(), FetchPackagesError> {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut read = 0;
+
+        loop {
+            let n = match resp.read(&mut chunk) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(FetchPackagesError::from_body_error(url, e)),
+            };
+            read += n as u64;
+            if read > limit {
+                return Err(FetchPackagesError::PayloadTooLarge {
+                    which: SizeLimit::Download,
+                    limit,
+                });
+            }
+            out.write_all(&chunk[..n])?;
+            on_chunk(n);
+            self.options.observer().on_bytes(n as u64);
+            if let Some(limit) = &self.options.rate_limit {
+                let delay = limit.delay(n);
+                // Don't sleep past the deadline only to notice it afterwards.
+                std::thread::sleep(self.options.remaining().map_or(delay, |r| delay.min(r)));
+            }
+            self.options.check_deadline()?;
+
+            // reqwest only bounds each read, so check the whole transfer here.
+            if self.options.timeout.is_some_and(|t| started.elapsed() > t) {
+                return Err(FetchPackagesError::Timeout {
+                    url: redact_url(url),
+                    source: None,
+                });
+            }
+        }
+    }
+
+    /// Decompress the finished download at `partial` to `tmp`, failing as soon as it exceeds
+    /// the decompressed size limit
+    ///
+    /// The download is streamed through the decoder, so only a chunk of either is in memory
+    /// at a time. Nothing is written under the final name until
+    /// [`FetchOptions::finish_index`].
+    fn decompress(
+        &self,
+        url: &str,
+        compression: Compression,
+        partial: &Path,
+        tmp: &Path,
+    ) -> Result<(), FetchPackagesError> {
+        let limit = self.options.max_decompressed;
+        let input = io::BufReader::new(std::fs::File::open(partial)?);
+        let input = check_head(compression, input)?;
+        // One byte more than allowed tells an index at the limit from a larger one.
+        let mut decoder = decoder(url, compression, input)?.take(limit.saturating_add(1));
+
+        let mut f = std::fs::File::create(tmp)?;
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut written = 0;
+        loop {
+            let n = match decoder.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(FetchPackagesError::from_decoder_error(url, e)),
+            };
+            written += n as u64;
+            if written > limit {
+                return Err(FetchPackagesError::PayloadTooLarge {
+                    which: SizeLimit::Decompressed,
+                    limit,
+                });
+            }
+            f.write_all(&chunk[..n])?;
+        }
+        // Make sure the data is on disk before the rename makes it visible.
+        f.sync_all()?;
+
+        Ok(())
+    }
+}
+
+/// Copy `path` from a local mirror to `partial`, as if it had been downloaded from `url`
+fn copy_local(
+    options: &FetchOptions,
+    url: &str,
+    path: &Path,
+    partial: &Path,
+    progress: &mut impl FnMut(u64, Option<u64>),
+) -> Result<Downloaded, FetchPackagesError> {
+    let local_err = |e| FetchPackagesError::from_local_io(path, e);
+    let total = std::fs::metadata(path).map_err(local_err)?.len();
+    options.check_size(SizeLimit::Download, total)?;
+
+    std::fs::write(FileMeta::path(partial), FileMeta::local(url).to_string())?;
+    progress(0, Some(total));
+    std::fs::copy(path, partial).map_err(local_err)?;
+    progress(total, Some(total));
+
+    Ok(Downloaded::Complete(Transfer::local(url, total)))
+}
+
+/// Check that `bytes` downloaded from `url` are in `compression` and decompress them,
+/// failing as soon as they exceed `limit` bytes
+///
+/// What they decompress to is also written to `sink` as it is decompressed.
+fn decode(
+    url: &str,
+    compression: Compression,
+    bytes: Vec<u8>,
+    limit: u64,
+    sink: &mut dyn Write,
+) -> Result<Vec<u8>, FetchPackagesError> {
+    compression.check_magic(&bytes)?;
+    let too_large = || FetchPackagesError::PayloadTooLarge {
+        which: SizeLimit::Decompressed,
+        limit,
+    };
+
+    match compression {
+        Compression::None | Compression::Auto if bytes.len() as u64 > limit => {
+            return Err(too_large())
+        }
+        Compression::None | Compression::Auto => {
+            sink.write_all(&bytes)
+                .map_err(FetchPackagesError::WriterError)?;
+            return Ok(bytes);
+        }
+        _ => {}
+    }
+    let mut res = vec![];
+    // One byte more than allowed tells an index at the limit from a larger one.
+    let mut reader = io::BufReader::new(
+        decoder(url, compression, Cursor::new(bytes))?.take(limit.saturating_add(1)),
+    );
+    loop {
+        let chunk = reader
+            .fill_buf()
+            .map_err(|e| FetchPackagesError::from_decoder_error(url, e))?;
+        if chunk.is_empty() {
+            break;
+        }
+        if (res.len() + chunk.len()) as u64 > limit {
+            return Err(too_large());
+        }
+        sink.write_all(chunk)
+            .map_err(FetchPackagesError::WriterError)?;
+        res.extend_from_slice(chunk);
+        let len = chunk.len();
+        reader.consume(len);
+    }
+
+    Ok(res)
+}
+
+/// Check that `input` is in `compression` and return a reader of all of it
+///
+/// A single read may return fewer bytes than the magic is long, so this reads until it has the
+/// whole magic or the input ends.
+fn check_head(
+    compression: Compression,
+    mut input: impl io::BufRead,
+) -> Result<impl io::BufRead, FetchPackagesError> {
+    let mut head = Vec::with_capacity(Compression::MAX_MAGIC_LEN);
+    (&mut input)
+        .take(Compression::MAX_MAGIC_LEN as u64)
+        .read_to_end(&mut head)?;
+    compression.check_magic(&head)?;
+
+    Ok(Cursor::new(head).chain(input))
+}
+
+/// A reader of what `input`, downloaded from `url`, decompresses to in `compression`
+fn decoder<'a>(
+    url: &str,
+    compression: Compression,
+    input: impl io::BufRead + 'a,
+) -> Result<Box<dyn Read + 'a>, FetchPackagesError> {
+    Ok(match compression {
+        Compression::None | Compression::Auto => Box::new(input),
+        Compression::Xz => Box::new(xz2::bufread::XzDecoder::new(input)),
+        Compression::Gzip => Box::new(flate2::bufread::GzDecoder::new(input)),
+        Compression::Zstd => Box::new(
+            zstd::stream::read::Decoder::with_buffer(input)
+                .map_err(|e| FetchPackagesError::from_decoder_error(url, e))?,
+        ),
+    })
+}
+
+/// Metadata of the last successful download, if the file it describes still exists
+fn previous_download(path: &Path) -> Option<FileMeta> {
+    if !path.exists() {
+        return None;
+    }
+
+    FileMeta::parse(&std::fs::read_to_string(FileMeta::path(path)).ok()?)
+}
+
+/// Record that the server confirmed the download at `path` to be current
+fn refresh_download(path: &Path) {
+    if let Some(mut meta) = previous_download(path) {
+        meta.fetched = Some(SystemTime::now());
+        let _ = std::fs::write(FileMeta::path(path), meta.to_string());
+    }
+}
+
+/// Check that the file at `path` is the file listed as `name` in the release file, without
+/// reading all of it into memory
+fn check_file_sha256(
+    name: &str,
+    expected: &ReleaseFile,
+    path: &Path,
+) -> Result<(), FetchPackagesError> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    check_digest(name, expected, size, hasher)
+}
+
+/// Parse the index left by an earlier download, checking it against `expected` if given
+fn read_local<T: IndexFile>(
+    path: &Path,
+    expected: Option<&ReleaseFile>,
+) -> Result<(T, u64), FetchPackagesError> {
+    let bytes = std::fs::read(path)?;
+    if let Some(expected) = expected {
+        check_sha256(&path.to_string_lossy(), expected, &bytes)?;
+    }
+
+    Ok((T::parse(&bytes)?, bytes.len() as u64))
+}
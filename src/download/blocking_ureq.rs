@@ -0,0 +1,378 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Read},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use http::{
+    header::{HeaderName, HeaderValue, AUTHORIZATION, LOCATION},
+    HeaderMap, Method, Request, StatusCode, Uri,
+};
+use ureq::{
+    config::Config,
+    tls::{parse_pem, Certificate, ClientCert, PemItem, RootCerts, TlsConfig},
+    unversioned::{
+        resolver::{DefaultResolver, ResolvedSocketAddrs, Resolver},
+        transport::{DefaultConnector, NextTimeout},
+    },
+    Agent, BodyReader, Proxy,
+};
+
+use super::{
+    content_length, invalid_proxy, is_same_origin, AddressFamily, FetchPackagesError,
+    FetcherBuilder, RedirectRules,
+};
+
+/// How long connecting and waiting for the response headers may each take unless
+/// [`FetcherBuilder::timeout`] is set, like the default of reqwest's blocking client
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The ureq agent of the blocking fetcher, with the part of the API of reqwest's blocking
+/// client the fetcher uses
+///
+/// Redirects are followed here rather than by ureq, so that [`RedirectRules`] applies to them
+/// the same way as with reqwest.
+#[derive(Clone)]
+pub(super) struct Client {
+    agent: Agent,
+    /// Sent with every request, `Authorization` only to the host first requested
+    headers: HeaderMap,
+    redirects: RedirectRules,
+    timeout: Option<Duration>,
+}
+
+impl FetcherBuilder<Agent> {
+    /// The client to build the fetcher with, using the agent passed to
+    /// [`with_client`](Self::with_client) if any
+    pub(super) fn build_client(&mut self) -> Result<Client, FetchPackagesError> {
+        if let Some(agent) = self.custom_client()? {
+            let config = agent.config();
+            let redirects = RedirectRules {
+                max_redirects: config.max_redirects() as usize,
+                deny_downgrade: false,
+                https_only: config.https_only(),
+                same_origin: false,
+            };
+            return Ok(Client {
+                agent,
+                headers: HeaderMap::new(),
+                redirects,
+                timeout: None,
+            });
+        }
+
+        let proxy = match self.proxy_url()? {
+            Some(url) => {
+                Some(Proxy::new(url.as_str()).map_err(|e| invalid_proxy(url.as_str(), &e))?)
+            }
+            None if !self.system_proxy => None,
+            None => Proxy::try_from_env(),
+        };
+        let mut config = Agent::config_builder()
+            .user_agent(self.user_agent_header())
+            .proxy(proxy)
+            .tls_config(self.tls_config()?)
+            .timeout_connect(Some(self.connect_timeout.unwrap_or(DEFAULT_TIMEOUT)));
+        config = match self.timeout {
+            Some(timeout) => config.timeout_global(Some(timeout)),
+            None => config.timeout_recv_response(Some(DEFAULT_TIMEOUT)),
+        };
+        let resolver = Resolve {
+            hosts: self.resolve.clone(),
+            family: self.address_family,
+            system: DefaultResolver::default(),
+        };
+
+        Ok(Client {
+            agent: Agent::with_parts(config.build(), DefaultConnector::new(), resolver),
+            headers: self.build_headers()?,
+            redirects: self.redirect_rules(),
+            timeout: self.timeout,
+        })
+    }
+
+    fn tls_config(&self) -> Result<TlsConfig, FetchPackagesError> {
+        let mut tls = TlsConfig::builder().disable_verification(self.accept_invalid_certs);
+
+        if !self.root_certificates.is_empty() {
+            // ureq replaces its own roots with the given ones, reqwest adds to them.
+            let mut roots: Vec<Certificate<'static>> = webpki_root_certs::TLS_SERVER_ROOT_CERTS
+                .iter()
+                .map(|cert| Certificate::from_der(cert))
+                .collect();
+            for pem in &self.root_certificates {
+                let invalid = |reason: &dyn std::fmt::Display| {
+                    FetchPackagesError::InvalidConfig(format!("invalid root certificate: {reason}"))
+                };
+                let mut found = false;
+                for item in parse_pem(pem) {
+                    if let PemItem::Certificate(cert) = item.map_err(|e| invalid(&e))? {
+                        roots.push(cert);
+                        found = true;
+                    }
+                }
+                if !found {
+                    return Err(invalid(&"no certificate found in PEM"));
+                }
+            }
+            tls = tls.root_certs(RootCerts::new_with_certs(&roots));
+        }
+
+        if let Some(pem) = &self.identity {
+            let invalid = |reason: &dyn std::fmt::Display| {
+                FetchPackagesError::InvalidConfig(format!("invalid client identity: {reason}"))
+            };
+            let (mut certs, mut key) = (vec![], None);
+            for item in parse_pem(pem) {
+                match item.map_err(|e| invalid(&e))? {
+                    PemItem::Certificate(cert) => certs.push(cert),
+                    PemItem::PrivateKey(k) => key = key.or(Some(k)),
+                    _ => {}
+                }
+            }
+            let (false, Some(key)) = (certs.is_empty(), key) else {
+                return Err(invalid(&"a certificate and a private key are required"));
+            };
+            tls = tls.client_cert(Some(ClientCert::new_with_certs(&certs, key)));
+        }
+
+        Ok(tls.build())
+    }
+}
+
+impl Client {
+    pub(super) fn agent(&self) -> &Agent {
+        &self.agent
+    }
+
+    pub(super) fn get(&self, url: &str) -> RequestBuilder {
+        self.request(Method::GET, url)
+    }
+
+    pub(super) fn head(&self, url: &str) -> RequestBuilder {
+        self.request(Method::HEAD, url)
+    }
+
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        RequestBuilder {
+            client: self.clone(),
+            method,
+            url: url.to_string(),
+            headers: HeaderMap::new(),
+            timeout: self.timeout,
+            error: None,
+        }
+    }
+}
+
+pub(super) struct RequestBuilder {
+    client: Client,
+    method: Method,
+    url: String,
+    headers: HeaderMap,
+    timeout: Option<Duration>,
+    /// The first invalid header, reported by [`send`](Self::send) like reqwest does
+    error: Option<http::Error>,
+}
+
+impl RequestBuilder {
+    pub(super) fn header<V>(mut self, name: HeaderName, value: V) -> Self
+    where
+        HeaderValue: TryFrom<V>,
+        <HeaderValue as TryFrom<V>>::Error: Into<http::Error>,
+    {
+        match HeaderValue::try_from(value) {
+            Ok(value) => {
+                self.headers.append(name, value);
+            }
+            Err(e) => self.error = self.error.or(Some(e.into())),
+        }
+        self
+    }
+
+    /// Fail once the request, including reading the body, takes longer than `timeout`
+    pub(super) fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(self.timeout.map_or(timeout, |t| t.min(timeout)));
+        self
+    }
+
+    /// Send the request, following the redirects the fetcher allows
+    pub(super) fn send(self) -> Result<Response, FetchPackagesError> {
+        let fail = |url: &str, e| FetchPackagesError::from_ureq(url, e);
+        if let Some(e) = self.error {
+            return Err(fail(&self.url, ureq::Error::Http(e)));
+        }
+        let mut url = url::Url::parse(&self.url)
+            .map_err(|e| fail(&self.url, ureq::Error::BadUri(e.to_string())))?;
+
+        let started = Instant::now();
+        let mut method = self.method;
+        let mut previous: Vec<url::Url> = vec![];
+        loop {
+            let mut req = Request::builder().method(method.clone()).uri(url.as_str());
+            for (name, value) in &self.client.headers {
+                // Like reqwest, only send credentials to the host they were given for.
+                let elsewhere = previous
+                    .first()
+                    .is_some_and(|origin| !is_same_origin(origin, &url));
+                if name != AUTHORIZATION || !elsewhere {
+                    req = req.header(name, value);
+                }
+            }
+            for (name, value) in &self.headers {
+                req = req.header(name, value);
+            }
+            let req = req
+                .body(())
+                .map_err(|e| fail(url.as_str(), ureq::Error::Http(e)))?;
+
+            let mut config = self
+                .client
+                .agent
+                .configure_request(req)
+                .http_status_as_error(false)
+                .max_redirects(0);
+            if let Some(timeout) = self.timeout {
+                config = config.timeout_global(Some(timeout.saturating_sub(started.elapsed())));
+            }
+            let resp = self
+                .client
+                .agent
+                .run(config.build())
+                .map_err(|e| fail(url.as_str(), e))?;
+
+            let status = resp.status();
+            let location = resp
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .filter(|_| status.is_redirection() && status != StatusCode::NOT_MODIFIED);
+            let Some(location) = location else {
+                let (parts, body) = resp.into_parts();
+                return Ok(Response {
+                    url,
+                    status,
+                    headers: parts.headers,
+                    body: body.into_reader(),
+                });
+            };
+
+            let next = url
+                .join(location)
+                .map_err(|e| fail(url.as_str(), ureq::Error::BadUri(e.to_string())))?;
+            previous.push(url);
+            if let Err(e) = self.client.redirects.check(&previous, &next) {
+                return Err(FetchPackagesError::from_redirect_error(&*e)
+                    .expect("redirect rules only fail with redirect errors"));
+            }
+            if status == StatusCode::SEE_OTHER && method != Method::HEAD {
+                method = Method::GET;
+            }
+            url = next;
+        }
+    }
+}
+
+pub(super) struct Response {
+    /// The URL after following redirects
+    url: url::Url,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: BodyReader<'static>,
+}
+
+impl Response {
+    pub(super) fn url(&self) -> &url::Url {
+        &self.url
+    }
+
+    pub(super) fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub(super) fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub(super) fn content_length(&self) -> Option<u64> {
+        content_length(&self.headers)
+    }
+
+    /// Fail on a 4xx or 5xx status, like reqwest
+    pub(super) fn error_for_status(self) -> Result<Self, FetchPackagesError> {
+        if self.status.is_client_error() || self.status.is_server_error() {
+            let e = ureq::Error::StatusCode(self.status.as_u16());
+            return Err(FetchPackagesError::from_ureq(self.url.as_str(), e));
+        }
+
+        Ok(self)
+    }
+}
+
+impl Read for Response {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+
+/// The addresses given to [`FetcherBuilder::resolve_host`], looking up any other host in DNS
+#[derive(Debug)]
+struct Resolve {
+    hosts: BTreeMap<String, Vec<SocketAddr>>,
+    family: AddressFamily,
+    system: DefaultResolver,
+}
+
+impl Resolver for Resolve {
+    fn resolve(
+        &self,
+        uri: &Uri,
+        config: &Config,
+        timeout: NextTimeout,
+    ) -> Result<ResolvedSocketAddrs, ureq::Error> {
+        let mut res = match uri.host().and_then(|host| self.hosts.get(host)) {
+            Some(addrs) => self.overridden(uri, addrs)?,
+            None => self.system.resolve(uri, config, timeout)?,
+        };
+
+        // ureq tries the addresses in order, so the other version is still the fallback.
+        let prefer_ipv6 = match self.family {
+            AddressFamily::Auto => return Ok(res),
+            AddressFamily::PreferIpv4 => false,
+            AddressFamily::PreferIpv6 => true,
+        };
+        res.sort_by_key(|addr| addr.is_ipv6() != prefer_ipv6);
+
+        Ok(res)
+    }
+}
+
+impl Resolve {
+    /// The addresses given for the host of `uri`, with the port of `uri`
+    fn overridden(
+        &self,
+        uri: &Uri,
+        addrs: &[SocketAddr],
+    ) -> Result<ResolvedSocketAddrs, ureq::Error> {
+        // Like reqwest, only the IP is overridden, the port comes from the URL.
+        let port = uri
+            .port_u16()
+            .unwrap_or(if uri.scheme_str() == Some("https") {
+                443
+            } else {
+                80
+            });
+        let mut res = self.empty();
+        for addr in addrs {
+            if res.try_push(SocketAddr::new(addr.ip(), port)).is_err() {
+                break;
+            }
+        }
+        if res.is_empty() {
+            return Err(ureq::Error::HostNotFound);
+        }
+
+        Ok(res)
+    }
+}
@@ -0,0 +1,3178 @@
+// The fetchers are what use the shared code, so a build with neither has plenty unused.
+#![cfg_attr(
+    not(any(feature = "blocking", feature = "blocking-ureq", feature = "async")),
+    allow(dead_code, unused_imports)
+)]
+
+#[cfg(all(feature = "blocking", feature = "blocking-ureq"))]
+compile_error!(
+    "features `blocking` and `blocking-ureq` are two backends of the same fetcher, enable only one"
+);
+
+#[cfg(feature = "download")]
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashSet},
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Instant, SystemTime},
+};
+
+#[cfg(feature = "download")]
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "download")]
+use http::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, ETAG,
+        LAST_MODIFIED,
+    },
+    StatusCode,
+};
+
+use std::{fmt, time::Duration};
+use thiserror::Error;
+
+use crate::{
+    contents::ParseContentsError, packages::ParseControlError, release::ParseReleaseError,
+};
+
+#[cfg(feature = "download")]
+use crate::{
+    arch::{get_arch_name, AOSCBranch},
+    contents::Contents,
+    packages::{Package, Packages},
+    release::{parse_date, Release, ReleaseFile},
+    sources::Sources,
+};
+
+#[cfg(feature = "async")]
+use crate::closure::ResolveError;
+
+#[cfg(all(feature = "download", feature = "verify"))]
+use crate::{keyring::Cert, release::SignatureError};
+
+#[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+mod blocking;
+#[cfg(feature = "blocking-ureq")]
+mod blocking_ureq;
+#[cfg(feature = "async")]
+mod nonblocking;
+#[cfg(feature = "download")]
+mod pdiff;
+
+#[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+pub use blocking::FetchPackages;
+#[cfg(feature = "async")]
+pub use nonblocking::{
+    benchmark_mirrors, compare_mirrors, ClosureDownload, FetchPackagesAsync, MirrorBenchmark,
+    MirrorStatus, ProgressEvent,
+};
+
+#[cfg(feature = "download")]
+const USER_AGENT: &str = "aosc";
+
+#[cfg(feature = "download")]
+const DEFAULT_MIRROR: &str = "https://repo.aosc.io/debs";
+
+/// Repository root of AOSC OS/Retro, see [`FetcherBuilder::aosc_branch`]
+#[cfg(feature = "download")]
+const DEFAULT_RETRO_MIRROR: &str = "https://repo.aosc.io/debs-retro";
+
+/// `product` followed by the version of this library, such as `mytool/0.3 libaosc/0.2.1`
+///
+/// Meant for [`FetcherBuilder::user_agent`], so mirror operators can tell both apart.
+#[cfg(feature = "download")]
+pub fn versioned_user_agent(product: &str) -> String {
+    format!("{product} libaosc/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// How far the clocks of a mirror and the client may disagree
+#[cfg(feature = "download")]
+const CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// Redirects followed for a single request, the same as reqwest's default
+#[cfg(feature = "download")]
+const MAX_REDIRECTS: usize = 10;
+
+/// Default of [`FetcherBuilder::max_download_size`]
+#[cfg(feature = "download")]
+const DEFAULT_MAX_DOWNLOAD_SIZE: u64 = 4 << 30;
+
+/// Default of [`FetcherBuilder::max_decompressed_size`]
+#[cfg(feature = "download")]
+const DEFAULT_MAX_DECOMPRESSED_SIZE: u64 = 4 << 30;
+
+/// Size of the reads that feed a file to a hasher
+#[cfg(feature = "async")]
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Compression format of the Packages index to download
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Xz,
+    Gzip,
+    Zstd,
+    /// Try every format the mirror may publish, see [`Compression::AUTO_ORDER`]
+    Auto,
+}
+
+impl Compression {
+    /// Formats tried by [`Compression::Auto`], in order of preference
+    pub const AUTO_ORDER: [Compression; 4] = [
+        Compression::Zstd,
+        Compression::Xz,
+        Compression::Gzip,
+        Compression::None,
+    ];
+
+    /// File name suffix used by the repository for this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None | Compression::Auto => "",
+            Compression::Xz => ".xz",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    #[cfg(feature = "download")]
+    fn candidates(&self) -> &[Compression] {
+        match self {
+            Compression::Auto => &Self::AUTO_ORDER,
+            c => std::slice::from_ref(c),
+        }
+    }
+
+    /// Length of the longest magic, how much of a download to read before checking it
+    #[cfg(feature = "download")]
+    const MAX_MAGIC_LEN: usize = 6;
+
+    #[cfg(feature = "download")]
+    fn magic(&self) -> &'static [u8] {
+        match self {
+            Compression::None | Compression::Auto => &[],
+            Compression::Xz => &[0xfd, b'7', b'z', b'X', b'Z', 0x00],
+            Compression::Gzip => &[0x1f, 0x8b],
+            Compression::Zstd => &[0x28, 0xb5, 0x2f, 0xfd],
+        }
+    }
+
+    #[cfg(feature = "download")]
+    fn check_magic(&self, head: &[u8]) -> Result<(), FetchPackagesError> {
+        if head.starts_with(self.magic()) {
+            Ok(())
+        } else {
+            Err(FetchPackagesError::UnexpectedFormat(*self))
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "uncompressed",
+            Compression::Xz => "xz",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Auto => "auto",
+        })
+    }
+}
+
+/// Which copies of a downloaded index are kept in the download directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexStorage {
+    Decompressed,
+    /// The body exactly as the server sent it, named after the index plus the format's
+    /// extension, such as `Packages_stable_main_amd64.xz`
+    Compressed,
+    Both,
+}
+
+/// A limit on the size of downloads, see [`FetchPackagesError::PayloadTooLarge`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeLimit {
+    /// [`FetcherBuilder::max_download_size`], or the size Packages lists for a package
+    Download,
+    /// [`FetcherBuilder::max_decompressed_size`]
+    Decompressed,
+}
+
+impl fmt::Display for SizeLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SizeLimit::Download => "download",
+            SizeLimit::Decompressed => "decompressed",
+        })
+    }
+}
+
+/// Which IP version to try first when connecting to mirrors
+///
+/// Whatever the choice, the other version is still tried if the preferred one doesn't connect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// Start with the version of the first address a name resolves to, and try the other
+    /// version too if that doesn't connect within 300 ms
+    Auto,
+    /// Try the IPv4 addresses of a host first, e.g. if the IPv6 route to a mirror is slow
+    PreferIpv4,
+    /// Try the IPv6 addresses of a host first
+    PreferIpv6,
+}
+
+/// How the indices of a repository are laid out below its URL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepoLayout {
+    /// Indices under `dists/<branch>/<component>/`, as on the AOSC mirrors
+    Pooled,
+    /// Indices such as `Packages.xz` and `InRelease` right in the repository root, as in
+    /// add-on repositories without `dists/`
+    ///
+    /// Arch, branch and component only name the local copies then.
+    Flat,
+}
+
+/// Whether fetched Packages were downloaded or reparsed from the local copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchSource {
+    Network,
+    /// The server confirmed with 304 that the local copy is current
+    Cache,
+    /// The local copy matched the SHA256 in the release file, so only the release file was
+    /// downloaded
+    MatchesRelease,
+    /// The local copy was reparsed as is, without the server confirming it, because of the
+    /// [`OfflinePolicy`]
+    ///
+    /// `age` counts from its download or the last time the server confirmed it.
+    Stale {
+        age: Duration,
+    },
+}
+
+/// What to do about the network when fetching an index into the download directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OfflinePolicy {
+    /// Fail if the index can't be downloaded
+    Strict,
+    /// Fall back to the local copy if the mirrors can't be reached, but not if they answer
+    /// with an error such as 404
+    FallbackToCache,
+    /// Only ever use the local copy, without any request
+    Offline,
+}
+
+/// Statistics of a single Packages fetch
+#[cfg(feature = "download")]
+#[derive(Debug, Clone)]
+pub struct FetchReport {
+    /// Bytes received over the wire, compressed if the index was
+    ///
+    /// Bytes kept from an earlier interrupted download are not counted, and a 304 counts 0.
+    pub transferred: u64,
+    /// Size of the parsed index after decompression
+    pub decompressed_size: u64,
+    /// Time from the start of the fetch, including `InRelease`, to the parsed index
+    pub elapsed: Duration,
+    /// URL of the index after following redirects, without the user name and password
+    pub url: String,
+    /// Final HTTP status, `None` for local mirrors
+    pub status: Option<StatusCode>,
+    /// `Last-Modified` of the response
+    ///
+    /// On 304 this falls back to the date the request was made with, if the server didn't
+    /// repeat it.
+    pub last_modified: Option<SystemTime>,
+    /// `ETag` of the response, with the same fallback on 304
+    pub etag: Option<String>,
+}
+
+/// Indices fetched by `fetch_snapshot` besides Packages, which always is
+#[cfg(feature = "download")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SnapshotParts {
+    pub contents: bool,
+    pub sources: bool,
+}
+
+/// A release file and indices of a branch that were all checked against it, so they
+/// describe the same state of the repository
+///
+/// Returned by `fetch_snapshot` of either fetcher.
+#[cfg(feature = "download")]
+pub struct Snapshot {
+    pub release: Release,
+    pub packages: Packages,
+    /// Only fetched if [`SnapshotParts::contents`] was set
+    pub contents: Option<Contents>,
+    /// Only fetched if [`SnapshotParts::sources`] was set
+    pub sources: Option<Sources>,
+}
+
+/// Hooks into the downloads of a fetcher, e.g. to export metrics
+///
+/// Every method does nothing by default. Index, release file and package downloads all go
+/// through the same hooks, which are called from whichever thread or task is downloading,
+/// so they should return quickly. Attach one with [`FetcherBuilder::observer`].
+#[cfg(feature = "download")]
+pub trait FetchObserver: Send + Sync {
+    /// A request for `url` is about to be sent, again for every retry
+    ///
+    /// Files of a `file://` mirror are read without a request.
+    fn on_request(&self, _url: &url::Url) {}
+
+    /// Another `_bytes` of a response body arrived
+    fn on_bytes(&self, _bytes: u64) {}
+
+    /// Attempt number `_attempt` failed with `_error`, which is about to be retried
+    fn on_retry(&self, _attempt: u32, _error: &FetchPackagesError) {}
+
+    /// An index, release file or package download finished
+    ///
+    /// For an index this is the report `fetch_packages_with_report` would return, for a
+    /// release file or package `decompressed_size` is its size.
+    fn on_complete(&self, _report: &FetchReport) {}
+
+    /// A refresh of a `spawn_refresher` task failed with `_error`, and is tried again at the
+    /// next interval
+    fn on_refresh_failed(&self, _error: &FetchPackagesError) {}
+}
+
+/// The observer of a fetcher without one
+#[cfg(feature = "download")]
+struct NoObserver;
+
+#[cfg(feature = "download")]
+impl FetchObserver for NoObserver {}
+
+#[derive(Debug, Error)]
+pub enum FetchPackagesError {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    #[error(transparent)]
+    ReqwestError(reqwest::Error),
+    #[cfg(feature = "blocking-ureq")]
+    #[error(transparent)]
+    UreqError(ureq::Error),
+    #[cfg(feature = "download")]
+    #[error("Request to {url} timed out")]
+    Timeout {
+        url: String,
+        #[cfg(any(feature = "blocking", feature = "async"))]
+        #[source]
+        source: Option<reqwest::Error>,
+        /// Set by the ureq backend, unless the `async` feature makes this a reqwest error
+        #[cfg(all(feature = "blocking-ureq", not(feature = "async")))]
+        #[source]
+        source: Option<ureq::Error>,
+    },
+    #[error("Failed to parse string to deb822 format")]
+    DebControl(ParseControlError),
+    #[error("Failed to parse Contents index")]
+    Contents(ParseContentsError),
+    #[error("Failed to parse release file")]
+    Release(ParseReleaseError),
+    #[cfg(all(feature = "download", feature = "verify"))]
+    #[error(transparent)]
+    Signature(#[from] SignatureError),
+    #[error("Checksum mismatch for {path}: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("Size mismatch for {path}: expected {expected} bytes, got {actual}")]
+    SizeMismatch {
+        path: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("Download is truncated: expected {expected} bytes, got {got}")]
+    TruncatedDownload { expected: u64, got: u64 },
+    #[error("Corrupt download from {url}: {message}")]
+    CorruptPayload { url: String, message: String },
+    #[error("Malformed pdiff {path}: {message}")]
+    Pdiff { path: String, message: String },
+    #[error("Response is not {0} data (unexpected magic bytes)")]
+    UnexpectedFormat(Compression),
+    #[error("Invalid fetcher configuration: {0}")]
+    InvalidConfig(String),
+    #[cfg(feature = "download")]
+    #[error("{} does not exist on the local mirror", .0.display())]
+    LocalFileNotFound(PathBuf),
+    #[cfg(feature = "download")]
+    #[error("No earlier download at {} to use offline", .0.display())]
+    NoLocalCopy(PathBuf),
+    #[cfg(feature = "download")]
+    #[error("APT sources under {} configure no AOSC OS repository", .0.display())]
+    NoSystemSuites(PathBuf),
+    #[error("Component {0} does not exist on the mirror")]
+    ComponentNotFound(String),
+    #[error("Topic {0} does not exist on the mirror")]
+    TopicNotFound(String),
+    #[error("Topic {topic} has no index for {arch}")]
+    TopicArchNotFound { topic: String, arch: String },
+    #[error("{0} is not listed in the release file")]
+    NotInRelease(String),
+    #[error("Host architecture {0} is not supported by AOSC OS")]
+    UnsupportedHostArch(String),
+    #[error("{0} requires authentication (401 Unauthorized)")]
+    Unauthorized(String),
+    #[error("Failed to write the index to the given writer")]
+    WriterError(#[source] std::io::Error),
+    #[error("Refused to follow redirect ({reason}): {}", .chain.join(" -> "))]
+    Redirect { chain: Vec<String>, reason: String },
+    #[error("Refused to fetch {0} over plain HTTP, the fetcher is HTTPS-only")]
+    InsecureTransport(String),
+    #[cfg(feature = "download")]
+    #[error(
+        "All mirrors failed: {}",
+        .0.iter().map(|(url, e)| format!("{url}: {e}")).collect::<Vec<_>>().join("; ")
+    )]
+    AllMirrorsFailed(Vec<(String, FetchPackagesError)>),
+    #[cfg(feature = "download")]
+    #[error("Release file is older than the allowed {} seconds", max_age.as_secs())]
+    StaleRelease { date: SystemTime, max_age: Duration },
+    #[cfg(feature = "download")]
+    #[error("Fetch did not finish within {} seconds", .0.as_secs_f64())]
+    DeadlineExceeded(Duration),
+    #[error("Failed to fetch {url} (arch={arch}, branch={branch}): {source}")]
+    Fetch {
+        url: String,
+        arch: String,
+        branch: String,
+        #[source]
+        source: Box<FetchPackagesError>,
+    },
+    #[error("Payload is larger than the {which} size limit of {limit} bytes")]
+    PayloadTooLarge { which: SizeLimit, limit: u64 },
+    #[error("Download failed after {attempts} attempts")]
+    RetriesExhausted {
+        attempts: u32,
+        #[source]
+        source: Box<FetchPackagesError>,
+    },
+    #[cfg(feature = "async")]
+    #[error(transparent)]
+    JoinError(#[from] tokio::task::JoinError),
+    #[cfg(feature = "async")]
+    #[error("Fetch was cancelled")]
+    Cancelled,
+    #[cfg(feature = "async")]
+    #[error(transparent)]
+    UnresolvedDependencies(#[from] ResolveError),
+    #[cfg(feature = "async")]
+    #[error("{filename} would be stored as {}, which {other} already is", path.display())]
+    DuplicateDestination {
+        path: PathBuf,
+        filename: String,
+        other: String,
+    },
+}
+
+impl FetchPackagesError {
+    #[cfg(feature = "download")]
+    fn is_not_found(&self) -> bool {
+        match self {
+            #[cfg(any(feature = "blocking", feature = "async"))]
+            FetchPackagesError::ReqwestError(e) => e.status() == Some(StatusCode::NOT_FOUND),
+            #[cfg(feature = "blocking-ureq")]
+            FetchPackagesError::UreqError(ureq::Error::StatusCode(404)) => true,
+            FetchPackagesError::LocalFileNotFound(_) => true,
+            FetchPackagesError::AllMirrorsFailed(failures) => {
+                failures.iter().all(|(_, e)| e.is_not_found())
+            }
+            FetchPackagesError::Fetch { source, .. } => source.is_not_found(),
+            _ => false,
+        }
+    }
+
+    /// Map a failed read of `path` on a local mirror, treating a missing file like a 404
+    #[cfg(feature = "download")]
+    fn from_local_io(path: &Path, e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            FetchPackagesError::LocalFileNotFound(path.to_path_buf())
+        } else {
+            FetchPackagesError::IoError(e)
+        }
+    }
+
+    /// Map a failure of the decoder reading the download from `url`
+    #[cfg(feature = "download")]
+    fn from_decoder_error(url: &str, e: std::io::Error) -> Self {
+        FetchPackagesError::CorruptPayload {
+            url: redact_url(url),
+            message: e.to_string(),
+        }
+    }
+
+    /// Whether the download was mangled on the way, e.g. by a proxy
+    #[cfg(feature = "download")]
+    fn is_corrupt_payload(&self) -> bool {
+        match self {
+            FetchPackagesError::CorruptPayload { .. } => true,
+            FetchPackagesError::Fetch { source, .. } => source.is_corrupt_payload(),
+            _ => false,
+        }
+    }
+
+    /// Whether the request may succeed if simply tried again
+    #[cfg(feature = "download")]
+    fn is_transient(&self) -> bool {
+        match self {
+            FetchPackagesError::Timeout { .. } | FetchPackagesError::TruncatedDownload { .. } => {
+                true
+            }
+            #[cfg(any(feature = "blocking", feature = "async"))]
+            FetchPackagesError::ReqwestError(e) => {
+                e.is_connect()
+                    || e.is_body()
+                    || e.is_decode()
+                    || e.status().is_some_and(|s| s.is_server_error())
+            }
+            #[cfg(feature = "blocking-ureq")]
+            FetchPackagesError::UreqError(e) => match e {
+                ureq::Error::StatusCode(status) => (500..600).contains(status),
+                ureq::Error::Io(_) | ureq::Error::Protocol(_) | ureq::Error::BodyStalled => true,
+                e => is_ureq_connect_error(e),
+            },
+            FetchPackagesError::Fetch { source, .. } => source.is_transient(),
+            _ => false,
+        }
+    }
+
+    /// Whether the mirrors couldn't be reached at all, as opposed to answering with an error
+    #[cfg(feature = "download")]
+    fn is_network_error(&self) -> bool {
+        match self {
+            FetchPackagesError::AllMirrorsFailed(failures) => {
+                failures.iter().all(|(_, e)| e.is_network_error())
+            }
+            FetchPackagesError::Fetch { source, .. } => source.is_network_error(),
+            #[cfg(any(feature = "blocking", feature = "async"))]
+            FetchPackagesError::ReqwestError(e) => e.is_connect() || e.is_timeout() || e.is_body(),
+            #[cfg(feature = "blocking-ureq")]
+            FetchPackagesError::UreqError(e) => {
+                matches!(e, ureq::Error::Io(_) | ureq::Error::BodyStalled)
+                    || is_ureq_connect_error(e)
+            }
+            FetchPackagesError::Timeout { .. } | FetchPackagesError::TruncatedDownload { .. } => {
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Recover the reqwest error from a failed read of a blocking response body
+    #[cfg(feature = "blocking")]
+    fn from_body_error(_url: &str, e: std::io::Error) -> Self {
+        if e.get_ref()
+            .is_some_and(|inner| inner.is::<reqwest::Error>())
+        {
+            let inner = e.into_inner().expect("checked above");
+            return (*inner.downcast::<reqwest::Error>().expect("checked above")).into();
+        }
+
+        FetchPackagesError::IoError(e)
+    }
+
+    /// Recover the ureq error from a failed read of the response body of `url`
+    #[cfg(feature = "blocking-ureq")]
+    fn from_body_error(url: &str, e: std::io::Error) -> Self {
+        match ureq::Error::from(e) {
+            ureq::Error::Io(e) => FetchPackagesError::IoError(e),
+            e => FetchPackagesError::from_ureq(url, e),
+        }
+    }
+
+    /// The error a redirect refused by [`RedirectRules`] was reported with, if `e` is one
+    #[cfg(feature = "download")]
+    fn from_redirect_error(e: &(dyn std::error::Error + 'static)) -> Option<Self> {
+        if let Some(InsecureRedirect(url)) = e.downcast_ref() {
+            Some(FetchPackagesError::InsecureTransport(url.clone()))
+        } else if let Some(RedirectError { chain, reason }) = e.downcast_ref() {
+            Some(FetchPackagesError::Redirect {
+                chain: chain.clone(),
+                reason: reason.clone(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether `e` means the server couldn't be reached at all
+#[cfg(feature = "blocking-ureq")]
+fn is_ureq_connect_error(e: &ureq::Error) -> bool {
+    matches!(
+        e,
+        ureq::Error::ConnectionFailed
+            | ureq::Error::HostNotFound
+            | ureq::Error::ConnectProxyFailed(_)
+            | ureq::Error::Timeout(_)
+    )
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+impl From<reqwest::Error> for FetchPackagesError {
+    fn from(e: reqwest::Error) -> Self {
+        if let Some(redirect) =
+            std::error::Error::source(&e).and_then(FetchPackagesError::from_redirect_error)
+        {
+            redirect
+        } else if e.status() == Some(StatusCode::UNAUTHORIZED) {
+            FetchPackagesError::Unauthorized(e.url().map(|u| u.to_string()).unwrap_or_default())
+        } else if e.is_timeout() {
+            FetchPackagesError::Timeout {
+                url: e.url().map(|u| u.to_string()).unwrap_or_default(),
+                source: Some(e),
+            }
+        } else {
+            FetchPackagesError::ReqwestError(e)
+        }
+    }
+}
+
+#[cfg(feature = "blocking-ureq")]
+impl FetchPackagesError {
+    /// Map the failure of a request for `url`, the same way as a reqwest error
+    fn from_ureq(url: &str, e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::StatusCode(401) => FetchPackagesError::Unauthorized(url.to_string()),
+            #[cfg(not(feature = "async"))]
+            ureq::Error::Timeout(_) => FetchPackagesError::Timeout {
+                url: url.to_string(),
+                source: Some(e),
+            },
+            #[cfg(feature = "async")]
+            ureq::Error::Timeout(_) => FetchPackagesError::Timeout {
+                url: url.to_string(),
+                source: None,
+            },
+            e => FetchPackagesError::UreqError(e),
+        }
+    }
+}
+
+/// Why a local index doesn't match its release file, see [`verify_local_index`]
+#[cfg(feature = "download")]
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error("{0} is not listed in the release file")]
+    NotListed(String),
+    #[error("Size mismatch for {entry}: expected {expected} bytes, got {actual}")]
+    SizeMismatch {
+        entry: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("Checksum mismatch for {entry}: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        entry: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("{0} checksums can't be verified")]
+    UnsupportedChecksum(&'static str),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+}
+
+/// A redirect refused by [`RedirectRules`], with the URLs up to it
+#[cfg(feature = "download")]
+#[derive(Debug, Error)]
+#[error("{reason}")]
+struct RedirectError {
+    chain: Vec<String>,
+    reason: String,
+}
+
+/// A redirect to plain HTTP refused by [`FetcherBuilder::https_only`]
+#[cfg(feature = "download")]
+#[derive(Debug, Error)]
+#[error("redirect to plain HTTP {0}")]
+struct InsecureRedirect(String);
+
+/// Which redirects a fetcher follows, see [`FetcherBuilder::max_redirects`]
+#[cfg(feature = "download")]
+#[derive(Debug, Clone)]
+struct RedirectRules {
+    max_redirects: usize,
+    deny_downgrade: bool,
+    https_only: bool,
+    /// Only follow redirects to the host and port of the URL first requested
+    same_origin: bool,
+}
+
+#[cfg(feature = "download")]
+impl RedirectRules {
+    /// Check the redirect to `next`, after the URLs in `previous` starting with the one
+    /// first requested
+    ///
+    /// A refused redirect is an [`InsecureRedirect`] or a [`RedirectError`].
+    fn check(
+        &self,
+        previous: &[url::Url],
+        next: &url::Url,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if self.https_only && next.scheme() != "https" {
+            return Err(InsecureRedirect(next.to_string()).into());
+        }
+        let (origin, last) = (&previous[0], &previous[previous.len() - 1]);
+        let reason = if previous.len() > self.max_redirects {
+            format!("more than {} redirects", self.max_redirects)
+        } else if self.deny_downgrade && last.scheme() == "https" && next.scheme() == "http" {
+            "redirect from HTTPS to HTTP".to_string()
+        } else if self.same_origin && !is_same_origin(origin, next) {
+            "custom headers can't be sent to another host".to_string()
+        } else {
+            return Ok(());
+        };
+
+        let mut chain: Vec<String> = previous.iter().map(|url| url.to_string()).collect();
+        chain.push(next.to_string());
+        Err(RedirectError { chain, reason }.into())
+    }
+}
+
+/// Whether `a` and `b` are on the same host and port
+#[cfg(feature = "download")]
+fn is_same_origin(a: &url::Url, b: &url::Url) -> bool {
+    a.host_str() == b.host_str() && a.port_or_known_default() == b.port_or_known_default()
+}
+
+/// The error for the invalid proxy URL `url`
+#[cfg(feature = "download")]
+fn invalid_proxy(url: &str, reason: &dyn fmt::Display) -> FetchPackagesError {
+    FetchPackagesError::InvalidConfig(format!("invalid proxy URL {url}: {reason}"))
+}
+
+/// Looks hosts up in DNS, or in the addresses given to
+/// [`FetcherBuilder::resolve_host`], and puts those of the preferred IP version first
+///
+/// The client tries them in order, falling back to the other version like for any host with
+/// addresses of both.
+#[cfg(any(feature = "blocking", feature = "async"))]
+struct PreferringResolver {
+    hosts: BTreeMap<String, Vec<SocketAddr>>,
+    prefer_ipv6: bool,
+}
+
+#[cfg(any(feature = "blocking", feature = "async"))]
+impl reqwest::dns::Resolve for PreferringResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_string();
+        // Port 0 has the client take the port from the URL.
+        let overrides = self.hosts.get(&host).map(|addrs| {
+            addrs
+                .iter()
+                .map(|addr| SocketAddr::new(addr.ip(), 0))
+                .collect::<Vec<_>>()
+        });
+        let prefer_ipv6 = self.prefer_ipv6;
+
+        Box::pin(async move {
+            let mut addrs = match overrides {
+                Some(addrs) => addrs,
+                None => lookup_host(host).await?,
+            };
+            // Stable, so the order within each version stays as resolved.
+            addrs.sort_by_key(|addr| addr.is_ipv6() != prefer_ipv6);
+
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// The addresses of `host` in DNS, looked up on a thread of the blocking pool of tokio
+#[cfg(feature = "async")]
+async fn lookup_host(host: String) -> std::io::Result<Vec<SocketAddr>> {
+    tokio::task::spawn_blocking(move || {
+        std::net::ToSocketAddrs::to_socket_addrs(&(host.as_str(), 0)).map(Iterator::collect)
+    })
+    .await?
+}
+
+/// The addresses of `host` in DNS
+///
+/// Without tokio the lookup runs on the runtime of the blocking client, so other requests of
+/// the client wait for it to finish.
+#[cfg(all(feature = "blocking", not(feature = "async")))]
+async fn lookup_host(host: String) -> std::io::Result<Vec<SocketAddr>> {
+    std::net::ToSocketAddrs::to_socket_addrs(&(host.as_str(), 0)).map(Iterator::collect)
+}
+
+#[cfg(feature = "download")]
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    retries: u32,
+    backoff: Duration,
+}
+
+#[cfg(feature = "download")]
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+#[cfg(feature = "download")]
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (starting at 1): exponential backoff with jitter
+    fn delay(&self, attempt: u32) -> Duration {
+        let exp = self.backoff.saturating_mul(1 << (attempt - 1).min(16));
+        // Up to half of the delay again, so that concurrent clients don't retry in lockstep.
+        let jitter = RandomState::new().build_hasher().finish() % 1024;
+
+        exp + exp.mul_f64(jitter as f64 / 2048.0)
+    }
+
+    /// Attach the number of attempts to an error that was still worth retrying
+    fn finish<T>(
+        &self,
+        res: Result<T, FetchPackagesError>,
+        attempts: u32,
+    ) -> Result<T, FetchPackagesError> {
+        match res {
+            Err(e) if attempts > 1 && e.is_transient() => {
+                Err(FetchPackagesError::RetriesExhausted {
+                    attempts,
+                    source: Box::new(e),
+                })
+            }
+            res => res,
+        }
+    }
+}
+
+/// Limit on the combined rate of all downloads of a fetcher
+#[cfg(feature = "download")]
+struct RateLimit {
+    bytes_per_sec: u64,
+    /// When the bytes read so far will have taken their share of the limit
+    next: Mutex<Instant>,
+}
+
+#[cfg(feature = "download")]
+impl RateLimit {
+    fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Account for `n` bytes that were just read, returning how long to wait before the next
+    /// read
+    ///
+    /// Concurrent downloads take turns, so together they stay within the limit.
+    fn delay(&self, n: usize) -> Duration {
+        let mut next = self.next.lock().expect("rate limit is never poisoned");
+        let now = Instant::now();
+        *next = (*next).max(now) + Duration::from_secs_f64(n as f64 / self.bytes_per_sec as f64);
+
+        next.saturating_duration_since(now)
+    }
+}
+
+/// Builder for [`FetchPackages`] and [`FetchPackagesAsync`], created by their `builder()`
+///
+/// `C` is the HTTP client type of the fetcher being built, a reqwest client, or a ureq agent
+/// for the blocking fetcher with the `blocking-ureq` feature.
+#[cfg(feature = "download")]
+pub struct FetcherBuilder<C> {
+    compression: Compression,
+    download_to: Option<PathBuf>,
+    mirror_urls: Option<Vec<String>>,
+    aosc_branch: AOSCBranch,
+    failover_not_found: bool,
+    component: String,
+    layout: RepoLayout,
+    retry: RetryPolicy,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    proxy: Option<String>,
+    system_proxy: bool,
+    user_agent: Option<String>,
+    headers: Vec<(String, String)>,
+    basic_auth: Option<(String, String)>,
+    max_redirects: Option<usize>,
+    deny_redirect_downgrade: bool,
+    https_only: bool,
+    root_certificates: Vec<Vec<u8>>,
+    identity: Option<Vec<u8>>,
+    accept_invalid_certs: bool,
+    address_family: AddressFamily,
+    resolve: BTreeMap<String, Vec<SocketAddr>>,
+    concurrency: usize,
+    max_rate: Option<u64>,
+    max_download: u64,
+    max_decompressed: u64,
+    segments: usize,
+    segment_min_size: u64,
+    by_hash: bool,
+    pdiff: bool,
+    verify: bool,
+    #[cfg(feature = "verify")]
+    keyring: Option<Vec<Cert>>,
+    max_release_age: Option<Duration>,
+    plain_name: bool,
+    storage: IndexStorage,
+    offline: OfflinePolicy,
+    observer: Option<Arc<dyn FetchObserver>>,
+    client: Option<C>,
+}
+
+#[cfg(feature = "download")]
+impl<C> FetcherBuilder<C> {
+    fn new() -> Self {
+        Self {
+            compression: Compression::Xz,
+            download_to: None,
+            mirror_urls: None,
+            aosc_branch: AOSCBranch::Mainline,
+            failover_not_found: false,
+            component: "main".to_string(),
+            layout: RepoLayout::Pooled,
+            retry: RetryPolicy::default(),
+            connect_timeout: None,
+            timeout: None,
+            proxy: None,
+            system_proxy: true,
+            user_agent: None,
+            headers: vec![],
+            basic_auth: None,
+            max_redirects: None,
+            deny_redirect_downgrade: false,
+            https_only: false,
+            root_certificates: vec![],
+            identity: None,
+            accept_invalid_certs: false,
+            address_family: AddressFamily::Auto,
+            resolve: BTreeMap::new(),
+            concurrency: 4,
+            max_rate: None,
+            max_download: DEFAULT_MAX_DOWNLOAD_SIZE,
+            max_decompressed: DEFAULT_MAX_DECOMPRESSED_SIZE,
+            segments: 1,
+            segment_min_size: 0,
+            by_hash: false,
+            pdiff: false,
+            verify: false,
+            #[cfg(feature = "verify")]
+            keyring: None,
+            max_release_age: None,
+            plain_name: false,
+            storage: IndexStorage::Decompressed,
+            offline: OfflinePolicy::Strict,
+            observer: None,
+            client: None,
+        }
+    }
+
+    /// Format of the index to download, defaults to [`Compression::Xz`]
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Directory downloaded indices are written to
+    ///
+    /// Required by every fetch except `fetch_packages_in_memory`, which never touches the
+    /// filesystem.
+    pub fn download_to<P: AsRef<Path>>(mut self, download_to: P) -> Self {
+        self.download_to = Some(download_to.as_ref().to_path_buf());
+        self
+    }
+
+    /// Repository root to download from, defaults to `https://repo.aosc.io/debs`
+    ///
+    /// A `file://` URL or an absolute path reads the repository from the local filesystem
+    /// instead, such as a mirror on a network share.
+    ///
+    /// The URL is checked when the fetcher is built. It has to be `http`, `https` or `file`
+    /// and can't have a query or fragment, as paths are appended to it. Trailing slashes are
+    /// dropped.
+    pub fn mirror_url(mut self, mirror_url: &str) -> Self {
+        self.mirror_urls = Some(vec![mirror_url.to_string()]);
+        self
+    }
+
+    /// Repository roots to download from, in order of preference
+    ///
+    /// Every request goes to the first mirror, and moves on to the next one if it fails with
+    /// a connection error, a timeout or a 5xx response (after any [`retries`](Self::retries)).
+    /// If all of them fail, [`FetchPackagesError::AllMirrorsFailed`] lists the failure of
+    /// each mirror. The list must not be empty, and each URL is checked like in
+    /// [`mirror_url`](Self::mirror_url).
+    pub fn mirror_urls(mut self, mirror_urls: &[&str]) -> Self {
+        self.mirror_urls = Some(mirror_urls.iter().map(|url| url.to_string()).collect());
+        self
+    }
+
+    /// AOSC OS branch whose repository to download from, defaults to
+    /// [`AOSCBranch::Mainline`]
+    ///
+    /// [`AOSCBranch::Afterglow`] downloads from `https://repo.aosc.io/debs-retro`, where the
+    /// packages of AOSC OS/Retro live, instead of `https://repo.aosc.io/debs`. Only changes
+    /// the default, so a [`mirror_url`](Self::mirror_url) still takes precedence. See
+    /// [`aosc_branch`](crate::arch::aosc_branch) to detect the branch of the running system.
+    pub fn aosc_branch(mut self, branch: AOSCBranch) -> Self {
+        self.aosc_branch = branch;
+        self
+    }
+
+    /// Also move on to the next mirror on 404, disabled by default
+    ///
+    /// A 404 usually means the branch or component doesn't exist on any mirror, but a mirror
+    /// that is behind may lack files the others already have.
+    pub fn failover_on_not_found(mut self, enabled: bool) -> Self {
+        self.failover_not_found = enabled;
+        self
+    }
+
+    /// Repository component to download the index of, defaults to `main`
+    pub fn component(mut self, component: &str) -> Self {
+        self.component = component.to_string();
+        self
+    }
+
+    /// Layout of the repositories at the mirror URLs, defaults to [`RepoLayout::Pooled`]
+    pub fn layout(mut self, layout: RepoLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Retry failed downloads up to `retries` more times
+    ///
+    /// Connection errors, timeouts, interrupted transfers and 5xx responses are retried,
+    /// 4xx responses never are. Defaults to 0.
+    ///
+    /// With retries enabled, an index that fails to decompress is also fetched once more with
+    /// `Cache-Control: no-cache`, in case a proxy cached a mangled copy.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retry.retries = retries;
+        self
+    }
+
+    /// Delay before the first retry, doubled for each following one (plus jitter)
+    pub fn retry_backoff(mut self, base: Duration) -> Self {
+        self.retry.backoff = base;
+        self
+    }
+
+    /// Timeout for establishing a connection, no timeout by default
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Timeout for a whole request, from connecting until the body has been read
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Send every request through the proxy at `url` (`http://`, `https://` or `socks5://`)
+    ///
+    /// The URL is checked when the fetcher is built.
+    pub fn proxy(mut self, url: &str) -> Self {
+        self.proxy = Some(url.to_string());
+        self
+    }
+
+    /// Honour `http_proxy`, `https_proxy` and `no_proxy` from the environment when no
+    /// [`proxy`](Self::proxy) is set, enabled by default
+    pub fn system_proxy(mut self, enabled: bool) -> Self {
+        self.system_proxy = enabled;
+        self
+    }
+
+    /// `User-Agent` header sent with every request, defaults to `aosc`
+    ///
+    /// See [`versioned_user_agent`] to include the version of this library.
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = Some(user_agent.to_string());
+        self
+    }
+
+    /// Send the header `name: value` with every request, in addition to earlier ones
+    ///
+    /// Meant for mirrors behind a gateway that wants a token. Name and value are checked when
+    /// the fetcher is built. The headers could carry credentials, so while any are set a
+    /// redirect to another host or port fails instead of taking them along.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Log in to the mirrors with HTTP basic authentication
+    ///
+    /// The credentials are sent with every request, but not along a redirect to another host
+    /// or port. Prefer this to putting them in the mirror URL, as URLs in errors and reports
+    /// only ever show `***` in their place. A mirror that refuses them fails with
+    /// [`FetchPackagesError::Unauthorized`].
+    pub fn with_basic_auth(mut self, user: &str, password: &str) -> Self {
+        self.basic_auth = Some((user.to_string(), password.to_string()));
+        self
+    }
+
+    /// Which IP version to try first, defaults to [`AddressFamily::Auto`]
+    ///
+    /// Applies to every connection, including those to proxies and redirect targets, and to
+    /// the addresses given to [`resolve_host`](Self::resolve_host). A host without addresses
+    /// of the preferred version is reached over the other one.
+    pub fn address_family(mut self, family: AddressFamily) -> Self {
+        self.address_family = family;
+        self
+    }
+
+    /// Connect to `addr` for requests to `host` instead of looking it up in DNS
+    ///
+    /// Meant for trying a particular backend of a mirror without editing `/etc/hosts`.
+    /// Requests still name `host`, so virtual hosts and TLS certificates work as usual. Calling
+    /// this again for the same host adds another address to try. Only the IP of `addr` is used,
+    /// the port is still taken from the URL.
+    pub fn resolve_host(mut self, host: &str, addr: SocketAddr) -> Self {
+        self.resolve
+            .entry(host.to_ascii_lowercase())
+            .or_default()
+            .push(addr);
+        self
+    }
+
+    /// Follow at most `hops` redirects per request, 0 refuses every redirect
+    ///
+    /// Defaults to 10. Going over the limit fails with [`FetchPackagesError::Redirect`],
+    /// naming the redirects followed so far.
+    pub fn max_redirects(mut self, hops: usize) -> Self {
+        self.max_redirects = Some(hops);
+        self
+    }
+
+    /// Refuse redirects from HTTPS to plain HTTP, off by default
+    pub fn deny_redirect_downgrade(mut self, deny: bool) -> Self {
+        self.deny_redirect_downgrade = deny;
+        self
+    }
+
+    /// Only talk to mirrors over HTTPS, off by default
+    ///
+    /// Building fails with [`FetchPackagesError::InsecureTransport`] if a mirror URL is
+    /// `http://`, and so does any request redirected to plain HTTP. `file://` mirrors and
+    /// local paths are still allowed, as they don't go over the network.
+    pub fn https_only(mut self, https_only: bool) -> Self {
+        self.https_only = https_only;
+        self
+    }
+
+    /// Also trust the CA certificates in the PEM bundle `pem`, e.g. of an internal mirror
+    ///
+    /// The built-in roots stay trusted. The bundle is checked when the fetcher is built.
+    pub fn root_certificate(mut self, pem: &[u8]) -> Self {
+        self.root_certificates.push(pem.to_vec());
+        self
+    }
+
+    /// Present the client certificate and private key in `pem` to servers that ask for one
+    ///
+    /// `pem` holds the certificate chain followed by an RSA, SEC1 or PKCS#8 key. It is checked
+    /// when the fetcher is built. The client uses rustls instead of the system TLS library
+    /// then, as only rustls takes the identity in this form.
+    pub fn client_identity(mut self, pem: &[u8]) -> Self {
+        self.identity = Some(pem.to_vec());
+        self
+    }
+
+    /// Accept any server certificate, disabled by default
+    ///
+    /// # Warning
+    ///
+    /// This disables all certificate checks, so anyone between the fetcher and the mirror can
+    /// serve arbitrary indices and packages. Only use it for test setups, and prefer
+    /// [`root_certificate`](Self::root_certificate) for mirrors with a private CA.
+    pub fn danger_accept_invalid_certs(mut self, enabled: bool) -> Self {
+        self.accept_invalid_certs = enabled;
+        self
+    }
+
+    /// Number of downloads [`FetchPackagesAsync::fetch_packages_multi`] runs at once,
+    /// defaults to 4
+    pub fn concurrency(mut self, limit: usize) -> Self {
+        self.concurrency = limit;
+        self
+    }
+
+    /// Limit the combined rate of all downloads to `bytes_per_sec`, unlimited by default
+    ///
+    /// Applies to index and package downloads, including concurrent ones, which share the
+    /// limit. Time spent waiting counts towards [`timeout`](Self::timeout). `None` removes
+    /// the limit.
+    pub fn max_rate_bytes_per_sec(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.max_rate = bytes_per_sec;
+        self
+    }
+
+    /// Refuse downloads larger than `bytes`, defaults to 4 GiB
+    ///
+    /// Checked against `Content-Length` before the body is read and enforced while it
+    /// arrives, failing with [`FetchPackagesError::PayloadTooLarge`]. Packages are limited to
+    /// the size listed in Packages, and refused if that is larger than `bytes`.
+    pub fn max_download_size(mut self, bytes: u64) -> Self {
+        self.max_download = bytes;
+        self
+    }
+
+    /// Refuse indices that decompress to more than `bytes`, defaults to 4 GiB
+    ///
+    /// Decompression stops as soon as the limit is exceeded, so a small download that
+    /// decompresses to far more, such as an xz bomb, fails with
+    /// [`FetchPackagesError::PayloadTooLarge`] instead of exhausting memory.
+    pub fn max_decompressed_size(mut self, bytes: u64) -> Self {
+        self.max_decompressed = bytes;
+        self
+    }
+
+    /// Report requests, transferred bytes, retries and finished downloads to `observer`
+    pub fn observer(mut self, observer: Box<dyn FetchObserver>) -> Self {
+        self.observer = Some(Arc::from(observer));
+        self
+    }
+
+    /// Download packages of at least `min_size` bytes in `segments` parts at once, disabled
+    /// by default
+    ///
+    /// Each part is a range request of its own, which helps where a single connection to the
+    /// mirror is slow. The parts are written into the file at their offsets, and the whole
+    /// file is checked against Packages as usual. Servers that don't support ranges get a
+    /// single request instead, as do packages on local mirrors. Fewer than 2 `segments` turn
+    /// this off.
+    pub fn segmented_download(mut self, segments: usize, min_size: u64) -> Self {
+        self.segments = segments;
+        self.segment_min_size = min_size;
+        self
+    }
+
+    /// Download indices by their hash if the release file allows it, disabled by default
+    ///
+    /// The release file of the branch is fetched first, and if it says `Acquire-By-Hash: yes`
+    /// indices are downloaded from `by-hash/SHA256/<hash>` and checked against that hash.
+    /// This avoids getting an index that doesn't match the release file while the mirror
+    /// is syncing. Indices whose by-hash copy is missing are downloaded as usual.
+    pub fn acquire_by_hash(mut self, enabled: bool) -> Self {
+        self.by_hash = enabled;
+        self
+    }
+
+    /// Update outdated local copies with the patches in `Packages.diff/`, disabled by default
+    ///
+    /// Applies to `fetch_packages_cached` of both fetchers. When the local copy is too old to
+    /// reuse, the patches from its version to the current one are downloaded and applied
+    /// instead of the whole index, as the repository lists them in `Packages.diff/Index`.
+    /// If the local copy isn't in that list, or any patch or the result fails its SHA256
+    /// check, the whole index is downloaded as usual.
+    pub fn pdiff(mut self, enabled: bool) -> Self {
+        self.pdiff = enabled;
+        self
+    }
+
+    /// Only trust release files signed by a key of `keyring`, which also enables
+    /// [`Self::verify_checksums`]
+    ///
+    /// `fetch_release` refuses an `InRelease` file without a valid signature with
+    /// [`FetchPackagesError::Signature`], see [`Release::from_inrelease_verified`]. A branch
+    /// without `InRelease` is checked by its `Release` and `Release.gpg` files instead. As
+    /// every index is checked against the verified release file, indices are only trusted
+    /// as far as the release file is. A keyring without certificates fails the build.
+    #[cfg(feature = "verify")]
+    pub fn with_keyring(mut self, keyring: Vec<Cert>) -> Self {
+        self.keyring = Some(keyring);
+        self.verify = true;
+        self
+    }
+
+    /// Only trust release files signed by a key the APT trust store of this system holds,
+    /// see [`Self::with_keyring`]
+    ///
+    /// The keyrings are loaded right away by [`system_keyring`](crate::keyring::system_keyring).
+    /// Files that fail to load are left out, call that directly to see them or to load the
+    /// keyrings of a chroot.
+    #[cfg(feature = "verify")]
+    pub fn with_system_keyring(self) -> Self {
+        let keyring = crate::keyring::system_keyring(Path::new("/"));
+        self.with_keyring(keyring.certs)
+    }
+
+    /// Check downloaded indices against the release file, disabled by default
+    ///
+    /// The release file of the branch is fetched first, and both the downloaded file and the
+    /// decompressed index have to match the size and SHA256 listed for them. A mismatch
+    /// fails with [`FetchPackagesError::ChecksumMismatch`] and the download is removed, an
+    /// index the release file doesn't list fails with [`FetchPackagesError::NotInRelease`].
+    /// A local copy confirmed by 304 is checked as well.
+    ///
+    /// A local copy that already matches the release file is used without downloading the
+    /// index again, see [`FetchSource::MatchesRelease`].
+    ///
+    /// The release file itself isn't verified unless `with_keyring` of the `verify` feature is
+    /// set as well, so on its own this only protects against corrupted or inconsistent mirrors.
+    pub fn verify_checksums(mut self, enabled: bool) -> Self {
+        self.verify = enabled;
+        self
+    }
+
+    /// Refuse indices of branches whose release file is older than `max_age`, disabled by default
+    ///
+    /// The `Date` of the release file is checked before every index is downloaded, and a
+    /// mirror that hasn't synced recently fails the fetch with
+    /// [`FetchPackagesError::StaleRelease`]. A few minutes of clock skew are tolerated. A
+    /// branch without a release file can't be checked and fails as well.
+    pub fn require_fresh_metadata(mut self, max_age: Duration) -> Self {
+        self.max_release_age = Some(max_age);
+        self
+    }
+
+    /// Store the index of the configured component as plain `Packages`, disabled by default
+    ///
+    /// By default it is stored as `Packages_<branch>_<component>_<arch>`, like apt's lists,
+    /// so fetching several arches or branches into one directory keeps all of them. With
+    /// this enabled every fetch overwrites the same file, as older versions did.
+    pub fn plain_file_name(mut self, enabled: bool) -> Self {
+        self.plain_name = enabled;
+        self
+    }
+
+    /// Which copies of downloaded indices to keep, defaults to [`IndexStorage::Decompressed`]
+    ///
+    /// Both copies come from the same response. The index is parsed either way, but only a
+    /// decompressed copy can be revalidated on the next fetch. Uncompressed indices are
+    /// always stored once, under the decompressed name.
+    pub fn index_storage(mut self, storage: IndexStorage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Whether to fall back to the local copy of an index, defaults to
+    /// [`OfflinePolicy::Strict`]
+    ///
+    /// A local copy that is used is reported as [`FetchSource::Stale`] along with its age.
+    /// This applies to indices fetched into the download directory, and only a decompressed
+    /// copy can be used, see [`index_storage`](Self::index_storage).
+    pub fn offline_policy(mut self, policy: OfflinePolicy) -> Self {
+        self.offline = policy;
+        self
+    }
+
+    /// Send every request with `client` instead of building a new one
+    ///
+    /// This shares the connection pool, TLS settings and middleware of an existing client.
+    /// The client is used as is, so none of [`connect_timeout`](Self::connect_timeout),
+    /// [`timeout`](Self::timeout), [`proxy`](Self::proxy),
+    /// [`system_proxy`](Self::system_proxy), [`user_agent`](Self::user_agent),
+    /// [`header`](Self::header), [`with_basic_auth`](Self::with_basic_auth),
+    /// [`address_family`](Self::address_family), [`resolve_host`](Self::resolve_host),
+    /// [`https_only`](Self::https_only), the redirect settings or the TLS settings can be set
+    /// along with it.
+    ///
+    /// To have fetchers for different mirrors share one pool, pass each the client of the
+    /// first one.
+    ///
+    /// A ureq agent, taken by the blocking fetcher with the `blocking-ureq` feature, only
+    /// follows redirects within its `max_redirects` and `https_only` settings, as the fetcher
+    /// follows them itself.
+    pub fn with_client(mut self, client: C) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// The client passed to [`with_client`](Self::with_client), if any
+    fn custom_client(&mut self) -> Result<Option<C>, FetchPackagesError> {
+        let Some(client) = self.client.take() else {
+            return Ok(None);
+        };
+
+        let conflicting = [
+            ("connect_timeout", self.connect_timeout.is_some()),
+            ("timeout", self.timeout.is_some()),
+            ("proxy", self.proxy.is_some()),
+            ("system_proxy", !self.system_proxy),
+            ("user_agent", self.user_agent.is_some()),
+            ("header", !self.headers.is_empty()),
+            ("with_basic_auth", self.basic_auth.is_some()),
+            ("max_redirects", self.max_redirects.is_some()),
+            ("deny_redirect_downgrade", self.deny_redirect_downgrade),
+            ("https_only", self.https_only),
+            ("root_certificate", !self.root_certificates.is_empty()),
+            ("client_identity", self.identity.is_some()),
+            ("danger_accept_invalid_certs", self.accept_invalid_certs),
+            ("address_family", self.address_family != AddressFamily::Auto),
+            ("resolve_host", !self.resolve.is_empty()),
+        ];
+        if let Some((setting, _)) = conflicting.iter().find(|(_, set)| *set) {
+            return Err(FetchPackagesError::InvalidConfig(format!(
+                "{setting} configures the client and can't be combined with with_client"
+            )));
+        }
+
+        Ok(Some(client))
+    }
+
+    fn user_agent_header(&self) -> &str {
+        self.user_agent.as_deref().unwrap_or(USER_AGENT)
+    }
+
+    /// The URL given to [`proxy`](Self::proxy), checked to be one of a supported proxy
+    fn proxy_url(&self) -> Result<Option<url::Url>, FetchPackagesError> {
+        let Some(url) = &self.proxy else {
+            return Ok(None);
+        };
+
+        let parsed = url::Url::parse(url).map_err(|e| invalid_proxy(url, &e))?;
+        if !matches!(parsed.scheme(), "http" | "https" | "socks5" | "socks5h") {
+            let reason = format!("unsupported scheme {}", parsed.scheme());
+            return Err(invalid_proxy(url, &reason));
+        }
+
+        Ok(Some(parsed))
+    }
+
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    fn build_proxy(&self) -> Result<Option<reqwest::Proxy>, FetchPackagesError> {
+        let Some(url) = self.proxy_url()? else {
+            return Ok(None);
+        };
+
+        let proxy = url.to_string();
+        reqwest::Proxy::all(url)
+            .map(Some)
+            .map_err(|e| invalid_proxy(&proxy, &e))
+    }
+
+    fn build_headers(&self) -> Result<HeaderMap, FetchPackagesError> {
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in &self.headers {
+            let invalid = |reason: &dyn fmt::Display| {
+                FetchPackagesError::InvalidConfig(format!("invalid header {name}: {reason}"))
+            };
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| invalid(&e))?;
+            let mut value = HeaderValue::from_str(value).map_err(|e| invalid(&e))?;
+            value.set_sensitive(true);
+            headers.append(name, value);
+        }
+
+        if let Some((user, password)) = &self.basic_auth {
+            let credentials = base64::Engine::encode(
+                &base64::engine::general_purpose::STANDARD,
+                format!("{user}:{password}"),
+            );
+            let mut value =
+                HeaderValue::from_str(&format!("Basic {credentials}")).map_err(|e| {
+                    FetchPackagesError::InvalidConfig(format!(
+                        "invalid basic auth credentials: {e}"
+                    ))
+                })?;
+            value.set_sensitive(true);
+            // reqwest drops it on redirects to another host, like its own basic auth.
+            headers.insert(AUTHORIZATION, value);
+        }
+
+        Ok(headers)
+    }
+
+    /// The resolver that puts the preferred [`address_family`](Self::address_family) first,
+    /// `None` for [`AddressFamily::Auto`]
+    ///
+    /// It also takes care of [`resolve_host`](Self::resolve_host), as reqwest skips a custom
+    /// resolver for hosts given to `resolve_to_addrs`.
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    fn preferring_resolver(&self) -> Option<Arc<PreferringResolver>> {
+        let prefer_ipv6 = match self.address_family {
+            AddressFamily::Auto => return None,
+            AddressFamily::PreferIpv4 => false,
+            AddressFamily::PreferIpv6 => true,
+        };
+
+        Some(Arc::new(PreferringResolver {
+            hosts: self.resolve.clone(),
+            prefer_ipv6,
+        }))
+    }
+
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    fn build_root_certificates(&self) -> Result<Vec<reqwest::Certificate>, FetchPackagesError> {
+        let mut certs = vec![];
+
+        for pem in &self.root_certificates {
+            let invalid = |reason: &dyn fmt::Display| {
+                FetchPackagesError::InvalidConfig(format!("invalid root certificate: {reason}"))
+            };
+            let bundle = reqwest::Certificate::from_pem_bundle(pem).map_err(|e| invalid(&e))?;
+            if bundle.is_empty() {
+                return Err(invalid(&"no certificate found in PEM"));
+            }
+            certs.extend(bundle);
+        }
+
+        Ok(certs)
+    }
+
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    fn build_identity(&self) -> Result<Option<reqwest::Identity>, FetchPackagesError> {
+        let Some(pem) = &self.identity else {
+            return Ok(None);
+        };
+
+        reqwest::Identity::from_pem(pem)
+            .map(Some)
+            .map_err(|e| FetchPackagesError::InvalidConfig(format!("invalid client identity: {e}")))
+    }
+
+    /// Redirects to follow, limited to the same host and port if custom headers are set
+    ///
+    /// reqwest only strips its own list of sensitive headers on such redirects.
+    fn redirect_rules(&self) -> RedirectRules {
+        RedirectRules {
+            max_redirects: self.max_redirects.unwrap_or(MAX_REDIRECTS),
+            deny_downgrade: self.deny_redirect_downgrade,
+            https_only: self.https_only,
+            same_origin: !self.headers.is_empty(),
+        }
+    }
+
+    #[cfg(any(feature = "blocking", feature = "async"))]
+    fn redirect_policy(&self) -> reqwest::redirect::Policy {
+        let rules = self.redirect_rules();
+
+        reqwest::redirect::Policy::custom(move |attempt| {
+            match rules.check(attempt.previous(), attempt.url()) {
+                Ok(()) => attempt.follow(),
+                Err(e) => attempt.error(e),
+            }
+        })
+    }
+
+    fn options(self) -> Result<FetchOptions, FetchPackagesError> {
+        if self.concurrency == 0 {
+            return Err(FetchPackagesError::InvalidConfig(
+                "concurrency must be at least 1".to_string(),
+            ));
+        }
+        if self.max_rate == Some(0) {
+            return Err(FetchPackagesError::InvalidConfig(
+                "max_rate_bytes_per_sec must be at least 1".to_string(),
+            ));
+        }
+        if self.max_download == 0 || self.max_decompressed == 0 {
+            return Err(FetchPackagesError::InvalidConfig(
+                "size limits must be at least 1 byte".to_string(),
+            ));
+        }
+        #[cfg(feature = "verify")]
+        if self
+            .keyring
+            .as_ref()
+            .is_some_and(|keyring| keyring.is_empty())
+        {
+            return Err(FetchPackagesError::InvalidConfig(
+                "the keyring has no certificates to verify release files with".to_string(),
+            ));
+        }
+        if self
+            .mirror_urls
+            .as_ref()
+            .is_some_and(|urls| urls.is_empty())
+        {
+            return Err(FetchPackagesError::InvalidConfig(
+                "at least one mirror URL is required".to_string(),
+            ));
+        }
+        let mirror_urls = self
+            .mirror_urls
+            .unwrap_or_else(|| {
+                vec![match self.aosc_branch {
+                    AOSCBranch::Mainline => DEFAULT_MIRROR,
+                    AOSCBranch::Afterglow => DEFAULT_RETRO_MIRROR,
+                }
+                .to_string()]
+            })
+            .into_iter()
+            .map(|url| normalize_checked_mirror_url(url, self.https_only))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(FetchOptions {
+            compression: self.compression,
+            download_to: self.download_to,
+            mirror_urls,
+            https_only: self.https_only,
+            failover_not_found: self.failover_not_found,
+            component: self.component,
+            layout: self.layout,
+            retry: self.retry,
+            #[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+            timeout: self.timeout,
+            #[cfg(feature = "async")]
+            concurrency: self.concurrency,
+            #[cfg(feature = "async")]
+            cancel: None,
+            rate_limit: self.max_rate.map(|rate| Arc::new(RateLimit::new(rate))),
+            max_download: self.max_download,
+            max_decompressed: self.max_decompressed,
+            observer: self.observer,
+            segments: self.segments,
+            segment_min_size: self.segment_min_size,
+            no_cache: false,
+            by_hash: self.by_hash,
+            pdiff: self.pdiff,
+            verify: self.verify,
+            #[cfg(feature = "verify")]
+            keyring: self.keyring.map(Arc::from),
+            max_release_age: self.max_release_age,
+            plain_name: self.plain_name,
+            storage: self.storage,
+            offline: self.offline,
+            #[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+            deadline: None,
+        })
+    }
+}
+
+/// Settings shared by both fetchers
+#[cfg(feature = "download")]
+#[derive(Clone)]
+struct FetchOptions {
+    compression: Compression,
+    download_to: Option<PathBuf>,
+    mirror_urls: Vec<String>,
+    https_only: bool,
+    failover_not_found: bool,
+    component: String,
+    layout: RepoLayout,
+    retry: RetryPolicy,
+    #[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+    timeout: Option<Duration>,
+    #[cfg(feature = "async")]
+    concurrency: usize,
+    /// Set for the copy of an async fetcher made by `with_cancellation`
+    #[cfg(feature = "async")]
+    cancel: Option<tokio_util::sync::CancellationToken>,
+    /// Shared by every copy of the options, so that all downloads of the fetcher count
+    rate_limit: Option<Arc<RateLimit>>,
+    max_download: u64,
+    max_decompressed: u64,
+    observer: Option<Arc<dyn FetchObserver>>,
+    segments: usize,
+    segment_min_size: u64,
+    /// Ask caches for a fresh copy, set for a single fetch after a corrupt download
+    no_cache: bool,
+    by_hash: bool,
+    pdiff: bool,
+    verify: bool,
+    /// Shared by every copy of the options
+    #[cfg(feature = "verify")]
+    keyring: Option<Arc<[Cert]>>,
+    max_release_age: Option<Duration>,
+    plain_name: bool,
+    storage: IndexStorage,
+    offline: OfflinePolicy,
+    /// When the current fetch has to be finished and the time it was given, set for a single
+    /// fetch by the blocking fetcher (the async one cancels the whole future instead)
+    #[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+    deadline: Option<(Instant, Duration)>,
+}
+
+#[cfg(feature = "download")]
+impl FetchOptions {
+    /// The index of the configured component
+    fn index(&self, arch: &str, branch: &str) -> Index {
+        let mut index = self.component_index(arch, branch, &self.component);
+        if self.plain_name {
+            index.file_name = "Packages".to_string();
+        }
+
+        index
+    }
+
+    /// The index of the configured component, stored at exactly `path`
+    fn index_at(&self, arch: &str, branch: &str, path: &Path) -> Result<Index, FetchPackagesError> {
+        if path.file_name().is_none() {
+            return Err(FetchPackagesError::InvalidConfig(format!(
+                "{} does not name a file",
+                path.display()
+            )));
+        }
+
+        Ok(Index {
+            dest: Some(path.to_path_buf()),
+            ..self.index(arch, branch)
+        })
+    }
+
+    /// The same options, but fetching from `mirror` alone
+    ///
+    /// `mirror` is checked like the mirrors given to the builder.
+    fn with_mirror(&self, mirror: &str) -> Result<FetchOptions, FetchPackagesError> {
+        Ok(FetchOptions {
+            mirror_urls: vec![normalize_checked_mirror_url(
+                mirror.to_string(),
+                self.https_only,
+            )?],
+            ..self.clone()
+        })
+    }
+
+    /// The index of the configured component, stored under a name that also tells the first
+    /// mirror apart, so that neither the copy nor its validators mix with those of another
+    fn mirror_index(&self, arch: &str, branch: &str) -> Index {
+        let mut index = self.index(arch, branch);
+        if let Some(mirror) = self.mirror_urls.first() {
+            index.file_name = format!("{}@{}", index.file_name, mirror_file_name(mirror));
+        }
+
+        index
+    }
+
+    /// The same options, but with downloads limited to the size Packages lists for `pkg`
+    fn for_package(&self, pkg: &Package) -> Result<FetchOptions, FetchPackagesError> {
+        self.check_size(SizeLimit::Download, pkg.size)?;
+
+        Ok(FetchOptions {
+            max_download: pkg.size,
+            ..self.clone()
+        })
+    }
+
+    /// Fail with [`FetchPackagesError::PayloadTooLarge`] if `size` bytes exceed `which` limit
+    fn check_size(&self, which: SizeLimit, size: u64) -> Result<(), FetchPackagesError> {
+        let limit = match which {
+            SizeLimit::Download => self.max_download,
+            SizeLimit::Decompressed => self.max_decompressed,
+        };
+        if size > limit {
+            return Err(FetchPackagesError::PayloadTooLarge { which, limit });
+        }
+
+        Ok(())
+    }
+
+    /// The same options, but with every index checked against the release file and
+    /// downloaded by hash where the release file allows it
+    fn for_snapshot(&self) -> FetchOptions {
+        FetchOptions {
+            verify: true,
+            by_hash: true,
+            ..self.clone()
+        }
+    }
+
+    /// The same options, but with the decompressed copy of indices always written
+    fn keeping_decompressed(&self) -> FetchOptions {
+        FetchOptions {
+            storage: match self.storage {
+                IndexStorage::Compressed => IndexStorage::Both,
+                storage => storage,
+            },
+            ..self.clone()
+        }
+    }
+
+    /// The index of `component`, stored under a name that tells it apart from others
+    fn component_index(&self, arch: &str, branch: &str, component: &str) -> Index {
+        Index {
+            arch: arch.to_string(),
+            branch: branch.to_string(),
+            name: self.index_name(&format!("{component}/binary-{arch}"), "Packages"),
+            file_name: list_file_name("Packages", &[branch, component, arch]),
+            dest: None,
+        }
+    }
+
+    fn contents_index(&self, arch: &str, branch: &str) -> Index {
+        let component = &self.component;
+
+        Index {
+            arch: arch.to_string(),
+            branch: branch.to_string(),
+            name: self.index_name(component, &format!("Contents-{arch}")),
+            file_name: list_file_name("Contents", &[branch, component, arch]),
+            dest: None,
+        }
+    }
+
+    fn sources_index(&self, branch: &str) -> Index {
+        let component = &self.component;
+
+        Index {
+            arch: "source".to_string(),
+            branch: branch.to_string(),
+            name: self.index_name(&format!("{component}/source"), "Sources"),
+            file_name: list_file_name("Sources", &[branch, component]),
+            dest: None,
+        }
+    }
+
+    /// Name of the index `file` in `dir` relative to the release file, which is the
+    /// repository root of a flat repository
+    fn index_name(&self, dir: &str, file: &str) -> String {
+        match self.layout {
+            RepoLayout::Pooled => format!("{dir}/{file}"),
+            RepoLayout::Flat => file.to_string(),
+        }
+    }
+
+    /// Path of `file` in the `.diff/` directory next to `index`, relative to the mirror root
+    fn diff_url_path(&self, index: &Index, file: &str) -> String {
+        self.dist_path(&index.branch, &format!("{}.diff/{file}", index.name))
+    }
+
+    /// Path of `path` in the release of `branch`, relative to the mirror root
+    fn dist_path(&self, branch: &str, path: &str) -> String {
+        match self.layout {
+            RepoLayout::Pooled => format!("dists/{}/{path}", encode_path_segment(branch)),
+            RepoLayout::Flat => path.to_string(),
+        }
+    }
+
+    /// The AOSC OS architecture of this machine, see [`get_arch_name`]
+    fn native_arch(&self) -> Result<&'static str, FetchPackagesError> {
+        get_arch_name().ok_or_else(|| {
+            FetchPackagesError::UnsupportedHostArch(std::env::consts::ARCH.to_string())
+        })
+    }
+
+    /// Where the decompressed copy of `index` is written to
+    fn local_path(&self, index: &Index) -> Result<PathBuf, FetchPackagesError> {
+        if let Some(dest) = &index.dest {
+            return Ok(dest.clone());
+        }
+        let dir = self.download_to.as_ref().ok_or_else(|| {
+            FetchPackagesError::InvalidConfig("download directory is not set".to_string())
+        })?;
+        // Names are escaped to a single file name, this makes sure nothing else is written.
+        let mut components = Path::new(&index.file_name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(FetchPackagesError::InvalidConfig(format!(
+                "{} is not a file name",
+                index.file_name
+            )));
+        }
+
+        Ok(dir.join(&index.file_name))
+    }
+
+    /// The inclusive byte ranges to download a file of `size` bytes in, if it is split up
+    fn segment_ranges(&self, size: u64) -> Option<Vec<(u64, u64)>> {
+        let segments = (self.segments as u64).min(size);
+        if segments < 2 || size < self.segment_min_size {
+            return None;
+        }
+
+        let len = size.div_ceil(segments);
+        Some(
+            (0..size)
+                .step_by(len as usize)
+                .map(|start| (start, (start + len).min(size) - 1))
+                .collect(),
+        )
+    }
+
+    /// The observer attached with [`FetcherBuilder::observer`], or one that ignores everything
+    fn observer(&self) -> &dyn FetchObserver {
+        self.observer.as_deref().unwrap_or(&NoObserver)
+    }
+
+    /// Tell the observer that a request for `url` is about to be sent
+    fn observe_request(&self, url: &str) {
+        if let (Some(observer), Ok(url)) = (&self.observer, url::Url::parse(url)) {
+            observer.on_request(&url);
+        }
+    }
+
+    /// Time left until the deadline of the current fetch, if it has one
+    #[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+    fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|(deadline, _)| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Fail with [`FetchPackagesError::Cancelled`] if the token of the fetcher was cancelled
+    #[cfg(feature = "async")]
+    fn check_cancelled(&self) -> Result<(), FetchPackagesError> {
+        match &self.cancel {
+            Some(token) if token.is_cancelled() => Err(FetchPackagesError::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait for `fut` unless the token of the fetcher is cancelled first
+    ///
+    /// A future that is ready wins over the token, so it is also checked before waiting.
+    #[cfg(feature = "async")]
+    async fn until_cancelled<F: std::future::Future>(
+        &self,
+        fut: F,
+    ) -> Result<F::Output, FetchPackagesError> {
+        self.check_cancelled()?;
+        match &self.cancel {
+            Some(token) => token
+                .run_until_cancelled(fut)
+                .await
+                .ok_or(FetchPackagesError::Cancelled),
+            None => Ok(fut.await),
+        }
+    }
+
+    /// Fail if the deadline of the current fetch has passed
+    #[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+    fn check_deadline(&self) -> Result<(), FetchPackagesError> {
+        match self.deadline {
+            Some((deadline, budget)) if Instant::now() >= deadline => {
+                Err(FetchPackagesError::DeadlineExceeded(budget))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the decompressed copy of an index in `compression` is written to disk
+    fn keep_decompressed(&self, compression: Compression) -> bool {
+        self.storage != IndexStorage::Compressed || compression == Compression::None
+    }
+
+    /// Move a parsed download into place, the decompressed copy from `tmp` to `dest`
+    fn finish_index(
+        &self,
+        compression: Compression,
+        partial: &Path,
+        tmp: &Path,
+        dest: &Path,
+    ) -> std::io::Result<()> {
+        if self.keep_decompressed(compression) {
+            std::fs::rename(tmp, dest)?;
+        } else {
+            // An older copy would be taken for this download when revalidating.
+            let _ = std::fs::remove_file(dest);
+        }
+
+        complete_partial(
+            partial,
+            dest,
+            self.compressed_path(dest, compression).as_deref(),
+        )
+    }
+
+    /// Move the index patched to `tmp` into place at `dest`, which was downloaded from `url`
+    ///
+    /// The validators of the download and a kept compressed copy no longer match the
+    /// contents, so they are dropped. Also used for indices that weren't downloaded to disk.
+    fn finish_patched(&self, tmp: &Path, dest: &Path, url: &str) -> std::io::Result<()> {
+        std::fs::rename(tmp, dest)?;
+        self.replaced(dest, url)
+    }
+
+    /// Drop what described the previous copy at `dest`, now replaced by one from `url`
+    fn replaced(&self, dest: &Path, url: &str) -> std::io::Result<()> {
+        for &compression in Compression::Auto.candidates() {
+            if let Some(compressed) = self.compressed_path(dest, compression) {
+                let _ = std::fs::remove_file(compressed);
+            }
+        }
+
+        let meta = FileMeta {
+            url: strip_credentials(url),
+            last_modified: None,
+            etag: None,
+            fetched: Some(SystemTime::now()),
+        };
+        std::fs::write(FileMeta::path(dest), meta.to_string())
+    }
+
+    /// Write each of `files`, the decompressed copy of an index and the URL it was fetched
+    /// from, to its path
+    ///
+    /// Every file is written before any previous copy is replaced. The previous copies are
+    /// then moved aside one at a time and only removed once all new ones are in place, so if
+    /// moving one fails, those already replaced are moved back. Another process may still see
+    /// some of the new files next to old ones while this runs, and a crash halfway leaves them
+    /// that way, with the old copies next to them under temporary names.
+    fn publish(&self, files: &[(PathBuf, Vec<u8>, String)]) -> Result<(), FetchPackagesError> {
+        let mut written = vec![];
+        for (path, bytes, _) in files {
+            if let Some(dir) = path.parent().filter(|dir| !dir.exists()) {
+                std::fs::create_dir_all(dir)?;
+            }
+            let tmp = TempPath(temp_path(path));
+            let mut f = std::fs::File::create(&*tmp)?;
+            std::io::Write::write_all(&mut f, bytes)?;
+            // Make sure the data is on disk before the rename makes it visible.
+            f.sync_all()?;
+            written.push(tmp);
+        }
+
+        let mut backups = vec![];
+        for (tmp, (path, ..)) in written.iter().zip(files) {
+            match replace_with_backup(tmp, path) {
+                Ok(backup) => backups.push((path, backup)),
+                Err(e) => {
+                    for (path, backup) in backups.into_iter().rev() {
+                        let _ = match backup {
+                            Some(backup) => std::fs::rename(&*backup, path),
+                            None => std::fs::remove_file(path),
+                        };
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+        // Dropping the backups removes the previous copies.
+        drop(backups);
+
+        for (path, _, url) in files {
+            self.replaced(path, url)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the local copy of `index` described by `meta` can be used without a request
+    ///
+    /// It has to be younger than `max_age` and downloaded from `index` on one of the mirrors.
+    fn is_cache_fresh(&self, index: &Index, meta: &FileMeta, max_age: Duration) -> bool {
+        let fresh = meta
+            .fetched
+            .and_then(|fetched| fetched.elapsed().ok())
+            .is_some_and(|age| age < max_age);
+        let by_hash = self.by_hash_url_path(index, "");
+
+        fresh
+            && self.mirror_urls.iter().any(|mirror| {
+                let Some(path) = meta
+                    .url
+                    .strip_prefix(&strip_credentials(&format!("{mirror}/")))
+                else {
+                    return false;
+                };
+
+                path.starts_with(&by_hash)
+                    || self
+                        .compression
+                        .candidates()
+                        .iter()
+                        .any(|&c| path == self.index_url_path(index, c))
+            })
+    }
+
+    /// Where the download of the index stored at `local` is kept, if it is
+    fn compressed_path(&self, local: &Path, compression: Compression) -> Option<PathBuf> {
+        if self.storage == IndexStorage::Decompressed || compression == Compression::None {
+            return None;
+        }
+
+        let mut name = local.as_os_str().to_owned();
+        name.push(compression.extension());
+        Some(PathBuf::from(name))
+    }
+
+    /// The URL of `path` on the preferred mirror
+    fn mirror_url(&self, path: &str) -> String {
+        self.mirror_urls(path)
+            .next()
+            .expect("mirror list is not empty")
+    }
+
+    /// The URL of `path` on each mirror, in order of preference
+    fn mirror_urls<'a>(&'a self, path: &'a str) -> impl Iterator<Item = String> + 'a {
+        self.mirror_urls
+            .iter()
+            .map(move |mirror| format!("{mirror}/{path}"))
+    }
+
+    /// Whether a request that failed with `e` should be sent to the next mirror
+    fn should_failover(&self, e: &FetchPackagesError) -> bool {
+        let e = match e {
+            FetchPackagesError::RetriesExhausted { source, .. } => source,
+            e => e,
+        };
+
+        e.is_transient() || (self.failover_not_found && e.is_not_found())
+    }
+
+    /// Path of `index` in `compression` relative to the mirror root
+    fn index_url_path(&self, index: &Index, compression: Compression) -> String {
+        self.dist_path(&index.branch, &index.release_path(compression))
+    }
+
+    /// Where the file with `sha256` is stored next to `index`, relative to the mirror root
+    fn by_hash_url_path(&self, index: &Index, sha256: &str) -> String {
+        let path = match index.name.rsplit_once('/') {
+            Some((dir, _)) => format!("{dir}/by-hash/SHA256/{sha256}"),
+            None => format!("by-hash/SHA256/{sha256}"),
+        };
+
+        self.dist_path(&index.branch, &path)
+    }
+
+    /// Paths to try for `index` in `compression`, with the hash to check each download against
+    ///
+    /// If `release` allows it and lists the index, its by-hash copy comes first. The canonical
+    /// path is always tried as well.
+    fn index_url_paths<'a>(
+        &self,
+        index: &Index,
+        compression: Compression,
+        release: Option<&'a Release>,
+    ) -> Result<Vec<(String, Option<&'a ReleaseFile>)>, FetchPackagesError> {
+        let path = index.release_path(compression);
+        let mut res = vec![];
+        if let Some(file) = release
+            .filter(|r| self.by_hash && r.acquire_by_hash.unwrap_or(false))
+            .and_then(|r| r.sha256.get(&path))
+        {
+            res.push((self.by_hash_url_path(index, &file.sha256), Some(file)));
+        }
+        res.push((
+            self.index_url_path(index, compression),
+            self.release_checksum(release, &path)?,
+        ));
+
+        Ok(res)
+    }
+
+    /// The entry of `path` in `release` to check it against, if checksums are verified
+    fn release_checksum<'a>(
+        &self,
+        release: Option<&'a Release>,
+        path: &str,
+    ) -> Result<Option<&'a ReleaseFile>, FetchPackagesError> {
+        if !self.verify {
+            return Ok(None);
+        }
+
+        release
+            .expect("the release file is fetched to verify checksums")
+            .sha256
+            .get(path)
+            .map(Some)
+            .ok_or_else(|| FetchPackagesError::NotInRelease(path.to_string()))
+    }
+
+    fn in_release_url_path(&self, branch: &str) -> String {
+        self.dist_path(branch, "InRelease")
+    }
+
+    /// Parse an `InRelease` file, checking its signature if a keyring is set
+    fn parse_in_release(&self, bytes: &[u8]) -> Result<Release, FetchPackagesError> {
+        #[cfg(feature = "verify")]
+        if let Some(keyring) = &self.keyring {
+            return Ok(Release::from_inrelease_verified(bytes, keyring)?);
+        }
+
+        Release::parse(bytes)
+    }
+
+    /// Parse a `Release` file checked against its detached `signature`
+    #[cfg(feature = "verify")]
+    fn parse_detached_release(
+        &self,
+        bytes: &[u8],
+        signature: &[u8],
+    ) -> Result<Release, FetchPackagesError> {
+        let keyring = self
+            .keyring
+            .as_ref()
+            .expect("detached release files are only fetched to verify them");
+
+        Ok(Release::from_release_verified(bytes, signature, keyring)?)
+    }
+}
+
+/// Failures of the mirrors tried so far for one request
+#[cfg(feature = "download")]
+#[derive(Default)]
+struct MirrorFailures(Vec<(String, FetchPackagesError)>);
+
+#[cfg(feature = "download")]
+impl MirrorFailures {
+    /// Record that `url` failed with `e`, or return `e` if it shouldn't fail over
+    fn push(
+        &mut self,
+        options: &FetchOptions,
+        url: String,
+        e: FetchPackagesError,
+    ) -> Result<(), FetchPackagesError> {
+        if !options.should_failover(&e) {
+            return Err(e);
+        }
+
+        self.0.push((redact_url(&url), e));
+
+        Ok(())
+    }
+
+    /// The error to return once every mirror failed
+    fn finish(mut self) -> FetchPackagesError {
+        if self.0.len() == 1 {
+            return self.0.pop().expect("checked above").1;
+        }
+
+        FetchPackagesError::AllMirrorsFailed(self.0)
+    }
+}
+
+/// An index on the mirror, and the name its decompressed copy is stored under
+#[cfg(feature = "download")]
+struct Index {
+    arch: String,
+    branch: String,
+    /// Path relative to the release file, without the compression extension
+    name: String,
+    file_name: String,
+    /// Exact path to store the decompressed copy at instead of `file_name` in the download
+    /// directory
+    dest: Option<PathBuf>,
+}
+
+#[cfg(feature = "download")]
+impl Index {
+    /// Path of the index in `compression` as listed in the release file
+    fn release_path(&self, compression: Compression) -> String {
+        format!("{}{}", self.name, compression.extension())
+    }
+
+    /// Wrap `source` with the URL of this index and its arch and branch
+    fn error(&self, url: String, source: FetchPackagesError) -> FetchPackagesError {
+        // Cancelling has nothing to do with the index, and callers look for it as is.
+        #[cfg(feature = "async")]
+        if matches!(source, FetchPackagesError::Cancelled) {
+            return source;
+        }
+        // Neither is failing to write the index out.
+        if matches!(source, FetchPackagesError::WriterError(_)) {
+            return source;
+        }
+
+        FetchPackagesError::Fetch {
+            url: redact_url(&url),
+            arch: self.arch.clone(),
+            branch: self.branch.clone(),
+            source: Box::new(source),
+        }
+    }
+}
+
+/// An index format the fetchers can parse
+#[cfg(feature = "download")]
+trait IndexFile: Sized {
+    fn parse(bytes: &[u8]) -> Result<Self, FetchPackagesError>;
+
+    /// Parse `bytes`, calling `check` between entries so a large index can be given up on
+    ///
+    /// Only indices that take long to parse stop early, others call `check` once.
+    #[cfg(feature = "async")]
+    fn parse_checked(
+        bytes: &[u8],
+        check: &dyn Fn() -> Result<(), FetchPackagesError>,
+    ) -> Result<Self, FetchPackagesError> {
+        check()?;
+        Self::parse(bytes)
+    }
+}
+
+#[cfg(feature = "download")]
+impl IndexFile for Packages {
+    fn parse(bytes: &[u8]) -> Result<Self, FetchPackagesError> {
+        bytes.try_into().map_err(FetchPackagesError::DebControl)
+    }
+
+    #[cfg(feature = "async")]
+    fn parse_checked(
+        bytes: &[u8],
+        check: &dyn Fn() -> Result<(), FetchPackagesError>,
+    ) -> Result<Self, FetchPackagesError> {
+        let text = std::str::from_utf8(bytes)
+            .map_err(|e| FetchPackagesError::DebControl(ParseControlError::Utf8(e)))?;
+        let mut packages = vec![];
+        for paragraph in paragraphs(text) {
+            check()?;
+            packages.push(paragraph.parse().map_err(FetchPackagesError::DebControl)?);
+        }
+
+        Ok(packages.into())
+    }
+}
+
+#[cfg(feature = "download")]
+impl IndexFile for Sources {
+    fn parse(bytes: &[u8]) -> Result<Self, FetchPackagesError> {
+        bytes.try_into().map_err(FetchPackagesError::DebControl)
+    }
+}
+
+#[cfg(feature = "download")]
+impl IndexFile for Release {
+    fn parse(bytes: &[u8]) -> Result<Self, FetchPackagesError> {
+        bytes.try_into().map_err(FetchPackagesError::Release)
+    }
+}
+
+#[cfg(feature = "download")]
+impl IndexFile for Contents {
+    fn parse(bytes: &[u8]) -> Result<Self, FetchPackagesError> {
+        bytes.try_into().map_err(FetchPackagesError::Contents)
+    }
+}
+
+/// An index along with the decompressed data it was parsed from
+#[cfg(feature = "download")]
+struct WithBytes<T>(T, Vec<u8>);
+
+#[cfg(feature = "download")]
+impl<T: IndexFile> IndexFile for WithBytes<T> {
+    fn parse(bytes: &[u8]) -> Result<Self, FetchPackagesError> {
+        Ok(WithBytes(T::parse(bytes)?, bytes.to_vec()))
+    }
+
+    #[cfg(feature = "async")]
+    fn parse_checked(
+        bytes: &[u8],
+        check: &dyn Fn() -> Result<(), FetchPackagesError>,
+    ) -> Result<Self, FetchPackagesError> {
+        Ok(WithBytes(T::parse_checked(bytes, check)?, bytes.to_vec()))
+    }
+}
+
+/// The paragraphs of the deb822 `text`, which blank lines separate
+#[cfg(feature = "async")]
+fn paragraphs(text: &str) -> Vec<&str> {
+    let mut res = vec![];
+    let (mut start, mut end) = (0, 0);
+    for line in text.split_inclusive('\n') {
+        if line.trim().is_empty() {
+            if !text[start..end].trim().is_empty() {
+                res.push(&text[start..end]);
+            }
+            start = end + line.len();
+        }
+        end += line.len();
+    }
+    if !text[start..].trim().is_empty() {
+        res.push(&text[start..]);
+    }
+
+    res
+}
+
+/// Add the packages of `all` that `packages` doesn't have an entry of the same name for
+#[cfg(feature = "download")]
+fn merge_all(mut packages: Packages, all: Packages) -> Packages {
+    let names: HashSet<String> = packages.iter().map(|p| p.package.clone()).collect();
+    packages.extend(all.into_iter().filter(|p| !names.contains(&p.package)));
+
+    packages
+}
+
+/// Check that all of a body announced as `expected` bytes arrived
+#[cfg(feature = "download")]
+fn check_length(expected: Option<u64>, got: u64) -> Result<(), FetchPackagesError> {
+    match expected {
+        Some(expected) if expected != got => {
+            Err(FetchPackagesError::TruncatedDownload { expected, got })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// `Content-Length` as sent by the server
+///
+/// reqwest reports 0 for the empty body of a HEAD response, so read the header instead.
+#[cfg(feature = "download")]
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+/// Whether the `Content-Range` of a 206 response is the range from `start` to `end`
+#[cfg(feature = "download")]
+fn is_content_range(headers: &HeaderMap, (start, end): (u64, u64)) -> bool {
+    headers
+        .get(CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("bytes "))
+        .and_then(|v| v.split_once('/'))
+        .is_some_and(|(range, _)| range == format!("{start}-{end}"))
+}
+
+/// Check the index at `index_path` against the size and SHA256 that `release` lists for it,
+/// without downloading anything
+///
+/// `relative_name` is the path of the index below `dists/<branch>/`, such as
+/// `main/binary-amd64/Packages`. Whether the local file is compressed is told from its
+/// content, and it is checked against the entry with the matching extension, so either
+/// `Packages` or `Packages.xz` may be given for a local copy in either format. The file is
+/// hashed as it is read.
+#[cfg(feature = "download")]
+pub fn verify_local_index(
+    release: &Release,
+    index_path: &Path,
+    relative_name: &str,
+) -> Result<(), VerifyError> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(index_path)?);
+    let head = std::io::BufRead::fill_buf(&mut file)?;
+    let compression = Compression::AUTO_ORDER
+        .into_iter()
+        .find(|c| head.starts_with(c.magic()))
+        .unwrap_or(Compression::None);
+
+    let base = Compression::AUTO_ORDER
+        .iter()
+        .filter(|c| **c != Compression::None)
+        .find_map(|c| relative_name.strip_suffix(c.extension()))
+        .unwrap_or(relative_name);
+    let entry = format!("{base}{}", compression.extension());
+    let expected = release
+        .sha256
+        .get(&entry)
+        .ok_or_else(|| VerifyError::NotListed(entry.clone()))?;
+
+    let actual = file.get_ref().metadata()?.len();
+    if actual != expected.size {
+        return Err(VerifyError::SizeMismatch {
+            entry,
+            expected: expected.size,
+            actual,
+        });
+    }
+
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    let actual = format!("{:x}", hasher.finalize());
+    if actual != expected.sha256 {
+        return Err(VerifyError::ChecksumMismatch {
+            entry,
+            expected: expected.sha256.clone(),
+            actual,
+        });
+    }
+
+    Ok(())
+}
+
+/// Check that `bytes` are the file listed as `path` in the release file
+#[cfg(feature = "download")]
+fn check_sha256(
+    path: &str,
+    expected: &ReleaseFile,
+    bytes: &[u8],
+) -> Result<(), FetchPackagesError> {
+    check_digest(
+        path,
+        expected,
+        bytes.len() as u64,
+        Sha256::new_with_prefix(bytes),
+    )
+}
+
+/// Check that a file of `size` bytes, all fed to `hasher`, is the file listed as `path` in
+/// the release file
+#[cfg(feature = "download")]
+fn check_digest(
+    path: &str,
+    expected: &ReleaseFile,
+    size: u64,
+    hasher: Sha256,
+) -> Result<(), FetchPackagesError> {
+    let actual = format!("{:x}", hasher.finalize());
+    if size != expected.size || actual != expected.sha256 {
+        return Err(FetchPackagesError::ChecksumMismatch {
+            path: path.to_string(),
+            expected: expected.sha256.clone(),
+            actual,
+        });
+    }
+
+    Ok(())
+}
+
+/// Check the mirror `url` and bring it into the form paths are appended to
+///
+/// A mirror given as an absolute path becomes a `file://` URL, as paths below the mirror root
+/// are percent-encoded, which only a URL decodes again.
+#[cfg(feature = "download")]
+fn normalize_mirror_url(url: String) -> Result<String, FetchPackagesError> {
+    let invalid = |reason: &dyn fmt::Display| {
+        FetchPackagesError::InvalidConfig(format!(
+            "invalid mirror URL {}: {reason}",
+            redact_url(&url)
+        ))
+    };
+
+    let parsed = if Path::new(&url).is_absolute() {
+        url::Url::from_file_path(&url).map_err(|()| invalid(&"not a valid path"))?
+    } else {
+        url::Url::parse(&url).map_err(|e| invalid(&e))?
+    };
+    if !matches!(parsed.scheme(), "http" | "https" | "file") {
+        return Err(invalid(&format!("unsupported scheme {}", parsed.scheme())));
+    }
+    if parsed.query().is_some() || parsed.fragment().is_some() {
+        return Err(invalid(&"a query or fragment can't be followed by a path"));
+    }
+
+    // `https://host/debs/` would lead to requests for `//dists/...`.
+    Ok(String::from(parsed).trim_end_matches('/').to_string())
+}
+
+/// [`normalize_mirror_url`], also refusing a plain HTTP mirror if `https_only` is set
+#[cfg(feature = "download")]
+fn normalize_checked_mirror_url(
+    url: String,
+    https_only: bool,
+) -> Result<String, FetchPackagesError> {
+    let url = normalize_mirror_url(url)?;
+    if https_only && url.starts_with("http:") {
+        return Err(FetchPackagesError::InsecureTransport(redact_url(&url)));
+    }
+
+    Ok(url)
+}
+
+/// Host, port and path of the normalized mirror `url` as a single file name component, such
+/// as `repo.aosc.io_debs`
+///
+/// Credentials in the URL are left out.
+#[cfg(feature = "download")]
+fn mirror_file_name(url: &str) -> String {
+    let Ok(parsed) = url::Url::parse(url) else {
+        return encode_file_name(url);
+    };
+    let mut name = parsed.host_str().unwrap_or_default().to_string();
+    if let Some(port) = parsed.port() {
+        name.push_str(&format!(":{port}"));
+    }
+    name.push_str(parsed.path().trim_end_matches('/'));
+
+    encode_file_name(name.trim_start_matches('/'))
+}
+
+/// `s` with everything but ASCII letters, digits, `.` and `-` replaced by `_`
+#[cfg(feature = "download")]
+fn encode_file_name(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// The file `url` refers to, if it is a `file://` URL
+#[cfg(feature = "download")]
+fn file_url_path(url: &str) -> Option<PathBuf> {
+    if !url.starts_with("file:") {
+        return None;
+    }
+
+    url::Url::parse(url).ok()?.to_file_path().ok()
+}
+
+/// Percent-encode `segment` so that it stays a single segment of a URL path
+///
+/// Topic names may contain characters such as `+` that mirrors would read differently.
+#[cfg(feature = "download")]
+fn encode_path_segment(segment: &str) -> String {
+    let mut res = String::with_capacity(segment.len());
+    for b in segment.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            res.push(b as char);
+        } else {
+            res.push_str(&format!("%{b:02X}"));
+        }
+    }
+
+    res
+}
+
+/// `url` with the user name and password replaced by `***`, for errors and reports
+#[cfg(feature = "download")]
+fn redact_url(url: &str) -> String {
+    match url::Url::parse(url) {
+        Ok(mut parsed) if !parsed.username().is_empty() || parsed.password().is_some() => {
+            let _ = parsed.set_username("***");
+            let _ = parsed.set_password(None);
+            parsed.into()
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// `url` without the user name and password, for what is stored next to downloads
+///
+/// Downloads are compared by this form, so they still match when the credentials change.
+#[cfg(feature = "download")]
+fn strip_credentials(url: &str) -> String {
+    match url::Url::parse(url) {
+        Ok(mut parsed) if !parsed.username().is_empty() || parsed.password().is_some() => {
+            let _ = parsed.set_username("");
+            let _ = parsed.set_password(None);
+            parsed.into()
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// Undo the percent-encoding of a single segment of a URL path
+#[cfg(feature = "async")]
+fn decode_path_segment(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                res.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                res.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&res).into_owned()
+}
+
+/// Names of the subdirectories in a directory listing served by a mirror, sorted
+///
+/// Understands the HTML autoindex pages of Apache, nginx and lighttpd, as well as the JSON
+/// listings of nginx (`autoindex_format json`) and Caddy.
+#[cfg(feature = "async")]
+fn parse_dir_listing(body: &str) -> Vec<String> {
+    let mut names = if body.trim_start().starts_with('[') {
+        json_listing_dirs(body)
+    } else {
+        html_listing_dirs(body)
+    };
+    names.retain(|name| !name.is_empty() && name != "." && name != ".." && !name.contains('/'));
+    names.sort();
+    names.dedup();
+
+    names
+}
+
+/// Subdirectories linked from an HTML listing, whose links end with a slash
+#[cfg(feature = "async")]
+fn html_listing_dirs(body: &str) -> Vec<String> {
+    let mut names = vec![];
+    let mut rest = body;
+    while let Some(pos) = rest.find("href=") {
+        rest = &rest[pos + "href=".len()..];
+        let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            continue;
+        };
+        let Some(end) = rest[1..].find(quote) else {
+            break;
+        };
+        let href = rest[1..end + 1].replace("&amp;", "&");
+        rest = &rest[end + 2..];
+
+        // Skip sorting links, parents and anything outside of the listed directory.
+        if href.contains(['?', '#', ':']) || href.starts_with('/') {
+            continue;
+        }
+        let href = href.strip_prefix("./").unwrap_or(&href);
+        if let Some(dir) = href.strip_suffix('/') {
+            names.push(decode_path_segment(dir));
+        }
+    }
+
+    names
+}
+
+/// An entry of a JSON listing, of which only the name is required
+///
+/// Directories are told apart by `"type": "directory"` (nginx), `"is_dir": true` (Caddy) or a
+/// trailing slash in the name.
+#[cfg(feature = "async")]
+#[derive(serde::Deserialize)]
+struct ListingEntry {
+    name: String,
+    #[serde(rename = "type", default)]
+    kind: Option<String>,
+    #[serde(default)]
+    is_dir: bool,
+}
+
+/// Subdirectories in a JSON listing, nothing if it isn't an array of entries
+#[cfg(feature = "async")]
+fn json_listing_dirs(body: &str) -> Vec<String> {
+    let entries: Vec<ListingEntry> = serde_json::from_str(body).unwrap_or_default();
+
+    entries
+        .into_iter()
+        .filter_map(|entry| match entry.name.strip_suffix('/') {
+            Some(dir) => Some(dir.to_string()),
+            None if entry.is_dir || entry.kind.as_deref() == Some("directory") => Some(entry.name),
+            None => None,
+        })
+        .collect()
+}
+
+/// Name of the `kind` index of `parts` in the download directory
+///
+/// Separators in the parts are replaced with `_` like APT does for its lists, so e.g. a branch
+/// of `../etc` can't name a file outside the directory.
+#[cfg(feature = "download")]
+fn list_file_name(kind: &str, parts: &[&str]) -> String {
+    let mut name = kind.to_string();
+    for part in parts {
+        name.push('_');
+        name.extend(
+            part.chars()
+                .map(|c| if std::path::is_separator(c) { '_' } else { c }),
+        );
+    }
+
+    name
+}
+
+/// Where the `.deb` of `pkg` is stored in `dir`
+#[cfg(feature = "download")]
+fn package_path(dir: &Path, pkg: &Package) -> PathBuf {
+    dir.join(pkg.filename.rsplit('/').next().unwrap_or(&pkg.filename))
+}
+
+/// Path of the `.deb` of `pkg` relative to the mirror root
+///
+/// Flat repositories often list their packages as `./name.deb`.
+#[cfg(feature = "download")]
+fn package_url_path(pkg: &Package) -> &str {
+    pkg.filename.trim_start_matches("./")
+}
+
+/// Check that the file at `path` is the `.deb` of `pkg`, comparing the size first
+#[cfg(feature = "download")]
+fn check_package(pkg: &Package, path: &Path) -> Result<(), FetchPackagesError> {
+    let actual = std::fs::metadata(path)?.len();
+    if actual != pkg.size {
+        return Err(FetchPackagesError::SizeMismatch {
+            path: pkg.filename.clone(),
+            expected: pkg.size,
+            actual,
+        });
+    }
+
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    let actual = format!("{:x}", hasher.finalize());
+    if actual != pkg.sha256 {
+        return Err(FetchPackagesError::ChecksumMismatch {
+            path: pkg.filename.clone(),
+            expected: pkg.sha256.clone(),
+            actual,
+        });
+    }
+
+    Ok(())
+}
+
+/// Move the finished download of `pkg` to `path`, or remove it if it isn't `pkg`
+#[cfg(feature = "download")]
+fn complete_package(pkg: &Package, partial: &Path, path: &Path) -> Result<(), FetchPackagesError> {
+    if let Err(e) = check_package(pkg, partial) {
+        discard_partial(partial);
+        return Err(e);
+    }
+
+    std::fs::rename(partial, path)?;
+    let _ = std::fs::remove_file(FileMeta::path(partial));
+
+    Ok(())
+}
+
+/// Check that `release` is no older than `max_age`
+#[cfg(feature = "download")]
+fn check_fresh(release: &Release, max_age: Duration) -> Result<(), FetchPackagesError> {
+    // A date in the future only means that the clock of the mirror is ahead.
+    let age = SystemTime::now()
+        .duration_since(release.date)
+        .unwrap_or_default();
+    if age > max_age + CLOCK_SKEW {
+        return Err(FetchPackagesError::StaleRelease {
+            date: release.date,
+            max_age,
+        });
+    }
+
+    Ok(())
+}
+
+/// Age of a local copy described by `meta`, counting from its download or the last time the
+/// server confirmed it
+///
+/// Falls back to `modified` of the file for a `.meta` file without the time of the download.
+#[cfg(feature = "download")]
+fn local_copy_age(meta: Option<&FileMeta>, modified: Option<SystemTime>) -> Duration {
+    meta.and_then(|meta| meta.fetched)
+        .or(modified)
+        .and_then(|fetched| fetched.elapsed().ok())
+        .unwrap_or_default()
+}
+
+/// Where the decompressed copy of `path` is written until it has been parsed
+///
+/// The name is unique so that concurrent fetches of the same index don't share the file.
+#[cfg(feature = "download")]
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let suffix = RandomState::new().build_hasher().finish();
+
+    path.with_file_name(format!(".{name}.tmp-{suffix:016x}"))
+}
+
+/// Locks of the files being downloaded in this process
+///
+/// Downloads of the same file, e.g. by clones of a fetcher, share its `.partial` file, so they
+/// have to take turns. `L` is the mutex type of the fetcher.
+#[cfg(feature = "download")]
+struct FileLocks<L>(Mutex<BTreeMap<PathBuf, Arc<L>>>);
+
+#[cfg(feature = "download")]
+impl<L: Default> FileLocks<L> {
+    const fn new() -> Self {
+        Self(Mutex::new(BTreeMap::new()))
+    }
+
+    /// The lock of `path`, which is forgotten once no download holds it anymore
+    fn get(&self, path: &Path) -> FileLock<'_, L> {
+        let mut locks = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let lock = locks.entry(path.to_path_buf()).or_default().clone();
+
+        FileLock {
+            locks: self,
+            path: path.to_path_buf(),
+            lock,
+        }
+    }
+}
+
+/// A handle to the lock of a file in [`FileLocks`]
+#[cfg(feature = "download")]
+struct FileLock<'a, L> {
+    locks: &'a FileLocks<L>,
+    path: PathBuf,
+    lock: Arc<L>,
+}
+
+#[cfg(feature = "download")]
+impl<L> std::ops::Deref for FileLock<'_, L> {
+    type Target = L;
+
+    fn deref(&self) -> &L {
+        &self.lock
+    }
+}
+
+#[cfg(feature = "download")]
+impl<L> Drop for FileLock<'_, L> {
+    fn drop(&mut self) {
+        let mut locks = self.locks.0.lock().unwrap_or_else(PoisonError::into_inner);
+        // One reference is ours and one is in the map.
+        if Arc::strong_count(&self.lock) == 2 {
+            locks.remove(&self.path);
+        }
+    }
+}
+
+/// A temporary file that is removed when dropped, unless it was renamed away before
+///
+/// This also cleans up after fetches that are cancelled halfway.
+#[cfg(feature = "download")]
+struct TempPath(PathBuf);
+
+#[cfg(feature = "download")]
+impl std::ops::Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(feature = "download")]
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Move `tmp` to `path`, keeping the file it replaces under a temporary name
+///
+/// If the move fails, the previous file is put back.
+#[cfg(feature = "download")]
+fn replace_with_backup(tmp: &Path, path: &Path) -> std::io::Result<Option<TempPath>> {
+    let backup = TempPath(temp_path(path));
+    let backup = match std::fs::rename(path, &*backup) {
+        Ok(()) => Some(backup),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    if let Err(e) = std::fs::rename(tmp, path) {
+        if let Some(backup) = &backup {
+            let _ = std::fs::rename(&**backup, path);
+        }
+        return Err(e);
+    }
+
+    Ok(backup)
+}
+
+/// Where the download of `path` is stored until it is complete
+#[cfg(feature = "download")]
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".partial");
+    PathBuf::from(name)
+}
+
+#[cfg(feature = "download")]
+fn discard_partial(partial: &Path) {
+    let _ = std::fs::remove_file(partial);
+    let _ = std::fs::remove_file(FileMeta::path(partial));
+}
+
+/// Finish a download that has been decompressed into `dest`, keeping its metadata for `dest`
+///
+/// The download itself is moved to `compressed` if given, and dropped otherwise.
+#[cfg(feature = "download")]
+fn complete_partial(partial: &Path, dest: &Path, compressed: Option<&Path>) -> std::io::Result<()> {
+    match compressed {
+        Some(compressed) => std::fs::rename(partial, compressed)?,
+        None => std::fs::remove_file(partial)?,
+    }
+    std::fs::rename(FileMeta::path(partial), FileMeta::path(dest))
+}
+
+/// Outcome of a single download
+#[cfg(feature = "download")]
+enum Downloaded {
+    Complete(Transfer),
+    /// The server answered a conditional request with 304
+    NotModified(Transfer),
+}
+
+#[cfg(feature = "download")]
+impl Downloaded {
+    fn into_transfer(self) -> Transfer {
+        match self {
+            Downloaded::Complete(transfer) | Downloaded::NotModified(transfer) => transfer,
+        }
+    }
+}
+
+/// What a single download received
+#[cfg(feature = "download")]
+struct Transfer {
+    url: String,
+    status: Option<StatusCode>,
+    bytes: u64,
+    last_modified: Option<String>,
+    etag: Option<String>,
+}
+
+#[cfg(feature = "download")]
+impl Transfer {
+    /// A transfer that has yet to receive the body of the response
+    fn from_response(url: &url::Url, status: StatusCode, headers: &HeaderMap) -> Self {
+        let meta = FileMeta::from_response(url.as_str(), headers);
+
+        Self {
+            url: meta.url,
+            status: Some(status),
+            bytes: 0,
+            last_modified: meta.last_modified,
+            etag: meta.etag,
+        }
+    }
+
+    /// A copy of `bytes` from a local mirror, which has no status or validators
+    fn local(url: &str, bytes: u64) -> Self {
+        Self {
+            url: strip_credentials(url),
+            status: None,
+            bytes,
+            last_modified: None,
+            etag: None,
+        }
+    }
+
+    /// The local copy described by `meta` reparsed without requesting it
+    fn local_copy(meta: Option<&FileMeta>) -> Self {
+        Self {
+            url: meta.map(|meta| redact_url(&meta.url)).unwrap_or_default(),
+            status: None,
+            bytes: 0,
+            last_modified: meta.and_then(|meta| meta.last_modified.clone()),
+            etag: meta.and_then(|meta| meta.etag.clone()),
+        }
+    }
+
+    /// Fill in the validators a 304 doesn't repeat from those the request was made with
+    fn revalidated(mut self, previous: Option<&FileMeta>) -> Self {
+        if let Some(previous) = previous {
+            self.last_modified = self.last_modified.or(previous.last_modified.clone());
+            self.etag = self.etag.or(previous.etag.clone());
+        }
+
+        self
+    }
+
+    fn report(&self, decompressed_size: u64, started: Instant) -> FetchReport {
+        FetchReport {
+            transferred: self.bytes,
+            decompressed_size,
+            elapsed: started.elapsed(),
+            url: self.url.clone(),
+            status: self.status,
+            last_modified: self
+                .last_modified
+                .as_deref()
+                .and_then(|date| parse_date(date).ok()),
+            etag: self.etag.clone(),
+        }
+    }
+}
+
+/// Where a file was downloaded from and its HTTP validators, stored next to it as `<name>.meta`
+///
+/// Used both to resume partial downloads with `If-Range` and to revalidate finished ones.
+#[cfg(feature = "download")]
+struct FileMeta {
+    url: String,
+    last_modified: Option<String>,
+    etag: Option<String>,
+    /// When the file was last downloaded or confirmed to be current
+    fetched: Option<SystemTime>,
+}
+
+#[cfg(feature = "download")]
+impl FileMeta {
+    fn path(file: &Path) -> PathBuf {
+        let mut name = file.as_os_str().to_owned();
+        name.push(".meta");
+        PathBuf::from(name)
+    }
+
+    fn from_response(url: &str, headers: &HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+
+        Self {
+            url: strip_credentials(url),
+            last_modified: header(LAST_MODIFIED),
+            etag: header(ETAG),
+            fetched: Some(SystemTime::now()),
+        }
+    }
+
+    /// Metadata of a file copied from a local mirror, which has no validators
+    fn local(url: &str) -> Self {
+        Self {
+            url: strip_credentials(url),
+            last_modified: None,
+            etag: None,
+            fetched: Some(SystemTime::now()),
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        let mut url = None;
+        let mut last_modified = None;
+        let mut etag = None;
+        let mut fetched = None;
+
+        for line in s.lines() {
+            let (key, value) = line.split_once(": ")?;
+            match key {
+                "URL" => url = Some(value.to_string()),
+                "Last-Modified" => last_modified = Some(value.to_string()),
+                "ETag" => etag = Some(value.to_string()),
+                "Fetched" => {
+                    fetched = value
+                        .parse()
+                        .ok()
+                        .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                }
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            url: url?,
+            last_modified,
+            etag,
+            fetched,
+        })
+    }
+
+    /// Validator for `If-Range`, which must be a strong ETag or a date
+    fn range_validator(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+
+    /// Offset to resume from, or 0 if the partial file can't be trusted to continue `url`
+    fn resume_offset(meta: Option<&Self>, url: &str, len: u64) -> u64 {
+        match meta {
+            Some(meta)
+                if meta.url == strip_credentials(url) && meta.range_validator().is_some() =>
+            {
+                len
+            }
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(feature = "download")]
+impl fmt::Display for FileMeta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "URL: {}", self.url)?;
+        if let Some(last_modified) = &self.last_modified {
+            writeln!(f, "Last-Modified: {last_modified}")?;
+        }
+        if let Some(etag) = &self.etag {
+            writeln!(f, "ETag: {etag}")?;
+        }
+        if let Some(fetched) = self.fetched {
+            let secs = fetched
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            writeln!(f, "Fetched: {secs}")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,2709 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures::{future::Either, Stream, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use tokio::{sync::watch, task::JoinHandle, time::MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+use reqwest::{
+    header::{CACHE_CONTROL, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, RANGE},
+    StatusCode,
+};
+
+use super::{
+    check_digest, check_fresh, check_length, check_package, check_sha256, complete_package,
+    content_length, discard_partial, encode_path_segment, file_url_path, is_content_range,
+    local_copy_age, merge_all, package_path, package_url_path, parse_dir_listing, partial_path,
+    pdiff::{apply_ed, DiffIndex},
+    strip_credentials, temp_path, Compression, Downloaded, FetchOptions, FetchPackagesError,
+    FetchReport, FetchSource, FetcherBuilder, FileLocks, FileMeta, Index, IndexFile,
+    MirrorFailures, OfflinePolicy, RepoLayout, SizeLimit, Snapshot, SnapshotParts, TempPath,
+    Transfer, WithBytes, HASH_CHUNK_SIZE, USER_AGENT,
+};
+use crate::{
+    apt::aosc_suites,
+    arch::{aosc_branch, AOSCBranch},
+    closure,
+    contents::Contents,
+    dependency::RelationKinds,
+    packages::{Package, Packages},
+    release::{Release, ReleaseFile},
+    sources::Sources,
+};
+
+/// Progress of an async Packages download
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// Bytes received so far, and the total from `Content-Length` if the server sent one
+    Progress {
+        downloaded: u64,
+        total: Option<u64>,
+    },
+    Finished,
+    Failed,
+}
+
+/// Result of benchmarking one mirror with [`benchmark_mirrors`]
+#[derive(Debug)]
+pub struct MirrorBenchmark {
+    pub url: String,
+    /// Time until the response headers arrived
+    pub latency: Option<Duration>,
+    /// Size of the downloaded object divided by the time of the whole request
+    pub bytes_per_sec: Option<f64>,
+    /// Why the mirror couldn't be benchmarked, in which case the other fields are `None`
+    pub error: Option<FetchPackagesError>,
+}
+
+/// Sync state of one mirror as reported by [`compare_mirrors`]
+#[derive(Debug)]
+pub struct MirrorStatus {
+    /// The mirror as it was given
+    pub url: String,
+    /// `Date` of the release file on the mirror, when it was last synced
+    pub date: Option<SystemTime>,
+    /// How far the mirror is behind the freshest one, zero for that one
+    pub lag: Option<Duration>,
+    /// Why the mirror couldn't be checked, in which case the other fields are `None`
+    pub error: Option<FetchPackagesError>,
+}
+
+/// Result of [`FetchPackagesAsync::download_closure`]
+#[derive(Debug, Default)]
+pub struct ClosureDownload {
+    /// Packages that were downloaded
+    pub downloaded: Vec<PathBuf>,
+    /// Packages that were already in the destination with the right hash
+    pub skipped: Vec<PathBuf>,
+    /// Bytes received over the network for all downloads
+    pub transferred: u64,
+}
+
+/// Files being downloaded by any fetcher in this process
+static DOWNLOAD_LOCKS: FileLocks<futures::lock::Mutex<()>> = FileLocks::new();
+
+/// Object downloaded from every mirror by [`benchmark_mirrors`]
+const BENCHMARK_PATH: &str = "dists/stable/InRelease";
+
+/// Time a single mirror gets in [`benchmark_mirrors`] before it is given up on
+const BENCHMARK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time a single mirror gets in [`compare_mirrors`] before it is given up on
+const MIRROR_STATUS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fetcher of indices and packages from AOSC mirrors
+///
+/// Cloning is cheap: clones share the HTTP client and its connection pool, as well as the
+/// rate limit, and can be used from several tasks at once.
+#[derive(Clone)]
+pub struct FetchPackagesAsync {
+    client: reqwest::Client,
+    options: FetchOptions,
+}
+
+impl FetcherBuilder<reqwest::Client> {
+    pub fn build(mut self) -> Result<FetchPackagesAsync, FetchPackagesError> {
+        let client = match self.custom_client()? {
+            Some(client) => client,
+            None => {
+                let mut client = reqwest::Client::builder()
+                    .user_agent(self.user_agent_header())
+                    .default_headers(self.build_headers()?)
+                    .redirect(self.redirect_policy());
+                if let Some(timeout) = self.connect_timeout {
+                    client = client.connect_timeout(timeout);
+                }
+                if let Some(timeout) = self.timeout {
+                    client = client.timeout(timeout);
+                }
+                for cert in self.build_root_certificates()? {
+                    client = client.add_root_certificate(cert);
+                }
+                if let Some(identity) = self.build_identity()? {
+                    client = client.use_rustls_tls().identity(identity);
+                }
+                if self.accept_invalid_certs {
+                    client = client.danger_accept_invalid_certs(true);
+                }
+                match self.preferring_resolver() {
+                    Some(resolver) => client = client.dns_resolver(resolver),
+                    None => {
+                        for (host, addrs) in &self.resolve {
+                            client = client.resolve_to_addrs(host, addrs);
+                        }
+                    }
+                }
+                match self.build_proxy()? {
+                    Some(proxy) => client = client.proxy(proxy),
+                    None if !self.system_proxy => client = client.no_proxy(),
+                    None => {}
+                }
+
+                client.build()?
+            }
+        };
+
+        Ok(FetchPackagesAsync {
+            client,
+            options: self.options()?,
+        })
+    }
+}
+
+impl FetchPackagesAsync {
+    /// # Panics
+    ///
+    /// Panics if the mirror URL is invalid, see [`FetcherBuilder::mirror_url`]. Use
+    /// [`Self::builder`] to get the error instead.
+    pub fn new<P: AsRef<Path>>(
+        compression: Compression,
+        download_to: P,
+        mirror_url: Option<&str>,
+    ) -> Self {
+        let mut builder = Self::builder()
+            .compression(compression)
+            .download_to(download_to);
+        if let Some(mirror_url) = mirror_url {
+            builder = builder.mirror_url(mirror_url);
+        }
+
+        builder.build().unwrap()
+    }
+
+    /// Fetcher for the repository of the AOSC OS branch this system runs, downloading to
+    /// `download_to`
+    ///
+    /// AOSC OS/Retro systems download from its own repository, see
+    /// [`FetcherBuilder::aosc_branch`]; any other system from the mainline one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the HTTP client can't be created. Use [`Self::builder`] to get the error
+    /// instead.
+    pub fn for_system<P: AsRef<Path>>(download_to: P) -> Self {
+        Self::builder()
+            .download_to(download_to)
+            .aosc_branch(aosc_branch().unwrap_or(AOSCBranch::Mainline))
+            .build()
+            .unwrap()
+    }
+
+    pub fn builder() -> FetcherBuilder<reqwest::Client> {
+        FetcherBuilder::new()
+    }
+
+    /// The HTTP client of this fetcher, to share its connection pool with another one
+    ///
+    /// See [`FetcherBuilder::with_client`].
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// A copy of the fetcher whose fetches and downloads stop once `token` is cancelled
+    ///
+    /// They then fail with [`FetchPackagesError::Cancelled`]. Unlike dropping the future,
+    /// this removes what was downloaded so far, and the local copy of an index stays as it
+    /// was. A large Packages index also stops being parsed between entries. The copy shares
+    /// the connection pool, so it is cheap to make one for every refresh.
+    pub fn with_cancellation(&self, token: CancellationToken) -> Self {
+        FetchPackagesAsync {
+            client: self.client.clone(),
+            options: FetchOptions {
+                cancel: Some(token),
+                ..self.options.clone()
+            },
+        }
+    }
+
+    pub async fn fetch_packages(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<Packages, FetchPackagesError> {
+        Ok(self
+            .fetch_unconditional(
+                &self.options.index(arch, branch),
+                self.options.compression,
+                &mut |_| {},
+            )
+            .await?
+            .0)
+    }
+
+    /// Fetch the Packages of the architecture of this machine
+    ///
+    /// The index is written to `Packages_<branch>_<component>_<arch>` with the detected
+    /// architecture. Fails with [`FetchPackagesError::UnsupportedHostArch`] if AOSC OS has no
+    /// such architecture.
+    pub async fn fetch_packages_native(
+        &self,
+        branch: &str,
+    ) -> Result<Packages, FetchPackagesError> {
+        self.fetch_packages(self.options.native_arch()?, branch)
+            .await
+    }
+
+    /// Fetch the Packages of each suite of the AOSC OS repository the system at `sysroot`
+    /// tracks, for the architecture of this machine, keyed by suite
+    ///
+    /// The suites are read from the APT sources, see [`aosc_suites`], but downloaded from the
+    /// mirrors of this fetcher. At most [`FetcherBuilder::concurrency`] downloads run at
+    /// once, and each index is written to `Packages_<suite>_<component>_<arch>`. Fails with
+    /// [`FetchPackagesError::NoSystemSuites`] if the sources list none.
+    pub async fn fetch_system_indices(
+        &self,
+        sysroot: &Path,
+    ) -> Result<BTreeMap<String, Packages>, FetchPackagesError> {
+        let arch = self.options.native_arch()?;
+        let suites = aosc_suites(sysroot)?;
+        if suites.is_empty() {
+            return Err(FetchPackagesError::NoSystemSuites(sysroot.to_path_buf()));
+        }
+
+        futures::stream::iter(suites)
+            .map(|suite| async move {
+                let packages = self.fetch_packages(arch, &suite).await?;
+                Ok((suite, packages))
+            })
+            .buffer_unordered(self.options.concurrency)
+            .try_collect()
+            .await
+    }
+
+    /// Fetch the Packages of the topic `topic`
+    ///
+    /// Topics often publish only some architectures, so a missing index fails with
+    /// [`FetchPackagesError::TopicArchNotFound`], or with [`FetchPackagesError::TopicNotFound`]
+    /// if the topic has no release file either.
+    pub async fn fetch_topic_packages(
+        &self,
+        arch: &str,
+        topic: &str,
+    ) -> Result<Packages, FetchPackagesError> {
+        match self.fetch_packages(arch, topic).await {
+            Err(e) if e.is_not_found() => Err(self.missing_topic_index(arch, topic).await),
+            res => res,
+        }
+    }
+
+    /// Fetch and parse the `InRelease` file of `branch`
+    ///
+    /// The PGP signature is stripped but not verified, unless the fetcher was built
+    /// `with_keyring` with the `verify` feature.
+    pub async fn fetch_release(&self, branch: &str) -> Result<Release, FetchPackagesError> {
+        Ok(self.fetch_release_with_report(branch).await?.0)
+    }
+
+    /// Fetch the `InRelease` file of `branch` along with what the server answered
+    ///
+    /// The report carries the final URL, status and validators of the response, see
+    /// [`Self::fetch_packages_with_report`] for the Packages index.
+    pub async fn fetch_release_with_report(
+        &self,
+        branch: &str,
+    ) -> Result<(Release, FetchReport), FetchPackagesError> {
+        let started = Instant::now();
+        let (transfer, bytes, release) = match self
+            .get_from_mirrors(&self.options.in_release_url_path(branch))
+            .await
+        {
+            Ok((transfer, bytes)) => {
+                let release = self.options.parse_in_release(&bytes)?;
+                (transfer, bytes, release)
+            }
+            // A repository without InRelease signs its Release file separately.
+            #[cfg(feature = "verify")]
+            Err(e) if e.is_not_found() && self.options.keyring.is_some() => {
+                let (transfer, bytes) = self
+                    .get_from_mirrors(&self.options.dist_path(branch, "Release"))
+                    .await?;
+                let (_, signature) = self
+                    .get_from_mirrors(&self.options.dist_path(branch, "Release.gpg"))
+                    .await?;
+                let release = self.options.parse_detached_release(&bytes, &signature)?;
+                (transfer, bytes, release)
+            }
+            Err(e) => return Err(e),
+        };
+        let report = transfer.report(bytes.len() as u64, started);
+        self.options.observer().on_complete(&report);
+
+        Ok((release, report))
+    }
+
+    /// List the branches on the mirror, including topics, from its `dists/` directory
+    ///
+    /// The mirror has to serve a listing of the directory, either an autoindex page of Apache,
+    /// nginx or lighttpd, or a JSON listing of nginx or Caddy.
+    ///
+    /// A [flat](RepoLayout::Flat) repository has no branches and fails with
+    /// [`FetchPackagesError::InvalidConfig`].
+    pub async fn list_branches(&self) -> Result<Vec<String>, FetchPackagesError> {
+        if self.options.layout == RepoLayout::Flat {
+            return Err(FetchPackagesError::InvalidConfig(
+                "a flat repository has no branches to list".to_string(),
+            ));
+        }
+
+        let mut failures = MirrorFailures::default();
+
+        for url in self.options.mirror_urls("dists/") {
+            let res = match file_url_path(&url) {
+                Some(path) => list_local_dirs(&path).await,
+                None => self
+                    .get_with_retry(&url)
+                    .await
+                    .map(|(_, bytes)| parse_dir_listing(&String::from_utf8_lossy(&bytes))),
+            };
+            match res {
+                Ok(branches) => return Ok(branches),
+                Err(e) => failures.push(&self.options, url, e)?,
+            }
+        }
+
+        Err(failures.finish())
+    }
+
+    /// Fetch Packages merged with the `binary-all` index of the same component
+    ///
+    /// Entries of `binary-<arch>` take precedence over `binary-all` ones of the same name.
+    /// A missing `binary-all` index is ignored, as the main AOSC repository folds those
+    /// packages into every arch index. It is written to `Packages_<branch>_<component>_all`.
+    /// A [flat](RepoLayout::Flat) repository only has the one index, which is returned as is.
+    pub async fn fetch_packages_with_all(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<Packages, FetchPackagesError> {
+        let packages = self.fetch_packages(arch, branch).await?;
+        if self.options.layout == RepoLayout::Flat {
+            return Ok(packages);
+        }
+        let index = self
+            .options
+            .component_index("all", branch, &self.options.component);
+
+        match self
+            .fetch_unconditional(&index, self.options.compression, &mut |_| {})
+            .await
+        {
+            Ok((all, ..)) => Ok(merge_all(packages, all)),
+            Err(e) if e.is_not_found() => Ok(packages),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Fetch Packages, giving up with [`FetchPackagesError::DeadlineExceeded`] after `deadline`
+    ///
+    /// The deadline covers the whole fetch, including retries, decompression and parsing, so
+    /// a mirror that trickles data can't hold the fetch up. Nothing of an aborted fetch is
+    /// left in the download directory.
+    pub async fn fetch_packages_with_deadline(
+        &self,
+        arch: &str,
+        branch: &str,
+        deadline: Duration,
+    ) -> Result<Packages, FetchPackagesError> {
+        let index = self.options.index(arch, branch);
+        let mut progress = |_| {};
+        let fetch = self.fetch_unconditional(&index, self.options.compression, &mut progress);
+
+        match tokio::time::timeout(deadline, fetch).await {
+            Ok(res) => Ok(res?.0),
+            Err(_) => {
+                // A temporary file is removed when its guard is dropped with the future.
+                discard_partial(&partial_path(&self.options.local_path(&index)?));
+                Err(FetchPackagesError::DeadlineExceeded(deadline))
+            }
+        }
+    }
+
+    /// Size of the Packages download according to the mirror, without downloading it
+    ///
+    /// Sends a HEAD request and returns `Content-Length`, or `None` if the server doesn't
+    /// send it. With [`Compression::Auto`] this is the size of the first format the mirror
+    /// has. Useful to show the expected size or to refuse large downloads on metered
+    /// connections.
+    pub async fn probe_packages_size(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<Option<u64>, FetchPackagesError> {
+        let index = self.options.index(arch, branch);
+        let mut not_found = None;
+
+        for &compression in self.options.compression.candidates() {
+            match self
+                .head_from_mirrors(&self.options.index_url_path(&index, compression))
+                .await
+            {
+                Err(e) if e.is_not_found() => not_found = Some(e),
+                res => return res,
+            }
+        }
+
+        Err(not_found.expect("candidate list is not empty"))
+    }
+
+    /// Fetch Packages, returning the path the decompressed index was written to
+    ///
+    /// The file is `Packages_<branch>_<component>_<arch>` in the download directory, or
+    /// `Packages` if [`FetcherBuilder::plain_file_name`] is set.
+    pub async fn fetch_packages_with_path(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<(Packages, PathBuf), FetchPackagesError> {
+        let index = self.options.index(arch, branch);
+        let (packages, ..) = self
+            .fetch_unconditional(&index, self.options.compression, &mut |_| {})
+            .await?;
+
+        Ok((packages, self.options.local_path(&index)?))
+    }
+
+    /// Fetch Packages, writing the decompressed index to exactly `path`
+    ///
+    /// This bypasses the naming in the download directory, which doesn't have to be set.
+    /// Missing parent directories are created, and the index is written to a temporary file
+    /// next to `path` first, like every other fetch. The decompressed copy is written even
+    /// with [`IndexStorage::Compressed`](super::IndexStorage::Compressed). Returns the
+    /// canonicalized path written to.
+    pub async fn fetch_packages_to(
+        &self,
+        arch: &str,
+        branch: &str,
+        path: &Path,
+    ) -> Result<(Packages, PathBuf), FetchPackagesError> {
+        let index = self.options.index_at(arch, branch, path)?;
+        let fetcher = FetchPackagesAsync {
+            client: self.client.clone(),
+            options: self.options.keeping_decompressed(),
+        };
+        let (packages, ..) = fetcher
+            .fetch_unconditional(&index, self.options.compression, &mut |_| {})
+            .await?;
+        let path = tokio::fs::canonicalize(path).await?;
+
+        Ok((packages, path))
+    }
+
+    /// Fetch Packages from `mirror` instead of the configured mirrors, for this fetch only
+    ///
+    /// Uses the same client and settings otherwise, and `mirror` is checked like the mirrors
+    /// given to the builder. The index is written to the usual file name followed by `@` and
+    /// the host and path of the mirror, such as `Packages_stable_main_amd64@repo.aosc.io_debs`,
+    /// so the copies and validators of different mirrors are kept apart.
+    pub async fn fetch_packages_from(
+        &self,
+        mirror: &str,
+        arch: &str,
+        branch: &str,
+    ) -> Result<Packages, FetchPackagesError> {
+        let options = self.options.with_mirror(mirror)?;
+        let index = options.mirror_index(arch, branch);
+        let fetcher = FetchPackagesAsync {
+            client: self.client.clone(),
+            options,
+        };
+        let (packages, ..) = fetcher
+            .fetch_unconditional(&index, self.options.compression, &mut |_| {})
+            .await?;
+
+        Ok(packages)
+    }
+
+    /// Fetch Packages, returning whether they were downloaded or the local copy was current
+    ///
+    /// Every fetch keeps the `ETag` of the download in a `.meta` file next to the index and
+    /// sends it as `If-None-Match` the next time the same URL is fetched, so switching mirror,
+    /// arch or branch never reuses a validator. On 304 the local copy is reparsed.
+    pub async fn fetch_packages_with_source(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<(Packages, FetchSource), FetchPackagesError> {
+        let (packages, _, source, _) = self
+            .fetch_unconditional(
+                &self.options.index(arch, branch),
+                self.options.compression,
+                &mut |_| {},
+            )
+            .await?;
+
+        Ok((packages, source))
+    }
+
+    /// Fetch Packages along with statistics of the transfer
+    ///
+    /// If the local copy was current the report describes the 304, with nothing transferred
+    /// and the size of the reparsed local copy.
+    pub async fn fetch_packages_with_report(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<(Packages, FetchReport), FetchPackagesError> {
+        let (packages, .., report) = self
+            .fetch_unconditional(
+                &self.options.index(arch, branch),
+                self.options.compression,
+                &mut |_| {},
+            )
+            .await?;
+
+        Ok((packages, report))
+    }
+
+    /// Fetch Packages in the first format the mirror publishes, returning the chosen format
+    ///
+    /// Formats are tried in [`Compression::AUTO_ORDER`]. A 404 moves on to the next format,
+    /// any other error is returned immediately.
+    pub async fn fetch_packages_auto(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<(Packages, Compression), FetchPackagesError> {
+        let (packages, compression, ..) = self
+            .fetch_unconditional(
+                &self.options.index(arch, branch),
+                Compression::Auto,
+                &mut |_| {},
+            )
+            .await?;
+
+        Ok((packages, compression))
+    }
+
+    /// Fetch Packages, reporting download progress to `progress` as chunks arrive
+    ///
+    /// Sizes count the bytes transferred over the wire, so compressed downloads report
+    /// compressed sizes. The last event is always [`ProgressEvent::Finished`] or
+    /// [`ProgressEvent::Failed`].
+    pub async fn fetch_packages_with_progress(
+        &self,
+        arch: &str,
+        branch: &str,
+        mut progress: impl FnMut(ProgressEvent) + Send,
+    ) -> Result<Packages, FetchPackagesError> {
+        let res = self
+            .fetch_unconditional(
+                &self.options.index(arch, branch),
+                self.options.compression,
+                &mut progress,
+            )
+            .await;
+
+        progress(match res {
+            Ok(_) => ProgressEvent::Finished,
+            Err(_) => ProgressEvent::Failed,
+        });
+
+        Ok(res?.0)
+    }
+
+    /// Fetch Packages, reusing the local copy without any request if it is younger than `max_age`
+    ///
+    /// The age counts from the last download of the local copy or the last time the server
+    /// confirmed it with 304, as recorded in its `.meta` file. A copy downloaded from another
+    /// mirror or for another arch or branch is never reused, and neither is one that can't be
+    /// parsed. Otherwise this is [`fetch_packages`](Self::fetch_packages), which refreshes the
+    /// local copy, or an update with patches if [`FetcherBuilder::pdiff`] is enabled. The
+    /// local copy isn't checked against the release file again.
+    pub async fn fetch_packages_cached(
+        &self,
+        arch: &str,
+        branch: &str,
+        max_age: Duration,
+    ) -> Result<Packages, FetchPackagesError> {
+        let index = self.options.index(arch, branch);
+        let local = self.options.local_path(&index)?;
+        if let Some(meta) = previous_download(&local).await {
+            if self.options.is_cache_fresh(&index, &meta, max_age) {
+                if let Ok((packages, _)) = read_local(&local, None).await {
+                    return Ok(packages);
+                }
+            }
+        }
+
+        if self.options.pdiff && self.options.offline != OfflinePolicy::Offline {
+            // A failed update leaves the local copy alone, so fetch the whole index then.
+            if let Ok(packages) = self.update_with_pdiff(&index, &local).await {
+                return Ok(packages);
+            }
+        }
+
+        let (packages, ..) = self
+            .fetch_unconditional(&index, self.options.compression, &mut |_| {})
+            .await?;
+
+        Ok(packages)
+    }
+
+    /// Fetch Packages only if it changed since the last successful download
+    ///
+    /// The `Last-Modified` date of every download is kept in a `.meta` file next to the index
+    /// and sent as `If-Modified-Since` on the next call, along with the `ETag` if there is
+    /// one. Returns `Ok(None)` if the server answers 304, in which case the copy already on
+    /// disk is current. Without recorded validators this is a normal fetch.
+    pub async fn fetch_packages_if_modified(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<Option<Packages>, FetchPackagesError> {
+        Ok(self
+            .fetch(
+                &self.options.index(arch, branch),
+                self.options.compression,
+                true,
+                &mut |_| {},
+            )
+            .await?
+            .map(|(packages, ..)| packages))
+    }
+
+    /// Refresh Packages every `interval` in a background task, publishing each new version
+    /// through the returned channel
+    ///
+    /// The first refresh happens right away. Later ones only ask for the index if it
+    /// changed, see [`Self::fetch_packages_if_modified`], so they need the download
+    /// directory and are cheap while nothing changes. A new version is only published if its
+    /// entries differ from the last one; until the first refresh succeeds the channel holds
+    /// an empty index. A failed refresh is reported to
+    /// [`FetchObserver::on_refresh_failed`](super::FetchObserver::on_refresh_failed) and
+    /// retried at the next interval. The task ends once the receiver and all its clones are
+    /// dropped, or once the fetcher is cancelled, see [`Self::with_cancellation`].
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn spawn_refresher(
+        &self,
+        arch: &str,
+        branch: &str,
+        interval: Duration,
+    ) -> (JoinHandle<()>, watch::Receiver<Arc<Packages>>) {
+        let (tx, rx) = watch::channel(Arc::new(Packages::default()));
+        let fetcher = self.clone();
+        let (arch, branch) = (arch.to_string(), branch.to_string());
+        let handle =
+            tokio::spawn(async move { fetcher.refresh(&arch, &branch, interval, tx).await });
+
+        (handle, rx)
+    }
+
+    /// The loop of [`Self::spawn_refresher`]
+    async fn refresh(
+        &self,
+        arch: &str,
+        branch: &str,
+        interval: Duration,
+        tx: watch::Sender<Arc<Packages>>,
+    ) {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut current = None;
+
+        loop {
+            // Stop as soon as nobody listens anymore instead of at the next refresh.
+            let tick = std::pin::pin!(ticks.tick());
+            let closed = std::pin::pin!(tx.closed());
+            if let Either::Right(_) = futures::future::select(tick, closed).await {
+                return;
+            }
+
+            let res = match current {
+                None => self.fetch_packages(arch, branch).await.map(Some),
+                Some(_) => self.fetch_packages_if_modified(arch, branch).await,
+            };
+            match res {
+                Ok(Some(packages)) => {
+                    let digest = packages_digest(&packages);
+                    if current.as_ref() != Some(&digest) {
+                        current = Some(digest);
+                        if tx.send(Arc::new(packages)).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Ok(None) => {}
+                Err(FetchPackagesError::Cancelled) => return,
+                Err(e) => self.options.observer().on_refresh_failed(&e),
+            }
+        }
+    }
+
+    /// Fetch the Packages of each of `components`, keyed by component
+    ///
+    /// Each index is written to `Packages_<branch>_<component>_<arch>` so they don't
+    /// overwrite each other. A component missing from the mirror fails the whole call with
+    /// [`FetchPackagesError::ComponentNotFound`].
+    pub async fn fetch_components(
+        &self,
+        arch: &str,
+        branch: &str,
+        components: &[&str],
+    ) -> Result<HashMap<String, Packages>, FetchPackagesError> {
+        let mut res = HashMap::new();
+
+        for &component in components {
+            let index = self.options.component_index(arch, branch, component);
+            let (packages, ..) = self
+                .fetch_unconditional(&index, self.options.compression, &mut |_| {})
+                .await
+                .map_err(|e| {
+                    if e.is_not_found() {
+                        FetchPackagesError::ComponentNotFound(component.to_string())
+                    } else {
+                        e
+                    }
+                })?;
+            res.insert(component.to_string(), packages);
+        }
+
+        Ok(res)
+    }
+
+    /// Fetch Packages without touching the filesystem
+    ///
+    /// The index is downloaded, decompressed and parsed in memory, so this works without a
+    /// download directory. Nothing is cached and every call downloads the whole index.
+    pub async fn fetch_packages_in_memory(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<Packages, FetchPackagesError> {
+        let (packages, _) = self
+            .fetch_in_memory(&self.options.index(arch, branch), self.options.compression)
+            .await?;
+
+        Ok(packages)
+    }
+
+    /// Stream Packages entries as they arrive, without downloading the whole index first
+    ///
+    /// The index is decompressed on the fly and every entry is yielded as soon as its
+    /// paragraph is complete. Nothing is cached, and the index isn't checked against the
+    /// release file since entries are yielded before it is complete. Dropping the stream
+    /// cancels the download.
+    ///
+    /// An entry that can't be parsed is yielded as an error and the stream goes on with the
+    /// next one. Errors of the download itself end the stream.
+    pub fn fetch_packages_stream(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> impl Stream<Item = Result<Package, FetchPackagesError>> + Send + '_ {
+        let index = self.options.index(arch, branch);
+
+        futures::stream::once(async move {
+            match self.open_index(&index).await {
+                Ok((url, reader)) => package_stream(reader)
+                    .map(move |res| res.map_err(|e| index.error(url.clone(), e)))
+                    .left_stream(),
+                Err(e) => futures::stream::iter([Err(e)]).right_stream(),
+            }
+        })
+        .flatten()
+    }
+
+    /// Fetch Packages without touching the filesystem, also writing the decompressed index
+    /// to `w`
+    ///
+    /// Works like [`Self::fetch_packages_in_memory`]. The index is written to `w` as it is
+    /// decompressed, before it is checked against the release file and parsed, so `w` may
+    /// hold part of an index or an index that doesn't match when the fetch fails. As a
+    /// download that turns out corrupt may have been partly written already, it isn't tried
+    /// again. Failures of `w` are returned as [`FetchPackagesError::WriterError`].
+    pub async fn fetch_packages_to_writer(
+        &self,
+        arch: &str,
+        branch: &str,
+        w: &mut (dyn tokio::io::AsyncWrite + Unpin + Send),
+    ) -> Result<Packages, FetchPackagesError> {
+        let index = self.options.index(arch, branch);
+        let release = self.index_release(&index.branch).await?;
+        let mut w = tokio_util::compat::TokioAsyncWriteCompatExt::compat_write(w);
+        let (packages, _) = self
+            .try_fetch_in_memory_with(&index, self.options.compression, release.as_ref(), &mut w)
+            .await?;
+        futures::AsyncWriteExt::flush(&mut w)
+            .await
+            .map_err(FetchPackagesError::WriterError)?;
+
+        Ok(packages)
+    }
+
+    /// Fetch the `Sources` index of the configured component
+    ///
+    /// The index is written to `Sources_<branch>_<component>`.
+    pub async fn fetch_sources(&self, branch: &str) -> Result<Sources, FetchPackagesError> {
+        let (sources, ..) = self
+            .fetch_unconditional(
+                &self.options.sources_index(branch),
+                self.options.compression,
+                &mut |_| {},
+            )
+            .await?;
+
+        Ok(sources)
+    }
+
+    /// Fetch the `Contents-<arch>` index of the configured component
+    ///
+    /// The index is tried in every format in [`Compression::AUTO_ORDER`] and written to
+    /// `Contents_<branch>_<component>_<arch>`.
+    pub async fn fetch_contents(
+        &self,
+        arch: &str,
+        branch: &str,
+    ) -> Result<Contents, FetchPackagesError> {
+        let (contents, ..) = self
+            .fetch_unconditional(
+                &self.options.contents_index(arch, branch),
+                Compression::Auto,
+                &mut |_| {},
+            )
+            .await?;
+
+        Ok(contents)
+    }
+
+    /// Fetch the Packages of `branch` together with its release file and the indices in
+    /// `what`, all checked against that one release file
+    ///
+    /// The release file is fetched once, and every index is downloaded by hash if the
+    /// release file allows it or checked against its table otherwise, so a mirror updating
+    /// halfway through can't mix old and new indices. The indices are written to the usual
+    /// files in the download directory only once all of them verified, and if any fails to
+    /// download or to be moved into place, the previous copies are kept. The files are still
+    /// replaced one after another, so a reader of the directory meanwhile may see a mix.
+    pub async fn fetch_snapshot(
+        &self,
+        arch: &str,
+        branch: &str,
+        what: SnapshotParts,
+    ) -> Result<Snapshot, FetchPackagesError> {
+        let fetcher = FetchPackagesAsync {
+            client: self.client.clone(),
+            options: self.options.for_snapshot(),
+        };
+        let options = &fetcher.options;
+        let packages_index = options.index(arch, branch);
+        let contents_index = what.contents.then(|| options.contents_index(arch, branch));
+        let sources_index = what.sources.then(|| options.sources_index(branch));
+        let indices: Vec<&Index> = [Some(&packages_index)]
+            .into_iter()
+            .chain([contents_index.as_ref(), sources_index.as_ref()])
+            .flatten()
+            .collect();
+        // Fail on a missing download directory before downloading anything.
+        let paths = indices
+            .iter()
+            .map(|index| options.local_path(index))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let release = fetcher.fetch_release(branch).await?;
+        if let Some(max_age) = options.max_release_age {
+            check_fresh(&release, max_age)?;
+        }
+
+        let mut files = vec![];
+        let mut fetched = |index: &Index, compression, bytes| {
+            let url = options.mirror_url(&options.index_url_path(index, compression));
+            files.push((bytes, url));
+        };
+        let (WithBytes(packages, bytes), compression) = fetcher
+            .fetch_verified(&packages_index, options.compression, &release)
+            .await?;
+        fetched(&packages_index, compression, bytes);
+        let contents = match &contents_index {
+            Some(index) => {
+                let (WithBytes(contents, bytes), compression) = fetcher
+                    .fetch_verified(index, Compression::Auto, &release)
+                    .await?;
+                fetched(index, compression, bytes);
+                Some(contents)
+            }
+            None => None,
+        };
+        let sources = match &sources_index {
+            Some(index) => {
+                let (WithBytes(sources, bytes), compression) = fetcher
+                    .fetch_verified(index, options.compression, &release)
+                    .await?;
+                fetched(index, compression, bytes);
+                Some(sources)
+            }
+            None => None,
+        };
+
+        let files: Vec<_> = paths
+            .into_iter()
+            .zip(files)
+            .map(|(path, (bytes, url))| (path, bytes, url))
+            .collect();
+        // Taken in path order, so two snapshots of overlapping indices can't deadlock.
+        let mut sorted: Vec<&Path> = files.iter().map(|(path, ..)| path.as_path()).collect();
+        sorted.sort();
+        let locks: Vec<_> = sorted
+            .into_iter()
+            .map(|path| DOWNLOAD_LOCKS.get(path))
+            .collect();
+        let mut guards = vec![];
+        for lock in &locks {
+            guards.push(lock.lock().await);
+        }
+        let owned = options.clone();
+        tokio::task::spawn_blocking(move || owned.publish(&files)).await??;
+
+        Ok(Snapshot {
+            release,
+            packages,
+            contents,
+            sources,
+        })
+    }
+
+    /// Fetch the Packages of several architectures concurrently, keyed by architecture
+    ///
+    /// At most [`FetcherBuilder::concurrency`] downloads run at once, and each index is
+    /// written to `Packages_<branch>_<component>_<arch>`. The first failure cancels the
+    /// remaining downloads and is returned, see [`Self::fetch_packages_multi_each`] to
+    /// keep the architectures that succeeded.
+    pub async fn fetch_packages_multi(
+        &self,
+        arches: &[&str],
+        branch: &str,
+    ) -> Result<HashMap<String, Packages>, FetchPackagesError> {
+        self.fetch_multi(arches, branch)
+            .map(|(arch, res)| res.map(|packages| (arch, packages)))
+            .try_collect()
+            .await
+    }
+
+    /// Like [`Self::fetch_packages_multi`], but fetch every architecture and return the
+    /// result of each one
+    pub async fn fetch_packages_multi_each(
+        &self,
+        arches: &[&str],
+        branch: &str,
+    ) -> HashMap<String, Result<Packages, FetchPackagesError>> {
+        self.fetch_multi(arches, branch).collect().await
+    }
+
+    fn fetch_multi<'a>(
+        &'a self,
+        arches: &'a [&str],
+        branch: &'a str,
+    ) -> impl Stream<Item = (String, Result<Packages, FetchPackagesError>)> + 'a {
+        // Duplicates would download to the same file at the same time.
+        let arches: HashSet<&str> = arches.iter().copied().collect();
+
+        futures::stream::iter(arches)
+            .map(move |arch| async move {
+                let index = self
+                    .options
+                    .component_index(arch, branch, &self.options.component);
+                let res = self
+                    .fetch_unconditional(&index, self.options.compression, &mut |_| {})
+                    .await
+                    .map(|(packages, ..)| packages);
+
+                (arch.to_string(), res)
+            })
+            .buffer_unordered(self.options.concurrency)
+    }
+
+    /// Fetch the Packages of `arch` in each of `branches` concurrently, keyed by branch
+    ///
+    /// Without `branches`, every branch on the mirror is fetched, see [`Self::list_branches`].
+    /// At most [`FetcherBuilder::concurrency`] downloads run at once, and each index is
+    /// written to `Packages_<branch>_<component>_<arch>`. Branches without an index for
+    /// `arch`, such as topics that weren't built for it, are left out of the result. Any other
+    /// failure cancels the remaining downloads and is returned.
+    pub async fn fetch_all_branches(
+        &self,
+        arch: &str,
+        branches: Option<&[&str]>,
+    ) -> Result<BTreeMap<String, Packages>, FetchPackagesError> {
+        // Duplicates would download to the same file at the same time.
+        let branches: BTreeSet<String> = match branches {
+            Some(branches) => branches.iter().map(|b| b.to_string()).collect(),
+            None => self.list_branches().await?.into_iter().collect(),
+        };
+
+        futures::stream::iter(branches)
+            .map(|branch| async move {
+                let index = self
+                    .options
+                    .component_index(arch, &branch, &self.options.component);
+                match self
+                    .fetch_unconditional(&index, self.options.compression, &mut |_| {})
+                    .await
+                {
+                    Ok((packages, ..)) => Ok(Some((branch, packages))),
+                    Err(e) if e.is_not_found() => Ok(None),
+                    Err(e) => Err(e),
+                }
+            })
+            .buffer_unordered(self.options.concurrency)
+            .try_filter_map(|res| async move { Ok(res) })
+            .try_collect()
+            .await
+    }
+
+    /// Download the `.deb` of `pkg` into the directory `dest`, returning the path of the file
+    ///
+    /// The package is fetched from `<mirror>/<filename>` in either [`RepoLayout`] and stored
+    /// under the last component of its file name. The download is checked against the size
+    /// and SHA256 listed in Packages and removed if either doesn't match, see
+    /// [`FetchPackagesError::SizeMismatch`] and [`FetchPackagesError::ChecksumMismatch`]. If a
+    /// file with the right hash is already there, nothing is downloaded. No more than the
+    /// listed size is downloaded, see [`FetcherBuilder::max_download_size`].
+    pub async fn download_package(
+        &self,
+        pkg: &Package,
+        dest: &Path,
+    ) -> Result<PathBuf, FetchPackagesError> {
+        let (path, _) = self.download_package_with_report(pkg, dest).await?;
+
+        Ok(path)
+    }
+
+    /// Download `pkg` like [`Self::download_package`], along with the report of the transfer,
+    /// or `None` if the file was already there
+    async fn download_package_with_report(
+        &self,
+        pkg: &Package,
+        dest: &Path,
+    ) -> Result<(PathBuf, Option<FetchReport>), FetchPackagesError> {
+        let path = package_path(dest, pkg);
+        let lock = DOWNLOAD_LOCKS.get(&path);
+        let _guard = lock.lock().await;
+        // Hashing a large package would block the runtime.
+        let (owned_pkg, owned_path) = (pkg.clone(), path.clone());
+        if tokio::task::spawn_blocking(move || check_package(&owned_pkg, &owned_path))
+            .await?
+            .is_ok()
+        {
+            return Ok((path, None));
+        }
+
+        let fetcher = FetchPackagesAsync {
+            client: self.client.clone(),
+            options: self.options.for_package(pkg)?,
+        };
+        if !dest.exists() {
+            tokio::fs::create_dir_all(dest).await?;
+        }
+
+        let started = Instant::now();
+        let partial = partial_path(&path);
+        let transfer = match fetcher
+            .download_segmented(package_url_path(pkg), &partial, pkg.size)
+            .await
+        {
+            Some(transfer) => transfer,
+            None => fetcher
+                .download_from_mirrors(package_url_path(pkg), &partial, None, &mut |_| {})
+                .await?
+                .into_transfer(),
+        };
+
+        let (owned_pkg, owned_path) = (pkg.clone(), path.clone());
+        tokio::task::spawn_blocking(move || complete_package(&owned_pkg, &partial, &owned_path))
+            .await??;
+        let report = transfer.report(pkg.size, started);
+        self.options.observer().on_complete(&report);
+
+        Ok((path, Some(report)))
+    }
+
+    /// Download the `.deb` of each of `pkgs` into `dest_dir`, keyed by `Filename`
+    ///
+    /// At most `concurrency` downloads run at once (at least one), and each package is
+    /// checked like in [`Self::download_package`]. A failed package doesn't stop the others,
+    /// its error is returned in its entry. Packages with the same `Filename` are downloaded
+    /// once. Files are stored under their base name, so a different file with the same base
+    /// name as an earlier one fails with [`FetchPackagesError::DuplicateDestination`].
+    pub async fn download_packages(
+        &self,
+        pkgs: &[Package],
+        dest_dir: &Path,
+        concurrency: usize,
+    ) -> HashMap<String, Result<PathBuf, FetchPackagesError>> {
+        let (pkgs, duplicates) = unique_destinations(pkgs, dest_dir);
+
+        let mut res: HashMap<_, _> = futures::stream::iter(pkgs)
+            .map(|pkg| async move {
+                let res = self.download_package(pkg, dest_dir).await;
+
+                (pkg.filename.clone(), res)
+            })
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await;
+        res.extend(
+            duplicates
+                .into_iter()
+                .map(|(pkg, e)| (pkg.filename.clone(), Err(e))),
+        );
+
+        res
+    }
+
+    /// Download the `.deb` of each of `roots` and everything they depend on into `dest`
+    ///
+    /// The dependencies are resolved from the `Depends` of `pkgs` first, taking the newest
+    /// version that satisfies each one and the packages that `Provides` a virtual package.
+    /// `kinds` picks whether recommended and suggested packages are included too, as far as
+    /// they are available. If any other dependency can't be resolved, nothing is downloaded and
+    /// [`FetchPackagesError::UnresolvedDependencies`] lists all of them. The packages are then
+    /// downloaded `concurrency` at a time and checked like in [`Self::download_package`],
+    /// stopping at the first one that fails. Nothing is downloaded either if two different
+    /// files would be stored under the same name, see
+    /// [`FetchPackagesError::DuplicateDestination`].
+    pub async fn download_closure(
+        &self,
+        pkgs: &Packages,
+        roots: &[&str],
+        kinds: RelationKinds,
+        dest: &Path,
+    ) -> Result<ClosureDownload, FetchPackagesError> {
+        let closure = closure::resolve(pkgs, roots, kinds)?.packages;
+        // Several versions of a package may be in the closure, but not the same file twice.
+        let (closure, duplicates) = unique_destinations(closure, dest);
+        if let Some((_, e)) = duplicates.into_iter().next() {
+            return Err(e);
+        }
+
+        let results: Vec<_> = futures::stream::iter(closure)
+            .map(|pkg| self.download_package_with_report(pkg, dest))
+            .buffer_unordered(self.options.concurrency)
+            .try_collect()
+            .await?;
+
+        let mut download = ClosureDownload::default();
+        for (path, report) in results {
+            match report {
+                Some(report) => {
+                    download.transferred += report.transferred;
+                    download.downloaded.push(path);
+                }
+                None => download.skipped.push(path),
+            }
+        }
+
+        Ok(download)
+    }
+
+    /// Parse the index `bytes`, stopping between entries if the fetch is cancelled
+    fn parse<T: IndexFile>(&self, bytes: &[u8]) -> Result<T, FetchPackagesError> {
+        match &self.options.cancel {
+            Some(_) => T::parse_checked(bytes, &|| self.options.check_cancelled()),
+            None => T::parse(bytes),
+        }
+    }
+
+    /// A copy of the fetcher that asks caches on the way for a fresh copy of everything
+    fn no_cache(&self) -> Self {
+        FetchPackagesAsync {
+            client: self.client.clone(),
+            options: FetchOptions {
+                no_cache: true,
+                ..self.options.clone()
+            },
+        }
+    }
+
+    async fn fetch_unconditional<T, F>(
+        &self,
+        index: &Index,
+        compression: Compression,
+        progress: &mut F,
+    ) -> Result<(T, Compression, FetchSource, FetchReport), FetchPackagesError>
+    where
+        T: IndexFile,
+        F: FnMut(ProgressEvent) + Send,
+    {
+        Ok(self
+            .fetch(index, compression, false, progress)
+            .await?
+            .expect("304 is only passed through for conditional fetches"))
+    }
+
+    /// Fetch Packages, revalidating the local copy if there is one
+    ///
+    /// Returns `None` on 304 if `conditional`, otherwise the local copy is reparsed.
+    async fn fetch<T, F>(
+        &self,
+        index: &Index,
+        compression: Compression,
+        conditional: bool,
+        progress: &mut F,
+    ) -> Result<Option<(T, Compression, FetchSource, FetchReport)>, FetchPackagesError>
+    where
+        T: IndexFile,
+        F: FnMut(ProgressEvent) + Send,
+    {
+        let started = Instant::now();
+        let res = if self.options.offline == OfflinePolicy::Offline {
+            self.read_stale(index, compression, started).await.map(Some)
+        } else {
+            self.fetch_from_network(index, compression, conditional, progress, started)
+                .await
+        };
+        if let Ok(Some((.., report))) = &res {
+            self.options.observer().on_complete(report);
+        }
+
+        res
+    }
+
+    /// [`Self::fetch`] unless the [`OfflinePolicy`] rules out the network
+    async fn fetch_from_network<T, F>(
+        &self,
+        index: &Index,
+        compression: Compression,
+        conditional: bool,
+        progress: &mut F,
+        started: Instant,
+    ) -> Result<Option<(T, Compression, FetchSource, FetchReport)>, FetchPackagesError>
+    where
+        T: IndexFile,
+        F: FnMut(ProgressEvent) + Send,
+    {
+        let res = match self
+            .try_fetch(index, compression, conditional, progress)
+            .await
+        {
+            Err(e) if e.is_corrupt_payload() && self.options.retry.retries > 0 => {
+                self.no_cache()
+                    .try_fetch(index, compression, conditional, progress)
+                    .await
+            }
+            res => res,
+        };
+
+        match res {
+            Err(e)
+                if self.options.offline == OfflinePolicy::FallbackToCache
+                    && e.is_network_error() =>
+            {
+                // Without a usable local copy, the network error is the one to report.
+                self.read_stale(index, compression, started)
+                    .await
+                    .map(Some)
+                    .map_err(|_| e)
+            }
+            res => res,
+        }
+    }
+
+    /// Reparse the local copy of `index` as is, as the [`OfflinePolicy`] asks for
+    async fn read_stale<T: IndexFile>(
+        &self,
+        index: &Index,
+        compression: Compression,
+        started: Instant,
+    ) -> Result<(T, Compression, FetchSource, FetchReport), FetchPackagesError> {
+        let local = self.options.local_path(index)?;
+        let Ok(metadata) = tokio::fs::metadata(&local).await else {
+            return Err(FetchPackagesError::NoLocalCopy(local));
+        };
+        let meta = previous_download(&local).await;
+        let (parsed, size) = read_local(&local, None).await?;
+
+        let report = Transfer::local_copy(meta.as_ref()).report(size, started);
+        let age = local_copy_age(meta.as_ref(), metadata.modified().ok());
+
+        Ok((parsed, compression, FetchSource::Stale { age }, report))
+    }
+
+    async fn try_fetch<T, F>(
+        &self,
+        index: &Index,
+        compression: Compression,
+        conditional: bool,
+        progress: &mut F,
+    ) -> Result<Option<(T, Compression, FetchSource, FetchReport)>, FetchPackagesError>
+    where
+        T: IndexFile,
+        F: FnMut(ProgressEvent) + Send,
+    {
+        let started = Instant::now();
+        let mut not_found = None;
+        let local = &self.options.local_path(index)?;
+        let dir = local
+            .parent()
+            .expect("indices are stored in the download directory");
+        let partial = partial_path(local);
+        let lock = DOWNLOAD_LOCKS.get(local);
+        let _guard = lock.lock().await;
+
+        if !dir.exists() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+
+        // Some CDNs get Last-Modified wrong, so only rely on it when asked to.
+        let previous = previous_download(local).await.map(|mut meta| {
+            if !conditional {
+                meta.last_modified = None;
+            }
+            meta
+        });
+
+        let release = self.index_release(&index.branch).await?;
+        let decompressed = self
+            .options
+            .release_checksum(release.as_ref(), &index.name)?;
+
+        // A local copy that matches the release file is current, a missing or broken one is
+        // downloaded again.
+        if let Some(expected) = decompressed {
+            if let Ok((parsed, size)) = read_local(local, Some(expected)).await {
+                refresh_download(local).await;
+                if conditional {
+                    return Ok(None);
+                }
+                let meta = previous_download(local).await;
+                let report = Transfer::local_copy(meta.as_ref()).report(size, started);
+                return Ok(Some((
+                    parsed,
+                    compression,
+                    FetchSource::MatchesRelease,
+                    report,
+                )));
+            }
+        }
+
+        for &compression in compression.candidates() {
+            let urls = match self
+                .options
+                .index_url_paths(index, compression, release.as_ref())
+            {
+                Ok(urls) => urls,
+                // Formats the release file doesn't list are skipped like missing ones.
+                Err(e) => {
+                    not_found = Some(e);
+                    continue;
+                }
+            };
+
+            for (path, expected) in urls {
+                let mut res = self
+                    .download_from_mirrors(&path, &partial, previous.as_ref(), progress)
+                    .await;
+                if matches!(res, Ok(Downloaded::NotModified(_))) {
+                    refresh_download(local).await;
+                }
+                if let (false, Ok(Downloaded::NotModified(transfer))) = (conditional, &res) {
+                    match read_local(local, decompressed).await {
+                        Ok((parsed, size)) => {
+                            let report = transfer.report(size, started);
+                            return Ok(Some((parsed, compression, FetchSource::Cache, report)));
+                        }
+                        // A broken local copy can't stand in for the download.
+                        Err(_) => {
+                            res = self
+                                .download_from_mirrors(&path, &partial, None, progress)
+                                .await
+                        }
+                    }
+                }
+
+                match res {
+                    Ok(Downloaded::Complete(transfer)) => {
+                        let tmp = TempPath(temp_path(local));
+                        let res = async {
+                            if let Some(expected) = expected {
+                                check_file_sha256(
+                                    &index.release_path(compression),
+                                    expected,
+                                    &partial,
+                                )
+                                .await?;
+                            }
+
+                            let bytes = self
+                                .decompress(&transfer.url, compression, &partial, &tmp)
+                                .await?;
+                            if let Some(expected) = decompressed {
+                                check_sha256(&index.name, expected, &bytes)?;
+                            }
+                            let parsed = self.parse(&bytes)?;
+                            self.options
+                                .finish_index(compression, &partial, &tmp, local)?;
+
+                            Ok::<_, FetchPackagesError>((parsed, bytes.len() as u64))
+                        }
+                        .await;
+                        if res.is_err() {
+                            discard_partial(&partial);
+                        }
+
+                        let (parsed, size) =
+                            res.map_err(|e| index.error(transfer.url.clone(), e))?;
+                        let report = transfer.report(size, started);
+                        return Ok(Some((parsed, compression, FetchSource::Network, report)));
+                    }
+                    Ok(Downloaded::NotModified(_)) => return Ok(None),
+                    // A by-hash copy that is gone falls back to the canonical path.
+                    Err(e) if e.is_not_found() => {
+                        not_found = Some(index.error(self.options.mirror_url(&path), e))
+                    }
+                    Err(e) => return Err(index.error(self.options.mirror_url(&path), e)),
+                }
+            }
+        }
+
+        Err(not_found.expect("candidate list is not empty"))
+    }
+
+    /// Update the local copy of `index` at `local` with the patches in its `.diff/` directory
+    async fn update_with_pdiff(
+        &self,
+        index: &Index,
+        local: &Path,
+    ) -> Result<Packages, FetchPackagesError> {
+        let lock = DOWNLOAD_LOCKS.get(local);
+        let _guard = lock.lock().await;
+        let previous = previous_download(local)
+            .await
+            .ok_or_else(|| FetchPackagesError::NoLocalCopy(local.into()))?;
+        let mut bytes = tokio::fs::read(local).await?;
+
+        let release = self.index_release(&index.branch).await?;
+        let diff_index_name = format!("{}.diff/Index", index.name);
+        let expected = self
+            .options
+            .release_checksum(release.as_ref(), &diff_index_name)?;
+        let decompressed = self
+            .options
+            .release_checksum(release.as_ref(), &index.name)?;
+
+        let path = self.options.diff_url_path(index, "Index");
+        let (_, diff_index) = self.get_from_mirrors(&path).await?;
+        if let Some(expected) = expected {
+            check_sha256(&diff_index_name, expected, &diff_index)?;
+        }
+        let malformed = |message| FetchPackagesError::Pdiff {
+            path: path.clone(),
+            message,
+        };
+        let diff_index = DiffIndex::parse(&diff_index).map_err(malformed)?;
+        let patches = diff_index
+            .patches_for(&bytes)
+            .ok_or_else(|| malformed("the local copy is not in the history".to_string()))?;
+        if patches.is_empty() {
+            refresh_download(local).await;
+            return Packages::parse(&bytes);
+        }
+
+        for patch in patches {
+            let path = self
+                .options
+                .diff_url_path(index, &format!("{}.gz", patch.name));
+            let (Transfer { url, .. }, compressed) = self.get_from_mirrors(&path).await?;
+            if let Some(expected) = patch.download {
+                check_sha256(&path, expected, &compressed)?;
+            }
+            let script = decode(
+                &url,
+                Compression::Gzip,
+                compressed.as_slice(),
+                self.options.max_decompressed,
+                &mut futures::io::sink(),
+            )
+            .await?;
+            check_sha256(&path, patch.uncompressed, &script)?;
+            bytes = apply_ed(&bytes, &script)
+                .map_err(|message| FetchPackagesError::Pdiff { path, message })?;
+        }
+
+        check_sha256(&index.name, diff_index.current(), &bytes)?;
+        if let Some(expected) = decompressed {
+            check_sha256(&index.name, expected, &bytes)?;
+        }
+        let packages = Packages::parse(&bytes)?;
+
+        let tmp = TempPath(temp_path(local));
+        let mut f = tokio::fs::File::create(&*tmp).await?;
+        tokio::io::AsyncWriteExt::write_all(&mut f, &bytes).await?;
+        // Make sure the data is on disk before the rename makes it visible.
+        f.sync_all().await?;
+        self.options.finish_patched(&tmp, local, &previous.url)?;
+
+        Ok(packages)
+    }
+
+    async fn fetch_in_memory<T: IndexFile>(
+        &self,
+        index: &Index,
+        compression: Compression,
+    ) -> Result<(T, Compression), FetchPackagesError> {
+        match self.try_fetch_in_memory(index, compression).await {
+            Err(e) if e.is_corrupt_payload() && self.options.retry.retries > 0 => {
+                self.no_cache()
+                    .try_fetch_in_memory(index, compression)
+                    .await
+            }
+            res => res,
+        }
+    }
+
+    async fn try_fetch_in_memory<T: IndexFile>(
+        &self,
+        index: &Index,
+        compression: Compression,
+    ) -> Result<(T, Compression), FetchPackagesError> {
+        let release = self.index_release(&index.branch).await?;
+        self.try_fetch_in_memory_with(
+            index,
+            compression,
+            release.as_ref(),
+            &mut futures::io::sink(),
+        )
+        .await
+    }
+
+    /// Like [`Self::fetch_in_memory`], but checked against `release` that was already fetched
+    async fn fetch_verified<T: IndexFile>(
+        &self,
+        index: &Index,
+        compression: Compression,
+        release: &Release,
+    ) -> Result<(T, Compression), FetchPackagesError> {
+        match self
+            .try_fetch_in_memory_with(index, compression, Some(release), &mut futures::io::sink())
+            .await
+        {
+            Err(e) if e.is_corrupt_payload() && self.options.retry.retries > 0 => {
+                self.no_cache()
+                    .try_fetch_in_memory_with(
+                        index,
+                        compression,
+                        Some(release),
+                        &mut futures::io::sink(),
+                    )
+                    .await
+            }
+            res => res,
+        }
+    }
+
+    /// Fetch `index`, checked against `release` if given, and write what it decompresses to
+    /// to `sink` as it is decompressed
+    async fn try_fetch_in_memory_with<T: IndexFile>(
+        &self,
+        index: &Index,
+        compression: Compression,
+        release: Option<&Release>,
+        sink: &mut (dyn futures::AsyncWrite + Unpin + Send),
+    ) -> Result<(T, Compression), FetchPackagesError> {
+        let started = Instant::now();
+        let mut not_found = None;
+        let decompressed = self.options.release_checksum(release, &index.name)?;
+
+        for &compression in compression.candidates() {
+            let urls = match self.options.index_url_paths(index, compression, release) {
+                Ok(urls) => urls,
+                // Formats the release file doesn't list are skipped like missing ones.
+                Err(e) => {
+                    not_found = Some(e);
+                    continue;
+                }
+            };
+
+            for (path, expected) in urls {
+                match self.get_from_mirrors(&path).await {
+                    Ok((transfer, bytes)) => {
+                        let url = &transfer.url;
+                        let res = async {
+                            if let Some(expected) = expected {
+                                check_sha256(&index.release_path(compression), expected, &bytes)?;
+                            }
+                            let limit = self.options.max_decompressed;
+                            let bytes =
+                                decode(url, compression, bytes.as_slice(), limit, &mut *sink)
+                                    .await?;
+                            if let Some(expected) = decompressed {
+                                check_sha256(&index.name, expected, &bytes)?;
+                            }
+
+                            Ok((self.parse(&bytes)?, bytes.len() as u64))
+                        }
+                        .await;
+
+                        let (parsed, size) = res.map_err(|e| index.error(url.clone(), e))?;
+                        self.options
+                            .observer()
+                            .on_complete(&transfer.report(size, started));
+                        return Ok((parsed, compression));
+                    }
+                    Err(e) if e.is_not_found() => {
+                        not_found = Some(index.error(self.options.mirror_url(&path), e))
+                    }
+                    Err(e) => return Err(index.error(self.options.mirror_url(&path), e)),
+                }
+            }
+        }
+
+        Err(not_found.expect("candidate list is not empty"))
+    }
+
+    /// Tell a topic without an index for `arch` apart from one that doesn't exist
+    async fn missing_topic_index(&self, arch: &str, topic: &str) -> FetchPackagesError {
+        match self
+            .get_from_mirrors(&self.options.in_release_url_path(topic))
+            .await
+        {
+            Ok(_) => FetchPackagesError::TopicArchNotFound {
+                topic: topic.to_string(),
+                arch: arch.to_string(),
+            },
+            Err(e) if e.is_not_found() => FetchPackagesError::TopicNotFound(topic.to_string()),
+            Err(e) => e,
+        }
+    }
+
+    /// The release file of `branch` to check indices against or download them by hash with,
+    /// if either is enabled
+    ///
+    /// Also checks that the release file is recent enough if
+    /// [`FetcherBuilder::require_fresh_metadata`] is set.
+    async fn index_release(&self, branch: &str) -> Result<Option<Release>, FetchPackagesError> {
+        let release = match self.options.max_release_age {
+            Some(max_age) => {
+                let release = self.fetch_release(branch).await?;
+                check_fresh(&release, max_age)?;
+                Some(release)
+            }
+            None if self.options.verify => Some(self.fetch_release(branch).await?),
+            None if self.options.by_hash => match self.fetch_release(branch).await {
+                Ok(release) => Some(release),
+                // Without a release file there are no hashes to go by.
+                Err(e) if e.is_not_found() => None,
+                Err(e) => return Err(e),
+            },
+            None => None,
+        };
+
+        Ok(release)
+    }
+
+    /// Download `path` into memory from the first mirror that can serve it, returning its URL
+    async fn get_from_mirrors(
+        &self,
+        path: &str,
+    ) -> Result<(Transfer, Vec<u8>), FetchPackagesError> {
+        let mut failures = MirrorFailures::default();
+
+        for url in self.options.mirror_urls(path) {
+            match self.get_with_retry(&url).await {
+                Ok(res) => return Ok(res),
+                Err(e) => failures.push(&self.options, url, e)?,
+            }
+        }
+
+        Err(failures.finish())
+    }
+
+    /// A reader of the decompressed `index` from the first mirror and format available,
+    /// along with its URL
+    async fn open_index(
+        &self,
+        index: &Index,
+    ) -> Result<(String, impl futures::AsyncBufRead + Send), FetchPackagesError> {
+        let mut not_found = None;
+
+        for &compression in self.options.compression.candidates() {
+            let path = self.options.index_url_path(index, compression);
+            let res = async {
+                let (url, body) = self.open_from_mirrors(&path).await?;
+                let reader = Limited {
+                    inner: decoder(compression, body).await?,
+                    read: 0,
+                    limit: self.options.max_decompressed,
+                    which: SizeLimit::Decompressed,
+                };
+
+                Ok::<_, FetchPackagesError>((url, futures::io::BufReader::new(reader)))
+            }
+            .await;
+
+            match res {
+                Ok(res) => return Ok(res),
+                Err(e) if e.is_not_found() => {
+                    not_found = Some(index.error(self.options.mirror_url(&path), e))
+                }
+                Err(e) => return Err(index.error(self.options.mirror_url(&path), e)),
+            }
+        }
+
+        Err(not_found.expect("candidate list is not empty"))
+    }
+
+    /// Start downloading `path` from the first mirror that can serve it, returning its URL
+    async fn open_from_mirrors(
+        &self,
+        path: &str,
+    ) -> Result<(String, Box<dyn futures::AsyncBufRead + Unpin + Send>), FetchPackagesError> {
+        let mut failures = MirrorFailures::default();
+
+        for url in self.options.mirror_urls(path) {
+            match self.open(&url).await {
+                Ok(body) => return Ok((url, body)),
+                Err(e) => failures.push(&self.options, url, e)?,
+            }
+        }
+
+        Err(failures.finish())
+    }
+
+    async fn open(
+        &self,
+        url: &str,
+    ) -> Result<Box<dyn futures::AsyncBufRead + Unpin + Send>, FetchPackagesError> {
+        if let Some(path) = file_url_path(url) {
+            let f = tokio::fs::File::open(&path)
+                .await
+                .map_err(|e| FetchPackagesError::from_local_io(&path, e))?;
+            self.options
+                .check_size(SizeLimit::Download, f.metadata().await?.len())?;
+
+            return Ok(Box::new(futures::io::BufReader::new(
+                tokio_util::compat::TokioAsyncReadCompatExt::compat(f),
+            )));
+        }
+
+        let resp = self
+            .options
+            .until_cancelled(self.request(url).send())
+            .await??
+            .error_for_status()?;
+        self.options
+            .check_size(SizeLimit::Download, resp.content_length().unwrap_or(0))?;
+        let observer = self.options.observer.clone();
+        let body = resp
+            .bytes_stream()
+            .inspect_ok(move |chunk| {
+                if let Some(observer) = &observer {
+                    observer.on_bytes(chunk.len() as u64);
+                }
+            })
+            .map_err(std::io::Error::other)
+            .into_async_read();
+
+        Ok(Box::new(futures::io::BufReader::new(Limited {
+            inner: body,
+            read: 0,
+            limit: self.options.max_download,
+            which: SizeLimit::Download,
+        })))
+    }
+
+    /// `Content-Length` of `path` on the first mirror that can serve it
+    async fn head_from_mirrors(&self, path: &str) -> Result<Option<u64>, FetchPackagesError> {
+        let mut failures = MirrorFailures::default();
+
+        for url in self.options.mirror_urls(path) {
+            match self.head(&url).await {
+                Err(e) => failures.push(&self.options, url, e)?,
+                res => return res,
+            }
+        }
+
+        Err(failures.finish())
+    }
+
+    async fn head(&self, url: &str) -> Result<Option<u64>, FetchPackagesError> {
+        if let Some(path) = file_url_path(url) {
+            return tokio::fs::metadata(&path)
+                .await
+                .map(|m| Some(m.len()))
+                .map_err(|e| FetchPackagesError::from_local_io(&path, e));
+        }
+
+        self.options.observe_request(url);
+        let resp = self
+            .options
+            .until_cancelled(self.client.head(url).send())
+            .await??
+            .error_for_status()?;
+
+        Ok(content_length(resp.headers()))
+    }
+
+    /// Download `path` to `partial` from the first mirror that can serve it
+    ///
+    /// `previous` only applies to the mirror it was downloaded from.
+    async fn download_from_mirrors<F>(
+        &self,
+        path: &str,
+        partial: &Path,
+        previous: Option<&FileMeta>,
+        progress: &mut F,
+    ) -> Result<Downloaded, FetchPackagesError>
+    where
+        F: FnMut(ProgressEvent) + Send,
+    {
+        let mut failures = MirrorFailures::default();
+
+        for url in self.options.mirror_urls(path) {
+            let previous = previous.filter(|meta| meta.url == strip_credentials(&url));
+            match self
+                .download_with_retry(&url, partial, previous, progress)
+                .await
+            {
+                Err(e) => failures.push(&self.options, url, e)?,
+                res => return res,
+            }
+        }
+
+        Err(failures.finish())
+    }
+
+    /// Download `path` from the preferred mirror to `partial` in several parts at once, if
+    /// [`FetcherBuilder::segmented_download`] applies to a file of `size` bytes
+    ///
+    /// Returns `None` if it doesn't, the server ignores ranges or any part fails, leaving
+    /// the file to a single-stream download. So is an interrupted download, to be resumed.
+    async fn download_segmented(&self, path: &str, partial: &Path, size: u64) -> Option<Transfer> {
+        let url = self.options.mirror_url(path);
+        let ranges = self.options.segment_ranges(size)?;
+        if file_url_path(&url).is_some() || tokio::fs::try_exists(partial).await.unwrap_or(true) {
+            return None;
+        }
+
+        // A server that ignores ranges answers the first one with the whole file.
+        let first = self.request_range(&url, ranges[0]).await?;
+        let mut transfer = Transfer::from_response(first.url(), first.status(), first.headers());
+        transfer.bytes = size;
+        let created = async { tokio::fs::File::create(partial).await?.set_len(size).await };
+        let done = created.await.is_ok() && {
+            let first = self.write_range(first, partial, ranges[0]);
+            let rest = ranges[1..].iter().map(|&range| {
+                let url = &url;
+                async move {
+                    match self.request_range(url, range).await {
+                        Some(resp) => self.write_range(resp, partial, range).await.is_ok(),
+                        None => false,
+                    }
+                }
+            });
+            // Wait for every part, even after one failed.
+            let (first, rest) = futures::join!(first, futures::future::join_all(rest));
+
+            first.is_ok() && rest.into_iter().all(|done| done)
+        };
+
+        if !done {
+            discard_partial(partial);
+        }
+        done.then_some(transfer)
+    }
+
+    /// Request the inclusive byte range `range` of `url`, if the server answers with it
+    async fn request_range(
+        &self,
+        url: &str,
+        (start, end): (u64, u64),
+    ) -> Option<reqwest::Response> {
+        let resp = self
+            .request(url)
+            .header(RANGE, format!("bytes={start}-{end}"))
+            .send()
+            .await
+            .ok()?;
+
+        (resp.status() == StatusCode::PARTIAL_CONTENT
+            && is_content_range(resp.headers(), (start, end)))
+        .then_some(resp)
+    }
+
+    /// Write the body of `resp`, the range `range` of a file, to its place in `partial`
+    async fn write_range(
+        &self,
+        resp: reqwest::Response,
+        partial: &Path,
+        (start, end): (u64, u64),
+    ) -> Result<(), FetchPackagesError> {
+        let mut f = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(partial)
+            .await?;
+        tokio::io::AsyncSeekExt::seek(&mut f, std::io::SeekFrom::Start(start)).await?;
+
+        let mut written = 0;
+        let mut chunks = resp.bytes_stream();
+        while let Some(chunk) = self.options.until_cancelled(chunks.next()).await? {
+            let chunk = chunk?;
+            written += chunk.len() as u64;
+            self.options.check_size(SizeLimit::Download, written)?;
+            tokio::io::AsyncWriteExt::write_all(&mut f, &chunk).await?;
+            self.options.observer().on_bytes(chunk.len() as u64);
+            if let Some(limit) = &self.options.rate_limit {
+                tokio::time::sleep(limit.delay(chunk.len())).await;
+            }
+        }
+        tokio::io::AsyncWriteExt::flush(&mut f).await?;
+
+        check_length(Some(end - start + 1), written)
+    }
+
+    /// Download a small file such as a release file into memory
+    async fn get_with_retry(&self, url: &str) -> Result<(Transfer, Vec<u8>), FetchPackagesError> {
+        if let Some(path) = file_url_path(url) {
+            let local_err = |e| FetchPackagesError::from_local_io(&path, e);
+            let size = tokio::fs::metadata(&path).await.map_err(local_err)?.len();
+            self.options.check_size(SizeLimit::Download, size)?;
+            let bytes = tokio::fs::read(&path).await.map_err(local_err)?;
+            return Ok((Transfer::local(url, bytes.len() as u64), bytes));
+        }
+
+        let mut attempt = 1;
+
+        loop {
+            let res = async {
+                let resp = self
+                    .options
+                    .until_cancelled(self.request(url).send())
+                    .await??
+                    .error_for_status()?;
+                let mut transfer =
+                    Transfer::from_response(resp.url(), resp.status(), resp.headers());
+                let expected = resp.content_length();
+                self.options
+                    .check_size(SizeLimit::Download, expected.unwrap_or(0))?;
+                let mut bytes = vec![];
+                let mut chunks = resp.bytes_stream();
+                while let Some(chunk) = self.options.until_cancelled(chunks.next()).await? {
+                    let chunk = chunk?;
+                    self.options
+                        .check_size(SizeLimit::Download, (bytes.len() + chunk.len()) as u64)?;
+                    bytes.extend_from_slice(&chunk);
+                    self.options.observer().on_bytes(chunk.len() as u64);
+                }
+                check_length(expected, bytes.len() as u64)?;
+                transfer.bytes = bytes.len() as u64;
+
+                Ok::<_, FetchPackagesError>((transfer, bytes))
+            }
+            .await;
+
+            match res {
+                Err(e) if e.is_transient() && attempt <= self.options.retry.retries => {
+                    self.options.observer().on_retry(attempt, &e);
+                    let delay = tokio::time::sleep(self.options.retry.delay(attempt));
+                    self.options.until_cancelled(delay).await?;
+                    attempt += 1;
+                }
+                res => return self.options.retry.finish(res, attempt),
+            }
+        }
+    }
+
+    async fn download_with_retry<F>(
+        &self,
+        url: &str,
+        partial: &Path,
+        previous: Option<&FileMeta>,
+        progress: &mut F,
+    ) -> Result<Downloaded, FetchPackagesError>
+    where
+        F: FnMut(ProgressEvent) + Send,
+    {
+        let mut attempt = 1;
+
+        let res = loop {
+            match self.download(url, partial, previous, progress).await {
+                Err(e) if e.is_transient() && attempt <= self.options.retry.retries => {
+                    self.options.observer().on_retry(attempt, &e);
+                    let delay = tokio::time::sleep(self.options.retry.delay(attempt));
+                    if let Err(e) = self.options.until_cancelled(delay).await {
+                        break Err(e);
+                    }
+                    attempt += 1;
+                }
+                res => break self.options.retry.finish(res, attempt),
+            }
+        };
+        if matches!(
+            res,
+            Err(FetchPackagesError::Cancelled | FetchPackagesError::PayloadTooLarge { .. })
+        ) {
+            // There is no resuming a cancelled or oversized download, so don't leave it behind.
+            discard_partial(partial);
+        }
+
+        res
+    }
+
+    /// Download `url` to `partial`, resuming a previous attempt at the same URL if possible
+    ///
+    /// If `previous` describes an earlier complete download of `url`, the request is made
+    /// conditional on its validators.
+    async fn download<F>(
+        &self,
+        url: &str,
+        partial: &Path,
+        previous: Option<&FileMeta>,
+        progress: &mut F,
+    ) -> Result<Downloaded, FetchPackagesError>
+    where
+        F: FnMut(ProgressEvent) + Send,
+    {
+        if let Some(path) = file_url_path(url) {
+            return copy_local(&self.options, url, &path, partial, progress).await;
+        }
+
+        let meta_path = FileMeta::path(partial);
+        let meta = tokio::fs::read_to_string(&meta_path)
+            .await
+            .ok()
+            .and_then(|s| FileMeta::parse(&s));
+        let mut offset = match tokio::fs::metadata(partial).await {
+            Ok(m) => FileMeta::resume_offset(meta.as_ref(), url, m.len()),
+            Err(_) => 0,
+        };
+
+        let resp = loop {
+            let mut req = self.request(url);
+            if let Some(validator) = meta.as_ref().and_then(|m| m.range_validator()) {
+                if offset > 0 {
+                    req = req
+                        .header(RANGE, format!("bytes={offset}-"))
+                        .header(IF_RANGE, validator);
+                }
+            }
+            if let Some(previous) = previous.filter(|_| offset == 0) {
+                if let Some(etag) = &previous.etag {
+                    req = req.header(IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &previous.last_modified {
+                    req = req.header(IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+
+            let resp = self.options.until_cancelled(req.send()).await??;
+            if offset > 0 && resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+                offset = 0;
+                continue;
+            }
+
+            break resp.error_for_status()?;
+        };
+
+        let mut transfer = Transfer::from_response(resp.url(), resp.status(), resp.headers());
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Downloaded::NotModified(transfer.revalidated(previous)));
+        }
+
+        // Anything but 206 is the full body, so start over.
+        let resume = offset > 0 && resp.status() == StatusCode::PARTIAL_CONTENT;
+        let start = if resume { offset } else { 0 };
+        let mut downloaded = start;
+        let total = resp.content_length().map(|len| len + downloaded);
+        self.options
+            .check_size(SizeLimit::Download, total.unwrap_or(0))?;
+
+        tokio::fs::write(
+            &meta_path,
+            FileMeta::from_response(url, resp.headers()).to_string(),
+        )
+        .await?;
+
+        let mut f = if resume {
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(partial)
+                .await?
+        } else {
+            tokio::fs::File::create(partial).await?
+        };
+
+        progress(ProgressEvent::Progress { downloaded, total });
+
+        let mut chunks = resp.bytes_stream();
+        let res = async {
+            while let Some(chunk) = self.options.until_cancelled(chunks.next()).await? {
+                let chunk = chunk?;
+                self.options
+                    .check_size(SizeLimit::Download, downloaded + chunk.len() as u64)?;
+                tokio::io::AsyncWriteExt::write_all(&mut f, &chunk).await?;
+                downloaded += chunk.len() as u64;
+                self.options.observer().on_bytes(chunk.len() as u64);
+                progress(ProgressEvent::Progress { downloaded, total });
+                if let Some(limit) = &self.options.rate_limit {
+                    tokio::time::sleep(limit.delay(chunk.len())).await;
+                }
+            }
+
+            Ok::<_, FetchPackagesError>(())
+        }
+        .await;
+
+        // Make sure whatever arrived is on disk so the next attempt can resume from it.
+        tokio::io::AsyncWriteExt::flush(&mut f).await?;
+
+        res?;
+        // Anything short of the announced size must not be decompressed.
+        check_length(total, downloaded)?;
+        transfer.bytes = downloaded - start;
+
+        Ok(Downloaded::Complete(transfer))
+    }
+
+    /// A GET request for `url`
+    fn request(&self, url: &str) -> reqwest::RequestBuilder {
+        self.options.observe_request(url);
+        let mut req = self.client.get(url);
+        if self.options.no_cache {
+            req = req.header(CACHE_CONTROL, "no-cache");
+        }
+        req
+    }
+
+    /// Decompress the finished download, writing the decompressed copy to `tmp` if it is kept
+    ///
+    /// Nothing is written under the final name until [`FetchOptions::finish_index`].
+    async fn decompress(
+        &self,
+        url: &str,
+        compression: Compression,
+        partial: &Path,
+        tmp: &Path,
+    ) -> Result<Vec<u8>, FetchPackagesError> {
+        let f = tokio::fs::File::open(partial).await?;
+        let body =
+            futures::io::BufReader::new(tokio_util::compat::TokioAsyncReadCompatExt::compat(f));
+
+        let limit = self.options.max_decompressed;
+        if !self.options.keep_decompressed(compression) {
+            return decode(url, compression, body, limit, &mut futures::io::sink()).await;
+        }
+
+        let mut reader = decoder(compression, body).await?;
+        let mut written = 0;
+        let f = tokio::fs::File::create(tmp).await?;
+        let mut f = tokio_util::compat::TokioAsyncWriteCompatExt::compat_write(f);
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            self.options.check_cancelled()?;
+            let n = futures::AsyncReadExt::read(&mut reader, &mut buf)
+                .await
+                .map_err(|e| FetchPackagesError::from_decoder_error(url, e))?;
+            if n == 0 {
+                break;
+            }
+            written += n as u64;
+            self.options.check_size(SizeLimit::Decompressed, written)?;
+            futures::AsyncWriteExt::write_all(&mut f, &buf[..n]).await?;
+        }
+        futures::AsyncWriteExt::flush(&mut f).await?;
+        // Make sure the data is on disk before the rename makes it visible.
+        f.get_ref().sync_all().await?;
+
+        // Read back at its final size, so the index is held in memory only once.
+        Ok(tokio::fs::read(tmp).await?)
+    }
+}
+
+/// Copy `path` from a local mirror to `partial`, as if it had been downloaded from `url`
+async fn copy_local(
+    options: &FetchOptions,
+    url: &str,
+    path: &Path,
+    partial: &Path,
+    progress: &mut (impl FnMut(ProgressEvent) + Send),
+) -> Result<Downloaded, FetchPackagesError> {
+    let local_err = |e| FetchPackagesError::from_local_io(path, e);
+    let total = tokio::fs::metadata(path).await.map_err(local_err)?.len();
+    options.check_size(SizeLimit::Download, total)?;
+
+    tokio::fs::write(FileMeta::path(partial), FileMeta::local(url).to_string()).await?;
+    progress(ProgressEvent::Progress {
+        downloaded: 0,
+        total: Some(total),
+    });
+    tokio::fs::copy(path, partial).await.map_err(local_err)?;
+    progress(ProgressEvent::Progress {
+        downloaded: total,
+        total: Some(total),
+    });
+
+    Ok(Downloaded::Complete(Transfer::local(url, total)))
+}
+
+/// Names of the subdirectories of `path`, sorted
+async fn list_local_dirs(path: &Path) -> Result<Vec<String>, FetchPackagesError> {
+    let local_err = |e| FetchPackagesError::from_local_io(path, e);
+    let mut entries = tokio::fs::read_dir(path).await.map_err(local_err)?;
+    let mut names = vec![];
+    while let Some(entry) = entries.next_entry().await.map_err(local_err)? {
+        if entry.file_type().await.map_err(local_err)?.is_dir() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    names.sort();
+
+    Ok(names)
+}
+
+/// Check that `body` downloaded from `url` is in `compression` and decompress it, failing
+/// as soon as it exceeds `limit` bytes
+///
+/// What it decompresses to is also written to `sink` as it is decompressed.
+async fn decode<R>(
+    url: &str,
+    compression: Compression,
+    body: R,
+    limit: u64,
+    sink: &mut (dyn futures::AsyncWrite + Unpin + Send),
+) -> Result<Vec<u8>, FetchPackagesError>
+where
+    R: futures::AsyncBufRead + Unpin + Send,
+{
+    let mut res = vec![];
+    let mut reader = decoder(compression, body).await?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = futures::AsyncReadExt::read(&mut reader, &mut buf)
+            .await
+            .map_err(|e| read_error(e, |e| FetchPackagesError::from_decoder_error(url, e)))?;
+        if n == 0 {
+            break;
+        }
+        if (res.len() + n) as u64 > limit {
+            return Err(FetchPackagesError::PayloadTooLarge {
+                which: SizeLimit::Decompressed,
+                limit,
+            });
+        }
+        futures::AsyncWriteExt::write_all(sink, &buf[..n])
+            .await
+            .map_err(FetchPackagesError::WriterError)?;
+        res.extend_from_slice(&buf[..n]);
+    }
+
+    Ok(res)
+}
+
+/// The Packages entries in `reader`, one per paragraph
+///
+/// A paragraph that can't be parsed is yielded as an error, one that can't be read ends the
+/// stream.
+fn package_stream<R>(reader: R) -> impl Stream<Item = Result<Package, FetchPackagesError>> + Send
+where
+    R: futures::AsyncBufRead + Send,
+{
+    futures::stream::unfold(Some(Box::pin(reader)), |reader| async move {
+        let mut reader = reader?;
+        let mut paragraph = String::new();
+
+        loop {
+            let start = paragraph.len();
+            match futures::AsyncBufReadExt::read_line(&mut reader, &mut paragraph).await {
+                Ok(0) if paragraph.trim().is_empty() => return None,
+                Ok(0) => break,
+                // Blank lines separate paragraphs, extra ones are skipped.
+                Ok(_) if paragraph[start..].trim().is_empty() => {
+                    if !paragraph.trim().is_empty() {
+                        break;
+                    }
+                    paragraph.clear();
+                }
+                Ok(_) => {}
+                Err(e) => return Some((Err(read_error(e, Into::into)), None)),
+            }
+        }
+
+        let package = paragraph
+            .parse::<Package>()
+            .map_err(FetchPackagesError::DebControl);
+
+        Some((package, Some(reader)))
+    })
+}
+
+/// A reader that fails once more than `limit` bytes were read from `inner`
+///
+/// The error is turned back into [`FetchPackagesError::PayloadTooLarge`] by [`read_error`].
+struct Limited<R> {
+    inner: R,
+    read: u64,
+    limit: u64,
+    which: SizeLimit,
+}
+
+/// The error of a [`Limited`] reader
+#[derive(Debug, thiserror::Error)]
+#[error("payload is larger than the {which} size limit of {limit} bytes")]
+struct LimitExceeded {
+    which: SizeLimit,
+    limit: u64,
+}
+
+impl<R: futures::AsyncRead + Unpin> futures::AsyncRead for Limited<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = futures::ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.read += n as u64;
+        if self.read > self.limit {
+            return Poll::Ready(Err(io::Error::other(LimitExceeded {
+                which: self.which,
+                limit: self.limit,
+            })));
+        }
+
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// [`FetchPackagesError::PayloadTooLarge`] if `e` is the error of a [`Limited`] reader,
+/// otherwise `e` mapped by `other`
+fn read_error(
+    e: io::Error,
+    other: impl FnOnce(io::Error) -> FetchPackagesError,
+) -> FetchPackagesError {
+    match e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<LimitExceeded>())
+    {
+        Some(&LimitExceeded { which, limit }) => {
+            FetchPackagesError::PayloadTooLarge { which, limit }
+        }
+        None => other(e),
+    }
+}
+
+/// An input that remembers whether it was read to the end
+struct TrackEnd<R> {
+    inner: R,
+    ended: bool,
+}
+
+impl<R: futures::AsyncRead + Unpin> futures::AsyncRead for TrackEnd<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = futures::ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if n == 0 && !buf.is_empty() {
+            self.ended = true;
+        }
+
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<R: futures::AsyncBufRead + Unpin> futures::AsyncBufRead for TrackEnd<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        let buf = futures::ready!(Pin::new(&mut this.inner).poll_fill_buf(cx))?;
+        if buf.is_empty() {
+            this.ended = true;
+        }
+
+        Poll::Ready(Ok(buf))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.inner).consume(amt)
+    }
+}
+
+/// A zstd decoder that fails on a truncated frame
+///
+/// async-compression takes the end of the input for the end of the frame. A complete frame
+/// is finished before its input is read to the end, so output that ends after it was is
+/// missing the rest of the frame.
+struct ZstdFrame<R>(async_compression::futures::bufread::ZstdDecoder<TrackEnd<R>>);
+
+impl<R: futures::AsyncBufRead + Unpin> futures::AsyncRead for ZstdFrame<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = futures::ready!(Pin::new(&mut self.0).poll_read(cx, buf))?;
+        if n == 0 && !buf.is_empty() && self.0.get_ref().ended {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "incomplete zstd frame",
+            )));
+        }
+
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Check that `body` is in `compression` and return a reader of its decompressed data
+///
+/// A chunk of the body may be shorter than the magic, so this reads until it has the whole
+/// magic or the body ends.
+async fn decoder<'a, R>(
+    compression: Compression,
+    mut body: R,
+) -> Result<Box<dyn futures::AsyncRead + Unpin + Send + 'a>, FetchPackagesError>
+where
+    R: futures::AsyncBufRead + Unpin + Send + 'a,
+{
+    let mut head = Vec::with_capacity(Compression::MAX_MAGIC_LEN);
+    futures::AsyncReadExt::read_to_end(
+        &mut futures::AsyncReadExt::take(&mut body, Compression::MAX_MAGIC_LEN as u64),
+        &mut head,
+    )
+    .await?;
+    compression.check_magic(&head)?;
+    let body = futures::AsyncReadExt::chain(futures::io::Cursor::new(head), body);
+
+    Ok(match compression {
+        Compression::None | Compression::Auto => Box::new(body),
+        Compression::Xz => Box::new(async_compression::futures::bufread::XzDecoder::new(body)),
+        Compression::Gzip => Box::new(async_compression::futures::bufread::GzipDecoder::new(body)),
+        Compression::Zstd => Box::new(ZstdFrame(
+            async_compression::futures::bufread::ZstdDecoder::new(TrackEnd {
+                inner: body,
+                ended: false,
+            }),
+        )),
+    })
+}
+
+/// Digest of what identifies the entries of `packages`, to tell whether an index changed
+fn packages_digest(packages: &Packages) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for pkg in packages {
+        for field in [
+            &pkg.package,
+            &pkg.architecture,
+            &pkg.version,
+            &pkg.filename,
+            &pkg.sha256,
+        ] {
+            hasher.update(field.as_bytes());
+            hasher.update([0]);
+        }
+    }
+
+    hasher.finalize().to_vec()
+}
+
+/// Each of `pkgs` that is stored in `dir` under a name no earlier one took, and the others
+/// that are different files stored under a taken name with their error
+///
+/// Entries with the same `Filename` share the file, so those after the first are left out
+/// without an error. Downloading a file twice at once would mix up the two downloads.
+fn unique_destinations<'a>(
+    pkgs: impl IntoIterator<Item = &'a Package>,
+    dir: &Path,
+) -> (Vec<&'a Package>, Vec<(&'a Package, FetchPackagesError)>) {
+    let mut taken: HashMap<PathBuf, &str> = HashMap::new();
+    let mut unique = vec![];
+    let mut duplicates = vec![];
+    for pkg in pkgs {
+        let path = package_path(dir, pkg);
+        match taken.get(&path) {
+            None => {
+                taken.insert(path, &pkg.filename);
+                unique.push(pkg);
+            }
+            Some(&other) if other == pkg.filename => {}
+            Some(&other) => {
+                let e = FetchPackagesError::DuplicateDestination {
+                    path,
+                    filename: pkg.filename.clone(),
+                    other: other.to_string(),
+                };
+                duplicates.push((pkg, e));
+            }
+        }
+    }
+
+    (unique, duplicates)
+}
+
+/// Metadata of the last successful download, if the file it describes still exists
+async fn previous_download(path: &Path) -> Option<FileMeta> {
+    if !tokio::fs::try_exists(path).await.unwrap_or(false) {
+        return None;
+    }
+
+    FileMeta::parse(&tokio::fs::read_to_string(FileMeta::path(path)).await.ok()?)
+}
+
+/// Record that the server confirmed the download at `path` to be current
+async fn refresh_download(path: &Path) {
+    if let Some(mut meta) = previous_download(path).await {
+        meta.fetched = Some(SystemTime::now());
+        let _ = tokio::fs::write(FileMeta::path(path), meta.to_string()).await;
+    }
+}
+
+/// Check that the file at `path` is the file listed as `name` in the release file, without
+/// reading all of it into memory
+async fn check_file_sha256(
+    name: &str,
+    expected: &ReleaseFile,
+    path: &Path,
+) -> Result<(), FetchPackagesError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut chunk = vec![0; HASH_CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        let n = tokio::io::AsyncReadExt::read(&mut file, &mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&chunk[..n]);
+        size += n as u64;
+    }
+
+    check_digest(name, expected, size, hasher)
+}
+
+/// Parse the index left by an earlier download, checking it against `expected` if given
+async fn read_local<T: IndexFile>(
+    path: &Path,
+    expected: Option<&ReleaseFile>,
+) -> Result<(T, u64), FetchPackagesError> {
+    let bytes = tokio::fs::read(path).await?;
+    if let Some(expected) = expected {
+        check_sha256(&path.to_string_lossy(), expected, &bytes)?;
+    }
+
+    Ok((T::parse(&bytes)?, bytes.len() as u64))
+}
+
+/// Download `dists/stable/InRelease` from each of `mirrors` concurrently, fastest first
+///
+/// A mirror that doesn't answer within 10 seconds or fails the request is listed last with
+/// its error.
+pub async fn benchmark_mirrors(mirrors: &[&str]) -> Vec<MirrorBenchmark> {
+    let mut res =
+        futures::future::join_all(mirrors.iter().map(|&mirror| benchmark_mirror(mirror))).await;
+
+    // `None` sorts before any throughput, so failed mirrors end up last.
+    res.sort_by(|a, b| {
+        b.bytes_per_sec
+            .partial_cmp(&a.bytes_per_sec)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    res
+}
+
+/// Check how recently each of `mirrors` synced `branch`, freshest first
+///
+/// The `InRelease` file of `branch` is fetched from all mirrors concurrently, giving each
+/// 10 seconds, and its `Date` compared to the newest one among them.
+/// Mirrors that couldn't be reached or served no valid release file come last.
+pub async fn compare_mirrors(mirrors: &[&str], branch: &str) -> Vec<MirrorStatus> {
+    let mut res =
+        futures::future::join_all(mirrors.iter().map(|&mirror| mirror_status(mirror, branch)))
+            .await;
+
+    let newest = res.iter().filter_map(|status| status.date).max();
+    for status in &mut res {
+        status.lag = status
+            .date
+            .zip(newest)
+            .map(|(date, newest)| newest.duration_since(date).unwrap_or_default());
+    }
+    // `None` sorts before any date, so failed mirrors end up last.
+    res.sort_by_key(|status| std::cmp::Reverse(status.date));
+
+    res
+}
+
+async fn mirror_status(mirror: &str, branch: &str) -> MirrorStatus {
+    let url = format!(
+        "{}/dists/{}/InRelease",
+        mirror.trim_end_matches('/'),
+        encode_path_segment(branch)
+    );
+
+    let fetch = async {
+        let client = reqwest::Client::builder().user_agent(USER_AGENT).build()?;
+        let resp = client.get(&url).send().await?.error_for_status()?;
+        let release = Release::parse(&resp.bytes().await?)?;
+
+        Ok::<_, FetchPackagesError>(release.date)
+    };
+
+    let res = match tokio::time::timeout(MIRROR_STATUS_TIMEOUT, fetch).await {
+        Ok(res) => res,
+        Err(_) => Err(FetchPackagesError::Timeout { url, source: None }),
+    };
+
+    match res {
+        Ok(date) => MirrorStatus {
+            url: mirror.to_string(),
+            date: Some(date),
+            lag: None,
+            error: None,
+        },
+        Err(e) => MirrorStatus {
+            url: mirror.to_string(),
+            date: None,
+            lag: None,
+            error: Some(e),
+        },
+    }
+}
+
+async fn benchmark_mirror(mirror: &str) -> MirrorBenchmark {
+    let url = format!("{mirror}/{BENCHMARK_PATH}");
+
+    let measure = async {
+        // Mirrors are on different hosts, so sharing a client wouldn't reuse connections.
+        let client = reqwest::Client::builder().user_agent(USER_AGENT).build()?;
+        let started = Instant::now();
+        let resp = client.get(&url).send().await?.error_for_status()?;
+        let latency = started.elapsed();
+
+        let mut len = 0;
+        let mut chunks = resp.bytes_stream();
+        while let Some(chunk) = chunks.next().await {
+            len += chunk?.len();
+        }
+
+        Ok::<_, FetchPackagesError>((latency, len as f64 / started.elapsed().as_secs_f64()))
+    };
+
+    let res = match tokio::time::timeout(BENCHMARK_TIMEOUT, measure).await {
+        Ok(res) => res,
+        Err(_) => Err(FetchPackagesError::Timeout {
+            url: url.clone(),
+            source: None,
+        }),
+    };
+
+    match res {
+        Ok((latency, bytes_per_sec)) => MirrorBenchmark {
+            url,
+            latency: Some(latency),
+            bytes_per_sec: Some(bytes_per_sec),
+            error: None,
+        },
+        Err(e) => MirrorBenchmark {
+            url,
+            latency: None,
+            bytes_per_sec: None,
+            error: Some(e),
+        },
+    }
+}
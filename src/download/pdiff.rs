@@ -0,0 +1,205 @@
+use deb822_lossless::{Deb822, FromDeb822, FromDeb822Paragraph};
+use sha2::{Digest, Sha256};
+
+use crate::release::ReleaseFile;
+
+/// The `Index` of a `Packages.diff/` directory, listing the patches from earlier versions
+/// of the index to the current one
+#[derive(Debug, FromDeb822)]
+pub(super) struct DiffIndex {
+    #[deb822(field = "SHA256-Current", deserialize_with = parse_current)]
+    current: ReleaseFile,
+    /// Earlier versions of the index, oldest first, named after the patch that updates them
+    #[deb822(field = "SHA256-History", deserialize_with = parse_entries)]
+    history: Vec<(String, ReleaseFile)>,
+    /// The patches, uncompressed
+    #[deb822(field = "SHA256-Patches", deserialize_with = parse_entries)]
+    patches: Vec<(String, ReleaseFile)>,
+    /// The patches as downloaded, named with their extension
+    #[deb822(field = "SHA256-Download", deserialize_with = parse_entries)]
+    download: Option<Vec<(String, ReleaseFile)>>,
+    /// `merged` if each patch goes straight to the current version
+    #[deb822(field = "X-Patch-Precedence")]
+    precedence: Option<String>,
+}
+
+/// A patch to download from `Packages.diff/<name>.gz`
+pub(super) struct Patch<'a> {
+    pub(super) name: &'a str,
+    /// Size and SHA256 of the compressed patch, if the index lists them
+    pub(super) download: Option<&'a ReleaseFile>,
+    pub(super) uncompressed: &'a ReleaseFile,
+}
+
+impl DiffIndex {
+    pub(super) fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let text = std::str::from_utf8(bytes).map_err(|e| e.to_string())?;
+        let index: Deb822 = text.parse().map_err(|e| format!("{e:?}"))?;
+        let para = index
+            .paragraphs()
+            .next()
+            .ok_or_else(|| "diff index is empty".to_string())?;
+
+        FromDeb822Paragraph::from_paragraph(&para)
+    }
+
+    /// Size and SHA256 of the current version of the index
+    pub(super) fn current(&self) -> &ReleaseFile {
+        &self.current
+    }
+
+    /// The patches that update `base` to the current version, in order
+    ///
+    /// Returns `None` if that version of the index isn't in the history, e.g. because it is
+    /// too old, or a patch is missing from the index.
+    pub(super) fn patches_for(&self, base: &[u8]) -> Option<Vec<Patch<'_>>> {
+        let sha256 = format!("{:x}", Sha256::digest(base));
+        let is_base = |file: &ReleaseFile| file.sha256 == sha256 && file.size == base.len() as u64;
+        if is_base(&self.current) {
+            return Some(vec![]);
+        }
+
+        let start = self.history.iter().position(|(_, file)| is_base(file))?;
+        let names = match self.precedence.as_deref() {
+            Some("merged") => &self.history[start..=start],
+            _ => &self.history[start..],
+        };
+
+        names.iter().map(|(name, _)| self.patch(name)).collect()
+    }
+
+    fn patch<'a>(&'a self, name: &'a str) -> Option<Patch<'a>> {
+        let uncompressed = find(&self.patches, name)?;
+        let download = self
+            .download
+            .as_deref()
+            .and_then(|download| find(download, &format!("{name}.gz")));
+
+        Some(Patch {
+            name,
+            download,
+            uncompressed,
+        })
+    }
+}
+
+fn find<'a>(entries: &'a [(String, ReleaseFile)], name: &str) -> Option<&'a ReleaseFile> {
+    entries
+        .iter()
+        .find(|(entry, _)| entry == name)
+        .map(|(_, file)| file)
+}
+
+/// Parse the `<hash> <size>` of `SHA256-Current`
+fn parse_current(s: &str) -> Result<ReleaseFile, String> {
+    let mut parts = s.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(sha256), Some(size), None) => Ok(ReleaseFile {
+            sha256: sha256.to_string(),
+            size: size
+                .parse()
+                .map_err(|e| format!("invalid size {size}: {e}"))?,
+        }),
+        _ => Err(format!("malformed current version: {}", s.trim())),
+    }
+}
+
+/// Parse the `<hash> <size> <name>` lines of a table, keeping their order
+fn parse_entries(s: &str) -> Result<Vec<(String, ReleaseFile)>, String> {
+    s.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(sha256), Some(size), Some(name), None) => Ok((
+                    name.to_string(),
+                    ReleaseFile {
+                        sha256: sha256.to_string(),
+                        size: size
+                            .parse()
+                            .map_err(|e| format!("invalid size {size}: {e}"))?,
+                    },
+                )),
+                _ => Err(format!("malformed patch entry: {}", line.trim())),
+            }
+        })
+        .collect()
+}
+
+/// Apply `patch`, an ed script as written by `diff --ed`, to `base`
+///
+/// Besides the `a`, `c` and `d` commands, this understands the `s/.//` that follows a line
+/// consisting of a single `.`, which the script has to write as `..`.
+pub(super) fn apply_ed(base: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut lines: Vec<&[u8]> = base.split_inclusive(|&b| b == b'\n').collect();
+    let mut script = patch.split_inclusive(|&b| b == b'\n');
+    // The line a command without an address applies to, as a count of the lines before it
+    let mut current: usize = 0;
+
+    while let Some(command) = script.next() {
+        let command = std::str::from_utf8(command)
+            .map_err(|_| "command is not UTF-8".to_string())?
+            .trim_end();
+        if command.is_empty() {
+            continue;
+        }
+        if command == "s/.//" {
+            let line = current
+                .checked_sub(1)
+                .and_then(|i| lines.get_mut(i))
+                .filter(|line| line.starts_with(b"."))
+                .ok_or_else(|| "s/.// without a line to apply to".to_string())?;
+            *line = &line[1..];
+            continue;
+        }
+        if command == "w" || command == "q" {
+            break;
+        }
+        if !command.is_ascii() {
+            return Err(format!("unsupported command {command}"));
+        }
+
+        let (address, op) = command.split_at(command.len() - 1);
+        let (start, end) = match address.split_once(',') {
+            _ if address.is_empty() => (current, current),
+            Some((start, end)) => (parse_line(start)?, parse_line(end)?),
+            None => {
+                let line = parse_line(address)?;
+                (line, line)
+            }
+        };
+        if start > end || end > lines.len() {
+            return Err(format!("command {command} is out of range"));
+        }
+
+        let at = match op {
+            "a" => end,
+            "c" | "d" if start > 0 => {
+                lines.drain(start - 1..end);
+                start - 1
+            }
+            _ => return Err(format!("unsupported command {command}")),
+        };
+        current = at;
+        if op == "d" {
+            continue;
+        }
+
+        let mut added = vec![];
+        loop {
+            match script.next() {
+                Some(b".\n" | b".") => break,
+                Some(line) => added.push(line),
+                None => return Err(format!("text of command {command} is not terminated")),
+            }
+        }
+        current += added.len();
+        lines.splice(at..at, added);
+    }
+
+    Ok(lines.concat())
+}
+
+fn parse_line(s: &str) -> Result<usize, String> {
+    s.parse().map_err(|_| format!("invalid line number {s}"))
+}
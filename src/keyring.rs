@@ -0,0 +1,267 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use pgp::{
+    composed::{Deserializable, SignedPublicKey},
+    packet::{Signature, SignatureType},
+    types::{Duration, KeyDetails, Timestamp, VerifyingKey},
+};
+use thiserror::Error;
+
+use crate::release::SignatureError;
+
+/// Why a keyring couldn't be loaded
+#[derive(Debug, Error)]
+pub enum KeyringError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Malformed keyring: {0}")]
+    Malformed(String),
+    #[error("Keyring has no certificates")]
+    Empty,
+}
+
+/// An OpenPGP certificate, a primary key along with its subkeys, to verify release files with
+///
+/// See [`Release::from_inrelease_verified`](crate::release::Release::from_inrelease_verified).
+#[derive(Debug, Clone)]
+pub struct Cert(SignedPublicKey);
+
+impl Cert {
+    /// Parse the certificates of a keyring, either binary like the `*.gpg` files of APT or
+    /// ASCII-armored like its `*.asc` files
+    ///
+    /// Fails if any certificate is malformed or its subkeys aren't bound to it.
+    pub fn from_bytes(data: &[u8]) -> Result<Vec<Cert>, KeyringError> {
+        let malformed = |e: pgp::errors::Error| KeyringError::Malformed(e.to_string());
+        let (keys, _) = SignedPublicKey::from_reader_many(data).map_err(malformed)?;
+        let certs = keys
+            .map(|key| {
+                let key = key.map_err(malformed)?;
+                key.verify_bindings().map_err(malformed)?;
+                Ok(Cert(key))
+            })
+            .collect::<Result<Vec<_>, KeyringError>>()?;
+        if certs.is_empty() {
+            return Err(KeyringError::Empty);
+        }
+
+        Ok(certs)
+    }
+
+    /// Fingerprint of the primary key in upper case hex, as `gpg` shows it
+    pub fn fingerprint(&self) -> String {
+        format!("{:X}", self.0.primary_key.fingerprint())
+    }
+
+    /// Check `signature` over `data` with the key of this certificate that made it, `None` if
+    /// no key of this certificate did
+    ///
+    /// Revoked keys and subkeys that aren't for signing are never used.
+    fn check(
+        &self,
+        signature: &Signature,
+        data: &[u8],
+        now: SystemTime,
+    ) -> Option<Result<(), SignatureError>> {
+        let key = &self.0;
+        if issued_by(signature, &key.primary_key) && key.details.revocation_signatures.is_empty() {
+            let self_signature = newest(
+                key.details
+                    .users
+                    .iter()
+                    .flat_map(|user| &user.signatures)
+                    .chain(&key.details.direct_signatures),
+            );
+            let validity = self_signature.and_then(Signature::key_expiration_time);
+            return Some(check_with(signature, &key.primary_key, validity, data, now));
+        }
+
+        key.public_subkeys.iter().find_map(|subkey| {
+            if !issued_by(signature, &subkey.key)
+                || subkey
+                    .signatures
+                    .iter()
+                    .any(|sig| sig.typ() == Some(SignatureType::SubkeyRevocation))
+            {
+                return None;
+            }
+            let binding = newest(
+                subkey
+                    .signatures
+                    .iter()
+                    .filter(|sig| sig.typ() == Some(SignatureType::SubkeyBinding)),
+            )?;
+            if !binding.key_flags().sign() {
+                return None;
+            }
+
+            Some(check_with(
+                signature,
+                &subkey.key,
+                binding.key_expiration_time(),
+                data,
+                now,
+            ))
+        })
+    }
+}
+
+/// Check the signatures of a release file over `data`, at least one of which has to be a
+/// valid signature by a key of `keyring`
+///
+/// If none is, the error is about the signature that came closest, an expired signature
+/// before a bad one before one by an unknown key.
+pub(crate) fn check_signatures(
+    signatures: &[Signature],
+    data: &[u8],
+    keyring: &[Cert],
+) -> Result<(), SignatureError> {
+    let now = SystemTime::now();
+    let mut closest: Option<SignatureError> = None;
+    for signature in signatures {
+        let res = keyring
+            .iter()
+            .find_map(|cert| cert.check(signature, data, now))
+            .unwrap_or_else(|| Err(SignatureError::UnknownKey(issuer(signature))));
+        match res {
+            Ok(()) => return Ok(()),
+            Err(e) if closest.as_ref().is_none_or(|c| rank(&e) < rank(c)) => closest = Some(e),
+            Err(_) => {}
+        }
+    }
+
+    Err(closest.unwrap_or(SignatureError::Unsigned))
+}
+
+/// How close a failed signature came to being valid, lower is closer
+fn rank(e: &SignatureError) -> u8 {
+    match e {
+        SignatureError::Expired { .. } => 0,
+        SignatureError::BadSignature(_) => 1,
+        _ => 2,
+    }
+}
+
+fn check_with(
+    signature: &Signature,
+    key: &impl VerifyingKey,
+    validity: Option<Duration>,
+    data: &[u8],
+    now: SystemTime,
+) -> Result<(), SignatureError> {
+    let id = format!("{:X}", key.fingerprint());
+    signature
+        .verify(key, data)
+        .map_err(|_| SignatureError::BadSignature(id.clone()))?;
+
+    let key_expiry = expiry(key.created_at(), validity);
+    let signature_expiry = signature
+        .created()
+        .and_then(|created| expiry(created, signature.signature_expiration_time()));
+    if let Some(expired) = [key_expiry, signature_expiry]
+        .into_iter()
+        .flatten()
+        .filter(|&at| at <= now)
+        .min()
+    {
+        return Err(SignatureError::Expired { key: id, expired });
+    }
+
+    Ok(())
+}
+
+/// When something created at `created` and valid for `validity` expires, a validity of zero
+/// meaning never like no validity at all
+fn expiry(created: Timestamp, validity: Option<Duration>) -> Option<SystemTime> {
+    validity
+        .map(std::time::Duration::from)
+        .filter(|validity| !validity.is_zero())
+        .map(|validity| SystemTime::from(created) + validity)
+}
+
+/// Whether `signature` names `key` as the key that made it
+fn issued_by(signature: &Signature, key: &impl KeyDetails) -> bool {
+    signature.issuer_fingerprint().contains(&&key.fingerprint())
+        || signature.issuer_key_id().contains(&&key.legacy_key_id())
+}
+
+/// The key `signature` names as the one that made it, as `gpg` shows it
+fn issuer(signature: &Signature) -> String {
+    match (
+        signature.issuer_fingerprint().first(),
+        signature.issuer_key_id().first(),
+    ) {
+        (Some(fingerprint), _) => format!("{fingerprint:X}"),
+        (None, Some(key_id)) => key_id.to_string().to_uppercase(),
+        (None, None) => "an unnamed key".to_string(),
+    }
+}
+
+fn newest<'a>(signatures: impl Iterator<Item = &'a Signature>) -> Option<&'a Signature> {
+    signatures.max_by_key(|sig| sig.created())
+}
+
+/// A keyring file of the APT trust store that couldn't be loaded
+#[derive(Debug, Error)]
+#[error("{}: {source}", path.display())]
+pub struct KeyringWarning {
+    pub path: PathBuf,
+    #[source]
+    pub source: KeyringError,
+}
+
+/// The certificates the APT trust store of a system holds, see [`system_keyring`]
+#[derive(Debug, Default)]
+pub struct SystemKeyring {
+    pub certs: Vec<Cert>,
+    /// Keyring files that couldn't be loaded, so their certificates are missing from
+    /// [`Self::certs`]
+    pub warnings: Vec<KeyringWarning>,
+}
+
+/// Load the certificates of the APT trust store of the system at `sysroot`
+///
+/// Reads the binary `*.gpg` and ASCII-armored `*.asc` keyrings in `etc/apt/trusted.gpg.d`, in
+/// name order like APT does. Pass `/` for the running system, or the mount point of a chroot.
+/// Other files are skipped. A keyring that can't be read or parsed doesn't stop the others
+/// from loading, but is reported in [`SystemKeyring::warnings`], as is a missing directory.
+pub fn system_keyring(sysroot: &Path) -> SystemKeyring {
+    let dir = sysroot.join("etc/apt/trusted.gpg.d");
+    let mut res = SystemKeyring::default();
+    let mut paths: Vec<PathBuf> = match fs::read_dir(&dir).and_then(|entries| {
+        entries
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }) {
+        Ok(paths) => paths,
+        Err(e) => {
+            res.warnings.push(KeyringWarning {
+                path: dir,
+                source: e.into(),
+            });
+            return res;
+        }
+    };
+    paths.sort();
+
+    for path in paths.into_iter().filter(|path| {
+        matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("gpg" | "asc")
+        )
+    }) {
+        match fs::read(&path)
+            .map_err(KeyringError::from)
+            .and_then(|data| Cert::from_bytes(&data))
+        {
+            Ok(certs) => res.certs.extend(certs),
+            Err(source) => res.warnings.push(KeyringWarning { path, source }),
+        }
+    }
+
+    res
+}
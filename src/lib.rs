@@ -1,2 +1,14 @@
+pub mod apt;
 pub mod arch;
+pub mod closure;
+pub mod contents;
+pub mod dependency;
+pub mod diff;
+pub mod download;
+#[cfg(feature = "verify")]
+pub mod keyring;
 pub mod packages;
+pub mod query;
+pub mod release;
+pub mod sources;
+pub mod version;
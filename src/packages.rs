@@ -1,197 +1,546 @@
 use deb822_lossless::{Deb822, FromDeb822, FromDeb822Paragraph, Paragraph, ParseError};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::Infallible,
+    fmt,
+    io::{self, Write},
+    ops::Deref,
+    str::FromStr,
+    sync::OnceLock,
+};
 
 #[cfg(feature = "download")]
-use std::io::{self, Cursor, ErrorKind, Read, Write};
+use sha2::{Digest, Sha256, Sha512};
+#[cfg(feature = "download")]
+use std::io::Read;
 
 #[cfg(feature = "download")]
-use std::path::{Path, PathBuf};
+use crate::download::VerifyError;
 
-use std::str::FromStr;
-use thiserror::Error;
+use crate::{
+    closure::{resolve, Closure, ResolveError},
+    dependency::{
+        parse_provided_names, parse_relationships, provided, Dependency, DependencyField,
+        DependencyGroup, DependencyParseError, ProvidedName, RelationKinds,
+    },
+    diff::PackagesDiff,
+    query::Query,
+    release::parse_yes_no,
+    version::{compare_versions, ParseVersionError, PkgVersion},
+};
 
-#[cfg(feature = "download")]
-const USER_AGENT: &str = "aosc";
+pub use crate::download::{
+    AddressFamily, Compression, FetchPackagesError, FetchSource, IndexStorage, OfflinePolicy,
+    RepoLayout, SizeLimit,
+};
 
 #[cfg(feature = "download")]
-const DEFAULT_MIRROR: &str = "https://repo.aosc.io/debs";
+pub use crate::download::{FetchObserver, FetchReport, FetcherBuilder, Snapshot, SnapshotParts};
+
+#[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+pub use crate::download::FetchPackages;
 
 #[cfg(feature = "async")]
-pub struct FetchPackagesAsync {
-    download_compress: bool,
-    client: reqwest::Client,
-    download_to: PathBuf,
-    mirror_url: String,
-}
+pub use crate::download::{ClosureDownload, FetchPackagesAsync, ProgressEvent};
 
-#[derive(Debug, Error)]
-pub enum FetchPackagesError {
-    #[error(transparent)]
-    IoError(#[from] std::io::Error),
-    #[cfg(feature = "download")]
+#[derive(Debug, thiserror::Error)]
+pub enum ParseControlError {
     #[error(transparent)]
-    ReqwestError(#[from] reqwest::Error),
-    #[error("Failed to parse string to deb822 format")]
-    DebControl(ParseControlError),
-    #[cfg(feature = "async")]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error("Failed convert to package from paragraph")]
+    Paragraph(String),
     #[error(transparent)]
-    JoinError(#[from] tokio::task::JoinError),
+    ParseError(#[from] ParseError),
 }
 
-#[cfg(feature = "async")]
-impl FetchPackagesAsync {
-    pub fn new<P: AsRef<Path>>(
-        download_compress: bool,
-        download_to: P,
-        mirror_url: Option<&str>,
-    ) -> Self {
-        Self {
-            download_compress,
-            client: reqwest::Client::builder()
-                .user_agent(USER_AGENT)
-                .build()
-                .unwrap(),
-            download_to: download_to.as_ref().to_path_buf(),
-            mirror_url: mirror_url.unwrap_or(DEFAULT_MIRROR).to_string(),
+/// A paragraph skipped by [`Packages::from_str_lenient`]
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("Failed to convert paragraph {index} to package: {message}")]
+pub struct ParagraphError {
+    /// Position of the paragraph in the index, counting from 0
+    pub index: usize,
+    /// Value of its `Package` field, if it has one
+    pub package: Option<String>,
+    pub message: String,
+}
+
+/// A parsed Packages index, which derefs to its entries in index order
+///
+/// Lookups by name, provided name or reverse dependency go through indices of positions
+/// that are only built on the first such lookup, so parsing isn't slowed down for callers
+/// that never look anything up. Every method that changes the entries drops those indices
+/// again.
+///
+/// Changing the entries through the deprecated public field bypasses that, so later
+/// lookups may return the wrong entries or panic.
+#[derive(Default)]
+pub struct Packages(
+    #[deprecated(note = "use the methods of Packages instead")] pub Vec<Package>,
+    Indices,
+);
+
+/// Lookup indices of [`Packages`], each built by the first lookup that needs it
+#[derive(Default)]
+struct Indices {
+    by_name: OnceLock<HashMap<String, Vec<usize>>>,
+    providers: OnceLock<ProviderIndex>,
+    reverse: OnceLock<ReverseIndex>,
+}
+
+/// Positions of the providers of each virtual package, with the version they provide it in
+type ProviderIndex = HashMap<String, Vec<(usize, Option<PkgVersion>)>>;
+
+/// Positions of the packages depending on each name, with the dependency on it
+type ReverseIndex = HashMap<String, Vec<(usize, DependencyField, Dependency)>>;
+
+/// A package that depends on another, see [`Packages::reverse_depends`]
+#[derive(Debug, Clone)]
+pub struct ReverseDep<'a> {
+    pub package: &'a Package,
+    pub field: DependencyField,
+    /// The dependency with its version constraint, on either the package asked about or a
+    /// virtual package it provides
+    pub dependency: Dependency,
+}
+
+/// How [`Packages::merge`] handles a package both sides have entries for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Replace the entries with those being merged in, such as a topic over stable
+    PreferOther,
+    /// Keep the side with the newest version, or the existing one if that's a tie
+    PreferNewerVersion,
+    /// Keep the entries of both sides
+    KeepBoth,
+}
+
+/// An entry [`Packages::merge`] replaced with one being merged in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replacement {
+    pub package: String,
+    /// Version of the entry that was dropped
+    pub old_version: String,
+    /// Newest version of the entries that took its place
+    pub new_version: String,
+}
+
+/// A package that provides a virtual package, see [`Packages::providers_of`]
+#[derive(Debug, Clone)]
+pub struct ProviderEntry<'a> {
+    pub package: &'a Package,
+    /// Version the virtual package is provided in, only set for a versioned `Provides`
+    pub version: Option<PkgVersion>,
+}
+
+impl FromStr for Packages {
+    type Err = ParseControlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pkgs: Deb822 = s.parse()?;
+        let mut res = vec![];
+        for para in pkgs.paragraphs() {
+            let pkg = package_from_paragraph(&para).map_err(ParseControlError::Paragraph)?;
+            res.push(pkg);
         }
+
+        Ok(res.into())
     }
+}
 
-    pub async fn fetch_packages(
-        &self,
-        arch: &str,
-        branch: &str,
-    ) -> Result<Packages, FetchPackagesError> {
-        let download_url = format!(
-            "{}/dists/{branch}/main/binary-{arch}/Packages{}",
-            self.mirror_url,
-            if self.download_compress { ".xz" } else { "" }
-        );
-
-        let resp = self
-            .client
-            .get(download_url)
-            .send()
-            .await?
-            .error_for_status()?;
-
-        let bytes_stream = futures::TryStreamExt::into_async_read(futures::TryStreamExt::map_err(
-            resp.bytes_stream(),
-            |e| io::Error::new(ErrorKind::Other, e),
-        ));
-
-        let reader: &mut (dyn futures::AsyncRead + Unpin + Send) = if self.download_compress {
-            &mut async_compression::futures::bufread::XzDecoder::new(futures::io::BufReader::new(
-                bytes_stream,
-            ))
-        } else {
-            &mut futures::io::BufReader::new(bytes_stream)
-        };
+impl Packages {
+    /// Parse an index like [`FromStr`], but skip paragraphs that aren't valid packages
+    ///
+    /// Every skipped paragraph is reported. Only an index that isn't deb822 at all fails.
+    pub fn from_str_lenient(s: &str) -> Result<(Self, Vec<ParagraphError>), ParseControlError> {
+        let pkgs: Deb822 = s.parse()?;
+        let mut res = vec![];
+        let mut errors = vec![];
+        for (index, para) in pkgs.paragraphs().enumerate() {
+            match package_from_paragraph(&para) {
+                Ok(pkg) => res.push(pkg),
+                Err(message) => errors.push(ParagraphError {
+                    index,
+                    package: para.get("Package"),
+                    message,
+                }),
+            }
+        }
 
-        let mut reader = tokio_util::compat::FuturesAsyncReadCompatExt::compat(reader);
+        Ok((res.into(), errors))
+    }
+
+    /// The first entry named `name`, see [`Self::get_all`] for all of them
+    pub fn get(&self, name: &str) -> Option<&Package> {
+        self.positions(name).first().map(|&i| &self.as_slice()[i])
+    }
 
-        let dir = &self.download_to;
+    /// Every entry named `name`, such as several versions or architectures of it, in index
+    /// order
+    ///
+    /// This can't be a slice of the entries: they stay in the order of the index, which
+    /// doesn't keep the entries of a name next to each other, so they are collected instead.
+    pub fn get_all(&self, name: &str) -> Vec<&Package> {
+        self.positions(name)
+            .iter()
+            .map(|&i| &self.as_slice()[i])
+            .collect()
+    }
+
+    /// Whether an entry is named `name`
+    pub fn contains(&self, name: &str) -> bool {
+        !self.positions(name).is_empty()
+    }
+
+    pub fn push(&mut self, pkg: Package) {
+        self.entries_mut().push(pkg);
+    }
+
+    /// Keep only the entries `f` returns true for
+    pub fn retain(&mut self, f: impl FnMut(&Package) -> bool) {
+        self.entries_mut().retain(f);
+    }
+
+    /// Add the entries of `other`, deciding by `strategy` for names both have entries for
+    ///
+    /// Returns the entries that were replaced, in the order of `other`. An entry that is
+    /// identical on both sides is kept once and not reported. New entries come after the
+    /// existing ones.
+    pub fn merge(&mut self, other: Packages, strategy: MergeStrategy) -> Vec<Replacement> {
+        let mut groups: Vec<Vec<Package>> = vec![];
+        let mut group_of: HashMap<String, usize> = HashMap::new();
+        for pkg in other {
+            match group_of.get(&pkg.package) {
+                Some(&i) => groups[i].push(pkg),
+                None => {
+                    group_of.insert(pkg.package.clone(), groups.len());
+                    groups.push(vec![pkg]);
+                }
+            }
+        }
+
+        let mut replaced = vec![];
+        let mut removed = HashSet::new();
+        let mut added = vec![];
+        for group in groups {
+            let ours = self.positions(&group[0].package);
+            let theirs_newest = newest(group.iter()).expect("groups are never empty");
+            let take_theirs = match strategy {
+                MergeStrategy::PreferOther | MergeStrategy::KeepBoth => true,
+                MergeStrategy::PreferNewerVersion => {
+                    newest(ours.iter().map(|&i| &self.as_slice()[i])).is_none_or(|our_newest| {
+                        compare_versions(&theirs_newest.version, &our_newest.version)
+                            == Ordering::Greater
+                    })
+                }
+            };
+            if !take_theirs {
+                continue;
+            }
+
+            if strategy != MergeStrategy::KeepBoth {
+                for &i in ours {
+                    let old = &self.as_slice()[i];
+                    if !group.contains(old) {
+                        removed.insert(i);
+                        replaced.push(Replacement {
+                            package: old.package.clone(),
+                            old_version: old.version.clone(),
+                            new_version: theirs_newest.version.clone(),
+                        });
+                    }
+                }
+            }
+            let is_new = |pkg: &Package| !ours.iter().any(|&i| self.as_slice()[i] == *pkg);
+            added.extend(group.into_iter().filter(is_new));
+        }
+
+        if !removed.is_empty() {
+            let mut i = 0;
+            self.retain(|_| {
+                i += 1;
+                !removed.contains(&(i - 1))
+            });
+        }
+        self.extend(added);
+
+        replaced
+    }
 
-        if !dir.exists() {
-            tokio::fs::create_dir_all(dir).await?;
+    /// Write the entries as a Packages index, separated by blank lines
+    pub fn write_to(&self, mut w: impl Write) -> io::Result<()> {
+        for (i, pkg) in self.as_slice().iter().enumerate() {
+            if i > 0 {
+                writeln!(w)?;
+            }
+            write!(w, "{pkg}")?;
         }
 
-        let mut f = tokio::fs::File::create(dir.join("Packages")).await?;
-        let mut buf = vec![];
-        tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut buf).await?;
-        tokio::io::AsyncWriteExt::write_all(&mut f, &buf).await?;
-
-        (buf.as_slice())
-            .try_into()
-            .map_err(FetchPackagesError::DebControl)
-    }
-}
-
-#[cfg(feature = "blocking")]
-pub struct FetchPackages {
-    download_compress: bool,
-    client: reqwest::blocking::Client,
-    download_to: PathBuf,
-    mirror_url: String,
-}
-
-#[cfg(feature = "blocking")]
-impl FetchPackages {
-    pub fn new<P: AsRef<Path>>(
-        download_compress: bool,
-        download_to: P,
-        mirror_url: Option<&str>,
-    ) -> Self {
-        Self {
-            download_compress,
-            client: reqwest::blocking::Client::builder()
-                .user_agent(USER_AGENT)
-                .build()
-                .unwrap(),
-            download_to: download_to.as_ref().to_path_buf(),
-            mirror_url: mirror_url.unwrap_or(DEFAULT_MIRROR).to_string(),
+        Ok(())
+    }
+
+    /// Iterate over the entries mutably, which drops the lookup indices as they may change
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Package> {
+        self.entries_mut().iter_mut()
+    }
+
+    /// The entries in index order
+    #[allow(deprecated)]
+    pub fn as_slice(&self) -> &[Package] {
+        &self.0
+    }
+
+    #[allow(deprecated)]
+    pub fn into_inner(self) -> Vec<Package> {
+        self.0
+    }
+
+    /// Packages whose `Provides` names `name`, in index order
+    ///
+    /// A package providing its own name isn't listed, as it already is `name`, and neither
+    /// is a malformed `Provides`.
+    pub fn providers_of(&self, name: &str) -> Vec<ProviderEntry<'_>> {
+        self.provider_positions(name)
+            .iter()
+            .map(|(i, version)| ProviderEntry {
+                package: &self.as_slice()[*i],
+                version: version.clone(),
+            })
+            .collect()
+    }
+
+    /// Packages that depend on `name` or a virtual package it provides, in index order per
+    /// name depended on
+    ///
+    /// `Pre-Depends`, `Depends` and `Recommends` are taken into account, each alternative of
+    /// them as if it was the only one. Fields that can't be parsed are skipped.
+    pub fn reverse_depends(&self, name: &str) -> Vec<ReverseDep<'_>> {
+        let reverse = self.1.reverse.get_or_init(|| {
+            let mut reverse: HashMap<_, Vec<_>> = HashMap::new();
+            for (i, pkg) in self.as_slice().iter().enumerate() {
+                let fields = [
+                    (DependencyField::PreDepends, pkg.parse_pre_depends()),
+                    (DependencyField::Depends, pkg.parse_depends()),
+                    (DependencyField::Recommends, pkg.parse_recommends()),
+                ];
+                for (field, groups) in fields {
+                    for dep in groups.unwrap_or_default().into_iter().flat_map(|g| g.0) {
+                        reverse
+                            .entry(dep.name.clone())
+                            .or_default()
+                            .push((i, field, dep));
+                    }
+                }
+            }
+
+            reverse
+        });
+
+        let mut names = vec![name.to_string()];
+        for &i in self.positions(name) {
+            for provided in provided(&self.as_slice()[i]) {
+                if !names.contains(&provided.name) {
+                    names.push(provided.name);
+                }
+            }
         }
+
+        names
+            .iter()
+            .filter_map(|name| reverse.get(name))
+            .flatten()
+            .map(|(i, field, dep)| ReverseDep {
+                package: &self.as_slice()[*i],
+                field: *field,
+                dependency: dep.clone(),
+            })
+            .collect()
+    }
+
+    /// The entries for changing them, which drops the lookup indices built from them
+    #[allow(deprecated)]
+    fn entries_mut(&mut self) -> &mut Vec<Package> {
+        self.1 = Indices::default();
+        &mut self.0
     }
 
-    pub fn fetch_packages(&self, arch: &str, branch: &str) -> Result<Packages, FetchPackagesError> {
-        let download_url = format!(
-            "{}/dists/{branch}/main/binary-{arch}/Packages{}",
-            self.mirror_url,
-            if self.download_compress { ".xz" } else { "" }
-        );
+    fn positions(&self, name: &str) -> &[usize] {
+        let by_name = self.1.by_name.get_or_init(|| {
+            let mut by_name: HashMap<_, Vec<_>> = HashMap::new();
+            for (i, pkg) in self.as_slice().iter().enumerate() {
+                by_name.entry(pkg.package.clone()).or_default().push(i);
+            }
+
+            by_name
+        });
 
-        let resp = self.client.get(download_url).send()?.error_for_status()?;
+        by_name.get(name).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    fn provider_positions(&self, name: &str) -> &[(usize, Option<PkgVersion>)] {
+        let providers = self.1.providers.get_or_init(|| {
+            let mut providers: HashMap<_, Vec<_>> = HashMap::new();
+            for (i, pkg) in self.as_slice().iter().enumerate() {
+                for provided in provided(pkg).filter(|provided| provided.name != pkg.package) {
+                    let entries = providers.entry(provided.name).or_default();
+                    // Only the first of a name listed twice by the same package counts.
+                    if entries.last().is_none_or(|(last, _)| *last != i) {
+                        entries.push((i, provided.version));
+                    }
+                }
+            }
+
+            providers
+        });
+
+        providers.get(name).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// The package that satisfies `dep`, the newest one if several do
+    ///
+    /// Packages named like the dependency are preferred over ones that provide it, see
+    /// [`Dependency::is_met_by`].
+    pub fn satisfies(&self, dep: &Dependency) -> Option<&Package> {
+        let real = self
+            .positions(&dep.name)
+            .iter()
+            .map(|&i| &self.as_slice()[i])
+            .filter(|pkg| dep.is_met_by(pkg));
+
+        newest(real).or_else(|| {
+            let providers = self
+                .provider_positions(&dep.name)
+                .iter()
+                .filter(|(_, version)| dep.accepts_provided(version.as_ref()))
+                .map(|&(i, _)| &self.as_slice()[i]);
+
+            newest(providers)
+        })
+    }
+
+    /// The packages needed to install `roots`, following `Depends` and `Pre-Depends`
+    ///
+    /// Roots may be given as dependencies, such as `foo (>= 1.0)`. Of alternatives the first
+    /// that can be satisfied is taken, and virtual packages resolve to their providers. A
+    /// package is only taken in one version. Fails with every dependency that can't be
+    /// satisfied, such as one on another version of a package already taken, along with the
+    /// packages that led to it.
+    pub fn dependency_closure(&self, roots: &[&str]) -> Result<Closure<'_>, ResolveError> {
+        resolve(self, roots, RelationKinds::default())
+    }
+
+    /// What changed from the snapshot `old` of an index to `new`
+    pub fn diff<'a>(old: &'a Packages, new: &'a Packages) -> PackagesDiff<'a> {
+        crate::diff::diff(old, new)
+    }
+
+    /// Packages matching `query`, in index order
+    pub fn filter(&self, query: &Query) -> Vec<&Package> {
+        self.as_slice()
+            .iter()
+            .filter(|pkg| query.matches(pkg))
+            .collect()
+    }
 
-        let dir = &self.download_to;
+    /// Packages marked `Essential: yes`
+    pub fn essential_set(&self) -> Vec<&Package> {
+        self.as_slice()
+            .iter()
+            .filter(|pkg| pkg.essential == Some(true))
+            .collect()
+    }
 
-        if !dir.exists() {
-            std::fs::create_dir_all(dir)?;
+    /// Packages grouped by the name of the source package they were built from
+    pub fn group_by_source(&self) -> BTreeMap<&str, Vec<&Package>> {
+        let mut groups: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for pkg in self.as_slice() {
+            groups.entry(pkg.source_package().0).or_default().push(pkg);
         }
 
-        let mut f = std::fs::File::create(dir.join("Packages"))?;
-
-        let bytes = resp.bytes()?.to_vec();
-        let decompressed = if self.download_compress {
-            let mut cursor = Cursor::new(&bytes);
-            let mut decoder = xz2::read::XzDecoder::new(&mut cursor);
-            let mut res = vec![];
-            decoder.read_to_end(&mut res)?;
-            res
-        } else {
-            bytes
-        };
+        groups
+    }
 
-        f.write_all(&decompressed)?;
+    /// Packages of the given `Priority`
+    pub fn by_priority(&self, priority: Priority) -> impl Iterator<Item = &Package> {
+        self.as_slice()
+            .iter()
+            .filter(move |pkg| pkg.priority.as_ref() == Some(&priority))
+    }
 
-        (decompressed.as_slice())
-            .try_into()
-            .map_err(FetchPackagesError::DebControl)
+    /// Packages that list `feature` in `X-AOSC-Features`
+    pub fn with_feature<'a>(&'a self, feature: &'a str) -> impl Iterator<Item = &'a Package> {
+        self.as_slice()
+            .iter()
+            .filter(move |pkg| pkg.has_feature(feature))
     }
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum ParseControlError {
-    #[error(transparent)]
-    Utf8(#[from] std::str::Utf8Error),
-    #[error("Failed convert to package from paragraph")]
-    Paragraph(String),
-    #[error(transparent)]
-    ParseError(#[from] ParseError),
+impl Deref for Packages {
+    type Target = [Package];
+
+    fn deref(&self) -> &Self::Target {
+        self.as_slice()
+    }
 }
 
-pub struct Packages(pub Vec<Package>);
+impl From<Vec<Package>> for Packages {
+    #[allow(deprecated)]
+    fn from(packages: Vec<Package>) -> Self {
+        Packages(packages, Indices::default())
+    }
+}
 
-impl FromStr for Packages {
-    type Err = ParseControlError;
+impl FromIterator<Package> for Packages {
+    fn from_iter<I: IntoIterator<Item = Package>>(iter: I) -> Self {
+        iter.into_iter().collect::<Vec<_>>().into()
+    }
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let pkgs: Deb822 = s.parse()?;
-        let mut res = vec![];
-        for para in pkgs.paragraphs() {
-            let pkg =
-                FromDeb822Paragraph::from_paragraph(&para).map_err(ParseControlError::Paragraph)?;
-            res.push(pkg);
-        }
+impl Extend<Package> for Packages {
+    fn extend<I: IntoIterator<Item = Package>>(&mut self, iter: I) {
+        self.entries_mut().extend(iter);
+    }
+}
+
+impl IntoIterator for Packages {
+    type Item = Package;
+    type IntoIter = std::vec::IntoIter<Package>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.into_inner().into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Packages {
+    type Item = &'a Package;
+    type IntoIter = std::slice::Iter<'a, Package>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.as_slice().iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a mut Packages {
+    type Item = &'a mut Package;
+    type IntoIter = std::slice::IterMut<'a, Package>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+/// Packages serialize as a plain sequence of their entries
+#[cfg(feature = "serde")]
+impl serde::Serialize for Packages {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.as_slice().serialize(serializer)
+    }
+}
 
-        Ok(Self(res))
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Packages {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<Package>::deserialize(deserializer).map(Packages::from)
     }
 }
 
@@ -211,8 +560,7 @@ impl FromStr for Package {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let pkg: Paragraph = s.parse()?;
-        let pkg: Package =
-            FromDeb822Paragraph::from_paragraph(&pkg).map_err(ParseControlError::Paragraph)?;
+        let pkg = package_from_paragraph(&pkg).map_err(ParseControlError::Paragraph)?;
 
         Ok(pkg)
     }
@@ -229,30 +577,66 @@ impl TryFrom<&[u8]> for Package {
     }
 }
 
-#[derive(Debug, Clone, FromDeb822)]
+/// An entry of a Packages index
+///
+/// Only the fields that identify the package and its `.deb` are required, as third-party
+/// repositories may leave out the others.
+#[derive(Debug, Clone, PartialEq, Eq, FromDeb822)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Package {
     #[deb822(field = "Package")]
     pub package: String,
+    /// Source package the package was built from, if it is named differently, optionally
+    /// followed by its version like `foo (1.2-3)`
+    #[deb822(field = "Source")]
+    pub source: Option<String>,
     #[deb822(field = "Architecture")]
     pub architecture: String,
     #[deb822(field = "Version")]
     pub version: String,
     #[deb822(field = "Section")]
-    pub section: String,
+    pub section: Option<String>,
+    #[deb822(field = "Priority")]
+    pub priority: Option<Priority>,
     #[deb822(field = "Installed-Size")]
-    pub install_size: u64,
+    pub install_size: Option<u64>,
     #[deb822(field = "Maintainer")]
-    pub maintainer: String,
+    pub maintainer: Option<String>,
     #[deb822(field = "Filename")]
     pub filename: String,
     #[deb822(field = "Size")]
     pub size: u64,
-    #[deb822(field = "SHA256")]
+    #[deb822(field = "SHA256", deserialize_with = parse_hex::<64>)]
     pub sha256: String,
+    #[deb822(field = "SHA512", deserialize_with = parse_hex::<128>)]
+    pub sha512: Option<String>,
+    /// Legacy checksum, only used if nothing stronger is given
+    #[deb822(field = "MD5sum", deserialize_with = parse_hex::<32>)]
+    pub md5sum: Option<String>,
+    /// Summary and long description, with the space continuation lines start with removed,
+    /// so verbatim lines keep the rest of their indentation
     #[deb822(field = "Description")]
-    pub description: String,
+    pub description: Option<String>,
+    /// Upstream project page
+    #[deb822(field = "Homepage")]
+    pub homepage: Option<String>,
     #[deb822(field = "Depends")]
     pub depends: Option<String>,
+    /// Like `Depends`, but has to be configured before this package is even unpacked
+    #[deb822(field = "Pre-Depends")]
+    pub pre_depends: Option<String>,
+    /// Whether the package is needed for the system to work at all
+    #[deb822(field = "Essential", deserialize_with = parse_yes_no)]
+    pub essential: Option<bool>,
+    /// Packages installed along with this one unless the user opts out
+    #[deb822(field = "Recommends")]
+    pub recommends: Option<String>,
+    /// Packages that may be useful along with this one
+    #[deb822(field = "Suggests")]
+    pub suggests: Option<String>,
+    /// Packages this one is useful along with, the opposite of `Suggests`
+    #[deb822(field = "Enhances")]
+    pub enhances: Option<String>,
     #[deb822(field = "Provides")]
     pub provides: Option<String>,
     #[deb822(field = "Conflicts")]
@@ -262,5 +646,380 @@ pub struct Package {
     #[deb822(field = "Breaks")]
     pub breaks: Option<String>,
     #[deb822(field = "X-AOSC-Features")]
-    pub featres: Option<String>,
+    pub features: Option<String>,
+}
+
+/// How important a package is to the system, from `Priority`
+///
+/// Values are matched regardless of case, and unknown ones are kept in lowercase.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Priority {
+    Required,
+    Important,
+    Standard,
+    Optional,
+    /// Deprecated in favour of `optional`, but still found in older packages
+    Extra,
+    Other(String),
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Priority::Required => "required",
+            Priority::Important => "important",
+            Priority::Standard => "standard",
+            Priority::Optional => "optional",
+            Priority::Extra => "extra",
+            Priority::Other(priority) => priority,
+        }
+    }
+}
+
+impl From<&str> for Priority {
+    fn from(priority: &str) -> Self {
+        match priority.to_ascii_lowercase().as_str() {
+            "required" => Priority::Required,
+            "important" => Priority::Important,
+            "standard" => Priority::Standard,
+            "optional" => Priority::Optional,
+            "extra" => Priority::Extra,
+            other => Priority::Other(other.to_string()),
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.trim().into())
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for Package {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_paragraph())
+    }
+}
+
+/// A checksum of a package in lowercase hex, see [`Package::best_checksum`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum<'a> {
+    Sha512(&'a str),
+    Sha256(&'a str),
+    Md5(&'a str),
+}
+
+impl Checksum<'_> {
+    /// Name of the algorithm as used in indices
+    pub fn name(&self) -> &'static str {
+        match self {
+            Checksum::Sha512(_) => "SHA512",
+            Checksum::Sha256(_) => "SHA256",
+            Checksum::Md5(_) => "MD5sum",
+        }
+    }
+
+    pub fn hex(&self) -> &str {
+        match self {
+            Checksum::Sha512(hex) | Checksum::Sha256(hex) | Checksum::Md5(hex) => hex,
+        }
+    }
+
+    /// Hash everything `r` yields and check it against this checksum
+    ///
+    /// `entry` names the data in a mismatch. MD5 can't be verified, as it is too weak to be
+    /// worth a dependency.
+    #[cfg(feature = "download")]
+    pub fn verify_reader(&self, entry: &str, mut r: impl Read) -> Result<(), VerifyError> {
+        let actual = match self {
+            Checksum::Sha512(_) => {
+                let mut hasher = Sha512::new();
+                io::copy(&mut r, &mut hasher)?;
+                format!("{:x}", hasher.finalize())
+            }
+            Checksum::Sha256(_) => {
+                let mut hasher = Sha256::new();
+                io::copy(&mut r, &mut hasher)?;
+                format!("{:x}", hasher.finalize())
+            }
+            Checksum::Md5(_) => return Err(VerifyError::UnsupportedChecksum(self.name())),
+        };
+        if actual != self.hex() {
+            return Err(VerifyError::ChecksumMismatch {
+                entry: entry.to_string(),
+                expected: self.hex().to_string(),
+                actual,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// A tag of `X-AOSC-Features`, naming something a package brings to the system
+///
+/// The repository has no fixed list of tags yet, so every tag is kept as it is written.
+/// Tags that become well known may get variants of their own, which is why the enum is
+/// non-exhaustive.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum AoscFeature {
+    /// A tag without a variant of its own
+    Custom(String),
+}
+
+impl AoscFeature {
+    pub fn as_str(&self) -> &str {
+        match self {
+            AoscFeature::Custom(tag) => tag,
+        }
+    }
+}
+
+impl From<&str> for AoscFeature {
+    fn from(tag: &str) -> Self {
+        AoscFeature::Custom(tag.to_string())
+    }
+}
+
+impl FromStr for AoscFeature {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.trim().into())
+    }
+}
+
+impl fmt::Display for AoscFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Package {
+    /// The package as a stanza of a Packages index
+    ///
+    /// Fields are written in a conventional order, starting with what identifies the package
+    /// and ending with its file and description, and unset ones are left out. Lines after
+    /// the first of a multi-line value, such as the long description, are indented by a space.
+    pub fn to_paragraph(&self) -> Paragraph {
+        let optional = |value: &Option<String>| value.clone();
+        let fields = [
+            ("Package", Some(self.package.clone())),
+            ("Source", optional(&self.source)),
+            ("Version", Some(self.version.clone())),
+            ("Architecture", Some(self.architecture.clone())),
+            (
+                "Essential",
+                self.essential
+                    .map(|essential| if essential { "yes" } else { "no" }.to_string()),
+            ),
+            ("Section", optional(&self.section)),
+            ("Priority", self.priority.as_ref().map(Priority::to_string)),
+            (
+                "Installed-Size",
+                self.install_size.map(|size| size.to_string()),
+            ),
+            ("Maintainer", optional(&self.maintainer)),
+            ("Pre-Depends", optional(&self.pre_depends)),
+            ("Depends", optional(&self.depends)),
+            ("Recommends", optional(&self.recommends)),
+            ("Suggests", optional(&self.suggests)),
+            ("Enhances", optional(&self.enhances)),
+            ("Breaks", optional(&self.breaks)),
+            ("Conflicts", optional(&self.conflicts)),
+            ("Replaces", optional(&self.replaces)),
+            ("Provides", optional(&self.provides)),
+            ("Homepage", optional(&self.homepage)),
+            ("X-AOSC-Features", optional(&self.features)),
+            ("Filename", Some(self.filename.clone())),
+            ("Size", Some(self.size.to_string())),
+            ("MD5sum", optional(&self.md5sum)),
+            ("SHA256", Some(self.sha256.clone())),
+            ("SHA512", optional(&self.sha512)),
+            ("Description", optional(&self.description)),
+        ];
+
+        let mut para = Paragraph::new();
+        for (key, value) in fields {
+            if let Some(value) = value {
+                para.insert(key, &value);
+            }
+        }
+
+        para
+    }
+
+    /// Parse `Depends` into its groups of alternatives, none if the field is missing
+    pub fn parse_depends(&self) -> Result<Vec<DependencyGroup>, DependencyParseError> {
+        parse_relationships(self.depends.as_deref().unwrap_or_default())
+    }
+
+    /// Parse `Pre-Depends` into its groups of alternatives, none if the field is missing
+    pub fn parse_pre_depends(&self) -> Result<Vec<DependencyGroup>, DependencyParseError> {
+        parse_relationships(self.pre_depends.as_deref().unwrap_or_default())
+    }
+
+    /// Parse `Recommends` into its groups of alternatives, none if the field is missing
+    pub fn parse_recommends(&self) -> Result<Vec<DependencyGroup>, DependencyParseError> {
+        parse_relationships(self.recommends.as_deref().unwrap_or_default())
+    }
+
+    /// Parse `Suggests` into its groups of alternatives, none if the field is missing
+    pub fn parse_suggests(&self) -> Result<Vec<DependencyGroup>, DependencyParseError> {
+        parse_relationships(self.suggests.as_deref().unwrap_or_default())
+    }
+
+    /// Parse `Enhances` into its groups of alternatives, none if the field is missing
+    pub fn parse_enhances(&self) -> Result<Vec<DependencyGroup>, DependencyParseError> {
+        parse_relationships(self.enhances.as_deref().unwrap_or_default())
+    }
+
+    /// Parse `Provides` into the virtual packages it names, none if the field is missing
+    pub fn parse_provides(&self) -> Result<Vec<ProvidedName>, DependencyParseError> {
+        parse_provided_names(&self.package, self.provides.as_deref().unwrap_or_default())
+    }
+
+    /// The raw `X-AOSC-Features` field
+    #[deprecated(note = "use the `features` field instead")]
+    pub fn featres(&self) -> Option<&str> {
+        self.features.as_deref()
+    }
+
+    /// Tags of `X-AOSC-Features`, which may be separated by commas, whitespace or both
+    pub fn aosc_features(&self) -> Vec<AoscFeature> {
+        feature_tags(self.features.as_deref())
+            .map(AoscFeature::from)
+            .collect()
+    }
+
+    /// Whether `X-AOSC-Features` lists `feature`
+    pub fn has_feature(&self, feature: &str) -> bool {
+        feature_tags(self.features.as_deref()).any(|tag| tag == feature.trim())
+    }
+
+    /// Name and version of the source package the package was built from
+    ///
+    /// Without a `Source` field, that is the package itself, and without a version in it,
+    /// the source has the same version as the package. The version is `None` if it can't be
+    /// parsed.
+    pub fn source_package(&self) -> (&str, Option<PkgVersion>) {
+        let Some(source) = self.source.as_deref() else {
+            return (&self.package, self.parsed_version().ok());
+        };
+
+        match source.split_once('(') {
+            Some((name, version)) => (
+                name.trim(),
+                version
+                    .strip_suffix(')')
+                    .and_then(|version| version.parse().ok()),
+            ),
+            None => (source.trim(), self.parsed_version().ok()),
+        }
+    }
+
+    /// First line of `Description`, empty if there is none
+    pub fn summary(&self) -> &str {
+        let description = self.description.as_deref().unwrap_or_default();
+
+        description.lines().next().unwrap_or_default().trim()
+    }
+
+    /// Lines of `Description` after the first, `None` if there are none
+    ///
+    /// Lines of a single `.` become blank lines between paragraphs, and verbatim lines, the
+    /// ones indented by more than the continuation space, keep their indentation.
+    pub fn long_description(&self) -> Option<String> {
+        let description = self.description.as_deref()?;
+        let long = description
+            .lines()
+            .skip(1)
+            .map(|line| if line.trim_end() == "." { "" } else { line })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let long = long.trim_end();
+
+        (!long.is_empty()).then(|| long.to_string())
+    }
+
+    /// The strongest checksum the index gives for the package
+    pub fn best_checksum(&self) -> Checksum<'_> {
+        match &self.sha512 {
+            Some(sha512) => Checksum::Sha512(sha512),
+            None => Checksum::Sha256(&self.sha256),
+        }
+    }
+
+    /// Check the `.deb` read from `r` against [`Self::best_checksum`]
+    #[cfg(feature = "download")]
+    pub fn verify_reader(&self, r: impl Read) -> Result<(), VerifyError> {
+        self.best_checksum().verify_reader(&self.filename, r)
+    }
+
+    /// Parse `Version` to compare it with others
+    pub fn parsed_version(&self) -> Result<PkgVersion, ParseVersionError> {
+        self.version.parse()
+    }
+}
+
+/// Read a package from `para`, keeping the indentation of verbatim description lines
+///
+/// deb822-lossless drops all the whitespace continuation lines start with, so multi-line
+/// descriptions are taken again from the text of the paragraph.
+fn package_from_paragraph(para: &Paragraph) -> Result<Package, String> {
+    let mut pkg: Package = FromDeb822Paragraph::from_paragraph(para)?;
+    if pkg.description.as_deref().is_some_and(|d| d.contains('\n')) {
+        pkg.description = raw_field(&para.to_string(), "Description");
+    }
+
+    Ok(pkg)
+}
+
+/// The value of `field` in the text of a paragraph, with only the first character of each
+/// continuation line removed
+fn raw_field(para: &str, field: &str) -> Option<String> {
+    let mut lines = para.lines().filter(|line| !line.starts_with('#'));
+    let first = lines.find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key == field).then(|| value.trim().to_string())
+    })?;
+
+    let continuation = lines.map_while(|line| line.strip_prefix([' ', '\t']));
+    let value = std::iter::once(first.as_str())
+        .chain(continuation.map(str::trim_end))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    Some(value)
+}
+
+fn feature_tags(field: Option<&str>) -> impl Iterator<Item = &str> {
+    field
+        .unwrap_or_default()
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|tag| !tag.is_empty())
+}
+
+fn newest<'a>(candidates: impl Iterator<Item = &'a Package>) -> Option<&'a Package> {
+    candidates.max_by(|a, b| compare_versions(&a.version, &b.version))
+}
+
+/// Check that a checksum is `LEN` hex digits, normalizing it to lowercase
+fn parse_hex<const LEN: usize>(s: &str) -> Result<String, String> {
+    if s.len() != LEN || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("expected {LEN} hex digits, got {s}"));
+    }
+
+    Ok(s.to_ascii_lowercase())
 }
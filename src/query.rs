@@ -0,0 +1,118 @@
+use std::ops::{Bound, Not, RangeBounds};
+
+use crate::packages::Package;
+
+/// Conditions on packages for [`Packages::filter`](crate::packages::Packages::filter)
+///
+/// Every condition added with the builder methods has to hold, while [`Query::or`] and `!`
+/// combine whole queries, such as
+/// `Query::new().section("utils").name_contains("zip").and(!Query::new().architecture("noarch"))`.
+/// An empty query matches every package.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    Name(String),
+    NamePrefix(String),
+    NameContains(String),
+    Section(String),
+    Architecture(String),
+    MaintainerContains(String),
+    Size(Bound<u64>, Bound<u64>),
+    Any(Vec<Query>),
+    Not(Box<Query>),
+    All(Box<Query>),
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn name(self, name: impl Into<String>) -> Self {
+        self.with(Condition::Name(name.into()))
+    }
+
+    pub fn name_prefix(self, prefix: impl Into<String>) -> Self {
+        self.with(Condition::NamePrefix(prefix.into()))
+    }
+
+    pub fn name_contains(self, part: impl Into<String>) -> Self {
+        self.with(Condition::NameContains(part.into()))
+    }
+
+    /// Packages in `section`, which packages without a `Section` never are
+    pub fn section(self, section: impl Into<String>) -> Self {
+        self.with(Condition::Section(section.into()))
+    }
+
+    pub fn architecture(self, arch: impl Into<String>) -> Self {
+        self.with(Condition::Architecture(arch.into()))
+    }
+
+    /// Packages whose `Maintainer` contains `part`, which packages without one never do
+    pub fn maintainer_contains(self, part: impl Into<String>) -> Self {
+        self.with(Condition::MaintainerContains(part.into()))
+    }
+
+    /// Packages whose `.deb` size in bytes is in `range`
+    pub fn size_range(self, range: impl RangeBounds<u64>) -> Self {
+        self.with(Condition::Size(
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+        ))
+    }
+
+    /// Packages matching both this query and `other`
+    pub fn and(self, other: Query) -> Self {
+        self.with(Condition::All(Box::new(other)))
+    }
+
+    /// Packages matching this query, `other` or both
+    pub fn or(self, other: Query) -> Self {
+        Query::new().with(Condition::Any(vec![self, other]))
+    }
+
+    pub fn matches(&self, pkg: &Package) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| condition.matches(pkg))
+    }
+
+    fn with(mut self, condition: Condition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+}
+
+/// Packages not matching the query
+impl Not for Query {
+    type Output = Query;
+
+    fn not(self) -> Query {
+        Query::new().with(Condition::Not(Box::new(self)))
+    }
+}
+
+impl Condition {
+    fn matches(&self, pkg: &Package) -> bool {
+        match self {
+            Condition::Name(name) => pkg.package == *name,
+            Condition::NamePrefix(prefix) => pkg.package.starts_with(prefix.as_str()),
+            Condition::NameContains(part) => pkg.package.contains(part.as_str()),
+            Condition::Section(section) => pkg.section.as_ref() == Some(section),
+            Condition::Architecture(arch) => pkg.architecture == *arch,
+            Condition::MaintainerContains(part) => pkg
+                .maintainer
+                .as_deref()
+                .is_some_and(|maintainer| maintainer.contains(part.as_str())),
+            Condition::Size(start, end) => (*start, *end).contains(&pkg.size),
+            Condition::Any(queries) => queries.iter().any(|query| query.matches(pkg)),
+            Condition::Not(query) => !query.matches(pkg),
+            Condition::All(query) => query.matches(pkg),
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use deb822_lossless::{Deb822, FromDeb822, FromDeb822Paragraph};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "verify")]
+use crate::keyring::{check_signatures, Cert};
+use crate::packages::ParseControlError;
+
+const SIGNED_HEADER: &str = "-----BEGIN PGP SIGNED MESSAGE-----";
+const SIGNATURE_HEADER: &str = "-----BEGIN PGP SIGNATURE-----";
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseReleaseError {
+    #[error(transparent)]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error("Malformed clearsigned release file")]
+    Armor,
+    #[error("Release file is empty")]
+    Empty,
+    #[error(transparent)]
+    Control(#[from] ParseControlError),
+}
+
+/// Why a release file was refused, see [`Release::from_inrelease_verified`]
+#[cfg(feature = "verify")]
+#[derive(Debug, thiserror::Error)]
+pub enum SignatureError {
+    #[error("Release file is not signed")]
+    Unsigned,
+    #[error("Release file is signed by {0}, which is not in the keyring")]
+    UnknownKey(String),
+    /// The signature, or the key that made it, expired at `expired`
+    #[error("Signature by {key} has expired")]
+    Expired { key: String, expired: SystemTime },
+    #[error("Bad signature by {0}")]
+    BadSignature(String),
+    #[error("Malformed signature: {0}")]
+    Malformed(String),
+    #[error(transparent)]
+    Release(#[from] ParseReleaseError),
+}
+
+/// Size and SHA256 of an index file listed in a release file
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReleaseFile {
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// Parse the `<hash> <size> <path>` lines of the SHA256 table
+fn parse_sha256(s: &str) -> Result<HashMap<String, ReleaseFile>, String> {
+    s.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(sha256), Some(size), Some(path), None) => Ok((
+                    path.to_string(),
+                    ReleaseFile {
+                        sha256: sha256.to_string(),
+                        size: size
+                            .parse()
+                            .map_err(|e| format!("invalid size {size}: {e}"))?,
+                    },
+                )),
+                _ => Err(format!("malformed checksum entry: {}", line.trim())),
+            }
+        })
+        .collect()
+}
+
+/// Parse a date such as `Sat, 10 Oct 2026 12:00:00 UTC`, the format of `Date` and `Valid-Until`
+/// as well as of HTTP headers
+///
+/// The zone is `UTC`, `GMT`, `Z` or a numeric offset like `+0800`.
+pub(crate) fn parse_date(s: &str) -> Result<SystemTime, String> {
+    let invalid = || format!("invalid date {s}");
+
+    // The day of the week is redundant.
+    let date = s.split_once(',').map_or(s, |(_, date)| date);
+    let mut parts = date.split_whitespace();
+    let (Some(day), Some(month), Some(year), Some(time), zone, None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return Err(invalid());
+    };
+
+    let day: i64 = day.parse().map_err(|_| invalid())?;
+    let month = MONTHS
+        .iter()
+        .position(|&m| m == month)
+        .ok_or_else(invalid)? as i64
+        + 1;
+    let year: i64 = year.parse().map_err(|_| invalid())?;
+
+    let mut time = time.split(':').map(|n| n.parse::<i64>().ok());
+    let (Some(Some(hour)), Some(Some(minute)), Some(Some(second)), None) =
+        (time.next(), time.next(), time.next(), time.next())
+    else {
+        return Err(invalid());
+    };
+
+    if !(1..=31).contains(&day)
+        || !(0..=23).contains(&hour)
+        || !(0..=59).contains(&minute)
+        || !(0..=60).contains(&second)
+    {
+        return Err(invalid());
+    }
+
+    let offset = match zone {
+        None | Some("UTC" | "GMT" | "Z") => 0,
+        Some(zone) => parse_offset(zone).ok_or_else(invalid)?,
+    };
+
+    let secs =
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+    let secs = u64::try_from(secs).map_err(|_| invalid())?;
+
+    Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Seconds east of UTC of an offset like `+0800`
+fn parse_offset(zone: &str) -> Option<i64> {
+    let (sign, digits) = match zone.split_at_checked(1)? {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let hours: i64 = digits[..2].parse().ok()?;
+    let minutes: i64 = digits[2..].parse().ok()?;
+
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Days from 1970-01-01 to the given date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Count from March so that the leap day is the last day of the year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+fn parse_words(s: &str) -> Result<Vec<String>, String> {
+    Ok(s.split_whitespace().map(|w| w.to_string()).collect())
+}
+
+pub(crate) fn parse_yes_no(s: &str) -> Result<bool, String> {
+    match s {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("expected yes or no, got {s}")),
+    }
+}
+
+/// A `Release` or `InRelease` file from `dists/<branch>/`
+#[derive(Debug, Clone, FromDeb822)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Release {
+    #[deb822(field = "Origin")]
+    pub origin: Option<String>,
+    #[deb822(field = "Label")]
+    pub label: Option<String>,
+    #[deb822(field = "Suite")]
+    pub suite: Option<String>,
+    /// When the release file was generated
+    #[deb822(field = "Date", deserialize_with = parse_date)]
+    pub date: SystemTime,
+    /// When clients should stop trusting the release file
+    #[deb822(field = "Valid-Until", deserialize_with = parse_date)]
+    pub valid_until: Option<SystemTime>,
+    #[deb822(field = "Architectures", deserialize_with = parse_words)]
+    pub architectures: Vec<String>,
+    #[deb822(field = "Components", deserialize_with = parse_words)]
+    pub components: Vec<String>,
+    /// Whether indices can be fetched from `by-hash/SHA256/<hash>` next to them
+    #[deb822(field = "Acquire-By-Hash", deserialize_with = parse_yes_no)]
+    pub acquire_by_hash: Option<bool>,
+    /// Index files keyed by their path relative to `dists/<branch>/`
+    #[deb822(field = "SHA256", deserialize_with = parse_sha256)]
+    pub sha256: HashMap<String, ReleaseFile>,
+}
+
+impl Release {
+    /// Whether `now` is past `Valid-Until`, a release file without it never expires
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.valid_until
+            .is_some_and(|valid_until| now > valid_until)
+    }
+
+    /// Parse a clearsigned `InRelease` file, checking first that it is signed by a key of
+    /// `keyring`
+    ///
+    /// At least one of the signatures has to be valid and unexpired, and the fields are read
+    /// from the signed text alone. A file that isn't clearsigned fails with
+    /// [`SignatureError::Unsigned`].
+    #[cfg(feature = "verify")]
+    pub fn from_inrelease_verified(data: &[u8], keyring: &[Cert]) -> Result<Self, SignatureError> {
+        let text = std::str::from_utf8(data).map_err(ParseReleaseError::from)?;
+        let text = text.trim_start();
+        if !text.starts_with(SIGNED_HEADER) {
+            return Err(SignatureError::Unsigned);
+        }
+        let (message, _) = pgp::composed::CleartextSignedMessage::from_string(text)
+            .map_err(|e| SignatureError::Malformed(e.to_string()))?;
+        // This is the text as signed, with CRLF line endings.
+        let signed = message.signed_text();
+        check_signatures(message.signatures(), signed.as_bytes(), keyring)?;
+
+        Ok(signed.replace("\r\n", "\n").parse()?)
+    }
+
+    /// Parse a `Release` file, checking first that `signature`, the `Release.gpg` file next
+    /// to it, holds a signature over it by a key of `keyring`
+    ///
+    /// See [`Self::from_inrelease_verified`] for `InRelease`, which repositories sign instead
+    /// or as well.
+    #[cfg(feature = "verify")]
+    pub fn from_release_verified(
+        data: &[u8],
+        signature: &[u8],
+        keyring: &[Cert],
+    ) -> Result<Self, SignatureError> {
+        use pgp::composed::{Deserializable, DetachedSignature};
+
+        let malformed = |e: pgp::errors::Error| SignatureError::Malformed(e.to_string());
+        let (signatures, _) = DetachedSignature::from_reader_many(signature).map_err(malformed)?;
+        let signatures = signatures
+            .map(|res| res.map(|detached| detached.signature))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(malformed)?;
+        check_signatures(&signatures, data, keyring)?;
+
+        Ok(Release::try_from(data)?)
+    }
+}
+
+/// The signed text of a clearsigned message, or `s` itself if it isn't signed
+///
+/// The signature is not checked.
+fn strip_armor(s: &str) -> Result<String, ParseReleaseError> {
+    let mut lines = s.lines();
+    if lines
+        .by_ref()
+        .find(|line| !line.trim().is_empty())
+        .is_none_or(|line| line.trim_end() != SIGNED_HEADER)
+    {
+        return Ok(s.to_string());
+    }
+
+    // Armor headers such as `Hash:` end at the first empty line.
+    lines
+        .by_ref()
+        .find(|line| line.trim().is_empty())
+        .ok_or(ParseReleaseError::Armor)?;
+
+    let mut res = String::new();
+    for line in lines {
+        if line.trim_end() == SIGNATURE_HEADER {
+            return Ok(res);
+        }
+
+        // Lines starting with a dash are escaped as `- -...`.
+        res.push_str(line.strip_prefix("- ").unwrap_or(line));
+        res.push('\n');
+    }
+
+    Err(ParseReleaseError::Armor)
+}
+
+impl FromStr for Release {
+    type Err = ParseReleaseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let text = strip_armor(s)?;
+        let release: Deb822 = text
+            .parse()
+            .map_err(|e| ParseReleaseError::Control(ParseControlError::ParseError(e)))?;
+        let para = release
+            .paragraphs()
+            .next()
+            .ok_or(ParseReleaseError::Empty)?;
+
+        Ok(FromDeb822Paragraph::from_paragraph(&para).map_err(ParseControlError::Paragraph)?)
+    }
+}
+
+impl TryFrom<&[u8]> for Release {
+    type Error = ParseReleaseError;
+
+    fn try_from(input: &[u8]) -> Result<Self, Self::Error> {
+        let s = std::str::from_utf8(input)?;
+        let release: Release = s.parse()?;
+
+        Ok(release)
+    }
+}
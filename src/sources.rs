@@ -0,0 +1,114 @@
+use deb822_lossless::{Deb822, FromDeb822, FromDeb822Paragraph, Paragraph};
+use std::str::FromStr;
+
+use crate::packages::ParseControlError;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Sources(pub Vec<SourcePackage>);
+
+impl FromStr for Sources {
+    type Err = ParseControlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let srcs: Deb822 = s.parse()?;
+        let mut res = vec![];
+        for para in srcs.paragraphs() {
+            let src =
+                FromDeb822Paragraph::from_paragraph(&para).map_err(ParseControlError::Paragraph)?;
+            res.push(src);
+        }
+
+        Ok(Self(res))
+    }
+}
+
+impl TryFrom<&[u8]> for Sources {
+    type Error = ParseControlError;
+
+    fn try_from(input: &[u8]) -> Result<Self, Self::Error> {
+        let s = std::str::from_utf8(input)?;
+        let srcs: Sources = s.parse()?;
+
+        Ok(srcs)
+    }
+}
+
+impl FromStr for SourcePackage {
+    type Err = ParseControlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let src: Paragraph = s.parse()?;
+        let src: SourcePackage =
+            FromDeb822Paragraph::from_paragraph(&src).map_err(ParseControlError::Paragraph)?;
+
+        Ok(src)
+    }
+}
+
+impl TryFrom<&[u8]> for SourcePackage {
+    type Error = ParseControlError;
+
+    fn try_from(input: &[u8]) -> Result<Self, Self::Error> {
+        let s = std::str::from_utf8(input)?;
+        let src: SourcePackage = s.parse()?;
+
+        Ok(src)
+    }
+}
+
+/// One file of a source package, as listed in `Files` or `Checksums-Sha256`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SourceFile {
+    /// MD5 in `Files`, SHA256 in `Checksums-Sha256`
+    pub checksum: String,
+    pub size: u64,
+    pub name: String,
+}
+
+/// Parse the `<checksum> <size> <name>` lines of a file list
+fn parse_file_list(s: &str) -> Result<Vec<SourceFile>, String> {
+    s.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(checksum), Some(size), Some(name), None) => Ok(SourceFile {
+                    checksum: checksum.to_string(),
+                    size: size
+                        .parse()
+                        .map_err(|e| format!("invalid size {size}: {e}"))?,
+                    name: name.to_string(),
+                }),
+                _ => Err(format!("malformed file entry: {}", line.trim())),
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, FromDeb822)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SourcePackage {
+    #[deb822(field = "Package")]
+    pub package: String,
+    #[deb822(field = "Binary")]
+    pub binary: Option<String>,
+    #[deb822(field = "Version")]
+    pub version: String,
+    #[deb822(field = "Maintainer")]
+    pub maintainer: String,
+    #[deb822(field = "Architecture")]
+    pub architecture: Option<String>,
+    #[deb822(field = "Build-Depends")]
+    pub build_depends: Option<String>,
+    #[deb822(field = "Directory")]
+    pub directory: String,
+    #[deb822(field = "Files", deserialize_with = parse_file_list)]
+    pub files: Vec<SourceFile>,
+    #[deb822(field = "Checksums-Sha256", deserialize_with = parse_file_list)]
+    pub checksums_sha256: Option<Vec<SourceFile>>,
+    #[deb822(field = "Vcs-Browser")]
+    pub vcs_browser: Option<String>,
+    #[deb822(field = "Vcs-Git")]
+    pub vcs_git: Option<String>,
+}
@@ -0,0 +1,184 @@
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseVersionError {
+    #[error("Version is empty")]
+    Empty,
+    #[error("Epoch of version {0} is not a number")]
+    InvalidEpoch(String),
+    #[error("Upstream version of {0} is empty")]
+    EmptyUpstream(String),
+    #[error("Revision of version {0} is empty")]
+    EmptyRevision(String),
+    #[error("Invalid character in version {0}")]
+    InvalidCharacter(String),
+}
+
+/// A package version of the form `[epoch:]upstream[-revision]`, ordered like dpkg does
+///
+/// Versions that only differ in how they are written compare equal, such as `1.0` and
+/// `0:1.00`. A missing epoch is 0, and a missing revision compares like an empty one. An
+/// epoch of 0 is left out when the version is displayed.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PkgVersion {
+    pub epoch: u64,
+    pub upstream: String,
+    pub revision: Option<String>,
+}
+
+impl FromStr for PkgVersion {
+    type Err = ParseVersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(ParseVersionError::Empty);
+        }
+        let (epoch, rest) = match s.split_once(':') {
+            Some((epoch, rest)) => (
+                epoch
+                    .parse()
+                    .map_err(|_| ParseVersionError::InvalidEpoch(s.to_string()))?,
+                rest,
+            ),
+            None => (0, s),
+        };
+        // Only the last hyphen starts the revision, the upstream version may contain more.
+        let (upstream, revision) = match rest.rsplit_once('-') {
+            Some((_, "")) => return Err(ParseVersionError::EmptyRevision(s.to_string())),
+            Some((upstream, revision)) => (upstream, Some(revision)),
+            None => (rest, None),
+        };
+        if upstream.is_empty() {
+            return Err(ParseVersionError::EmptyUpstream(s.to_string()));
+        }
+        let valid = |part: &str, extra: &str| {
+            part.chars()
+                .all(|c| c.is_ascii_alphanumeric() || ".+~".contains(c) || extra.contains(c))
+        };
+        if !valid(upstream, "-:") || revision.is_some_and(|revision| !valid(revision, "")) {
+            return Err(ParseVersionError::InvalidCharacter(s.to_string()));
+        }
+
+        Ok(PkgVersion {
+            epoch,
+            upstream: upstream.to_string(),
+            revision: revision.map(str::to_string),
+        })
+    }
+}
+
+impl fmt::Display for PkgVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.epoch > 0 {
+            write!(f, "{}:", self.epoch)?;
+        }
+        f.write_str(&self.upstream)?;
+        if let Some(revision) = &self.revision {
+            write!(f, "-{revision}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Ord for PkgVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| compare_part(&self.upstream, &other.upstream))
+            .then_with(|| {
+                compare_part(
+                    self.revision.as_deref().unwrap_or_default(),
+                    other.revision.as_deref().unwrap_or_default(),
+                )
+            })
+    }
+}
+
+impl PartialOrd for PkgVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for PkgVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for PkgVersion {}
+
+/// Compare two version strings like [`PkgVersion`] does, without parsing them first
+///
+/// Meant for versions taken from an index, which are assumed to be valid. An epoch that
+/// isn't a number counts as 0.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a_epoch, a_upstream, a_revision) = split_version(a);
+    let (b_epoch, b_upstream, b_revision) = split_version(b);
+
+    a_epoch
+        .cmp(&b_epoch)
+        .then_with(|| compare_part(a_upstream, b_upstream))
+        .then_with(|| compare_part(a_revision, b_revision))
+}
+
+fn split_version(version: &str) -> (u64, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) => (epoch.parse().unwrap_or(0), rest),
+        None => (0, version),
+    };
+    let (upstream, revision) = rest.rsplit_once('-').unwrap_or((rest, ""));
+
+    (epoch, upstream, revision)
+}
+
+/// Compare alternating runs of non-digits and digits, where `~` sorts before anything
+fn compare_part(mut a: &str, mut b: &str) -> Ordering {
+    while !a.is_empty() || !b.is_empty() {
+        let a_len = a.find(|c: char| c.is_ascii_digit()).unwrap_or(a.len());
+        let b_len = b.find(|c: char| c.is_ascii_digit()).unwrap_or(b.len());
+        let ord = compare_non_digits(&a[..a_len], &b[..b_len]);
+        if ord != Ordering::Equal {
+            return ord;
+        }
+        (a, b) = (&a[a_len..], &b[b_len..]);
+
+        let a_len = a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len());
+        let b_len = b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len());
+        let (a_num, b_num) = (
+            a[..a_len].trim_start_matches('0'),
+            b[..b_len].trim_start_matches('0'),
+        );
+        let ord = a_num.len().cmp(&b_num.len()).then_with(|| a_num.cmp(b_num));
+        if ord != Ordering::Equal {
+            return ord;
+        }
+        (a, b) = (&a[a_len..], &b[b_len..]);
+    }
+
+    Ordering::Equal
+}
+
+fn compare_non_digits(a: &str, b: &str) -> Ordering {
+    // Letters sort before other characters, and `~` before the end of the string.
+    let weight = |c: Option<char>| match c {
+        Some('~') => -1,
+        None => 0,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(c) => c as i32 + 256,
+    };
+    let (mut a, mut b) = (a.chars(), b.chars());
+
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (x, y) => match weight(x).cmp(&weight(y)) {
+                Ordering::Equal => continue,
+                ord => return ord,
+            },
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use libaosc::{
+    closure::Unresolved,
+    packages::{Package, Packages},
+};
+
+/// Stanzas with just the fields an index needs besides the relationships
+fn index(stanzas: &[(&str, &str, &str)]) -> Packages {
+    let mut index = String::new();
+    for (i, (name, version, relations)) in stanzas.iter().enumerate() {
+        index.push_str(&format!(
+            "Package: {name}\nVersion: {version}\nArchitecture: amd64\n\
+             Filename: pool/{name}_{version}.deb\nSize: {}\nSHA256: {:064x}\n{relations}\n",
+            100 + i,
+            i
+        ));
+    }
+
+    index.parse().unwrap()
+}
+
+fn fixture() -> Packages {
+    index(&[
+        ("a", "1.0", "Depends: b, c\n"),
+        ("b", "1.0", "Depends: a (>= 1.0)\n"),
+        ("c", "1.0", "Depends: d (>= 2)\n"),
+        ("d", "2.0", ""),
+        ("d", "1.0", ""),
+        ("e", "1.0", "Depends: d (<< 2)\n"),
+        ("f", "1.0", "Depends: d (<< 2) | g\n"),
+        ("g", "1.0", "Provides: mta\n"),
+        ("h", "1.0", "Depends: mta\n"),
+        ("x", "1.0", "Depends: y\n"),
+        ("y", "1.0", "Pre-Depends: z\n"),
+        ("z", "1.0", "Depends: missing (>= 1)\n"),
+    ])
+}
+
+fn chosen(pkgs: &[&Package]) -> Vec<String> {
+    pkgs.iter()
+        .map(|pkg| format!("{} {}", pkg.package, pkg.version))
+        .collect()
+}
+
+#[test]
+fn end_cycles() {
+    let pkgs = fixture();
+    let closure = pkgs.dependency_closure(&["a"]).unwrap();
+    assert_eq!(
+        chosen(&closure.packages),
+        ["a 1.0", "b 1.0", "c 1.0", "d 2.0"]
+    );
+    assert_eq!(closure.download_size(), 100 + 101 + 102 + 103);
+}
+
+#[test]
+fn resolve_virtual_packages_and_alternatives() {
+    let pkgs = fixture();
+    let closure = pkgs.dependency_closure(&["h", "g"]).unwrap();
+    assert_eq!(chosen(&closure.packages), ["h 1.0", "g 1.0"]);
+
+    // With d 2.0 taken, the first alternative could only be met by another version of it.
+    let closure = pkgs.dependency_closure(&["c", "f"]).unwrap();
+    assert_eq!(
+        chosen(&closure.packages),
+        ["c 1.0", "f 1.0", "d 2.0", "g 1.0"]
+    );
+}
+
+#[test]
+fn report_version_conflicts() {
+    let pkgs = fixture();
+    let err = pkgs.dependency_closure(&["c", "e"]).unwrap_err();
+    assert_eq!(
+        err.unresolved,
+        [Unresolved {
+            dependency: "d (<< 2)".to_string(),
+            chain: vec!["e".to_string()],
+            conflict: Some("d 2.0".to_string()),
+        }]
+    );
+
+    // Whichever comes first is taken.
+    let err = pkgs.dependency_closure(&["e", "c"]).unwrap_err();
+    assert_eq!(
+        err.unresolved[0].to_string(),
+        "d (>= 2) (needed by c, but d 1.0 is already chosen)"
+    );
+
+    let err = pkgs
+        .dependency_closure(&["d (= 2.0)", "d (= 1.0)"])
+        .unwrap_err();
+    assert_eq!(
+        err.unresolved[0].to_string(),
+        "d (= 1.0) (requested, but d 2.0 is already chosen)"
+    );
+}
+
+#[test]
+fn report_chains_to_unresolved_dependencies() {
+    let pkgs = fixture();
+    let err = pkgs.dependency_closure(&["x", "nope", "a"]).unwrap_err();
+    assert_eq!(
+        err.unresolved,
+        [
+            Unresolved {
+                dependency: "nope".to_string(),
+                chain: vec![],
+                conflict: None,
+            },
+            Unresolved {
+                dependency: "missing (>= 1)".to_string(),
+                chain: vec!["x".to_string(), "y".to_string(), "z".to_string()],
+                conflict: None,
+            },
+        ]
+    );
+    assert_eq!(
+        err.to_string(),
+        "Unresolvable dependencies: nope (requested), missing (>= 1) (needed by x -> y -> z)"
+    );
+}
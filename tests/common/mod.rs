@@ -0,0 +1,292 @@
+// Each test binary uses its own part of these helpers.
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+pub const INDEX: &str = include_str!("../data/Packages");
+pub const INDEX_ZST: &[u8] = include_bytes!("../data/Packages.zst");
+
+/// Path of the index of `branch` in a mirror, relative to its root
+pub fn index_path(branch: &str, file_name: &str) -> String {
+    format!("/dists/{branch}/main/binary-amd64/{file_name}")
+}
+
+/// What the server answers a request for a path with
+pub enum Reply {
+    File(Vec<u8>),
+    /// A 301 to the given location
+    Redirect(String),
+    /// A file whose first bytes are sent one at a time, each in a separate write
+    Trickle(Vec<u8>),
+    /// A file with validators, answered with 304 if the request has a matching
+    /// `If-None-Match` or `If-Modified-Since`
+    ///
+    /// With `ranges`, a `Range: bytes=N-` is answered with 206 unless its `If-Range` doesn't
+    /// match, otherwise the whole file is sent.
+    Validated {
+        body: Vec<u8>,
+        etag: Option<String>,
+        last_modified: Option<String>,
+        ranges: bool,
+    },
+    /// A file answered with 503 to each of its first `failures` requests
+    Flaky {
+        failures: usize,
+        body: Vec<u8>,
+    },
+}
+
+/// A request the server received, with lowercase header names
+pub struct Request {
+    pub path: String,
+    pub headers: HashMap<String, String>,
+}
+
+/// An HTTP/1.1 server on localhost serving a fixed set of files
+pub struct Server {
+    addr: SocketAddr,
+    requests: Arc<AtomicUsize>,
+    received: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Server {
+    pub fn start(files: impl IntoIterator<Item = (String, Vec<u8>)>) -> Self {
+        Self::start_with(
+            files
+                .into_iter()
+                .map(|(path, body)| (path, Reply::File(body))),
+        )
+    }
+
+    pub fn start_with(replies: impl IntoIterator<Item = (String, Reply)>) -> Self {
+        let files: Arc<HashMap<String, Reply>> = Arc::new(replies.into_iter().collect());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let received = Arc::new(Mutex::new(vec![]));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let counter = requests.clone();
+        let log = received.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let files = files.clone();
+                let log = log.clone();
+                counter.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || serve(stream, &files, &log));
+            }
+        });
+
+        Server {
+            addr,
+            requests,
+            received,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Number of connections accepted so far
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+
+    /// Headers of the requests for `path` so far, in the order they were received
+    pub fn headers_of(&self, path: &str) -> Vec<HashMap<String, String>> {
+        self.received
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|req| req.path == path)
+            .map(|req| req.headers.clone())
+            .collect()
+    }
+}
+
+fn serve(stream: TcpStream, files: &HashMap<String, Reply>, log: &Mutex<Vec<Request>>) {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) if line == "\r\n" => break,
+            Ok(_) => {
+                if let Some((name, value)) = line.split_once(':') {
+                    headers.insert(name.to_ascii_lowercase(), value.trim().to_string());
+                }
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    log.lock().unwrap().push(Request {
+        path: path.to_string(),
+        headers: headers.clone(),
+    });
+    let mut stream = &stream;
+    let response = match files.get(path) {
+        Some(Reply::Redirect(location)) => {
+            let head = format!(
+                "HTTP/1.1 301 Moved Permanently\r\nLocation: {location}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            );
+            stream.write_all(head.as_bytes())
+        }
+        Some(Reply::File(body)) => {
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream
+                .write_all(head.as_bytes())
+                .and_then(|_| match method {
+                    "HEAD" => Ok(()),
+                    _ => stream.write_all(body),
+                })
+        }
+        Some(Reply::Trickle(body)) => {
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            let (first, rest) = body.split_at(body.len().min(8));
+            stream.write_all(head.as_bytes()).and_then(|_| {
+                for byte in first {
+                    stream.write_all(std::slice::from_ref(byte))?;
+                    stream.flush()?;
+                    thread::sleep(Duration::from_millis(20));
+                }
+                stream.write_all(rest)
+            })
+        }
+        Some(Reply::Validated {
+            body,
+            etag,
+            last_modified,
+            ranges,
+        }) => {
+            let mut validators = String::new();
+            if let Some(etag) = etag {
+                validators += &format!("ETag: {etag}\r\n");
+            }
+            if let Some(last_modified) = last_modified {
+                validators += &format!("Last-Modified: {last_modified}\r\n");
+            }
+            let matches = |header: &str, validator: &Option<String>| {
+                headers
+                    .get(header)
+                    .is_some_and(|v| Some(v) == validator.as_ref())
+            };
+            let if_range = headers
+                .get("if-range")
+                .is_none_or(|v| Some(v) == etag.as_ref() || Some(v) == last_modified.as_ref());
+            let start = headers
+                .get("range")
+                .and_then(|range| range.strip_prefix("bytes="))
+                .and_then(|range| range.strip_suffix('-'))
+                .and_then(|start| start.parse::<usize>().ok())
+                .filter(|&start| *ranges && if_range && start < body.len());
+
+            let (head, body) = if matches("if-none-match", etag)
+                || matches("if-modified-since", last_modified)
+            {
+                let head =
+                    format!("HTTP/1.1 304 Not Modified\r\n{validators}Connection: close\r\n\r\n");
+                (head, &[][..])
+            } else if let Some(start) = start {
+                let head = format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {start}-{}/{}\r\n\
+                     Content-Length: {}\r\n{validators}Connection: close\r\n\r\n",
+                    body.len() - 1,
+                    body.len(),
+                    body.len() - start
+                );
+                (head, &body[start..])
+            } else {
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n{validators}Connection: close\r\n\r\n",
+                    body.len()
+                );
+                (head, &body[..])
+            };
+            stream
+                .write_all(head.as_bytes())
+                .and_then(|_| match method {
+                    "HEAD" => Ok(()),
+                    _ => stream.write_all(body),
+                })
+        }
+        Some(Reply::Flaky { failures, body }) => {
+            let seen = log
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|req| req.path == path)
+                .count();
+            let head = match seen > *failures {
+                true => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                ),
+                false => "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\
+                          Connection: close\r\n\r\n"
+                    .to_string(),
+            };
+            stream
+                .write_all(head.as_bytes())
+                .and_then(|_| match seen > *failures {
+                    true => stream.write_all(body),
+                    false => Ok(()),
+                })
+        }
+        None => stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+    };
+    let _ = response.and_then(|_| stream.flush());
+}
+
+/// A directory under the system temporary directory, removed on drop
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "libaosc-{name}-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).unwrap();
+
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,81 @@
+use libaosc::contents::{Contents, Owner, ParseContentsError};
+
+fn owner(section: &str, package: &str) -> Owner {
+    Owner {
+        section: Some(section.to_string()),
+        package: package.to_string(),
+    }
+}
+
+#[test]
+fn split_paths_with_spaces_at_last_whitespace() {
+    let contents: Contents = "usr/bin/bash                                  shells/bash\n\
+         usr/share/fonts/Noto Sans Mono/Regular.ttf    fonts/noto-fonts\n\
+         usr/share/doc/a  b\tc/README \t utils/abc\n"
+        .parse()
+        .unwrap();
+
+    assert_eq!(contents.len(), 3);
+    assert_eq!(
+        contents.packages_owning("/usr/share/fonts/Noto Sans Mono/Regular.ttf"),
+        [owner("fonts", "noto-fonts")]
+    );
+    // Whitespace within the path is kept as it is, only the run before the owners goes.
+    assert_eq!(
+        contents.packages_owning("usr/share/doc/a  b\tc/README"),
+        [owner("utils", "abc")]
+    );
+    assert!(contents
+        .packages_owning("usr/share/doc/a b c/README")
+        .is_empty());
+}
+
+#[test]
+fn skip_header_up_to_file_location() {
+    let index = "This file maps each file available in the AOSC OS\n\
+                 repository to the packages containing it.\n\
+                 \n\
+                 FILE                                          LOCATION\n\
+                 usr/bin/bash                                  shells/bash\n";
+    let contents: Contents = index.parse().unwrap();
+    assert_eq!(contents.len(), 1);
+    assert_eq!(
+        contents.packages_owning("usr/bin/bash"),
+        [owner("shells", "bash")]
+    );
+
+    // Without the header line, the free-form text is taken for entries.
+    let contents: Contents = "Free-form text\nusr/bin/bash shells/bash\n"
+        .parse()
+        .unwrap();
+    assert_eq!(contents.len(), 2);
+    assert_eq!(contents.packages_owning("Free-form")[0].package, "text");
+    assert!(matches!(
+        "no-owner\n".parse::<Contents>(),
+        Err(ParseContentsError::MalformedLine(1))
+    ));
+}
+
+#[test]
+fn parse_multiple_owners() {
+    let contents: Contents = "usr/bin/vi    editors/vim,contrib/editors/nvi,busybox,\n\
+                              usr/bin/vi    admin/vi-alt\n"
+        .parse()
+        .unwrap();
+
+    assert_eq!(
+        contents.packages_owning("usr/bin/vi"),
+        [
+            owner("editors", "vim"),
+            owner("contrib/editors", "nvi"),
+            Owner {
+                section: None,
+                package: "busybox".to_string(),
+            },
+            owner("admin", "vi-alt"),
+        ]
+    );
+    let (path, owners) = contents.iter().next().unwrap();
+    assert_eq!(path, "usr/bin/vi");
+    assert_eq!(owners.len(), 4);
+}
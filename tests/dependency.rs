@@ -0,0 +1,198 @@
+use libaosc::{
+    dependency::{
+        format_relationships, parse_provided_names, parse_relationships, Dependency,
+        DependencyParseError, ProvidedName, Relation,
+    },
+    version::PkgVersion,
+};
+
+fn version(s: &str) -> PkgVersion {
+    s.parse().unwrap()
+}
+
+fn dep(s: &str) -> Dependency {
+    s.parse().unwrap()
+}
+
+#[test]
+fn parse_whitespace_variants() {
+    let expected = Dependency {
+        name: "foo".to_string(),
+        constraint: Some((Relation::LaterOrEqual, version("1.0"))),
+        arch_qualifier: None,
+        architectures: vec![],
+    };
+    for s in [
+        "foo (>= 1.0)",
+        "foo(>=1.0)",
+        "foo (>=1.0)",
+        "foo ( >= 1.0 )",
+        "  foo\t(>=  1.0)  ",
+        "foo\n (>= 1.0)",
+    ] {
+        assert_eq!(dep(s), expected, "{s:?}");
+        assert_eq!(dep(s).to_string(), "foo (>= 1.0)", "{s:?}");
+    }
+}
+
+#[test]
+fn parse_epochs_and_revisions() {
+    let dep = dep("libfoo (>= 1:2.0-3)");
+    assert_eq!(
+        dep.constraint,
+        Some((Relation::LaterOrEqual, version("1:2.0-3")))
+    );
+    assert!(dep.satisfied_by(&version("1:2.0-3")));
+    assert!(dep.satisfied_by(&version("1:2.1")));
+    // Any epoch beats no epoch, whatever the rest of the version.
+    assert!(!dep.satisfied_by(&version("9.0")));
+    assert_eq!(dep.to_string(), "libfoo (>= 1:2.0-3)");
+}
+
+#[test]
+fn parse_architectures() {
+    let dep = dep("python3:any (>= 3.11) [amd64 !i486]");
+    assert_eq!(dep.name, "python3");
+    assert_eq!(dep.arch_qualifier.as_deref(), Some("any"));
+    assert_eq!(dep.architectures, ["amd64", "!i486"]);
+    assert_eq!(dep.to_string(), "python3:any (>= 3.11) [amd64 !i486]");
+
+    let dep = self::dep("foo[ amd64 ]");
+    assert_eq!(dep.architectures, ["amd64"]);
+    assert_eq!(dep.constraint, None);
+
+    for s in ["foo []", "foo [amd64", "foo:", "foo: (>= 1)"] {
+        assert!(s.parse::<Dependency>().is_err(), "{s:?}");
+    }
+}
+
+#[test]
+fn reject_malformed_dependencies() {
+    for (s, expected) in [
+        ("", "EmptyAlternative"),
+        ("Foo!", "InvalidName"),
+        ("foo (>= 1.0", "InvalidConstraint"),
+        ("foo (~ 1.0)", "InvalidConstraint"),
+        ("foo (>=)", "InvalidConstraint"),
+        ("foo [amd64", "InvalidArchitectures"),
+        ("foo bar", "TrailingText"),
+        ("foo (>= 1.0) [amd64] baz", "TrailingText"),
+    ] {
+        let err = s.parse::<Dependency>().unwrap_err();
+        assert!(format!("{err:?}").starts_with(expected), "{s:?}: {err:?}");
+    }
+}
+
+#[test]
+fn skip_empty_fields_and_entries() {
+    assert!(parse_relationships("").unwrap().is_empty());
+    assert!(parse_relationships(" , ,").unwrap().is_empty());
+
+    let groups = parse_relationships("foo, , bar,").unwrap();
+    assert_eq!(format_relationships(&groups), "foo, bar");
+
+    for field in ["foo | , bar", "| foo", "foo ||bar"] {
+        assert!(
+            matches!(
+                parse_relationships(field),
+                Err(DependencyParseError::EmptyAlternative(_))
+            ),
+            "{field:?}"
+        );
+    }
+}
+
+#[test]
+fn round_trip_normalized_fields() {
+    for (field, normalized) in [
+        ("a(>=1)|b [amd64],c:any", "a (>= 1) | b [amd64], c:any"),
+        (
+            "libc6 (>= 2.36),  libfoo1 (= 1:1.0-1) | libfoo-compat ,perl:any",
+            "libc6 (>= 2.36), libfoo1 (= 1:1.0-1) | libfoo-compat, perl:any",
+        ),
+        ("foo (< 2), bar (> 1)", "foo (<= 2), bar (>= 1)"),
+    ] {
+        let groups = parse_relationships(field).unwrap();
+        let written = format_relationships(&groups);
+        assert_eq!(written, normalized, "{field:?}");
+
+        // The normalized form parses to the same groups and is written back as is.
+        let reparsed = parse_relationships(&written).unwrap();
+        assert_eq!(reparsed, groups, "{field:?}");
+        assert_eq!(format_relationships(&reparsed), written, "{field:?}");
+    }
+}
+
+#[test]
+fn satisfy_every_relation() {
+    let cases = [
+        ("<<", [true, false, false]),
+        ("<=", [true, true, false]),
+        ("<", [true, true, false]),
+        ("=", [false, true, false]),
+        (">=", [false, true, true]),
+        (">", [false, true, true]),
+        (">>", [false, false, true]),
+    ];
+    for (op, expected) in cases {
+        let dep = dep(&format!("foo ({op} 2.0)"));
+        for (candidate, expected) in ["1.9", "2.0", "2.0.1"].into_iter().zip(expected) {
+            assert_eq!(
+                dep.satisfied_by(&version(candidate)),
+                expected,
+                "{candidate} {op} 2.0"
+            );
+        }
+    }
+
+    assert_eq!("<".parse(), Ok(Relation::EarlierOrEqual));
+    assert_eq!(">".parse(), Ok(Relation::LaterOrEqual));
+    assert!(dep("foo").satisfied_by(&version("0")));
+}
+
+#[test]
+fn parse_versioned_provides() {
+    let provided = parse_provided_names("foo", "bar (= 1:2.0), baz ,, qux(=3)").unwrap();
+    assert_eq!(
+        provided,
+        [
+            ProvidedName {
+                name: "bar".to_string(),
+                version: Some(version("1:2.0")),
+            },
+            ProvidedName {
+                name: "baz".to_string(),
+                version: None,
+            },
+            ProvidedName {
+                name: "qux".to_string(),
+                version: Some(version("3")),
+            },
+        ]
+    );
+    assert_eq!(provided[0].to_string(), "bar (= 1:2.0)");
+    assert_eq!(provided[1].to_string(), "baz");
+    assert!(parse_provided_names("foo", "").unwrap().is_empty());
+}
+
+#[test]
+fn reject_invalid_provides() {
+    for entry in [
+        "bar (>= 1.0)",
+        "bar (<< 1.0)",
+        "bar [amd64]",
+        "bar:any",
+        "bar | baz",
+        "(= 1.0)",
+        "bar (= )",
+    ] {
+        let err = parse_provided_names("foo", &format!("ok, {entry}")).unwrap_err();
+        match err {
+            DependencyParseError::InvalidProvides { package, entry: e } => {
+                assert_eq!(package, "foo");
+                assert_eq!(e, entry);
+            }
+            e => panic!("unexpected error {e:?} for {entry:?}"),
+        }
+    }
+}
@@ -0,0 +1,389 @@
+use libaosc::packages::{MergeStrategy, Package, Packages, Replacement};
+
+fn fixture() -> Packages {
+    include_str!("data/Packages").parse().unwrap()
+}
+
+fn names<'a>(pkgs: impl IntoIterator<Item = &'a Package>) -> Vec<&'a str> {
+    pkgs.into_iter().map(|pkg| pkg.package.as_str()).collect()
+}
+
+#[test]
+fn filter_and_collect() {
+    let utils: Packages = fixture()
+        .into_iter()
+        .filter(|pkg| pkg.section.as_deref() == Some("utils"))
+        .collect();
+
+    assert_eq!(names(&utils), ["coreutils", "zip", "unzip", "p7zip"]);
+    assert!(utils.contains("zip"));
+    assert!(!utils.contains("bash"));
+}
+
+#[test]
+fn iterate_by_reference() {
+    let pkgs = fixture();
+    let mut total = 0;
+    for pkg in &pkgs {
+        total += pkg.size;
+    }
+
+    assert_eq!(pkgs.len(), 8);
+    assert!(!pkgs.is_empty());
+    assert!(Packages::default().is_empty());
+    assert_eq!(total, pkgs.iter().map(|pkg| pkg.size).sum::<u64>());
+    assert_eq!(
+        names(pkgs.iter().filter(|pkg| pkg.essential == Some(true))),
+        ["bash", "coreutils"]
+    );
+}
+
+#[test]
+fn extend_keeps_lookups_current() {
+    let mut pkgs = fixture();
+    let libs: Packages = fixture()
+        .into_iter()
+        .filter(|pkg| pkg.section.as_deref() == Some("libs"))
+        .map(|mut pkg| {
+            pkg.package = format!("{}-dev", pkg.package);
+            pkg
+        })
+        .collect();
+    assert!(!pkgs.contains("libcurl-dev"));
+
+    pkgs.extend(libs.iter().cloned());
+    assert_eq!(pkgs.get("libcurl-dev").unwrap().version, "8.6.0");
+    assert_eq!(pkgs.len(), 9);
+}
+
+#[test]
+fn mutate_through_iter_mut() {
+    let mut pkgs = fixture();
+    assert!(pkgs.contains("zip"));
+
+    for pkg in &mut pkgs {
+        if pkg.package == "zip" {
+            pkg.package = "zip3".to_string();
+        }
+    }
+
+    assert!(!pkgs.contains("zip"));
+    assert_eq!(pkgs.get("zip3").unwrap().version, "3.0-4");
+}
+
+#[test]
+fn round_trip_through_deb822() {
+    let pkgs = fixture();
+    let mut written = vec![];
+    pkgs.write_to(&mut written).unwrap();
+
+    let parsed = Packages::try_from(written.as_slice()).unwrap();
+    assert_eq!(parsed.as_slice(), pkgs.as_slice());
+
+    // Writing what was read back changes nothing.
+    let mut rewritten = vec![];
+    parsed.write_to(&mut rewritten).unwrap();
+    assert_eq!(rewritten, written);
+}
+
+#[test]
+fn write_multi_line_descriptions() {
+    let pkgs = fixture();
+    let stanza = pkgs.get("coreutils").unwrap().to_string();
+    assert!(
+        stanza.contains(
+            "Description: GNU core utilities\n Basic file, shell and text manipulation \
+             utilities.\n .\n These are expected to exist on every operating system.\n"
+        ),
+        "{stanza}"
+    );
+
+    let parsed: Packages = stanza.parse().unwrap();
+    assert_eq!(parsed.as_slice(), [pkgs.get("coreutils").unwrap().clone()]);
+}
+
+#[test]
+fn diff_versions_by_dpkg_order() {
+    let with_zip = |version: &str, sha256: &str| {
+        let mut pkgs = fixture();
+        for pkg in &mut pkgs {
+            if pkg.package == "zip" {
+                pkg.version = version.to_string();
+                pkg.sha256 = sha256.repeat(64);
+            }
+        }
+        pkgs
+    };
+
+    // Spelled differently, but the same version to dpkg.
+    let (old, new) = (with_zip("1:2.0", "a"), with_zip("1:2.00", "a"));
+    let diff = Packages::diff(&old, &new);
+    assert!(diff.is_empty(), "{diff}");
+
+    // So a different `.deb` is a rebuild rather than a version change.
+    let new = with_zip("1:2.00", "b");
+    let diff = Packages::diff(&old, &new);
+    assert!(diff.changed.is_empty(), "{diff}");
+    assert_eq!(names(diff.rebuilt.iter().map(|r| r.new)), ["zip"]);
+
+    let new = with_zip("1:2.0.1", "a");
+    let diff = Packages::diff(&old, &new);
+    assert_eq!(names(diff.changed.iter().map(|c| c.new)), ["zip"]);
+    assert!(diff.changed[0].is_upgrade());
+}
+
+#[test]
+fn keep_verbatim_description_lines() {
+    let stanza = "Package: hello\nVersion: 2.12\nArchitecture: amd64\n\
+                  Filename: pool/stable/main/h/hello_2.12_amd64.deb\nSize: 1024\n\
+                  SHA256: 5d41402abc4b2a76b9719d911017c592ae1f6d2ac0b5d16e1c0e8fa1ab1d6f87\n\
+                  Description: Example program\n Prints a greeting:\n .\n   $ hello\n   \
+                  Hello, world!\n .\n Then exits.\n";
+    let pkg: Package = stanza.parse().unwrap();
+
+    assert_eq!(pkg.summary(), "Example program");
+    assert_eq!(
+        pkg.long_description().as_deref(),
+        Some("Prints a greeting:\n\n  $ hello\n  Hello, world!\n\nThen exits.")
+    );
+
+    // Written back the way it was read.
+    assert_eq!(pkg.to_string(), stanza);
+}
+
+#[test]
+fn end_paragraphs_at_dot_lines() {
+    let pkgs = fixture();
+    let coreutils = pkgs.get("coreutils").unwrap();
+    assert_eq!(
+        coreutils.long_description().as_deref(),
+        Some(
+            "Basic file, shell and text manipulation utilities.\n\n\
+             These are expected to exist on every operating system."
+        )
+    );
+
+    let pkg: Package = "Package: a\nVersion: 1\nArchitecture: all\nFilename: a.deb\nSize: 1\n\
+                        SHA256: 5d41402abc4b2a76b9719d911017c592ae1f6d2ac0b5d16e1c0e8fa1ab1d6f87\n\
+                        Description: a\n .\n"
+        .parse()
+        .unwrap();
+    assert_eq!(pkg.long_description(), None);
+}
+
+#[test]
+fn parse_aosc_features() {
+    use libaosc::packages::AoscFeature;
+
+    let mut pkgs = fixture();
+    let firefox = pkgs.get("firefox").unwrap();
+    assert_eq!(
+        firefox.aosc_features(),
+        [
+            AoscFeature::Custom("audio".to_string()),
+            AoscFeature::Custom("web-browser".to_string())
+        ]
+    );
+
+    for (field, tags) in [
+        ("audio,web-browser", &["audio", "web-browser"][..]),
+        ("audio web-browser", &["audio", "web-browser"]),
+        (
+            "  audio ,\tweb-browser,, video ",
+            &["audio", "web-browser", "video"],
+        ),
+        ("", &[]),
+    ] {
+        for pkg in &mut pkgs {
+            if pkg.package == "firefox" {
+                pkg.features = Some(field.to_string());
+            }
+        }
+        let firefox = pkgs.get("firefox").unwrap();
+        let parsed = firefox.aosc_features();
+        assert_eq!(
+            parsed.iter().map(AoscFeature::as_str).collect::<Vec<_>>(),
+            tags,
+            "{field:?}"
+        );
+        for tag in tags {
+            assert!(firefox.has_feature(tag), "{field:?}");
+            assert!(firefox.has_feature(&format!(" {tag} ")), "{field:?}");
+        }
+        assert!(!firefox.has_feature("web"), "{field:?}");
+    }
+
+    assert_eq!(
+        " audio ".parse(),
+        Ok(AoscFeature::Custom("audio".to_string()))
+    );
+    assert_eq!(AoscFeature::from("audio").to_string(), "audio");
+}
+
+#[test]
+fn filter_by_aosc_feature() {
+    let mut pkgs = fixture();
+    for pkg in &mut pkgs {
+        if pkg.package == "curl" {
+            pkg.features = Some("network web-browser".to_string());
+        }
+    }
+
+    assert_eq!(names(pkgs.with_feature("web-browser")), ["curl", "firefox"]);
+    assert_eq!(names(pkgs.with_feature("audio")), ["firefox"]);
+    assert!(pkgs.with_feature("printing").next().is_none());
+}
+
+#[test]
+fn get_all_entries_of_a_name() {
+    let mut pkgs = fixture();
+    let mut zip = pkgs.get("zip").unwrap().clone();
+    zip.version = "3.1-1".to_string();
+    pkgs.push(zip);
+
+    let versions: Vec<_> = pkgs
+        .get_all("zip")
+        .iter()
+        .map(|pkg| pkg.version.as_str())
+        .collect();
+    assert_eq!(versions, ["3.0-4", "3.1-1"]);
+    assert_eq!(pkgs.get("zip").unwrap().version, "3.0-4");
+    assert!(pkgs.get_all("zip3").is_empty());
+}
+
+/// Entries of the fixture named like `entries`, in the given versions
+fn topic(entries: &[(&str, &str)]) -> Packages {
+    let pkgs = fixture();
+    entries
+        .iter()
+        .map(|&(name, version)| {
+            let mut pkg = pkgs.get(name).unwrap().clone();
+            pkg.version = version.to_string();
+            pkg
+        })
+        .collect()
+}
+
+fn replacements(replaced: &[Replacement]) -> Vec<(&str, &str, &str)> {
+    replaced
+        .iter()
+        .map(|r| {
+            (
+                r.package.as_str(),
+                r.old_version.as_str(),
+                r.new_version.as_str(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn merge_preferring_other() {
+    let mut pkgs = fixture();
+    let mut other = topic(&[("zip", "2.9-1"), ("curl", "8.6.0")]);
+    let mut zstd = pkgs.get("bash").unwrap().clone();
+    zstd.package = "zstd".to_string();
+    other.push(zstd);
+
+    let replaced = pkgs.merge(other, MergeStrategy::PreferOther);
+    // The identical curl is neither reported nor added twice.
+    assert_eq!(replacements(&replaced), [("zip", "3.0-4", "2.9-1")]);
+    assert_eq!(pkgs.len(), 9);
+    assert_eq!(names(pkgs.get_all("zip")), ["zip"]);
+    assert_eq!(pkgs.get("zip").unwrap().version, "2.9-1");
+    assert_eq!(pkgs.get_all("curl").len(), 1);
+    assert!(pkgs.contains("zstd"));
+}
+
+#[test]
+fn merge_preferring_newer_versions() {
+    let mut pkgs = fixture();
+    // By dpkg order 6.0-10 is newer than 6.0-6, and any epoch beats none.
+    let other = topic(&[
+        ("zip", "2.9-1"),
+        ("unzip", "6.0-10"),
+        ("p7zip", "17.06-1"),
+        ("coreutils", "1:9.0-1"),
+    ]);
+
+    let replaced = pkgs.merge(other, MergeStrategy::PreferNewerVersion);
+    assert_eq!(
+        replacements(&replaced),
+        [
+            ("unzip", "6.0-6", "6.0-10"),
+            ("coreutils", "9.4-1", "1:9.0-1")
+        ]
+    );
+    assert_eq!(pkgs.len(), 8);
+    assert_eq!(pkgs.get("zip").unwrap().version, "3.0-4");
+    assert_eq!(pkgs.get("unzip").unwrap().version, "6.0-10");
+    assert_eq!(pkgs.get("p7zip").unwrap().version, "1:17.05-1");
+    assert_eq!(pkgs.get("coreutils").unwrap().version, "1:9.0-1");
+
+    // A tie keeps the existing entries.
+    let replaced = pkgs.merge(
+        topic(&[("zip", "3.0-4")]),
+        MergeStrategy::PreferNewerVersion,
+    );
+    assert!(replaced.is_empty());
+    assert_eq!(pkgs.len(), 8);
+}
+
+#[test]
+fn merge_keeping_both() {
+    let mut pkgs = fixture();
+    let other = topic(&[("zip", "3.1-1"), ("zip", "3.2-1"), ("curl", "8.6.0")]);
+
+    let replaced = pkgs.merge(other, MergeStrategy::KeepBoth);
+    assert!(replaced.is_empty());
+    assert_eq!(pkgs.len(), 10);
+    let versions: Vec<_> = pkgs
+        .get_all("zip")
+        .iter()
+        .map(|pkg| pkg.version.as_str())
+        .collect();
+    assert_eq!(versions, ["3.0-4", "3.1-1", "3.2-1"]);
+    assert_eq!(pkgs.get_all("curl").len(), 1);
+}
+
+#[test]
+fn keep_lookups_current_after_merge() {
+    let mut pkgs = fixture();
+    // Build every index before the merge.
+    assert_eq!(
+        names(pkgs.providers_of("7zip").iter().map(|p| p.package)),
+        ["p7zip"]
+    );
+    assert_eq!(
+        names(pkgs.reverse_depends("libcurl").iter().map(|r| r.package)),
+        ["curl"]
+    );
+    assert!(pkgs.get("zip").is_some());
+
+    let mut other = topic(&[("p7zip", "1:17.06-1"), ("libcurl", "8.7.0")]);
+    for pkg in &mut other {
+        if pkg.package == "p7zip" {
+            pkg.provides = Some("7zip, 7z".to_string());
+        }
+    }
+    let mut curl = pkgs.get("curl").unwrap().clone();
+    curl.package = "curl-minimal".to_string();
+    other.push(curl);
+    pkgs.merge(other, MergeStrategy::PreferOther);
+
+    assert_eq!(
+        names(pkgs.providers_of("7z").iter().map(|p| p.package)),
+        ["p7zip"]
+    );
+    assert_eq!(pkgs.providers_of("7zip")[0].package.version, "1:17.06-1");
+    assert_eq!(
+        names(pkgs.reverse_depends("libcurl").iter().map(|r| r.package)),
+        ["curl", "curl-minimal"]
+    );
+    assert_eq!(pkgs.get("libcurl").unwrap().version, "8.7.0");
+    assert_eq!(pkgs.get_all("libcurl").len(), 1);
+    // Entries moved by the merge are still found at their new positions.
+    for pkg in pkgs.iter() {
+        assert_eq!(pkgs.get(&pkg.package).unwrap(), pkg);
+    }
+}
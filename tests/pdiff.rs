@@ -0,0 +1,191 @@
+#![cfg(any(feature = "blocking", feature = "blocking-ureq", feature = "async"))]
+
+mod common;
+
+use std::time::Duration;
+
+use common::{index_path, Server, TempDir, INDEX};
+use libaosc::packages::{Compression, Packages};
+
+const BASES: [&str; 2] = [
+    include_str!("data/pdiff/Packages.1"),
+    include_str!("data/pdiff/Packages.2"),
+];
+
+const PATCHES: [&str; 2] = ["T-2026-10-01-0800.00", "T-2026-10-08-0800.00"];
+
+/// The files of `Packages.diff/`, whose patches go from each version in [`BASES`] to the next
+/// in the `chained` variant, and straight to the current version in the `merged` one
+fn diff_dir(variant: &str) -> Vec<(String, Vec<u8>)> {
+    let (index, patches): (&[u8], [&[u8]; 2]) = match variant {
+        "chained" => (
+            include_bytes!("data/pdiff/chained/Index"),
+            [
+                include_bytes!("data/pdiff/chained/T-2026-10-01-0800.00.gz"),
+                include_bytes!("data/pdiff/chained/T-2026-10-08-0800.00.gz"),
+            ],
+        ),
+        "merged" => (
+            include_bytes!("data/pdiff/merged/Index"),
+            [
+                include_bytes!("data/pdiff/merged/T-2026-10-01-0800.00.gz"),
+                include_bytes!("data/pdiff/merged/T-2026-10-08-0800.00.gz"),
+            ],
+        ),
+        _ => unreachable!(),
+    };
+
+    let diff_path = |name: &str| index_path("stable", &format!("Packages.diff/{name}"));
+    let mut files = vec![(diff_path("Index"), index.to_vec())];
+    for (name, patch) in PATCHES.iter().zip(patches) {
+        files.push((diff_path(&format!("{name}.gz")), patch.to_vec()));
+    }
+
+    files
+}
+
+fn expected() -> Packages {
+    Packages::try_from(INDEX.as_bytes()).unwrap()
+}
+
+/// Download `base` into `dir` as the local copy of the stable index
+#[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+fn seed_blocking(dir: &TempDir, base: &str) {
+    use libaosc::packages::FetchPackages;
+
+    let server = Server::start([(index_path("stable", "Packages"), base.into())]);
+    FetchPackages::builder()
+        .compression(Compression::None)
+        .mirror_url(&server.url())
+        .system_proxy(false)
+        .download_to(dir.path())
+        .build()
+        .unwrap()
+        .fetch_packages("amd64", "stable")
+        .unwrap();
+}
+
+#[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+fn pdiff_fetcher(server: &Server, dir: &TempDir) -> libaosc::packages::FetchPackages {
+    use libaosc::packages::FetchPackages;
+
+    FetchPackages::builder()
+        .compression(Compression::Zstd)
+        .mirror_url(&server.url())
+        .system_proxy(false)
+        .pdiff(true)
+        .download_to(dir.path())
+        .build()
+        .unwrap()
+}
+
+#[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+#[test]
+fn apply_patches_blocking() {
+    for variant in ["chained", "merged"] {
+        // Only the patches are served, so the update can't fall back to a full download.
+        let server = Server::start(diff_dir(variant));
+        for (version, base) in BASES.iter().enumerate() {
+            let dir = TempDir::new("pdiff-blocking");
+            seed_blocking(&dir, base);
+            let fetch = pdiff_fetcher(&server, &dir);
+
+            let pkgs = fetch
+                .fetch_packages_cached("amd64", "stable", Duration::ZERO)
+                .unwrap_or_else(|e| panic!("{variant} from version {version}: {e}"));
+            assert_eq!(pkgs.as_slice(), expected().as_slice(), "{variant}");
+
+            // The patched copy is current now.
+            let pkgs = fetch
+                .fetch_packages_cached("amd64", "stable", Duration::ZERO)
+                .unwrap();
+            assert_eq!(pkgs.as_slice(), expected().as_slice(), "{variant}");
+        }
+    }
+}
+
+#[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+#[test]
+fn fall_back_without_history_blocking() {
+    let mut files = diff_dir("chained");
+    files.push((
+        index_path("stable", "Packages.zst"),
+        common::INDEX_ZST.to_vec(),
+    ));
+    let server = Server::start(files);
+    let dir = TempDir::new("pdiff-old-blocking");
+    seed_blocking(
+        &dir,
+        &BASES[0].replace("Version: 5.2.21", "Version: 5.2.15"),
+    );
+
+    let pkgs = pdiff_fetcher(&server, &dir)
+        .fetch_packages_cached("amd64", "stable", Duration::ZERO)
+        .unwrap();
+    assert_eq!(pkgs.as_slice(), expected().as_slice());
+}
+
+#[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+#[test]
+fn fall_back_on_bad_patch_blocking() {
+    let mut files = diff_dir("chained");
+    // The second patch no longer matches its hash.
+    files[2].1 = include_bytes!("data/pdiff/merged/T-2026-10-01-0800.00.gz").to_vec();
+    let server = Server::start(files.clone());
+    let dir = TempDir::new("pdiff-bad-blocking");
+    seed_blocking(&dir, BASES[0]);
+
+    let err = pdiff_fetcher(&server, &dir)
+        .fetch_packages_cached("amd64", "stable", Duration::ZERO)
+        .map(drop)
+        .unwrap_err();
+    assert!(err.to_string().contains("404"), "unexpected error {err}");
+
+    files.push((
+        index_path("stable", "Packages.zst"),
+        common::INDEX_ZST.to_vec(),
+    ));
+    let server = Server::start(files);
+    let pkgs = pdiff_fetcher(&server, &dir)
+        .fetch_packages_cached("amd64", "stable", Duration::ZERO)
+        .unwrap();
+    assert_eq!(pkgs.as_slice(), expected().as_slice());
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn apply_patches_async() {
+    use libaosc::packages::FetchPackagesAsync;
+
+    for variant in ["chained", "merged"] {
+        let server = Server::start(diff_dir(variant));
+        for (version, base) in BASES.iter().enumerate() {
+            let seed = Server::start([(index_path("stable", "Packages"), base.as_bytes().into())]);
+            let dir = TempDir::new("pdiff-async");
+            let builder = || {
+                FetchPackagesAsync::builder()
+                    .system_proxy(false)
+                    .download_to(dir.path())
+            };
+            builder()
+                .compression(Compression::None)
+                .mirror_url(&seed.url())
+                .build()
+                .unwrap()
+                .fetch_packages("amd64", "stable")
+                .await
+                .unwrap();
+
+            let pkgs = builder()
+                .compression(Compression::Zstd)
+                .mirror_url(&server.url())
+                .pdiff(true)
+                .build()
+                .unwrap()
+                .fetch_packages_cached("amd64", "stable", Duration::ZERO)
+                .await
+                .unwrap_or_else(|e| panic!("{variant} from version {version}: {e}"));
+            assert_eq!(pkgs.as_slice(), expected().as_slice(), "{variant}");
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use libaosc::{
+    packages::{Package, Packages},
+    query::Query,
+};
+
+fn fixture() -> Packages {
+    include_str!("data/Packages").parse().unwrap()
+}
+
+fn names(pkgs: Vec<&Package>) -> Vec<&str> {
+    pkgs.into_iter().map(|pkg| pkg.package.as_str()).collect()
+}
+
+#[test]
+fn match_names() {
+    let pkgs = fixture();
+    assert_eq!(names(pkgs.filter(&Query::new().name("zip"))), ["zip"]);
+    assert_eq!(
+        names(pkgs.filter(&Query::new().name_prefix("c"))),
+        ["coreutils", "curl"]
+    );
+    assert_eq!(
+        names(pkgs.filter(&Query::new().name_contains("zip"))),
+        ["zip", "unzip", "p7zip"]
+    );
+    assert_eq!(
+        names(pkgs.filter(&Query::new().name_contains("curl").name_prefix("lib"))),
+        ["libcurl"]
+    );
+    assert!(pkgs.filter(&Query::new().name("zi")).is_empty());
+    assert_eq!(pkgs.filter(&Query::new()).len(), pkgs.len());
+}
+
+#[test]
+fn match_sections_and_maintainers() {
+    let mut pkgs = fixture();
+    for pkg in &mut pkgs {
+        match pkg.package.as_str() {
+            "zip" => pkg.section = None,
+            "unzip" => pkg.maintainer = Some("Jane Doe <jane@example.org>".to_string()),
+            "p7zip" => pkg.maintainer = None,
+            _ => {}
+        }
+    }
+
+    assert_eq!(
+        names(pkgs.filter(&Query::new().section("utils"))),
+        ["coreutils", "unzip", "p7zip"]
+    );
+    assert!(pkgs.filter(&Query::new().section("util")).is_empty());
+    assert_eq!(
+        names(pkgs.filter(&Query::new().maintainer_contains("example.org"))),
+        ["unzip"]
+    );
+    // A package without a maintainer contains no part of one, so only the negation matches.
+    let aosc = Query::new().maintainer_contains("aosc.io");
+    assert_eq!(pkgs.filter(&aosc).len(), 6);
+    assert_eq!(names(pkgs.filter(&!aosc)), ["unzip", "p7zip"]);
+}
+
+#[test]
+fn match_size_ranges() {
+    let pkgs = fixture();
+    assert_eq!(
+        names(pkgs.filter(&Query::new().size_range(..500_000))),
+        ["zip", "unzip"]
+    );
+    assert_eq!(
+        names(pkgs.filter(&Query::new().size_range(10_000_000..))),
+        ["firefox"]
+    );
+    // Both ends of an inclusive range are in it, the end of an exclusive one isn't.
+    assert_eq!(
+        names(pkgs.filter(&Query::new().size_range(160_412..=221_004))),
+        ["zip", "unzip"]
+    );
+    assert_eq!(
+        names(pkgs.filter(&Query::new().size_range(160_412..221_004))),
+        ["unzip"]
+    );
+    assert!(pkgs.filter(&Query::new().size_range(1..1)).is_empty());
+}
+
+#[test]
+fn combine_queries() {
+    let mut pkgs = fixture();
+    for pkg in &mut pkgs {
+        if pkg.package == "p7zip" {
+            pkg.architecture = "noarch".to_string();
+        }
+    }
+    let utils = || Query::new().section("utils");
+
+    let query = utils()
+        .name_contains("zip")
+        .and(!Query::new().architecture("noarch"));
+    assert_eq!(names(pkgs.filter(&query)), ["zip", "unzip"]);
+
+    let query = utils().name("zip").or(Query::new().section("net"));
+    assert_eq!(names(pkgs.filter(&query)), ["zip", "curl"]);
+
+    // Conditions added after `or` apply to both sides.
+    let query = Query::new()
+        .name_prefix("un")
+        .or(Query::new().name_prefix("p7"))
+        .size_range(..1_000_000);
+    assert_eq!(names(pkgs.filter(&query)), ["unzip"]);
+
+    let query = !(utils().or(Query::new().section("libs")));
+    assert_eq!(names(pkgs.filter(&query)), ["bash", "curl", "firefox"]);
+    assert_eq!(names(pkgs.filter(&!!utils())), names(pkgs.filter(&utils())));
+    assert!(pkgs.filter(&utils().and(!utils())).is_empty());
+}
@@ -0,0 +1,129 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libaosc::release::Release;
+
+const RELEASE: &str = include_str!("data/release/Release");
+const IN_RELEASE: &str = include_str!("data/release/InRelease");
+
+/// 2020-01-02 00:00:00 UTC, the `Date` of the fixtures
+const FIXTURE_DATE: u64 = 1577923200;
+
+/// A minimal release file with the given `Date` and further fields
+fn release(date: &str, fields: &str) -> String {
+    format!(
+        "Suite: stable\nDate: {date}\nArchitectures: amd64\nComponents: main\n{fields}SHA256:\n"
+    )
+}
+
+fn date_of(date: &str) -> Option<SystemTime> {
+    release(date, "")
+        .parse::<Release>()
+        .ok()
+        .map(|release| release.date)
+}
+
+fn at(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+#[test]
+fn parse_plain_release() {
+    let release: Release = RELEASE.parse().unwrap();
+    assert_eq!(release.origin.as_deref(), Some("AOSC"));
+    assert_eq!(release.label.as_deref(), Some("AOSC OS"));
+    assert_eq!(release.suite.as_deref(), Some("stable"));
+    assert_eq!(release.date, at(FIXTURE_DATE));
+    assert_eq!(release.valid_until, None);
+    assert_eq!(release.architectures, ["amd64"]);
+    assert_eq!(release.components, ["main"]);
+    assert_eq!(release.acquire_by_hash, None);
+    assert_eq!(release.sha256.len(), 2);
+    assert_eq!(release.sha256["main/binary-amd64/Packages"].size, 3466);
+}
+
+#[test]
+fn parse_clearsigned_release() {
+    let release: Release = IN_RELEASE.parse().unwrap();
+    let plain: Release = RELEASE.parse().unwrap();
+    assert_eq!(release.suite, plain.suite);
+    assert_eq!(release.date, plain.date);
+    assert_eq!(release.sha256, plain.sha256);
+
+    // Signers may dash-escape any line, not only those starting with a dash.
+    let escaped = "-----BEGIN PGP SIGNED MESSAGE-----\n\
+                   Hash: SHA256\n\
+                   \n\
+                   - Suite: stable\n\
+                   Date: Thu, 02 Jan 2020 00:00:00 UTC\n\
+                   Architectures: amd64\n\
+                   - Components: main contrib\n\
+                   SHA256:\n\
+                   -----BEGIN PGP SIGNATURE-----\n\
+                   \n\
+                   -----END PGP SIGNATURE-----\n";
+    let release: Release = escaped.parse().unwrap();
+    assert_eq!(release.suite.as_deref(), Some("stable"));
+    assert_eq!(release.components, ["main", "contrib"]);
+
+    // The signed text has to end with a signature.
+    let unterminated = escaped.split("-----BEGIN PGP SIGNATURE").next().unwrap();
+    assert!(unterminated.parse::<Release>().is_err());
+}
+
+#[test]
+fn parse_dates_with_offsets() {
+    for (date, secs) in [
+        ("Thu, 02 Jan 2020 00:00:00 UTC", FIXTURE_DATE),
+        ("Thu, 02 Jan 2020 00:00:00 GMT", FIXTURE_DATE),
+        ("Thu, 02 Jan 2020 00:00:00 Z", FIXTURE_DATE),
+        ("Thu, 02 Jan 2020 00:00:00", FIXTURE_DATE),
+        ("02 Jan 2020 00:00:00 UTC", FIXTURE_DATE),
+        ("Thu, 02 Jan 2020 08:00:00 +0800", FIXTURE_DATE),
+        ("Wed, 01 Jan 2020 22:30:00 -0130", FIXTURE_DATE),
+        ("Thu, 29 Feb 2024 12:00:00 UTC", 1709208000),
+        // A leap second is taken as the first second of the next minute.
+        ("Wed, 31 Dec 2025 23:59:60 UTC", 1767225600),
+    ] {
+        assert_eq!(date_of(date), Some(at(secs)), "{date}");
+    }
+}
+
+#[test]
+fn reject_out_of_range_dates() {
+    for date in [
+        "Thu, 02 Jan 2020 24:00:00 UTC",
+        "Thu, 02 Jan 2020 -1:00:00 UTC",
+        "Thu, 02 Jan 2020 00:60:00 UTC",
+        "Thu, 02 Jan 2020 00:-1:00 UTC",
+        "Thu, 02 Jan 2020 00:00:61 UTC",
+        "Thu, 02 Jan 2020 00:00:-1 UTC",
+        "Thu, 00 Jan 2020 00:00:00 UTC",
+        "Thu, 32 Jan 2020 00:00:00 UTC",
+        "Thu, 02 Foo 2020 00:00:00 UTC",
+        "Thu, 02 Jan 2020 00:00 UTC",
+        "Thu, 02 Jan 2020 00:00:00 +08",
+        "Thu, 02 Jan 2020 00:00:00 CEST",
+        "Thu, 01 Jan 1970 00:00:00 +0100",
+    ] {
+        assert_eq!(date_of(date), None, "{date}");
+    }
+}
+
+#[test]
+fn expire_after_valid_until() {
+    let release: Release = release(
+        "Thu, 02 Jan 2020 00:00:00 UTC",
+        "Valid-Until: Thu, 09 Jan 2020 00:00:00 UTC\n",
+    )
+    .parse()
+    .unwrap();
+    let valid_until = at(FIXTURE_DATE + 7 * 86400);
+    assert_eq!(release.valid_until, Some(valid_until));
+    assert!(!release.is_expired(at(FIXTURE_DATE)));
+    assert!(!release.is_expired(valid_until));
+    assert!(release.is_expired(valid_until + Duration::from_secs(1)));
+
+    // Without Valid-Until, a release file never expires.
+    let release: Release = RELEASE.parse().unwrap();
+    assert!(!release.is_expired(at(u32::MAX as u64)));
+}
@@ -0,0 +1,66 @@
+#![cfg(feature = "serde")]
+
+use libaosc::{
+    dependency::DependencyGroup,
+    packages::{Package, Packages},
+    release::Release,
+    version::PkgVersion,
+};
+
+fn fixture() -> Packages {
+    Packages::try_from(include_bytes!("data/Packages").as_slice()).unwrap()
+}
+
+#[test]
+fn round_trip_packages_through_json() {
+    let pkgs = fixture();
+    let json = serde_json::to_string(&pkgs).unwrap();
+    let parsed: Packages = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed.as_slice(), pkgs.as_slice());
+
+    // A plain sequence, so it can be read without this crate.
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(value.as_array().map(Vec::len), Some(pkgs.len()));
+    assert_eq!(value[0]["package"], "bash");
+}
+
+#[test]
+fn round_trip_optional_fields() {
+    let pkgs = fixture();
+    let pkg = pkgs.get("zip").unwrap();
+    assert!(pkg.features.is_none());
+
+    let mut value = serde_json::to_value(pkg).unwrap();
+    assert!(value["features"].is_null());
+    let parsed: Package = serde_json::from_value(value.clone()).unwrap();
+    assert_eq!(&parsed, pkg);
+
+    // Absent works as well as null.
+    value.as_object_mut().unwrap().remove("features");
+    let parsed: Package = serde_json::from_value(value).unwrap();
+    assert_eq!(&parsed, pkg);
+}
+
+#[test]
+fn round_trip_dependencies_and_versions() {
+    for pkg in fixture().iter() {
+        let depends = pkg.parse_depends().unwrap();
+        let json = serde_json::to_string(&depends).unwrap();
+        let parsed: Vec<DependencyGroup> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, depends, "{}", pkg.package);
+
+        let version = pkg.parsed_version().unwrap();
+        let json = serde_json::to_string(&version).unwrap();
+        let parsed: PkgVersion = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.to_string(), version.to_string());
+    }
+}
+
+#[test]
+fn round_trip_release() {
+    let release: Release = include_str!("data/release/Release").parse().unwrap();
+    let value = serde_json::to_value(&release).unwrap();
+    let parsed: Release = serde_json::from_value(value.clone()).unwrap();
+    assert_eq!(serde_json::to_value(&parsed).unwrap(), value);
+    assert_eq!(parsed.suite.as_deref(), Some("stable"));
+}
@@ -0,0 +1,253 @@
+#![cfg(feature = "verify")]
+
+mod common;
+
+use std::fs;
+
+use libaosc::{
+    keyring::{system_keyring, Cert, KeyringError},
+    release::{Release, SignatureError},
+};
+
+const ARCHIVE_ASC: &[u8] = include_bytes!("data/keyring/archive.asc");
+const ARCHIVE_GPG: &[u8] = include_bytes!("data/keyring/archive.gpg");
+const RELEASE: &[u8] = include_bytes!("data/release/Release");
+const RELEASE_GPG: &[u8] = include_bytes!("data/release/Release.gpg");
+const IN_RELEASE: &str = include_str!("data/release/InRelease");
+
+/// The signing subkey of the test archive key
+const ARCHIVE_SIGNING_KEY: &str = "8F19AA4B7E336070F31CF92A5400386380A740A0";
+
+fn keyring() -> Vec<Cert> {
+    Cert::from_bytes(ARCHIVE_ASC).unwrap()
+}
+
+#[test]
+fn parse_armored_and_binary_keyrings() {
+    let armored = Cert::from_bytes(ARCHIVE_ASC).unwrap();
+    let binary = Cert::from_bytes(ARCHIVE_GPG).unwrap();
+    assert_eq!(armored.len(), 1);
+    assert_eq!(
+        armored[0].fingerprint(),
+        "BB23843710FE3FEE6062FF3CF3985AA7848029AA"
+    );
+    assert_eq!(binary[0].fingerprint(), armored[0].fingerprint());
+
+    assert!(Cert::from_bytes(b"not a keyring").is_err());
+}
+
+#[test]
+fn load_system_keyring() {
+    let sysroot = common::TempDir::new("system-keyring");
+    let dir = sysroot.path().join("etc/apt/trusted.gpg.d");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("aosc-archive.gpg"), ARCHIVE_GPG).unwrap();
+    fs::write(
+        dir.join("other.asc"),
+        include_bytes!("data/keyring/unknown.asc"),
+    )
+    .unwrap();
+    fs::write(dir.join("broken.asc"), "not a keyring").unwrap();
+    fs::write(dir.join("README"), "not a keyring either").unwrap();
+
+    let keyring = system_keyring(sysroot.path());
+    let fingerprints: Vec<_> = keyring.certs.iter().map(Cert::fingerprint).collect();
+    assert_eq!(
+        fingerprints,
+        [
+            "BB23843710FE3FEE6062FF3CF3985AA7848029AA",
+            "B7D03590AC8155DB5366E14C4D149EB50D6FFF38"
+        ]
+    );
+    assert_eq!(keyring.warnings.len(), 1, "{:?}", keyring.warnings);
+    assert_eq!(keyring.warnings[0].path, dir.join("broken.asc"));
+    assert!(matches!(
+        keyring.warnings[0].source,
+        KeyringError::Malformed(_)
+    ));
+}
+
+#[test]
+fn warn_about_missing_trust_store() {
+    let sysroot = common::TempDir::new("no-keyring");
+
+    let keyring = system_keyring(sysroot.path());
+    assert!(keyring.certs.is_empty());
+    assert_eq!(keyring.warnings.len(), 1);
+    assert_eq!(
+        keyring.warnings[0].path,
+        sysroot.path().join("etc/apt/trusted.gpg.d")
+    );
+    assert!(matches!(keyring.warnings[0].source, KeyringError::Io(_)));
+}
+
+#[test]
+fn verify_in_release() {
+    let release = Release::from_inrelease_verified(IN_RELEASE.as_bytes(), &keyring()).unwrap();
+    assert_eq!(release.suite.as_deref(), Some("stable"));
+    assert_eq!(release.sha256.len(), 2);
+}
+
+#[test]
+fn refuse_unsigned_release() {
+    let err = Release::from_inrelease_verified(RELEASE, &keyring()).unwrap_err();
+    assert!(matches!(err, SignatureError::Unsigned), "{err:?}");
+}
+
+#[test]
+fn refuse_unknown_key() {
+    let data = include_bytes!("data/release/InRelease.unknown");
+    let err = Release::from_inrelease_verified(data, &keyring()).unwrap_err();
+    assert!(
+        matches!(err, SignatureError::UnknownKey(ref key) if key == "B7D03590AC8155DB5366E14C4D149EB50D6FFF38"),
+        "{err:?}"
+    );
+
+    // The same file passes with the key that signed it.
+    let unknown = Cert::from_bytes(include_bytes!("data/keyring/unknown.asc")).unwrap();
+    Release::from_inrelease_verified(data, &unknown).unwrap();
+}
+
+#[test]
+fn refuse_expired_signature() {
+    let data = include_bytes!("data/release/InRelease.expired");
+    let err = Release::from_inrelease_verified(data, &keyring()).unwrap_err();
+    assert!(
+        matches!(err, SignatureError::Expired { ref key, .. } if key == ARCHIVE_SIGNING_KEY),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn refuse_tampered_in_release() {
+    let data = IN_RELEASE.replace("Suite: stable", "Suite: sid");
+    let err = Release::from_inrelease_verified(data.as_bytes(), &keyring()).unwrap_err();
+    assert!(
+        matches!(err, SignatureError::BadSignature(ref key) if key == ARCHIVE_SIGNING_KEY),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn verify_detached_release() {
+    let release = Release::from_release_verified(RELEASE, RELEASE_GPG, &keyring()).unwrap();
+    assert_eq!(release.suite.as_deref(), Some("stable"));
+
+    let tampered = String::from_utf8(RELEASE.to_vec())
+        .unwrap()
+        .replace("Suite: stable", "Suite: sid");
+    let err =
+        Release::from_release_verified(tampered.as_bytes(), RELEASE_GPG, &keyring()).unwrap_err();
+    assert!(matches!(err, SignatureError::BadSignature(_)), "{err:?}");
+
+    let err = Release::from_release_verified(RELEASE, b"", &keyring()).unwrap_err();
+    assert!(matches!(err, SignatureError::Malformed(_)), "{err:?}");
+}
+
+#[cfg(any(feature = "blocking", feature = "blocking-ureq", feature = "async"))]
+fn mirror(files: &[(&str, &[u8])]) -> common::Server {
+    common::Server::start(files.iter().map(|(name, data)| {
+        let path = match *name {
+            "Packages.zst" => common::index_path("stable", name),
+            _ => format!("/dists/stable/{name}"),
+        };
+        (path, data.to_vec())
+    }))
+}
+
+#[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+fn blocking_fetcher(
+    server: &common::Server,
+    dir: &common::TempDir,
+) -> libaosc::packages::FetchPackages {
+    use libaosc::packages::{Compression, FetchPackages};
+
+    FetchPackages::builder()
+        .compression(Compression::Zstd)
+        .mirror_url(&server.url())
+        .system_proxy(false)
+        .download_to(dir.path())
+        .with_keyring(keyring())
+        .build()
+        .unwrap()
+}
+
+#[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+#[test]
+fn fetch_verified_packages_blocking() {
+    let server = mirror(&[
+        ("InRelease", IN_RELEASE.as_bytes()),
+        ("Packages.zst", common::INDEX_ZST),
+    ]);
+    let dir = common::TempDir::new("verify-blocking");
+    let fetch = blocking_fetcher(&server, &dir);
+
+    let pkgs = fetch.fetch_packages("amd64", "stable").unwrap();
+    assert_eq!(pkgs.len(), common::INDEX.matches("Package: ").count());
+}
+
+#[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+#[test]
+fn fetch_detached_release_blocking() {
+    let server = mirror(&[("Release", RELEASE), ("Release.gpg", RELEASE_GPG)]);
+    let dir = common::TempDir::new("verify-detached-blocking");
+    let fetch = blocking_fetcher(&server, &dir);
+
+    let release = fetch.fetch_release("stable").unwrap();
+    assert_eq!(release.suite.as_deref(), Some("stable"));
+}
+
+#[cfg(any(feature = "blocking", feature = "blocking-ureq"))]
+#[test]
+fn refuse_unsigned_metadata_blocking() {
+    use libaosc::packages::FetchPackagesError;
+
+    let server = mirror(&[("InRelease", RELEASE), ("Packages.zst", common::INDEX_ZST)]);
+    let dir = common::TempDir::new("verify-unsigned-blocking");
+    let fetch = blocking_fetcher(&server, &dir);
+
+    let err = fetch
+        .fetch_packages("amd64", "stable")
+        .map(drop)
+        .unwrap_err();
+    assert!(
+        matches!(err, FetchPackagesError::Signature(SignatureError::Unsigned)),
+        "{err:?}"
+    );
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn refuse_unknown_key_async() {
+    use libaosc::packages::{Compression, FetchPackagesAsync, FetchPackagesError};
+
+    let server = mirror(&[
+        (
+            "InRelease",
+            include_bytes!("data/release/InRelease.unknown"),
+        ),
+        ("Packages.zst", common::INDEX_ZST),
+    ]);
+    let dir = common::TempDir::new("verify-unknown-async");
+    let fetch = FetchPackagesAsync::builder()
+        .compression(Compression::Zstd)
+        .mirror_url(&server.url())
+        .system_proxy(false)
+        .download_to(dir.path())
+        .with_keyring(keyring())
+        .build()
+        .unwrap();
+
+    let err = fetch
+        .fetch_packages("amd64", "stable")
+        .await
+        .map(drop)
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            FetchPackagesError::Signature(SignatureError::UnknownKey(_))
+        ),
+        "{err:?}"
+    );
+}
@@ -0,0 +1,60 @@
+use std::cmp::Ordering;
+
+use libaosc::version::{compare_versions, PkgVersion};
+
+/// The pairs of `data/versions` with the order dpkg gives them
+fn corpus() -> Vec<(&'static str, Ordering, &'static str)> {
+    include_str!("data/versions")
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut parts = line.split(' ');
+            let (a, op, b) = (parts.next(), parts.next(), parts.next());
+            let ordering = match op {
+                Some("<") => Ordering::Less,
+                Some("=") => Ordering::Equal,
+                Some(">") => Ordering::Greater,
+                _ => panic!("malformed line {line}"),
+            };
+            (a.unwrap(), ordering, b.unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn compare_like_dpkg() {
+    for (a, ordering, b) in corpus() {
+        assert_eq!(compare_versions(a, b), ordering, "{a} vs {b}");
+        assert_eq!(compare_versions(b, a), ordering.reverse(), "{b} vs {a}");
+    }
+}
+
+#[test]
+fn order_parsed_versions_like_dpkg() {
+    for (a, ordering, b) in corpus() {
+        let (a_version, b_version): (PkgVersion, PkgVersion) =
+            (a.parse().unwrap(), b.parse().unwrap());
+        assert_eq!(a_version.cmp(&b_version), ordering, "{a} vs {b}");
+        assert_eq!(b_version.cmp(&a_version), ordering.reverse(), "{b} vs {a}");
+        assert_eq!(a_version == b_version, ordering.is_eq(), "{a} vs {b}");
+    }
+}
+
+#[test]
+fn display_parsed_versions() {
+    for (a, _, _) in corpus() {
+        let version: PkgVersion = a.parse().unwrap();
+        let shown = version.to_string();
+        assert_eq!(shown.trim_start_matches("0:"), a.trim_start_matches("0:"));
+        assert_eq!(shown.parse::<PkgVersion>().unwrap(), version, "{a}");
+    }
+}
+
+#[test]
+fn refuse_malformed_versions() {
+    for version in [
+        "", "1.0-", ":1.0", "a:1.0", "1:", "1:-1", "1.0_1", "1.0-1:2",
+    ] {
+        assert!(version.parse::<PkgVersion>().is_err(), "{version}");
+    }
+}